@@ -0,0 +1,60 @@
+CREATE TYPE order_status AS ENUM (
+    'placed',
+    'paid',
+    'packed',
+    'shipped',
+    'delivered',
+    'returned',
+    'cancelled'
+);
+
+ALTER TABLE "order_items" ADD COLUMN status order_status NOT NULL DEFAULT 'placed';
+UPDATE "order_items" SET status = 'shipped' WHERE dispatched = TRUE;
+ALTER TABLE "order_items" DROP COLUMN dispatched;
+
+ALTER TABLE "order" ADD COLUMN status order_status NOT NULL DEFAULT 'placed';
+
+CREATE TABLE IF NOT EXISTS "order_status_history" (
+    history_id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    order_id UUID NOT NULL,
+    item_id UUID NOT NULL,
+    from_status order_status,
+    to_status order_status NOT NULL,
+    changed_by UUID,
+    changed_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (order_id, item_id) REFERENCES "order_items"(order_id, item_id) ON DELETE CASCADE,
+    FOREIGN KEY (changed_by) REFERENCES "user"(user_id) ON DELETE SET NULL
+);
+
+CREATE INDEX IF NOT EXISTS order_status_history_order_idx ON "order_status_history"(order_id, changed_at);
+
+-- The order takes the least advanced status of its live items,
+-- and is only cancelled once every item in it is cancelled.
+CREATE OR REPLACE FUNCTION order_status_rollup() RETURNS TRIGGER LANGUAGE plpgsql AS $$
+BEGIN
+    UPDATE "order"
+    SET status = COALESCE(
+        (SELECT MIN(status) FROM "order_items"
+         WHERE "order_items"."order_id" = NEW.order_id AND status <> 'cancelled'),
+        'cancelled'
+    )
+    WHERE "order"."order_id" = NEW.order_id;
+    RETURN NEW;
+END;
+$$;
+
+CREATE TRIGGER order_status_trigger
+    AFTER INSERT OR UPDATE OF status
+    ON "order_items"
+    FOR EACH ROW
+    EXECUTE FUNCTION order_status_rollup();
+
+UPDATE "order" SET status = COALESCE(
+    (SELECT MIN(status) FROM "order_items"
+     WHERE "order_items"."order_id" = "order"."order_id" AND status <> 'cancelled'),
+    'placed'
+);
+
+INSERT INTO "order_status_history" ("order_id","item_id","from_status","to_status","changed_at")
+SELECT oi."order_id", oi."item_id", NULL, oi."status", o."order_date"
+FROM "order_items" oi INNER JOIN "order" o ON oi."order_id" = o."order_id";
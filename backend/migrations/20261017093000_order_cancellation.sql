@@ -0,0 +1,5 @@
+ALTER TABLE "order_items" ADD COLUMN cancelled_by UUID;
+ALTER TABLE "order_items" ADD COLUMN cancellation_reason TEXT;
+ALTER TABLE "order_items" ADD COLUMN cancelled_at TIMESTAMP;
+ALTER TABLE "order_items"
+    ADD FOREIGN KEY (cancelled_by) REFERENCES "user"(user_id) ON DELETE SET NULL;
@@ -0,0 +1,59 @@
+ALTER TABLE "order_items" ADD COLUMN title VARCHAR(100);
+ALTER TABLE "order_items" ADD COLUMN unit_price NUMERIC;
+
+-- Orders placed before prices were captured take the current item price.
+UPDATE "order_items" SET
+    title = "item"."title",
+    unit_price = "item"."price"
+FROM "item" WHERE "item"."item_id" = "order_items"."item_id";
+
+ALTER TABLE "order_items" ALTER COLUMN title SET NOT NULL;
+ALTER TABLE "order_items" ALTER COLUMN unit_price SET NOT NULL;
+ALTER TABLE "order_items"
+    ADD COLUMN line_total NUMERIC GENERATED ALWAYS AS (unit_price * quantity) STORED;
+
+-- total is kept separate from subtotal so that fees and discounts can be
+-- added to an order later without changing what subtotal means.
+ALTER TABLE "order" ADD COLUMN subtotal NUMERIC NOT NULL DEFAULT 0;
+ALTER TABLE "order" ADD COLUMN total NUMERIC NOT NULL DEFAULT 0;
+
+UPDATE "order" SET
+    subtotal = t."subtotal",
+    total = t."subtotal"
+FROM (
+    SELECT "order_id", SUM("line_total") AS "subtotal" FROM "order_items" GROUP BY "order_id"
+) AS t
+WHERE t."order_id" = "order"."order_id";
+
+CREATE OR REPLACE FUNCTION order_items_snapshot_guard() RETURNS TRIGGER LANGUAGE plpgsql AS $$
+BEGIN
+    IF NEW.title IS DISTINCT FROM OLD.title
+        OR NEW.unit_price IS DISTINCT FROM OLD.unit_price
+        OR NEW.quantity IS DISTINCT FROM OLD.quantity THEN
+        RAISE EXCEPTION 'order item price snapshot is immutable';
+    END IF;
+    RETURN NEW;
+END;
+$$;
+
+CREATE TRIGGER order_items_snapshot_trigger
+    BEFORE UPDATE
+    ON "order_items"
+    FOR EACH ROW
+    EXECUTE FUNCTION order_items_snapshot_guard();
+
+CREATE OR REPLACE FUNCTION order_totals_guard() RETURNS TRIGGER LANGUAGE plpgsql AS $$
+BEGIN
+    IF NEW.subtotal IS DISTINCT FROM OLD.subtotal
+        OR NEW.total IS DISTINCT FROM OLD.total THEN
+        RAISE EXCEPTION 'order totals are immutable';
+    END IF;
+    RETURN NEW;
+END;
+$$;
+
+CREATE TRIGGER order_totals_trigger
+    BEFORE UPDATE
+    ON "order"
+    FOR EACH ROW
+    EXECUTE FUNCTION order_totals_guard();
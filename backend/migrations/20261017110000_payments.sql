@@ -0,0 +1,28 @@
+-- New orders wait in pending_payment until their payment is captured.
+-- The value is only added here, it cannot be used in the same transaction.
+ALTER TYPE order_status ADD VALUE IF NOT EXISTS 'pending_payment' BEFORE 'placed';
+
+CREATE TYPE payment_status AS ENUM (
+    'pending',
+    'authorized',
+    'captured',
+    'declined',
+    'failed',
+    'refunded'
+);
+
+CREATE TABLE IF NOT EXISTS "payment" (
+    payment_id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    order_id UUID NOT NULL,
+    provider VARCHAR(50) NOT NULL,
+    provider_reference VARCHAR(255) UNIQUE,
+    amount NUMERIC NOT NULL,
+    refunded_amount NUMERIC NOT NULL DEFAULT 0,
+    status payment_status NOT NULL DEFAULT 'pending',
+    failure_reason TEXT,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (order_id) REFERENCES "order"(order_id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS payment_order_idx ON "payment"(order_id);
@@ -0,0 +1,38 @@
+CREATE TABLE IF NOT EXISTS "stock_reservation" (
+    cart_id UUID NOT NULL,
+    item_id UUID NOT NULL,
+    quantity INTEGER NOT NULL CHECK (quantity > 0),
+    expires_at TIMESTAMP NOT NULL,
+    FOREIGN KEY (cart_id) REFERENCES "user"(user_id) ON DELETE CASCADE,
+    FOREIGN KEY (item_id) REFERENCES "item"(item_id) ON DELETE CASCADE,
+    PRIMARY KEY (cart_id, item_id)
+);
+
+CREATE INDEX IF NOT EXISTS stock_reservation_item_idx ON "stock_reservation"(item_id, expires_at);
+
+-- Units held by other carts' unexpired reservations are not available.
+-- A cart's own reservation never counts against it.
+CREATE OR REPLACE FUNCTION stock_validation(item_id UUID, quantity INT, cart_id UUID) RETURNS BOOLEAN AS $$
+DECLARE
+    id_1 alias for $1;
+    quantity_1 alias for $2;
+    cart_1 alias for $3;
+    result BOOLEAN;
+BEGIN
+    SELECT EXISTS (
+        SELECT * FROM "stock" WHERE "stock"."item_id" = id_1 AND "stock"."quantity" - (
+            SELECT COALESCE(SUM("stock_reservation"."quantity"),0) FROM "stock_reservation"
+            WHERE "stock_reservation"."item_id" = id_1
+            AND "stock_reservation"."expires_at" > CURRENT_TIMESTAMP
+            AND "stock_reservation"."cart_id" IS DISTINCT FROM cart_1
+        ) >= quantity_1
+    ) INTO result ;
+    RETURN result;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION stock_validation(item_id UUID, quantity INT) RETURNS BOOLEAN AS $$
+BEGIN
+    RETURN stock_validation($1, $2, NULL::UUID);
+END;
+$$ LANGUAGE plpgsql;
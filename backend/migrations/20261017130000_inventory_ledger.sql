@@ -0,0 +1,76 @@
+CREATE TYPE movement_reason AS ENUM (
+    'restock',
+    'sale',
+    'cancellation',
+    'correction',
+    'return'
+);
+
+CREATE TABLE IF NOT EXISTS "inventory_movement" (
+    movement_id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    -- orders movements made within the same transaction
+    seq BIGSERIAL NOT NULL,
+    item_id UUID NOT NULL,
+    delta INT NOT NULL CHECK (delta <> 0),
+    reason movement_reason NOT NULL,
+    balance_after INT NOT NULL,
+    order_id UUID,
+    note TEXT,
+    created_by UUID,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (item_id) REFERENCES "item"(item_id) ON DELETE CASCADE,
+    FOREIGN KEY (order_id) REFERENCES "order"(order_id) ON DELETE SET NULL,
+    FOREIGN KEY (created_by) REFERENCES "user"(user_id) ON DELETE SET NULL
+);
+
+CREATE INDEX IF NOT EXISTS inventory_movement_item_idx ON "inventory_movement"(item_id, seq);
+
+-- Existing stock becomes the opening balance of each item's ledger
+INSERT INTO "inventory_movement" ("item_id","delta","reason","balance_after","note")
+SELECT "item_id","quantity",'correction',"quantity",'Opening balance'
+FROM "stock" WHERE "quantity" <> 0;
+
+UPDATE "stock" SET "quantity" = 0 WHERE "quantity" < 0;
+ALTER TABLE "stock" ADD CONSTRAINT stock_quantity_non_negative CHECK (quantity >= 0);
+
+-- Every movement is applied to the stock balance as it is recorded
+CREATE OR REPLACE FUNCTION apply_inventory_movement() RETURNS TRIGGER LANGUAGE plpgsql AS $$
+BEGIN
+    INSERT INTO "stock" ("item_id","quantity") VALUES (NEW.item_id, NEW.delta)
+    ON CONFLICT ("item_id") DO UPDATE SET "quantity" = "stock"."quantity" + EXCLUDED."quantity"
+    RETURNING "quantity" INTO NEW.balance_after;
+    RETURN NEW;
+END;
+$$;
+
+CREATE TRIGGER inventory_movement_apply_trigger
+    BEFORE INSERT
+    ON "inventory_movement"
+    FOR EACH ROW
+    EXECUTE FUNCTION apply_inventory_movement();
+
+-- Stock may only be written by the movement trigger, and movements may only
+-- be changed by cascades from the rows they reference.
+CREATE OR REPLACE FUNCTION ledger_guard() RETURNS TRIGGER LANGUAGE plpgsql AS $$
+BEGIN
+    IF pg_trigger_depth() <= 1 THEN
+        RAISE EXCEPTION '% on % must go through inventory_movement', TG_OP, TG_TABLE_NAME;
+    END IF;
+    IF TG_OP = 'DELETE' THEN
+        RETURN OLD;
+    END IF;
+    RETURN NEW;
+END;
+$$;
+
+CREATE TRIGGER stock_ledger_trigger
+    BEFORE INSERT OR UPDATE
+    ON "stock"
+    FOR EACH ROW
+    EXECUTE FUNCTION ledger_guard();
+
+CREATE TRIGGER inventory_movement_guard_trigger
+    BEFORE UPDATE OR DELETE
+    ON "inventory_movement"
+    FOR EACH ROW
+    EXECUTE FUNCTION ledger_guard();
@@ -0,0 +1,20 @@
+CREATE TABLE IF NOT EXISTS "category" (
+    category_id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    parent_id UUID,
+    name VARCHAR(100) NOT NULL,
+    slug VARCHAR(100) UNIQUE NOT NULL,
+    date_created TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (parent_id) REFERENCES "category"(category_id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS category_parent_idx ON "category"(parent_id);
+
+CREATE TABLE IF NOT EXISTS "item_category" (
+    item_id UUID NOT NULL,
+    category_id UUID NOT NULL,
+    FOREIGN KEY (item_id) REFERENCES "item"(item_id) ON DELETE CASCADE,
+    FOREIGN KEY (category_id) REFERENCES "category"(category_id) ON DELETE CASCADE,
+    PRIMARY KEY (item_id, category_id)
+);
+
+CREATE INDEX IF NOT EXISTS item_category_category_idx ON "item_category"(category_id);
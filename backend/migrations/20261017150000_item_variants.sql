@@ -0,0 +1,110 @@
+CREATE TABLE IF NOT EXISTS "item_variant" (
+    variant_id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    item_id UUID NOT NULL,
+    sku VARCHAR(64) UNIQUE NOT NULL,
+    -- attribute name to value, e.g. {"size": "M", "colour": "red"}
+    attributes JSONB NOT NULL DEFAULT '{}',
+    -- overrides the item price when set
+    price NUMERIC CHECK (price >= 0),
+    date_created TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (item_id) REFERENCES "item"(item_id) ON DELETE CASCADE,
+    UNIQUE (item_id, attributes)
+);
+
+CREATE INDEX IF NOT EXISTS item_variant_item_idx ON "item_variant"(item_id);
+
+-- Every existing item becomes a single variant sharing its id, so rows
+-- which referenced the item now reference its variant unchanged.
+INSERT INTO "item_variant" ("variant_id","item_id","sku")
+SELECT "item_id","item_id","item_id"::text FROM "item";
+
+ALTER TABLE "stock" RENAME COLUMN item_id TO variant_id;
+ALTER TABLE "stock" DROP CONSTRAINT IF EXISTS stock_item_id_fkey;
+ALTER TABLE "stock"
+    ADD FOREIGN KEY (variant_id) REFERENCES "item_variant"(variant_id) ON DELETE CASCADE;
+
+ALTER TABLE "cart" RENAME COLUMN item_id TO variant_id;
+ALTER TABLE "cart" DROP CONSTRAINT IF EXISTS cart_item_id_fkey;
+ALTER TABLE "cart"
+    ADD FOREIGN KEY (variant_id) REFERENCES "item_variant"(variant_id) ON DELETE CASCADE;
+
+ALTER TABLE "stock_reservation" RENAME COLUMN item_id TO variant_id;
+ALTER TABLE "stock_reservation" DROP CONSTRAINT IF EXISTS stock_reservation_item_id_fkey;
+ALTER TABLE "stock_reservation"
+    ADD FOREIGN KEY (variant_id) REFERENCES "item_variant"(variant_id) ON DELETE CASCADE;
+
+ALTER TABLE "inventory_movement" RENAME COLUMN item_id TO variant_id;
+ALTER TABLE "inventory_movement" DROP CONSTRAINT IF EXISTS inventory_movement_item_id_fkey;
+ALTER TABLE "inventory_movement"
+    ADD FOREIGN KEY (variant_id) REFERENCES "item_variant"(variant_id) ON DELETE CASCADE;
+
+CREATE OR REPLACE FUNCTION apply_inventory_movement() RETURNS TRIGGER LANGUAGE plpgsql AS $$
+BEGIN
+    INSERT INTO "stock" ("variant_id","quantity") VALUES (NEW.variant_id, NEW.delta)
+    ON CONFLICT ("variant_id") DO UPDATE SET "quantity" = "stock"."quantity" + EXCLUDED."quantity"
+    RETURNING "quantity" INTO NEW.balance_after;
+    RETURN NEW;
+END;
+$$;
+
+-- Order items keep the item they belong to, and are keyed by variant so
+-- that one order can hold several variants of the same item.
+ALTER TABLE "order_items" ADD COLUMN variant_id UUID;
+UPDATE "order_items" SET variant_id = item_id;
+ALTER TABLE "order_items" ALTER COLUMN variant_id SET NOT NULL;
+ALTER TABLE "order_items"
+    ADD FOREIGN KEY (variant_id) REFERENCES "item_variant"(variant_id) ON DELETE CASCADE;
+
+ALTER TABLE "order_status_history" ADD COLUMN variant_id UUID;
+UPDATE "order_status_history" SET variant_id = item_id;
+ALTER TABLE "order_status_history" ALTER COLUMN variant_id SET NOT NULL;
+ALTER TABLE "order_status_history" DROP CONSTRAINT IF EXISTS order_status_history_order_id_item_id_fkey;
+
+ALTER TABLE "order_items" DROP CONSTRAINT order_items_pkey;
+ALTER TABLE "order_items" ADD PRIMARY KEY (order_id, variant_id);
+ALTER TABLE "order_status_history"
+    ADD FOREIGN KEY (order_id, variant_id)
+    REFERENCES "order_items"(order_id, variant_id) ON DELETE CASCADE;
+
+-- The parameter names change, so the old functions are dropped first
+DROP FUNCTION IF EXISTS stock_validation(UUID, INT);
+DROP FUNCTION IF EXISTS stock_validation(UUID, INT, UUID);
+
+CREATE OR REPLACE FUNCTION stock_validation(variant_id UUID, quantity INT, cart_id UUID) RETURNS BOOLEAN AS $$
+DECLARE
+    id_1 alias for $1;
+    quantity_1 alias for $2;
+    cart_1 alias for $3;
+    result BOOLEAN;
+BEGIN
+    SELECT EXISTS (
+        SELECT * FROM "stock" WHERE "stock"."variant_id" = id_1 AND "stock"."quantity" - (
+            SELECT COALESCE(SUM("stock_reservation"."quantity"),0) FROM "stock_reservation"
+            WHERE "stock_reservation"."variant_id" = id_1
+            AND "stock_reservation"."expires_at" > CURRENT_TIMESTAMP
+            AND "stock_reservation"."cart_id" IS DISTINCT FROM cart_1
+        ) >= quantity_1
+    ) INTO result ;
+    RETURN result;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION stock_validation(variant_id UUID, quantity INT) RETURNS BOOLEAN AS $$
+BEGIN
+    RETURN stock_validation($1, $2, NULL::UUID);
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION variant_ownership(variant_id UUID, user_id UUID) RETURNS BOOLEAN AS $$
+DECLARE
+    id_1 alias for $1;
+    id_2 alias for $2;
+    result BOOLEAN;
+BEGIN
+    SELECT EXISTS (
+        SELECT * FROM "item_variant" INNER JOIN "item" ON "item_variant"."item_id" = "item"."item_id"
+        WHERE "item_variant"."variant_id" = id_1 AND "item"."user_id" = id_2
+    ) INTO result ;
+    RETURN result;
+END;
+$$ LANGUAGE plpgsql;
@@ -0,0 +1,7 @@
+-- Titles weigh more than descriptions when ranking search results
+ALTER TABLE "item" ADD COLUMN search_vector tsvector GENERATED ALWAYS AS (
+    setweight(to_tsvector('english', coalesce(title, '')), 'A') ||
+    setweight(to_tsvector('english', coalesce(content, '')), 'B')
+) STORED;
+
+CREATE INDEX IF NOT EXISTS item_search_idx ON "item" USING GIN (search_vector);
@@ -0,0 +1,5 @@
+CREATE EXTENSION IF NOT EXISTS pg_trgm SCHEMA public;
+
+-- Serve the word similarity operator (<%) used by autocomplete
+CREATE INDEX IF NOT EXISTS item_title_trgm_idx ON "item" USING GIN (title gin_trgm_ops);
+CREATE INDEX IF NOT EXISTS category_name_trgm_idx ON "category" USING GIN (name gin_trgm_ops);
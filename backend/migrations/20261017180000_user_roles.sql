@@ -0,0 +1,35 @@
+CREATE TYPE user_role AS ENUM (
+    'buyer',
+    'seller',
+    'admin',
+    'support'
+);
+
+CREATE TABLE IF NOT EXISTS "user_roles" (
+    user_id UUID NOT NULL,
+    role user_role NOT NULL,
+    granted_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (user_id, role),
+    FOREIGN KEY (user_id) REFERENCES "user"(user_id) ON DELETE CASCADE
+);
+
+-- Every account can shop, selling is opted into through /user/seller
+CREATE OR REPLACE FUNCTION grant_buyer_role() RETURNS TRIGGER LANGUAGE plpgsql AS $$
+BEGIN
+    INSERT INTO "user_roles" ("user_id","role") VALUES (NEW.user_id, 'buyer')
+    ON CONFLICT DO NOTHING;
+    RETURN NEW;
+END;
+$$;
+
+CREATE TRIGGER user_buyer_role_trigger
+    AFTER INSERT
+    ON "user"
+    FOR EACH ROW
+    EXECUTE FUNCTION grant_buyer_role();
+
+-- Existing accounts keep what they could do before roles existed
+INSERT INTO "user_roles" ("user_id","role")
+SELECT "user_id",'buyer'::user_role FROM "user";
+INSERT INTO "user_roles" ("user_id","role")
+SELECT DISTINCT "user_id",'seller'::user_role FROM "item";
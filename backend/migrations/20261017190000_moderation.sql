@@ -0,0 +1,43 @@
+ALTER TABLE "user" ADD COLUMN suspended_at TIMESTAMP;
+ALTER TABLE "item" ADD COLUMN hidden_at TIMESTAMP;
+
+CREATE TYPE moderation_kind AS ENUM (
+    'suspend_user',
+    'unsuspend_user',
+    'hide_item',
+    'unhide_item',
+    'remove_comment'
+);
+
+-- Targets are not foreign keys so that the record outlives what it is about
+CREATE TABLE IF NOT EXISTS "moderation_action" (
+    action_id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    moderator_id UUID,
+    kind moderation_kind NOT NULL,
+    -- the suspended user, or the author of a removed comment
+    user_id UUID,
+    -- the hidden item, or the item a removed comment was on
+    item_id UUID,
+    reason TEXT,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (moderator_id) REFERENCES "user"(user_id) ON DELETE SET NULL
+);
+
+CREATE INDEX IF NOT EXISTS moderation_action_created_idx
+    ON "moderation_action"(created_at, action_id);
+
+-- Ratings were only calculated when a comment was added
+CREATE OR REPLACE FUNCTION ratings_removal() RETURNS TRIGGER LANGUAGE plpgsql AS $$
+BEGIN
+    UPDATE "item"
+    SET rating = (SELECT AVG(rating) FROM "comment" WHERE "comment"."item_id" = OLD.item_id)
+    WHERE "item"."item_id" = OLD.item_id;
+    RETURN OLD;
+END;
+$$;
+
+CREATE TRIGGER ratings_removal_trigger
+    AFTER DELETE
+    ON "comment"
+    FOR EACH ROW
+    EXECUTE FUNCTION ratings_removal();
@@ -0,0 +1,61 @@
+CREATE TYPE report_target AS ENUM (
+    'item',
+    'comment',
+    'user'
+);
+
+CREATE TYPE report_reason AS ENUM (
+    'counterfeit',
+    'prohibited',
+    'misleading',
+    'abusive',
+    'spam',
+    'fraud',
+    'other'
+);
+
+CREATE TYPE report_status AS ENUM (
+    'open',
+    'actioned',
+    'dismissed'
+);
+
+-- The reports about one item, comment or user, gathered for the moderation queue.
+-- A comment is the one left by user_id on item_id.
+CREATE TABLE IF NOT EXISTS "report_case" (
+    case_id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    target report_target NOT NULL,
+    item_id UUID,
+    user_id UUID,
+    status report_status NOT NULL DEFAULT 'open',
+    report_count INT NOT NULL DEFAULT 0,
+    opened_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    resolved_by UUID,
+    resolved_at TIMESTAMP,
+    resolution_note TEXT,
+    FOREIGN KEY (resolved_by) REFERENCES "user"(user_id) ON DELETE SET NULL,
+    CHECK (
+        (target = 'item' AND item_id IS NOT NULL AND user_id IS NULL)
+        OR (target = 'comment' AND item_id IS NOT NULL AND user_id IS NOT NULL)
+        OR (target = 'user' AND item_id IS NULL AND user_id IS NOT NULL)
+    )
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS report_case_target_idx ON "report_case"(
+    target,
+    COALESCE(item_id, '00000000-0000-0000-0000-000000000000'),
+    COALESCE(user_id, '00000000-0000-0000-0000-000000000000')
+);
+CREATE INDEX IF NOT EXISTS report_case_queue_idx ON "report_case"(status, report_count, case_id);
+
+-- Each user reports a case once
+CREATE TABLE IF NOT EXISTS "report" (
+    case_id UUID NOT NULL,
+    reporter_id UUID NOT NULL,
+    reason report_reason NOT NULL,
+    note TEXT,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (case_id, reporter_id),
+    FOREIGN KEY (case_id) REFERENCES "report_case"(case_id) ON DELETE CASCADE,
+    FOREIGN KEY (reporter_id) REFERENCES "user"(user_id) ON DELETE CASCADE
+);
@@ -0,0 +1,11 @@
+-- Only a SHA-256 of each token is kept, the token itself is only ever mailed
+CREATE TABLE IF NOT EXISTS "password_reset" (
+    token_hash VARCHAR(64) PRIMARY KEY,
+    user_id UUID NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    expires_at TIMESTAMP NOT NULL,
+    used_at TIMESTAMP,
+    FOREIGN KEY (user_id) REFERENCES "user"(user_id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS password_reset_user_idx ON "password_reset"(user_id);
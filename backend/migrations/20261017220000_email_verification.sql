@@ -0,0 +1,16 @@
+ALTER TABLE "user" ADD COLUMN email_verified BOOLEAN NOT NULL DEFAULT FALSE;
+-- Accounts made before verification existed are trusted as they are
+UPDATE "user" SET email_verified = TRUE;
+
+-- Tokens are kept as a SHA-256, and only verify the address they were mailed to
+CREATE TABLE IF NOT EXISTS "email_verification" (
+    token_hash VARCHAR(64) PRIMARY KEY,
+    user_id UUID NOT NULL,
+    email_id VARCHAR(255) NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    expires_at TIMESTAMP NOT NULL,
+    used_at TIMESTAMP,
+    FOREIGN KEY (user_id) REFERENCES "user"(user_id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS email_verification_user_idx ON "email_verification"(user_id, created_at);
@@ -0,0 +1,7 @@
+-- Lets users tell their sessions apart before revoking them
+ALTER TABLE "session" ADD COLUMN created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP;
+ALTER TABLE "session" ADD COLUMN last_seen_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP;
+ALTER TABLE "session" ADD COLUMN user_agent VARCHAR(512);
+ALTER TABLE "session" ADD COLUMN ip_address VARCHAR(45);
+
+CREATE INDEX IF NOT EXISTS session_user_idx ON "session"(user_id);
@@ -0,0 +1,8 @@
+-- Sessions slide forward on activity, but never past their absolute expiry
+ALTER TABLE "session" ADD COLUMN absolute_expiry TIMESTAMP;
+UPDATE "session" SET absolute_expiry = expiry;
+ALTER TABLE "session" ALTER COLUMN absolute_expiry SET NOT NULL;
+
+-- A SHA-256 of the refresh token, which trades the session for a new one
+ALTER TABLE "session" ADD COLUMN refresh_hash VARCHAR(64) UNIQUE;
+ALTER TABLE "session" ADD COLUMN refresh_expires_at TIMESTAMP;
@@ -0,0 +1,2 @@
+-- A SHA-256 of the token requests authenticated by cookie have to repeat
+ALTER TABLE "session" ADD COLUMN csrf_hash VARCHAR(64);
@@ -0,0 +1,34 @@
+-- The secret is kept as base32, since codes are computed from it. It only
+-- counts once enabled_at is set, when the user has confirmed a code.
+CREATE TABLE IF NOT EXISTS "user_totp" (
+    user_id UUID PRIMARY KEY,
+    secret VARCHAR(64) NOT NULL,
+    -- the last time step a code was accepted for, so that codes are single use
+    last_step BIGINT,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    enabled_at TIMESTAMP,
+    FOREIGN KEY (user_id) REFERENCES "user"(user_id) ON DELETE CASCADE
+);
+
+-- Only a SHA-256 of each recovery code is kept
+CREATE TABLE IF NOT EXISTS "recovery_code" (
+    code_hash VARCHAR(64) PRIMARY KEY,
+    user_id UUID NOT NULL,
+    used_at TIMESTAMP,
+    FOREIGN KEY (user_id) REFERENCES "user"(user_id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS recovery_code_user_idx ON "recovery_code"(user_id);
+
+-- Handed out by a login whose password was right, until the code is given
+CREATE TABLE IF NOT EXISTS "login_challenge" (
+    token_hash VARCHAR(64) PRIMARY KEY,
+    user_id UUID NOT NULL,
+    -- whether the session is to be kept in cookies
+    cookie BOOLEAN NOT NULL DEFAULT FALSE,
+    attempts INT NOT NULL DEFAULT 0,
+    expires_at TIMESTAMP NOT NULL,
+    FOREIGN KEY (user_id) REFERENCES "user"(user_id) ON DELETE CASCADE
+);
+
+ALTER TYPE moderation_kind ADD VALUE IF NOT EXISTS 'reset_two_factor';
@@ -0,0 +1,25 @@
+CREATE TYPE login_scope AS ENUM ('username', 'ip');
+
+-- Failed logins by username and by IP, whether or not the username exists
+CREATE TABLE IF NOT EXISTS "login_throttle" (
+    scope login_scope NOT NULL,
+    key VARCHAR(255) NOT NULL,
+    failures INT NOT NULL DEFAULT 0,
+    last_failure_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    locked_until TIMESTAMP,
+    PRIMARY KEY (scope, key)
+);
+
+CREATE INDEX IF NOT EXISTS login_throttle_last_failure_idx ON "login_throttle"(last_failure_at);
+
+-- Every lockout, kept for the login metrics
+CREATE TABLE IF NOT EXISTS "login_lockout" (
+    lockout_id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    scope login_scope NOT NULL,
+    key VARCHAR(255) NOT NULL,
+    failures INT NOT NULL,
+    locked_until TIMESTAMP NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS login_lockout_created_idx ON "login_lockout"(created_at);
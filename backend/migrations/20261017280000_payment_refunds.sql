@@ -0,0 +1,22 @@
+-- Refunds are recorded as pending before the provider is asked for them, so
+-- that no row stays locked while it answers. A refund whose call failed or
+-- timed out stays pending and is issued again under the same refund_id.
+CREATE TYPE refund_status AS ENUM (
+    'pending',
+    'issued'
+);
+
+CREATE TABLE IF NOT EXISTS "payment_refund" (
+    refund_id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    payment_id UUID NOT NULL,
+    amount NUMERIC NOT NULL CHECK (amount > 0),
+    status refund_status NOT NULL DEFAULT 'pending',
+    -- why the last attempt to issue it failed
+    failure_reason TEXT,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    issued_at TIMESTAMP,
+    FOREIGN KEY (payment_id) REFERENCES "payment"(payment_id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS payment_refund_pending_idx
+    ON "payment_refund"(created_at) WHERE status = 'pending';
@@ -0,0 +1,16 @@
+-- Movements update the stock row rather than upserting it. An upsert checks
+-- stock_quantity_non_negative against the row it proposes, the bare delta,
+-- before it finds the conflict, so every decrease failed the check.
+CREATE OR REPLACE FUNCTION apply_inventory_movement() RETURNS TRIGGER LANGUAGE plpgsql AS $$
+BEGIN
+    UPDATE "stock" SET "quantity" = "quantity" + NEW.delta
+    WHERE "variant_id" = NEW.variant_id
+    RETURNING "quantity" INTO NEW.balance_after;
+    IF NOT FOUND THEN
+        INSERT INTO "stock" ("variant_id","quantity") VALUES (NEW.variant_id, NEW.delta)
+        ON CONFLICT ("variant_id") DO UPDATE SET "quantity" = "stock"."quantity" + EXCLUDED."quantity"
+        RETURNING "quantity" INTO NEW.balance_after;
+    END IF;
+    RETURN NEW;
+END;
+$$;
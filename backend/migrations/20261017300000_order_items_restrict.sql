@@ -0,0 +1,10 @@
+-- Order lines keep the item and variant they were bought as, along with the
+-- title and price snapshot taken then. Items which were ordered can no
+-- longer be deleted rather than taking the lines of paid orders with them.
+ALTER TABLE "order_items" DROP CONSTRAINT IF EXISTS order_items_item_id_fkey;
+ALTER TABLE "order_items"
+    ADD FOREIGN KEY (item_id) REFERENCES "item"(item_id) ON DELETE RESTRICT;
+
+ALTER TABLE "order_items" DROP CONSTRAINT IF EXISTS order_items_variant_id_fkey;
+ALTER TABLE "order_items"
+    ADD FOREIGN KEY (variant_id) REFERENCES "item_variant"(variant_id) ON DELETE RESTRICT;
@@ -0,0 +1,568 @@
+use crate::{
+    auth::AuthUser,
+    errors::{ErrorResponse, MyError},
+    listing::{fetch_page, Cursor, Keyset, Listing, Page, Paginated},
+    two_factor::reset_two_factor,
+    user::GeneralResponse,
+    AppState,
+};
+use axum::{
+    extract::{OriginalUri, Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Form, Json,
+};
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{FromRow, Postgres, Transaction};
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
+
+/// What a moderator did
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize, ToSchema, sqlx::Type)]
+#[sqlx(type_name = "moderation_kind", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum ModerationKind {
+    SuspendUser,
+    UnsuspendUser,
+    HideItem,
+    UnhideItem,
+    RemoveComment,
+    ResetTwoFactor,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct ModerationForm {
+    /// Why the action was taken, kept in the audit record
+    reason: Option<String>,
+}
+
+/// Audit record of a moderation action
+#[derive(Serialize, FromRow, ToSchema)]
+pub struct ModerationAction {
+    action_id: Uuid,
+    /// null for actions taken automatically, or once the moderator's account is deleted
+    moderator_id: Option<Uuid>,
+    kind: ModerationKind,
+    /// the suspended user, or the author of a removed comment
+    user_id: Option<Uuid>,
+    /// the hidden item, or the item a removed comment was on
+    item_id: Option<Uuid>,
+    reason: Option<String>,
+    created_at: NaiveDateTime,
+}
+
+#[derive(Deserialize, ToSchema, IntoParams)]
+pub struct ModerationQuery {
+    /// Number of actions to fetch per page
+    take: Option<u32>,
+    /// Page number to fetch, ignored when a cursor is given
+    page_no: Option<u32>,
+    /// `next_cursor` of the previous page, to fetch the page after it
+    cursor: Option<String>,
+    /// Only fetch actions of this kind
+    kind: Option<ModerationKind>,
+    /// Only fetch actions about this user
+    user_id: Option<Uuid>,
+    /// Only fetch actions about this item
+    item_id: Option<Uuid>,
+}
+
+/// Actions in the order they were taken, newest first
+const NEWEST_ACTIONS: Keyset = Keyset {
+    columns: &[(r#""created_at""#, "timestamp"), (r#""action_id""#, "uuid")],
+    descending: true,
+};
+
+/// Longest free text given with a moderation action or a report
+const TEXT_MAX_CHARS: usize = 1000;
+
+/// Trims the free text sent in the `field` of a form, which may be left empty
+pub fn free_text(text: Option<String>, field: &str) -> Result<Option<String>, MyError> {
+    match text.as_deref().map(str::trim) {
+        None | Some("") => Ok(None),
+        Some(text) if text.chars().count() > TEXT_MAX_CHARS => Err(MyError::CustomError((
+            422,
+            format!(
+                "{} cannot be more than {} characters",
+                field, TEXT_MAX_CHARS
+            ),
+        ))),
+        Some(text) => Ok(Some(text.to_string())),
+    }
+}
+
+/// Records a moderation action in the audit log, in the transaction taking it.
+/// Actions taken automatically have no moderator.
+pub async fn record_action(
+    txn: &mut Transaction<'_, Postgres>,
+    moderator_id: Option<Uuid>,
+    kind: ModerationKind,
+    user_id: Option<Uuid>,
+    item_id: Option<Uuid>,
+    reason: Option<String>,
+) -> Result<(), MyError> {
+    let query = r#"
+        INSERT INTO "moderation_action" ("moderator_id","kind","user_id","item_id","reason")
+        VALUES ($1,$2,$3,$4,$5);
+    "#;
+    sqlx::query(query)
+        .bind(moderator_id)
+        .bind(kind)
+        .bind(user_id)
+        .bind(item_id)
+        .bind(reason)
+        .execute(&mut **txn)
+        .await
+        .map(|_| ())
+        .map_err(|_| MyError::InternalServerError)
+}
+
+#[derive(FromRow)]
+struct Moderated {
+    /// whether the target was already in the state asked for
+    unchanged: bool,
+}
+
+/// Suspends or reinstates a user. Suspending also ends every session of the
+/// user, so that they are logged out straight away.
+async fn set_suspended(
+    state: &AppState,
+    moderator_id: Uuid,
+    user_id: Uuid,
+    suspend: bool,
+    reason: Option<String>,
+) -> Result<(), MyError> {
+    let mut txn = state
+        .db_pool
+        .begin()
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    let query = r#"
+        SELECT ("suspended_at" IS NOT NULL) = $2 AS "unchanged" FROM "user"
+        WHERE "user_id" = $1 FOR UPDATE;
+    "#;
+    let current = sqlx::query_as::<_, Moderated>(query)
+        .bind(user_id)
+        .bind(suspend)
+        .fetch_optional(&mut *txn)
+        .await
+        .map_err(|_| MyError::InternalServerError)?
+        .ok_or(MyError::NotFound)?;
+    if current.unchanged {
+        return Err(MyError::CustomError((
+            409,
+            match suspend {
+                true => "User is already suspended".to_string(),
+                false => "User is not suspended".to_string(),
+            },
+        )));
+    }
+    let query = r#"
+        UPDATE "user" SET "suspended_at" = CASE WHEN $2 THEN CURRENT_TIMESTAMP END
+        WHERE "user_id" = $1;
+    "#;
+    sqlx::query(query)
+        .bind(user_id)
+        .bind(suspend)
+        .execute(&mut *txn)
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    if suspend {
+        sqlx::query(r#"DELETE FROM "session" WHERE "user_id" = $1"#)
+            .bind(user_id)
+            .execute(&mut *txn)
+            .await
+            .map_err(|_| MyError::InternalServerError)?;
+    }
+    let kind = match suspend {
+        true => ModerationKind::SuspendUser,
+        false => ModerationKind::UnsuspendUser,
+    };
+    record_action(
+        &mut txn,
+        Some(moderator_id),
+        kind,
+        Some(user_id),
+        None,
+        reason,
+    )
+    .await?;
+    txn.commit().await.map_err(|_| MyError::InternalServerError)
+}
+
+/// Hides an item from the listings, or shows it again
+async fn set_hidden(
+    state: &AppState,
+    moderator_id: Uuid,
+    item_id: Uuid,
+    hide: bool,
+    reason: Option<String>,
+) -> Result<(), MyError> {
+    let mut txn = state
+        .db_pool
+        .begin()
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    let query = r#"
+        SELECT ("hidden_at" IS NOT NULL) = $2 AS "unchanged" FROM "item"
+        WHERE "item_id" = $1 FOR UPDATE;
+    "#;
+    let current = sqlx::query_as::<_, Moderated>(query)
+        .bind(item_id)
+        .bind(hide)
+        .fetch_optional(&mut *txn)
+        .await
+        .map_err(|_| MyError::InternalServerError)?
+        .ok_or(MyError::NotFound)?;
+    if current.unchanged {
+        return Err(MyError::CustomError((
+            409,
+            match hide {
+                true => "Item is already hidden".to_string(),
+                false => "Item is not hidden".to_string(),
+            },
+        )));
+    }
+    let query = r#"
+        UPDATE "item" SET "hidden_at" = CASE WHEN $2 THEN CURRENT_TIMESTAMP END
+        WHERE "item_id" = $1;
+    "#;
+    sqlx::query(query)
+        .bind(item_id)
+        .bind(hide)
+        .execute(&mut *txn)
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    let kind = match hide {
+        true => ModerationKind::HideItem,
+        false => ModerationKind::UnhideItem,
+    };
+    record_action(
+        &mut txn,
+        Some(moderator_id),
+        kind,
+        None,
+        Some(item_id),
+        reason,
+    )
+    .await?;
+    txn.commit().await.map_err(|_| MyError::InternalServerError)
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/user/{user_id}/suspend",
+    request_body(content = ModerationForm, content_type = "application/x-www-form-urlencoded"),
+    security(
+        ("session_id" = ["admin"])
+    ),
+    responses(
+        (status = 200, body = GeneralResponse),
+        (status = 401, body = ErrorResponse),
+        (status = 403, body = ErrorResponse),
+        (status = 404, body = ErrorResponse),
+        (status = 409, body = ErrorResponse),
+        (status = 422, body = ErrorResponse),
+        (status = 500, body = ErrorResponse)
+    )
+)]
+/// Suspend User
+///
+/// Endpoint to suspend a user, which logs them out everywhere and stops them from logging in
+pub async fn suspend_user(
+    state: State<AppState>,
+    AuthUser(moderator): AuthUser,
+    Path(user_id): Path<Uuid>,
+    Form(form_data): Form<ModerationForm>,
+) -> Result<impl IntoResponse, MyError> {
+    if user_id == moderator.user_id {
+        return Err(MyError::CustomError((
+            422,
+            "Cannot suspend oneself".to_string(),
+        )));
+    }
+    let reason = free_text(form_data.reason, "reason")?;
+    set_suspended(&state, moderator.user_id, user_id, true, reason).await?;
+    Ok((
+        StatusCode::OK,
+        Json(json!(GeneralResponse {
+            detail: "User suspended".to_string()
+        })),
+    ))
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/user/{user_id}/unsuspend",
+    request_body(content = ModerationForm, content_type = "application/x-www-form-urlencoded"),
+    security(
+        ("session_id" = ["admin"])
+    ),
+    responses(
+        (status = 200, body = GeneralResponse),
+        (status = 401, body = ErrorResponse),
+        (status = 403, body = ErrorResponse),
+        (status = 404, body = ErrorResponse),
+        (status = 409, body = ErrorResponse),
+        (status = 422, body = ErrorResponse),
+        (status = 500, body = ErrorResponse)
+    )
+)]
+/// Unsuspend User
+///
+/// Endpoint to let a suspended user log in again
+pub async fn unsuspend_user(
+    state: State<AppState>,
+    AuthUser(moderator): AuthUser,
+    Path(user_id): Path<Uuid>,
+    Form(form_data): Form<ModerationForm>,
+) -> Result<impl IntoResponse, MyError> {
+    let reason = free_text(form_data.reason, "reason")?;
+    set_suspended(&state, moderator.user_id, user_id, false, reason).await?;
+    Ok((
+        StatusCode::OK,
+        Json(json!(GeneralResponse {
+            detail: "User unsuspended".to_string()
+        })),
+    ))
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/user/{user_id}/2fa/reset",
+    request_body(content = ModerationForm, content_type = "application/x-www-form-urlencoded"),
+    security(
+        ("session_id" = ["admin"])
+    ),
+    responses(
+        (status = 200, body = GeneralResponse),
+        (status = 401, body = ErrorResponse),
+        (status = 403, body = ErrorResponse),
+        (status = 409, body = ErrorResponse),
+        (status = 422, body = ErrorResponse),
+        (status = 500, body = ErrorResponse)
+    )
+)]
+/// Reset Two-Factor Authentication
+///
+/// Endpoint to turn off two-factor authentication for a user who lost their
+/// authenticator app and recovery codes, so that they log in with their password
+pub async fn reset_user_two_factor(
+    state: State<AppState>,
+    AuthUser(moderator): AuthUser,
+    Path(user_id): Path<Uuid>,
+    Form(form_data): Form<ModerationForm>,
+) -> Result<impl IntoResponse, MyError> {
+    let reason = free_text(form_data.reason, "reason")?;
+    let mut txn = state
+        .db_pool
+        .begin()
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    reset_two_factor(&mut txn, user_id).await?;
+    record_action(
+        &mut txn,
+        Some(moderator.user_id),
+        ModerationKind::ResetTwoFactor,
+        Some(user_id),
+        None,
+        reason,
+    )
+    .await?;
+    txn.commit()
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    Ok((
+        StatusCode::OK,
+        Json(json!(GeneralResponse {
+            detail: "Two-factor authentication reset".to_string()
+        })),
+    ))
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/item/{item_id}/hide",
+    request_body(content = ModerationForm, content_type = "application/x-www-form-urlencoded"),
+    security(
+        ("session_id" = ["admin", "support"])
+    ),
+    responses(
+        (status = 200, body = GeneralResponse),
+        (status = 401, body = ErrorResponse),
+        (status = 403, body = ErrorResponse),
+        (status = 404, body = ErrorResponse),
+        (status = 409, body = ErrorResponse),
+        (status = 422, body = ErrorResponse),
+        (status = 500, body = ErrorResponse)
+    )
+)]
+/// Hide Item
+///
+/// Endpoint to take an item down, it is left out of every listing and only its seller can still see it
+pub async fn hide_item(
+    state: State<AppState>,
+    AuthUser(moderator): AuthUser,
+    Path(item_id): Path<Uuid>,
+    Form(form_data): Form<ModerationForm>,
+) -> Result<impl IntoResponse, MyError> {
+    let reason = free_text(form_data.reason, "reason")?;
+    set_hidden(&state, moderator.user_id, item_id, true, reason).await?;
+    Ok((
+        StatusCode::OK,
+        Json(json!(GeneralResponse {
+            detail: "Item hidden".to_string()
+        })),
+    ))
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/item/{item_id}/unhide",
+    request_body(content = ModerationForm, content_type = "application/x-www-form-urlencoded"),
+    security(
+        ("session_id" = ["admin", "support"])
+    ),
+    responses(
+        (status = 200, body = GeneralResponse),
+        (status = 401, body = ErrorResponse),
+        (status = 403, body = ErrorResponse),
+        (status = 404, body = ErrorResponse),
+        (status = 409, body = ErrorResponse),
+        (status = 422, body = ErrorResponse),
+        (status = 500, body = ErrorResponse)
+    )
+)]
+/// Unhide Item
+///
+/// Endpoint to put a hidden item back in the listings
+pub async fn unhide_item(
+    state: State<AppState>,
+    AuthUser(moderator): AuthUser,
+    Path(item_id): Path<Uuid>,
+    Form(form_data): Form<ModerationForm>,
+) -> Result<impl IntoResponse, MyError> {
+    let reason = free_text(form_data.reason, "reason")?;
+    set_hidden(&state, moderator.user_id, item_id, false, reason).await?;
+    Ok((
+        StatusCode::OK,
+        Json(json!(GeneralResponse {
+            detail: "Item unhidden".to_string()
+        })),
+    ))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/admin/item/{item_id}/comment/{user_id}",
+    request_body(content = ModerationForm, content_type = "application/x-www-form-urlencoded"),
+    params(
+        ("item_id" = Uuid, Path, description = "Item the comment is on"),
+        ("user_id" = Uuid, Path, description = "Author of the comment")
+    ),
+    security(
+        ("session_id" = ["admin", "support"])
+    ),
+    responses(
+        (status = 200, body = GeneralResponse),
+        (status = 401, body = ErrorResponse),
+        (status = 403, body = ErrorResponse),
+        (status = 404, body = ErrorResponse),
+        (status = 422, body = ErrorResponse),
+        (status = 500, body = ErrorResponse)
+    )
+)]
+/// Remove Comment
+///
+/// Endpoint to remove a user's comment on an item, the rating of the item is
+/// recalculated from the comments left
+pub async fn remove_comment(
+    state: State<AppState>,
+    AuthUser(moderator): AuthUser,
+    Path((item_id, user_id)): Path<(Uuid, Uuid)>,
+    Form(form_data): Form<ModerationForm>,
+) -> Result<impl IntoResponse, MyError> {
+    let reason = free_text(form_data.reason, "reason")?;
+    let mut txn = state
+        .db_pool
+        .begin()
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    let removed = sqlx::query(r#"DELETE FROM "comment" WHERE "item_id" = $1 AND "user_id" = $2"#)
+        .bind(item_id)
+        .bind(user_id)
+        .execute(&mut *txn)
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    if removed.rows_affected() == 0 {
+        return Err(MyError::NotFound);
+    }
+    record_action(
+        &mut txn,
+        Some(moderator.user_id),
+        ModerationKind::RemoveComment,
+        Some(user_id),
+        Some(item_id),
+        reason,
+    )
+    .await?;
+    txn.commit()
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    Ok((
+        StatusCode::OK,
+        Json(json!(GeneralResponse {
+            detail: "Comment removed".to_string()
+        })),
+    ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/actions",
+    params(
+        ModerationQuery
+    ),
+    security(
+        ("session_id" = ["admin", "support"])
+    ),
+    responses(
+        (status = 200, body = Paginated<ModerationAction>),
+        (status = 401, body = ErrorResponse),
+        (status = 403, body = ErrorResponse),
+        (status = 422, body = ErrorResponse),
+        (status = 500, body = ErrorResponse)
+    )
+)]
+/// Get Moderation Actions
+///
+/// Endpoint to page through the audit log of moderation actions, newest first
+pub async fn get_actions(
+    state: State<AppState>,
+    OriginalUri(uri): OriginalUri,
+    Query(pagination): Query<ModerationQuery>,
+) -> Result<impl IntoResponse, MyError> {
+    let cursor = match &pagination.cursor {
+        Some(token) => Some(Cursor::decode(token)?),
+        None => None,
+    };
+    let mut listing = Listing::keyset(
+        &NEWEST_ACTIONS,
+        r#""action_id","moderator_id","kind","user_id","item_id","reason","created_at""#,
+    );
+    listing.push(r#" FROM "moderation_action""#);
+    if let Some(kind) = pagination.kind {
+        listing.condition(r#""kind" = "#).bind(kind);
+    }
+    if let Some(user_id) = pagination.user_id {
+        listing.condition(r#""user_id" = "#).bind(user_id);
+    }
+    if let Some(item_id) = pagination.item_id {
+        listing.condition(r#""item_id" = "#).bind(item_id);
+    }
+    let query = listing.paginate(Page::new(pagination.take, pagination.page_no), cursor)?;
+    let response = fetch_page::<ModerationAction>(query, &uri, &state.db_pool).await?;
+    Ok((StatusCode::OK, Json(json!(response))))
+}
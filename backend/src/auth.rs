@@ -0,0 +1,403 @@
+use crate::{
+    errors::MyError,
+    user::{check_session_validity, token_hash, NewSession, SessionResponse, UserWithSession},
+    AppState,
+};
+use axum::{
+    extract::{ConnectInfo, FromRequestParts, OptionalFromRequestParts, Request, State},
+    http::{
+        header::{COOKIE, SET_COOKIE, USER_AGENT},
+        request::Parts,
+        HeaderMap, HeaderValue, Method, StatusCode,
+    },
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, Pool, Postgres};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+/// Header the session id is sent in
+pub const SESSION_HEADER: &str = "session_id";
+/// HttpOnly cookie the session id is sent in, for browsers
+pub const SESSION_COOKIE: &str = "session_id";
+/// HttpOnly cookie the refresh token is sent in, only to `/user/sessions/refresh`
+pub const REFRESH_COOKIE: &str = "refresh_token";
+/// Cookie the frontend reads the CSRF token from
+pub const CSRF_COOKIE: &str = "csrf_token";
+/// Header a request authenticated by cookie has to repeat the CSRF token in,
+/// unless it is a GET, HEAD or OPTIONS
+pub const CSRF_HEADER: &str = "x-csrf-token";
+
+/// What a user may do. Every user is a buyer, the other roles are granted.
+/// The roles an endpoint accepts are listed as the scopes of its security
+/// requirement in the API documentation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize, ToSchema, sqlx::Type)]
+#[sqlx(type_name = "user_role", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Buyer,
+    Seller,
+    Admin,
+    Support,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Buyer => "buyer",
+            Role::Seller => "seller",
+            Role::Admin => "admin",
+            Role::Support => "support",
+        }
+    }
+}
+
+/// The user behind the session sent with a request. Handlers taking it as an
+/// argument answer 401 when the session is missing, malformed or expired.
+#[derive(Clone)]
+pub struct AuthUser(pub UserWithSession);
+
+impl FromRequestParts<AppState> for AuthUser {
+    type Rejection = MyError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, MyError> {
+        // Already authenticated by `require_role`
+        if let Some(user) = parts.extensions.get::<AuthUser>() {
+            return Ok(user.clone());
+        }
+        match session_credential(parts)? {
+            Some(credential) => authenticate(state, parts, credential).await,
+            None => Err(MyError::CustomError((
+                401,
+                "Session id is missing".to_string(),
+            ))),
+        }
+    }
+}
+
+/// Public endpoints take `Option<AuthUser>`, which is None when no session is
+/// sent. A session which is sent but not valid is still turned away.
+impl OptionalFromRequestParts<AppState> for AuthUser {
+    type Rejection = MyError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Option<Self>, MyError> {
+        match session_credential(parts)? {
+            Some(credential) => authenticate(state, parts, credential).await.map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+/// How the session id of a request was sent
+#[derive(Clone, Copy)]
+enum Credential {
+    Header(Uuid),
+    Cookie(Uuid),
+}
+
+/// The session id of a request, from the header or else the session cookie
+fn session_credential(parts: &Parts) -> Result<Option<Credential>, MyError> {
+    let (value, credential): (&str, fn(Uuid) -> Credential) =
+        match parts.headers.get(SESSION_HEADER) {
+            Some(value) => (value.to_str().unwrap_or_default(), Credential::Header),
+            None => match cookie(&parts.headers, SESSION_COOKIE) {
+                Some(value) => (value, Credential::Cookie),
+                None => return Ok(None),
+            },
+        };
+    Uuid::parse_str(value.trim())
+        .map(|session_id| Some(credential(session_id)))
+        .map_err(|_| MyError::CustomError((401, "Session id is malformed".to_string())))
+}
+
+/// The value of a cookie sent with a request
+pub fn cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|cookies| cookies.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+async fn authenticate(
+    state: &AppState,
+    parts: &Parts,
+    credential: Credential,
+) -> Result<AuthUser, MyError> {
+    let session_id = match credential {
+        Credential::Header(session_id) | Credential::Cookie(session_id) => session_id,
+    };
+    // Browsers attach cookies to requests other sites make, custom headers they
+    // do not. Checked first, so that forged requests do not renew the session.
+    let safe = matches!(parts.method, Method::GET | Method::HEAD | Method::OPTIONS);
+    if let Credential::Cookie(session_id) = credential {
+        if !safe {
+            check_csrf(state, &parts.headers, session_id).await?;
+        }
+    }
+    match check_session_validity(state, session_id).await? {
+        Some(user) => Ok(AuthUser(user)),
+        None => Err(MyError::CustomError((
+            401,
+            "Session has expired or does not exist".to_string(),
+        ))),
+    }
+}
+
+/// Turns away with a 403 requests whose CSRF header does not match the token
+/// handed out with the session
+pub async fn check_csrf(
+    state: &AppState,
+    headers: &HeaderMap,
+    session_id: Uuid,
+) -> Result<(), MyError> {
+    let csrf_token = headers
+        .get(CSRF_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .ok_or(MyError::CustomError((
+            403,
+            "CSRF token is missing".to_string(),
+        )))?;
+    sqlx::query(r#"SELECT 1 FROM "session" WHERE "session_id" = $1 AND "csrf_hash" = $2"#)
+        .bind(session_id)
+        .bind(token_hash(csrf_token.trim()))
+        .fetch_optional(&state.db_pool)
+        .await
+        .map_err(|_| MyError::InternalServerError)?
+        .map(|_| ())
+        .ok_or(MyError::CustomError((
+            403,
+            "CSRF token is invalid".to_string(),
+        )))
+}
+
+/// What a browser is told of a session it holds in cookies
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct CookieSession {
+    pub expiry: NaiveDateTime,
+    /// To be sent back in the `x-csrf-token` header. Also kept in the
+    /// `csrf_token` cookie, which scripts can read.
+    pub csrf_token: String,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct CookieSessionResponse {
+    pub detail: CookieSession,
+}
+
+fn set_cookie(name: &str, value: &str, path: &str, max_age: u64, http_only: bool) -> HeaderValue {
+    let http_only = match http_only {
+        true => "; HttpOnly",
+        false => "",
+    };
+    HeaderValue::from_str(&format!(
+        "{name}={value}; Path={path}; Max-Age={max_age}; Secure; SameSite=Strict{http_only}"
+    ))
+    .expect("Cookies are made of header safe characters")
+}
+
+/// Answers with a new session, in the body for clients sending the
+/// `session_id` header, or in cookies when `cookie` is asked for
+pub fn session_created(state: &AppState, session: NewSession, cookie: bool) -> Response {
+    println!("Session {} created", session.session_id);
+    if !cookie {
+        return (
+            StatusCode::CREATED,
+            Json(SessionResponse { detail: session }),
+        )
+            .into_response();
+    }
+    let session_age = state.session_absolute_timeout.as_secs();
+    let refresh_age = state.refresh_token_ttl.as_secs();
+    let mut response = (
+        StatusCode::CREATED,
+        Json(CookieSessionResponse {
+            detail: CookieSession {
+                expiry: session.expiry,
+                csrf_token: session.csrf_token.clone(),
+            },
+        }),
+    )
+        .into_response();
+    let headers = response.headers_mut();
+    let session_id = session.session_id.to_string();
+    headers.append(
+        SET_COOKIE,
+        set_cookie(SESSION_COOKIE, &session_id, "/", session_age, true),
+    );
+    headers.append(
+        SET_COOKIE,
+        set_cookie(
+            REFRESH_COOKIE,
+            &session.refresh_token,
+            "/user/sessions/refresh",
+            refresh_age,
+            true,
+        ),
+    );
+    headers.append(
+        SET_COOKIE,
+        set_cookie(CSRF_COOKIE, &session.csrf_token, "/", refresh_age, false),
+    );
+    response
+}
+
+/// Has the browser drop the cookies of its session
+pub fn clear_session_cookies(headers: &mut HeaderMap) {
+    headers.append(SET_COOKIE, set_cookie(SESSION_COOKIE, "", "/", 0, true));
+    headers.append(
+        SET_COOKIE,
+        set_cookie(REFRESH_COOKIE, "", "/user/sessions/refresh", 0, true),
+    );
+    headers.append(SET_COOKIE, set_cookie(CSRF_COOKIE, "", "/", 0, false));
+}
+
+/// Where a request comes from, recorded with the sessions it creates so that
+/// users can tell their sessions apart
+pub struct ClientInfo {
+    pub user_agent: Option<String>,
+    /// Only known when the app is served with `ConnectInfo`
+    pub ip_address: Option<String>,
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for ClientInfo {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Infallible> {
+        let user_agent = parts
+            .headers
+            .get(USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .map(|agent| agent.chars().take(512).collect());
+        let ip_address = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip().to_string());
+        Ok(ClientInfo {
+            user_agent,
+            ip_address,
+        })
+    }
+}
+
+/// Roles which are let through by [`require_role`], any one of them will do
+#[derive(Clone, Copy)]
+pub struct Requires(pub &'static [Role]);
+
+/// Middleware turning away requests whose user holds none of the required
+/// roles with a 403. The user is kept in the request for the `AuthUser` of
+/// the handler, so the session is only looked up once.
+pub async fn require_role(
+    State((state, Requires(roles))): State<(AppState, Requires)>,
+    request: Request,
+    next: Next,
+) -> Result<Response, MyError> {
+    let (mut parts, body) = request.into_parts();
+    let user =
+        <AuthUser as FromRequestParts<AppState>>::from_request_parts(&mut parts, &state).await?;
+    let granted = user_roles(&state.db_pool, user.0.user_id).await?;
+    if !roles.iter().any(|role| granted.contains(role)) {
+        let names = roles.iter().map(Role::as_str).collect::<Vec<&str>>();
+        return Err(MyError::CustomError((
+            403,
+            format!("Requires the {} role", names.join(" or ")),
+        )));
+    }
+    parts.extensions.insert(user);
+    Ok(next.run(Request::from_parts(parts, body)).await)
+}
+
+#[derive(FromRow)]
+struct Grant {
+    role: Role,
+}
+
+/// Roles held by the user
+pub async fn user_roles(pool: &Pool<Postgres>, user_id: Uuid) -> Result<Vec<Role>, MyError> {
+    let query = r#"
+        SELECT "role" FROM "user_roles" WHERE "user_id" = $1 ORDER BY "role";
+    "#;
+    sqlx::query_as::<_, Grant>(query)
+        .bind(user_id)
+        .fetch_all(pool)
+        .await
+        .map(|grants| grants.into_iter().map(|grant| grant.role).collect())
+        .map_err(|_| MyError::InternalServerError)
+}
+
+/// Grants a role to the user, granting a role the user holds does nothing
+pub async fn grant_role(pool: &Pool<Postgres>, user_id: Uuid, role: Role) -> Result<(), MyError> {
+    let query = r#"
+        INSERT INTO "user_roles" ("user_id","role") VALUES ($1,$2)
+        ON CONFLICT DO NOTHING;
+    "#;
+    sqlx::query(query)
+        .bind(user_id)
+        .bind(role)
+        .execute(pool)
+        .await
+        .map(|_| ())
+        .map_err(|_| MyError::InternalServerError)
+}
+
+/// Makes the account named `username` an operator by granting it the admin role
+pub async fn grant_admin(pool: &Pool<Postgres>, username: &str) -> Result<(), MyError> {
+    let query = r#"
+        INSERT INTO "user_roles" ("user_id","role")
+        SELECT "user_id",'admin' FROM "user" WHERE "username" = $1
+        ON CONFLICT DO NOTHING;
+    "#;
+    sqlx::query(query)
+        .bind(username)
+        .execute(pool)
+        .await
+        .map(|_| ())
+        .map_err(|_| MyError::InternalServerError)
+}
+
+#[derive(FromRow)]
+struct Verified {
+    email_verified: bool,
+}
+
+/// Middleware turning away users who have not verified their email id with a
+/// 403, unless the policy is turned off through `REQUIRE_VERIFIED_EMAIL`
+pub async fn require_verified_email(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Result<Response, MyError> {
+    if !state.require_verified_email {
+        return Ok(next.run(request).await);
+    }
+    let (mut parts, body) = request.into_parts();
+    let user =
+        <AuthUser as FromRequestParts<AppState>>::from_request_parts(&mut parts, &state).await?;
+    let verified = sqlx::query_as::<_, Verified>(
+        r#"SELECT "email_verified" FROM "user" WHERE "user_id" = $1"#,
+    )
+    .bind(user.0.user_id)
+    .fetch_one(&state.db_pool)
+    .await
+    .map_err(|_| MyError::InternalServerError)?;
+    if !verified.email_verified {
+        return Err(MyError::CustomError((
+            403,
+            "Verify your email id before listing items".to_string(),
+        )));
+    }
+    parts.extensions.insert(user);
+    Ok(next.run(Request::from_parts(parts, body)).await)
+}
@@ -1,12 +1,9 @@
-use crate::errors::MyError;
-use crate::user::{check_session_validity, extract_session_header, GeneralResponse};
+use crate::auth::AuthUser;
+use crate::errors::{ErrorResponse, MyError};
+use crate::reservation::{lock_cart_stock, reserve_cart};
+use crate::user::GeneralResponse;
 use crate::AppState;
-use axum::{
-    extract::State,
-    http::{HeaderMap, StatusCode},
-    response::IntoResponse,
-    Form, Json,
-};
+use axum::{extract::State, http::StatusCode, response::IntoResponse, Form, Json};
 // use chrono::{NaiveDateTime, Utc};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
@@ -16,7 +13,8 @@ use uuid::Uuid;
 
 #[derive(FromRow, ToSchema, Deserialize, Serialize)]
 pub struct CartItem {
-    item_id: Uuid,
+    ///variant_id of the item variant in the cart
+    variant_id: Uuid,
     quantity: i32,
 }
 
@@ -30,15 +28,23 @@ pub struct CartResponse {
     detail: Cart,
 }
 
+#[derive(ToSchema, Serialize)]
+pub struct ReservationResponse {
+    detail: String,
+    /// Time until which the items in the cart are held for checkout
+    reserved_until: Option<chrono::NaiveDateTime>,
+}
+
 #[utoipa::path(
     get,
     path = "/cart",
     security(
-        ("session_id" = [])
+        ("session_id" = ["buyer"])
     ),
     responses(
         (status = 200 , body = CartResponse),
         (status = 401, body = GeneralResponse),
+        (status = 403, body = ErrorResponse),
         (status = 500, body = GeneralResponse)
     )
 )]
@@ -47,151 +53,134 @@ pub struct CartResponse {
 /// Endpoint to get all items in a user's cart
 pub async fn get_cart(
     state: State<AppState>,
-    headers: HeaderMap,
+    AuthUser(user): AuthUser,
 ) -> Result<impl IntoResponse, MyError> {
-    let session_id = extract_session_header(headers).await?;
-    match check_session_validity(&state.db_pool, session_id).await {
-        Some(user) => {
-            let query = r#"SELECT "item_id","quantity" FROM "cart" WHERE "cart_id" = $1"#;
+    let query = r#"SELECT "variant_id","quantity" FROM "cart" WHERE "cart_id" = $1"#;
 
-            match sqlx::query_as::<_, CartItem>(query)
-                .bind(user.user_id)
-                .fetch_all(&state.db_pool)
-                .await
-            {
-                Ok(cart) => Ok((
-                    StatusCode::OK,
-                    Json(json!(CartResponse {
-                        detail: Cart { items: cart }
-                    })),
-                )),
-                Err(_e) => Err(MyError::InternalServerError),
-            }
-        }
-        None => Err(MyError::UnauthorizedError),
+    match sqlx::query_as::<_, CartItem>(query)
+        .bind(user.user_id)
+        .fetch_all(&state.db_pool)
+        .await
+    {
+        Ok(cart) => Ok((
+            StatusCode::OK,
+            Json(json!(CartResponse {
+                detail: Cart { items: cart }
+            })),
+        )),
+        Err(_e) => Err(MyError::InternalServerError),
     }
 }
 
 #[utoipa::path(post,
     path = "/cart/item",
     security(
-        ("session_id"=[])
+        ("session_id" = ["buyer"])
     ),
     responses(
         (status =200 ,body = GeneralResponse),
         (status =500 ,body = GeneralResponse),
         (status =401 ,body = GeneralResponse),
+        (status = 403, body = ErrorResponse),
     )
 )]
 pub async fn add_item(
-    headers: HeaderMap,
     state: State<AppState>,
+    AuthUser(userresponse): AuthUser,
     Form(form_data): Form<CartItem>,
 ) -> Result<impl IntoResponse, MyError> {
-    let session_id = extract_session_header(headers).await?;
-    match check_session_validity(&state.db_pool, session_id).await {
-        Some(userresponse) => {
-            //create plsql function to check and return stock issues
-            let query = r#"INSERT INTO "cart" ("cart_id","item_id","quantity") 
-            SELECT $1,$2,$3 WHERE stock_validation($2,$3) IS TRUE AND item_ownership($2,$1) IS NOT TRUE
-            ON CONFLICT("cart_id","item_id")
-            DO UPDATE SET "quantity" = EXCLUDED."quantity" RETURNING "item_id","quantity""#;
-            match sqlx::query_as::<_, CartItem>(query)
-                .bind(userresponse.user_id)
-                .bind(form_data.item_id)
-                .bind(form_data.quantity)
-                .fetch_optional(&state.db_pool)
-                .await
-            {
-                Ok(response) => match response {
-                    Some(_t) => Ok((
-                        StatusCode::OK,
-                        Json(json!(GeneralResponse {
-                            detail: "Item Added to cart".to_string()
-                        })),
-                    )),
-                    None => Err(MyError::ConflictError),
-                },
-                Err(_e) => Err(MyError::InternalServerError),
-            }
-        }
-        None => Err(MyError::UnauthorizedError),
+    //create plsql function to check and return stock issues
+    let query = r#"INSERT INTO "cart" ("cart_id","variant_id","quantity") 
+    SELECT $1,$2,$3 WHERE stock_validation($2,$3,$1) IS TRUE AND variant_ownership($2,$1) IS NOT TRUE
+    ON CONFLICT("cart_id","variant_id")
+    DO UPDATE SET "quantity" = EXCLUDED."quantity" RETURNING "variant_id","quantity""#;
+    match sqlx::query_as::<_, CartItem>(query)
+        .bind(userresponse.user_id)
+        .bind(form_data.variant_id)
+        .bind(form_data.quantity)
+        .fetch_optional(&state.db_pool)
+        .await
+    {
+        Ok(response) => match response {
+            Some(_t) => Ok((
+                StatusCode::OK,
+                Json(json!(GeneralResponse {
+                    detail: "Item Added to cart".to_string()
+                })),
+            )),
+            None => Err(MyError::ConflictError),
+        },
+        Err(_e) => Err(MyError::InternalServerError),
     }
 }
 #[utoipa::path(
     post,
     path = "/cart/update",
     security(
-        ("session_id" = [])
+        ("session_id" = ["buyer"])
     ),
     responses(
         (status = 401, body = GeneralResponse),
+        (status = 403, body = ErrorResponse),
         (status = 200, body = GeneralResponse),
         (status = 500, body = GeneralResponse)
     )
 )]
 pub async fn update_cart_item(
-    headers: HeaderMap,
     state: State<AppState>,
+    AuthUser(userresponse): AuthUser,
     Form(form_data): Form<CartItem>,
 ) -> Result<impl IntoResponse, MyError> {
-    let session_id = extract_session_header(headers).await?;
-    match check_session_validity(&state.db_pool, session_id).await {
-        Some(userresponse) => {
-            if form_data.quantity > 0 {
-                let query = r#"UPDATE "cart" SET "quantity" = $3 WHERE "cart_id" = $1 AND "item_id" = $2 AND item_ownership($2,$1) IS NOT TRUE AND stock_validation($2,$3) IS TRUE RETURNING "item_id","quantity""#;
-                match sqlx::query_as::<_, CartItem>(query)
-                    .bind(userresponse.user_id)
-                    .bind(form_data.item_id)
-                    .bind(form_data.quantity)
-                    .fetch_optional(&state.db_pool)
-                    .await
-                {
-                    Ok(response) => match response {
-                        Some(_t) => Ok((
-                            StatusCode::OK,
-                            Json(json!(GeneralResponse {
-                                detail: "Cart updated".to_string()
-                            })),
-                        )),
-                        None => Ok((
-                            StatusCode::OK,
-                            Json(json!(GeneralResponse {
-                                detail: "Cart Not Updated".to_string()
-                            })),
-                        )),
-                    },
-                    Err(_e) => Err(MyError::InternalServerError),
-                }
-            } else {
-                match sqlx::query!(
-                    r#"DELETE FROM "cart" WHERE "cart_id" = $1 AND "item_id" = $2"#,
-                    userresponse.user_id,
-                    form_data.item_id,
-                )
-                .execute(&state.db_pool)
-                .await
-                {
-                    Ok(_response) => Ok((
-                        StatusCode::OK,
-                        Json(json!(GeneralResponse {
-                            detail: "Cart updated".to_string()
-                        })),
-                    )),
-                    Err(_e) => Err(MyError::InternalServerError),
-                }
-            }
+    if form_data.quantity > 0 {
+        let query = r#"UPDATE "cart" SET "quantity" = $3 WHERE "cart_id" = $1 AND "variant_id" = $2 AND variant_ownership($2,$1) IS NOT TRUE AND stock_validation($2,$3,$1) IS TRUE RETURNING "variant_id","quantity""#;
+        match sqlx::query_as::<_, CartItem>(query)
+            .bind(userresponse.user_id)
+            .bind(form_data.variant_id)
+            .bind(form_data.quantity)
+            .fetch_optional(&state.db_pool)
+            .await
+        {
+            Ok(response) => match response {
+                Some(_t) => Ok((
+                    StatusCode::OK,
+                    Json(json!(GeneralResponse {
+                        detail: "Cart updated".to_string()
+                    })),
+                )),
+                None => Ok((
+                    StatusCode::OK,
+                    Json(json!(GeneralResponse {
+                        detail: "Cart Not Updated".to_string()
+                    })),
+                )),
+            },
+            Err(_e) => Err(MyError::InternalServerError),
+        }
+    } else {
+        match sqlx::query(r#"DELETE FROM "cart" WHERE "cart_id" = $1 AND "variant_id" = $2"#)
+            .bind(userresponse.user_id)
+            .bind(form_data.variant_id)
+            .execute(&state.db_pool)
+            .await
+        {
+            Ok(_response) => Ok((
+                StatusCode::OK,
+                Json(json!(GeneralResponse {
+                    detail: "Cart updated".to_string()
+                })),
+            )),
+            Err(_e) => Err(MyError::InternalServerError),
         }
-        None => Err(MyError::UnauthorizedError),
     }
 }
 #[utoipa::path(
     get,
     path = "/cart/subcheckout",
-    security (("session_id" = [])),
+    security (("session_id" = ["buyer"])),
     responses(
-        (status = 200 , body = GeneralResponse),
+        (status = 200 , body = ReservationResponse),
         (status = 401 , body = GeneralResponse),
+        (status = 403, body = ErrorResponse),
         (status = 500 , body = GeneralResponse),
         (status = 409 , body = CartResponse)
     )
@@ -199,36 +188,51 @@ pub async fn update_cart_item(
 /// SubCheckout Cart
 ///
 /// Checking whether items in the cart are still in stock.
+/// Items which are in stock are held for the cart until `reserved_until`,
+/// items which are not are removed from the cart and returned.
 pub async fn check_cart(
-    headers: HeaderMap,
     state: State<AppState>,
+    AuthUser(user): AuthUser,
 ) -> Result<impl IntoResponse, MyError> {
-    let session_id = extract_session_header(headers).await?;
-    match check_session_validity(&state.db_pool, session_id).await {
-        Some(user) => {
-            let query = r#"DELETE FROM "cart" 
-            where
-            stock_validation("item_id","quantity") IS NOT TRUE 
-            AND
-            "cart_id" = $1 RETURNING "item_id","quantity"; 
-                "#;
-            match sqlx::query_as::<_, CartItem>(query)
-                .bind(user.user_id)
-                .fetch_all(&state.db_pool)
-                .await
-                .map_err(|_| MyError::InternalServerError)?
-            {
-                items => match items.len() {
-                    0 => Ok((
-                        StatusCode::OK,
-                        Json(json!(GeneralResponse {
-                            detail: "Items In Stock, Proceed to Checkout".to_string()
-                        })),
-                    )),
-                    _ => Ok((StatusCode::CONFLICT, Json(json!(Cart { items: items })))),
-                },
+    let mut txn = state
+        .db_pool
+        .begin()
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    lock_cart_stock(&mut txn, user.user_id).await?;
+    let query = r#"DELETE FROM "cart" 
+    where
+    stock_validation("variant_id","quantity","cart_id") IS NOT TRUE 
+    AND
+    "cart_id" = $1 RETURNING "variant_id","quantity"; 
+        "#;
+    match sqlx::query_as::<_, CartItem>(query)
+        .bind(user.user_id)
+        .fetch_all(&mut *txn)
+        .await
+        .map_err(|_| MyError::InternalServerError)?
+    {
+        items => match items.len() {
+            0 => {
+                let reserved_until =
+                    reserve_cart(&mut txn, user.user_id, state.reservation_window).await?;
+                txn.commit()
+                    .await
+                    .map_err(|_| MyError::InternalServerError)?;
+                Ok((
+                    StatusCode::OK,
+                    Json(json!(ReservationResponse {
+                        detail: "Items In Stock, Proceed to Checkout".to_string(),
+                        reserved_until,
+                    })),
+                ))
             }
-        }
-        None => Err(MyError::UnauthorizedError),
+            _ => {
+                txn.commit()
+                    .await
+                    .map_err(|_| MyError::InternalServerError)?;
+                Ok((StatusCode::CONFLICT, Json(json!(Cart { items: items }))))
+            }
+        },
     }
 }
@@ -0,0 +1,187 @@
+use crate::{auth::AuthUser, errors::MyError, user::GeneralResponse, AppState, ErrorResponse};
+use axum::{extract::State, http::StatusCode, response::IntoResponse, Form, Json};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{FromRow, PgConnection};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+#[derive(Deserialize, ToSchema)]
+pub struct CategoryForm {
+    name: String,
+    /// Url friendly name of the category, derived from the name when left out
+    slug: Option<String>,
+    /// category_id of the parent category, left out for a top level category
+    parent_id: Option<Uuid>,
+}
+
+#[derive(Serialize, FromRow, ToSchema)]
+pub struct CategoryId {
+    category_id: Uuid,
+}
+
+#[derive(Serialize, FromRow, ToSchema)]
+pub struct Category {
+    category_id: Uuid,
+    parent_id: Option<Uuid>,
+    name: String,
+    slug: String,
+    /// Number of items filed directly under the category
+    item_count: i64,
+    /// Number of items filed under the category or any of its descendants
+    total_item_count: i64,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct CategoryTree {
+    categories: Vec<Category>,
+}
+
+/// Slugs are lowercase ascii letters and digits separated by single dashes
+pub fn valid_slug(slug: &str) -> bool {
+    !slug.is_empty()
+        && slug.len() <= 100
+        && slug.split('-').all(|part| {
+            !part.is_empty() && part.bytes().all(|b| matches!(b, b'a'..=b'z' | b'0'..=b'9'))
+        })
+}
+
+fn slugify(name: &str) -> String {
+    name.to_lowercase()
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<&str>>()
+        .join("-")
+}
+
+/// Parses a comma separated list of category ids, as sent by the item forms
+pub fn parse_category_ids(category_ids: &str) -> Result<Vec<Uuid>, MyError> {
+    let mut ids = category_ids
+        .split(',')
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .map(Uuid::parse_str)
+        .collect::<Result<Vec<Uuid>, _>>()
+        .map_err(|_| MyError::UnproccessableEntityError)?;
+    ids.sort();
+    ids.dedup();
+    Ok(ids)
+}
+
+/// Replaces the categories an item is filed under
+pub async fn assign_categories(
+    conn: &mut PgConnection,
+    item_id: Uuid,
+    category_ids: &[Uuid],
+) -> Result<(), MyError> {
+    sqlx::query(r#"DELETE FROM "item_category" WHERE "item_id" = $1"#)
+        .bind(item_id)
+        .execute(&mut *conn)
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    let query = r#"
+        INSERT INTO "item_category" ("item_id","category_id")
+        SELECT $1, "category_id" FROM UNNEST($2::uuid[]) AS t("category_id");
+    "#;
+    match sqlx::query(query)
+        .bind(item_id)
+        .bind(category_ids)
+        .execute(conn)
+        .await
+    {
+        Ok(_) => Ok(()),
+        Err(sqlx::Error::Database(e)) if e.is_foreign_key_violation() => {
+            Err(MyError::CustomError((422, "Unknown category".to_string())))
+        }
+        Err(_) => Err(MyError::InternalServerError),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/category/create",
+    request_body(content = CategoryForm, content_type = "application/x-www-form-urlencoded"),
+    security(
+        ("session_id" = ["admin"])
+    ),
+    responses(
+        (status = 201, body = CategoryId),
+        (status = 401, body = GeneralResponse),
+        (status = 403, body = ErrorResponse),
+        (status = 409, body = ErrorResponse),
+        (status = 422, body = ErrorResponse),
+        (status = 500, body = ErrorResponse)
+    )
+)]
+/// Create Category
+///
+/// Endpoint to add a category, optionally under an existing parent category
+pub async fn create_category(
+    state: State<AppState>,
+    _user: AuthUser,
+    Form(form_data): Form<CategoryForm>,
+) -> Result<impl IntoResponse, MyError> {
+    let name = form_data.name.trim();
+    let slug = form_data.slug.unwrap_or_else(|| slugify(name));
+    if name.is_empty() || name.len() > 100 || !valid_slug(&slug) {
+        return Err(MyError::UnproccessableEntityError);
+    }
+    let query = r#"
+        INSERT INTO "category" ("parent_id","name","slug")
+        VALUES ($1,$2,$3) RETURNING "category_id";
+    "#;
+    match sqlx::query_as::<_, CategoryId>(query)
+        .bind(form_data.parent_id)
+        .bind(name)
+        .bind(&slug)
+        .fetch_one(&state.db_pool)
+        .await
+    {
+        Ok(category) => Ok((StatusCode::CREATED, Json(json!(category)))),
+        Err(sqlx::Error::Database(e)) if e.is_unique_violation() => Err(MyError::CustomError((
+            409,
+            "Category slug already exists".to_string(),
+        ))),
+        Err(sqlx::Error::Database(e)) if e.is_foreign_key_violation() => Err(MyError::CustomError(
+            (422, "Unknown parent category".to_string()),
+        )),
+        Err(_) => Err(MyError::InternalServerError),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/category",
+    responses(
+        (status = 200, body = CategoryTree),
+        (status = 500, body = ErrorResponse)
+    )
+)]
+/// Get Categories
+///
+/// Endpoint to list every category with its parent and item counts,
+/// the tree is rebuilt from the parent_id of each category
+pub async fn get_categories(state: State<AppState>) -> Result<impl IntoResponse, MyError> {
+    let query = r#"
+        WITH RECURSIVE "ancestry" AS (
+            SELECT "category_id", "category_id" AS "ancestor_id" FROM "category"
+            UNION ALL
+            SELECT a."category_id", c."parent_id"
+            FROM "ancestry" a INNER JOIN "category" c ON c."category_id" = a."ancestor_id"
+            WHERE c."parent_id" IS NOT NULL
+        )
+        SELECT c."category_id", c."parent_id", c."name", c."slug",
+        (SELECT COUNT(*) FROM "item_category" ic
+         WHERE ic."category_id" = c."category_id") AS "item_count",
+        (SELECT COUNT(DISTINCT ic."item_id") FROM "ancestry" a
+         INNER JOIN "item_category" ic ON ic."category_id" = a."category_id"
+         WHERE a."ancestor_id" = c."category_id") AS "total_item_count"
+        FROM "category" c
+        ORDER BY c."name";
+    "#;
+    let categories = sqlx::query_as::<_, Category>(query)
+        .fetch_all(&state.db_pool)
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    Ok((StatusCode::OK, Json(json!(CategoryTree { categories }))))
+}
@@ -0,0 +1,178 @@
+use crate::{
+    auth::AuthUser,
+    errors::MyError,
+    listing::{fetch_page, Cursor, Keyset, Listing, Page, Paginated},
+    user::GeneralResponse,
+    AppState, ErrorResponse,
+};
+use axum::{
+    extract::{OriginalUri, Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{FromRow, PgConnection};
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
+
+/// Why the stock of an item moved
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize, ToSchema, sqlx::Type)]
+#[sqlx(type_name = "movement_reason", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum MovementReason {
+    Restock,
+    Sale,
+    Cancellation,
+    Correction,
+    Return,
+}
+
+impl MovementReason {
+    /// Sales and cancellations are only recorded by orders
+    pub fn is_manual(&self) -> bool {
+        matches!(
+            self,
+            MovementReason::Restock | MovementReason::Correction | MovementReason::Return
+        )
+    }
+}
+
+#[derive(FromRow, ToSchema, Serialize)]
+pub struct InventoryMovement {
+    ///movement_id of the movement
+    movement_id: Uuid,
+    ///variant_id of the item variant whose stock moved
+    variant_id: Uuid,
+    ///change in stock, negative when units left stock
+    delta: i32,
+    ///reason for the movement
+    reason: MovementReason,
+    ///stock of the variant after the movement
+    balance_after: i32,
+    ///order_id of the order which caused the movement
+    order_id: Option<Uuid>,
+    ///note left by the seller
+    note: Option<String>,
+    ///user_id of the user who caused the movement
+    created_by: Option<Uuid>,
+    ///time of the movement
+    created_at: chrono::NaiveDateTime,
+}
+
+/// Movements in the order they were recorded, newest first
+const NEWEST_MOVEMENTS: Keyset = Keyset {
+    columns: &[(r#""seq""#, "bigint")],
+    descending: true,
+};
+
+#[derive(Deserialize, ToSchema, IntoParams)]
+pub struct StockHistoryQuery {
+    /// Number of movements to fetch per page
+    take: Option<u32>,
+    /// Page number to fetch, ignored when a cursor is given
+    page_no: Option<u32>,
+    /// `next_cursor` of the previous page, to fetch the page after it
+    cursor: Option<String>,
+}
+
+#[derive(FromRow)]
+struct Balance {
+    balance_after: i32,
+}
+
+#[derive(FromRow)]
+struct Ownership {
+    owned: bool,
+}
+
+/// Records a movement in the variant's ledger and returns the resulting stock.
+///
+/// Fails with a conflict if the movement would take the stock below zero.
+pub async fn record_movement(
+    conn: &mut PgConnection,
+    variant_id: Uuid,
+    delta: i32,
+    reason: MovementReason,
+    note: Option<String>,
+    created_by: Option<Uuid>,
+) -> Result<i32, MyError> {
+    let query = r#"
+        INSERT INTO "inventory_movement" ("variant_id","delta","reason","note","created_by")
+        VALUES ($1,$2,$3,$4,$5)
+        RETURNING "balance_after";
+    "#;
+    match sqlx::query_as::<_, Balance>(query)
+        .bind(variant_id)
+        .bind(delta)
+        .bind(reason)
+        .bind(note)
+        .bind(created_by)
+        .fetch_one(conn)
+        .await
+    {
+        Ok(balance) => Ok(balance.balance_after),
+        Err(sqlx::Error::Database(e)) if e.is_check_violation() => Err(MyError::CustomError((
+            409,
+            "Not enough stock for this adjustment".to_string(),
+        ))),
+        Err(_) => Err(MyError::InternalServerError),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/item/{item_id}/stock/history",
+    params(
+        ("item_id" = Uuid, Path, description = "item_id of the item"),
+        StockHistoryQuery
+    ),
+    security(
+        ("session_id" = ["seller"])
+    ),
+    responses(
+        (status = 200, body = Paginated<InventoryMovement>),
+        (status = 401, body = GeneralResponse),
+        (status = 403, body = ErrorResponse),
+        (status = 404, body = ErrorResponse),
+        (status = 422, body = ErrorResponse),
+        (status = 500, body = ErrorResponse)
+    )
+)]
+/// Get Stock History
+///
+/// Endpoint for the seller to page through the stock movements of every variant of an item, newest first
+pub async fn get_stock_history(
+    state: State<AppState>,
+    AuthUser(user): AuthUser,
+    OriginalUri(uri): OriginalUri,
+    Path(item_id): Path<Uuid>,
+    Query(pagination): Query<StockHistoryQuery>,
+) -> Result<impl IntoResponse, MyError> {
+    let ownership = sqlx::query_as::<_, Ownership>(r#"SELECT item_ownership($1,$2) AS "owned""#)
+        .bind(item_id)
+        .bind(user.user_id)
+        .fetch_one(&state.db_pool)
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    if !ownership.owned {
+        return Err(MyError::NotFound);
+    }
+    let cursor = match &pagination.cursor {
+        Some(token) => Some(Cursor::decode(token)?),
+        None => None,
+    };
+    let mut listing = Listing::keyset(
+        &NEWEST_MOVEMENTS,
+        r#""movement_id","variant_id","delta","reason","balance_after","order_id","note","created_by","created_at""#,
+    );
+    listing
+        .push(r#" FROM "inventory_movement""#)
+        .condition(r#""variant_id" IN (SELECT "variant_id" FROM "item_variant" WHERE "item_id" = "#)
+        .bind(item_id)
+        .push(")");
+    let query = listing.paginate(Page::new(pagination.take, pagination.page_no), cursor)?;
+    let movements = fetch_page::<InventoryMovement>(query, &uri, &state.db_pool).await?;
+    Ok((StatusCode::OK, Json(json!(movements))))
+}
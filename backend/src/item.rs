@@ -1,50 +1,72 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 use crate::{
+    auth::AuthUser,
+    category::{assign_categories, parse_category_ids, valid_slug},
     errors::MyError,
+    inventory::{record_movement, MovementReason},
+    listing::{fetch_page, Cursor, Keyset, Listing, Page, PageQuery, Paginated},
     objects::{get_presigned_url, put_object},
-    user::{check_session_validity, extract_session_header, GeneralResponse},
+    user::GeneralResponse,
     AppState, CommentFilters, ErrorResponse, Filters, Order,
 };
 use axum::{
-    extract::{Multipart, Path, Query, State},
-    http::{HeaderMap, StatusCode},
+    extract::{Multipart, OriginalUri, Path, Query, State},
+    http::StatusCode,
     response::IntoResponse,
     Form, Json,
 };
 // use chrono::{NaiveDateTime, Utc};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use sqlx::FromRow;
+use sqlx::{types::Json as SqlJson, FromRow, Postgres, QueryBuilder};
 use utoipa::{IntoParams, ToSchema};
 use uuid::Uuid;
 
-struct PaginationParams {
-    take: u32,
-    offset: u32,
-}
-
-#[derive(Deserialize, ToSchema, IntoParams)]
+#[derive(Clone, Deserialize, ToSchema, IntoParams)]
 pub struct ItemsQuery {
     /// Number of items to fetch per page
     take: Option<u32>,
-    /// Page number to fetch
+    /// Page number to fetch, ignored when a cursor is given
     page_no: Option<u32>,
+    /// `next_cursor` of the previous page, to fetch the page after it
+    cursor: Option<String>,
     /// The Filter should be of either Price(Order), Rating(Order), DateOfCreation(Order), Alphabetical(Order)
+    /// or Relevance, which ranks items by how well they match `search_string`
     ///
     /// The Order should be either Inc or Dec
     #[schema(value_type=String,example = "Rating(Inc)")]
     filter: Option<String>,
     /// Search String to filter items
     search_string: Option<String>,
+    /// Slug of a category, matches items in the category or any of its descendants
+    category: Option<String>,
+    /// Lowest price of the items to fetch
+    #[schema(value_type = Option<String>, format = Float, example = "10.00")]
+    #[param(value_type = Option<String>, format = Float, example = "10.00")]
+    min_price: Option<rust_decimal::Decimal>,
+    /// Highest price of the items to fetch
+    #[schema(value_type = Option<String>, format = Float, example = "100.00")]
+    #[param(value_type = Option<String>, format = Float, example = "100.00")]
+    max_price: Option<rust_decimal::Decimal>,
+    /// Lowest average rating of the items to fetch, between 0 and 5
+    min_rating: Option<f32>,
+    /// user_id of the seller whose items to fetch
+    seller_id: Option<Uuid>,
+    /// Only fetch items which have stock left
+    in_stock: Option<bool>,
+    /// Only fetch items listed on or after this date
+    created_after: Option<chrono::NaiveDate>,
 }
 
 #[derive(Deserialize, ToSchema, IntoParams)]
 pub struct CommentQuery {
     /// Number of items to fetch per page
     take: Option<u32>,
-    /// Page number to fetch
+    /// Page number to fetch, ignored when a cursor is given
     page_no: Option<u32>,
+    /// `next_cursor` of the previous page, to fetch the page after it
+    cursor: Option<String>,
     /// The Filter should be of either Rating(Order), DateOfCreation(Order)
     ///
     /// The Order should be either Inc or Dec
@@ -62,6 +84,10 @@ pub struct ItemForm {
     price: rust_decimal::Decimal,
     #[schema(value_type = Vec<String>, format = "binary", required = false)]
     item_media: Option<Vec<Vec<u8>>>,
+    /// Comma separated category_ids to file the item under
+    category_ids: Option<String>,
+    /// SKU of the item's first variant, generated when left out
+    sku: Option<String>,
 }
 
 #[derive(Deserialize, ToSchema)]
@@ -70,6 +96,8 @@ pub struct EditItemForm {
     content: String,
     #[schema(value_type = String, format = Float, example = "10.00")]
     price: rust_decimal::Decimal,
+    /// Comma separated category_ids replacing the item's categories, left out to keep them
+    category_ids: Option<String>,
 }
 #[derive(Deserialize, ToSchema, FromRow, Serialize)]
 pub struct RateForm {
@@ -78,6 +106,16 @@ pub struct RateForm {
     item_id: Uuid,
 }
 
+/// A comment as listed, along with its author so that it can be moderated
+#[derive(Serialize, FromRow, ToSchema)]
+pub struct Comment {
+    user_id: Uuid,
+    item_id: Uuid,
+    rating: i32,
+    content: String,
+    date_created: Option<chrono::NaiveDateTime>,
+}
+
 #[derive(Serialize, Deserialize, FromRow, ToSchema)]
 pub struct ItemId {
     item_id: Uuid,
@@ -100,22 +138,124 @@ pub struct MediaResponse {
     item_id: Uuid,
 }
 
+#[derive(Serialize, Deserialize, FromRow, ToSchema)]
+pub struct VariantId {
+    variant_id: Uuid,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct VariantForm {
+    /// Stock keeping unit, unique across all items
+    sku: String,
+    /// JSON object of attribute names to values
+    #[schema(example = r#"{"size":"M","colour":"red"}"#)]
+    attributes: Option<String>,
+    /// Price of the variant, the item price is used when left out
+    #[schema(value_type = Option<String>, format = Float, example = "12.00")]
+    price: Option<rust_decimal::Decimal>,
+}
+
+#[derive(Serialize, Deserialize, FromRow, ToSchema)]
+pub struct ItemVariant {
+    variant_id: Uuid,
+    sku: String,
+    #[schema(value_type = Object)]
+    attributes: SqlJson<BTreeMap<String, String>>,
+    /// Price of the variant, falling back to the item price
+    price: rust_decimal::Decimal,
+    stock: i32,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct VariantMatrix {
+    /// Every value offered for each attribute across the variants
+    options: BTreeMap<String, Vec<String>>,
+    variants: Vec<ItemVariant>,
+}
+
 #[derive(Deserialize, Serialize, FromRow, ToSchema)]
 pub struct ItemStock {
-    item_id: Uuid,
+    variant_id: Uuid,
     quantity: i32,
 }
 
+#[derive(Deserialize, ToSchema)]
+pub struct StockAdjustmentForm {
+    variant_id: Uuid,
+    /// Change in stock, negative to take units out of stock
+    delta: i32,
+    /// One of restock, correction or return
+    reason: MovementReason,
+    /// Optional note kept in the stock history
+    note: Option<String>,
+}
+
 #[derive(Serialize, Deserialize, ToSchema)]
 pub struct ItemResponse {
     detail: Item,
     media: Option<Vec<String>>,
     sameuser: bool,
+    /// Variants of the item, only returned for a single item
+    variants: Option<VariantMatrix>,
+    /// Matches of `search_string` in the item, only returned when searching
+    highlight: Option<Highlight>,
+}
+
+#[derive(Serialize, Deserialize, FromRow, ToSchema)]
+pub struct Highlight {
+    #[serde(skip)]
+    item_id: Uuid,
+    /// HTML-escaped title with the matched words wrapped in `<mark>` tags
+    title: String,
+    /// HTML-escaped excerpt of the content around the matched words, wrapped
+    /// in `<mark>` tags
+    snippet: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct SearchHit {
+    detail: Item,
+    highlight: Highlight,
 }
 
 #[derive(Serialize, ToSchema)]
 pub struct PageResponse {
-    items: Vec<ItemResponse>,
+    #[serde(flatten)]
+    page: Paginated<ItemResponse>,
+    /// Counts over every item matching the query, not just this page
+    facets: Facets,
+}
+
+#[derive(Serialize, FromRow, ToSchema)]
+pub struct PriceBucket {
+    #[schema(value_type = String, format = Float)]
+    min_price: rust_decimal::Decimal,
+    /// Exclusive upper bound, empty for the last bucket
+    #[schema(value_type = Option<String>, format = Float)]
+    max_price: Option<rust_decimal::Decimal>,
+    count: i64,
+}
+
+#[derive(Serialize, FromRow, ToSchema)]
+pub struct RatingBucket {
+    /// Items rated at least this much
+    min_rating: i32,
+    count: i64,
+}
+
+#[derive(Serialize, FromRow, ToSchema)]
+pub struct SellerFacet {
+    user_id: Uuid,
+    username: String,
+    count: i64,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Facets {
+    price: Vec<PriceBucket>,
+    rating: Vec<RatingBucket>,
+    /// Sellers with the most matching items, at most 20
+    sellers: Vec<SellerFacet>,
 }
 
 #[derive(Deserialize, ToSchema, Debug, IntoParams)]
@@ -123,9 +263,36 @@ pub struct SearchQuery {
     query: String,
 }
 
+#[derive(Deserialize, ToSchema, Debug, IntoParams)]
+pub struct AutocompleteQuery {
+    /// Partial text typed so far, misspellings are tolerated
+    query: String,
+    /// Maximum number of suggestions, at most 10
+    limit: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, FromRow, ToSchema)]
+pub struct Suggestion {
+    /// Either "item" or "category"
+    kind: String,
+    /// item_id or category_id, depending on the kind
+    id: Uuid,
+    /// Item title or category name
+    text: String,
+    /// Slug of the category, only set for categories
+    slug: Option<String>,
+    /// Trigram word similarity between the query and the text, from 0 to 1
+    score: f32,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct AutocompleteResult {
+    suggestions: Vec<Suggestion>,
+}
+
 #[derive(Serialize, ToSchema)]
 pub struct SearchResult {
-    keywords: Vec<Item>,
+    keywords: Vec<SearchHit>,
 }
 
 #[utoipa::path(
@@ -134,147 +301,173 @@ pub struct SearchResult {
     responses (
         (status = 201, body = GeneralResponse),
         (status = 401, body = ErrorResponse),
+        (status = 403, body = ErrorResponse),
         (status = 500, body = ErrorResponse)
     ),
     request_body(content_type = "multipart/form-data", content = ItemForm),
     security(
-        ("session_id"=[])
+        ("session_id" = ["seller"])
     )
 )]
 /// Create Item
 ///
 /// Endpoint to create an Item
 pub async fn create_item(
-    headers: HeaderMap,
     state: State<AppState>,
+    AuthUser(userwithsession): AuthUser,
     // Form(form_data): Form<ItemForm>,
     mut multipart: Multipart,
 ) -> Result<impl IntoResponse, MyError> {
-    let session_id = extract_session_header(headers).await?;
     //multipart form handling
 
-    match check_session_validity(&state.db_pool, session_id).await {
-        Some(userwithsession) => {
-            let mut txn = state.db_pool.begin().await.unwrap();
-
-            let mut form_data = ItemForm {
-                title: "".to_string(),
-                content: "".to_string(),
-                price: rust_decimal::Decimal::new(0, 0),
-                item_media: None,
-            };
-
-            let mut item_media: Vec<Vec<u8>> = vec![];
-
-            while let Some(field) = multipart.next_field().await.unwrap() {
-                let name = field.name().unwrap().to_owned();
-                let data;
-                match field.bytes().await {
-                    Ok(bytes) => data = bytes.to_vec(),
-                    Err(_e) => {
-                        return Err(MyError::UnproccessableEntityError);
-                    }
-                }
+    let mut txn = state.db_pool.begin().await.unwrap();
 
-                match name.as_str() {
-                    "title" => form_data.title = String::from_utf8(data).unwrap(),
-                    "content" => form_data.content = String::from_utf8(data).unwrap(),
-                    "price" => form_data.price = String::from_utf8(data).unwrap().parse().unwrap(),
-                    "item_media" => {
-                        if data.len() > 0 {
-                            item_media.push(data);
-                        } else {
-                            ()
-                        }
-                    }
-                    _ => (),
-                }
+    let mut form_data = ItemForm {
+        title: "".to_string(),
+        content: "".to_string(),
+        price: rust_decimal::Decimal::new(0, 0),
+        item_media: None,
+        category_ids: None,
+        sku: None,
+    };
+
+    let mut item_media: Vec<Vec<u8>> = vec![];
+
+    while let Some(field) = multipart.next_field().await.unwrap() {
+        let name = field.name().unwrap().to_owned();
+        let data;
+        match field.bytes().await {
+            Ok(bytes) => data = bytes.to_vec(),
+            Err(_e) => {
+                return Err(MyError::UnproccessableEntityError);
             }
+        }
 
-            match item_media.len() {
-                0 => form_data.item_media = None,
-                _ => form_data.item_media = Some(item_media.clone()),
+        match name.as_str() {
+            "title" => form_data.title = String::from_utf8(data).unwrap(),
+            "content" => form_data.content = String::from_utf8(data).unwrap(),
+            "price" => form_data.price = String::from_utf8(data).unwrap().parse().unwrap(),
+            "sku" => {
+                form_data.sku =
+                    Some(String::from_utf8(data).map_err(|_| MyError::UnproccessableEntityError)?)
             }
+            "category_ids" => {
+                form_data.category_ids =
+                    Some(String::from_utf8(data).map_err(|_| MyError::UnproccessableEntityError)?)
+            }
+            "item_media" => {
+                if data.len() > 0 {
+                    item_media.push(data);
+                } else {
+                    ()
+                }
+            }
+            _ => (),
+        }
+    }
 
-            match sqlx::query_as::<_, ItemId>(
-                r#"
-                INSERT INTO "item" ("user_id","title","content","price")
-                VALUES ($1,$2,$3,$4) returning "item_id""#,
+    match item_media.len() {
+        0 => form_data.item_media = None,
+        _ => form_data.item_media = Some(item_media.clone()),
+    }
+    let category_ids = match &form_data.category_ids {
+        Some(category_ids) => parse_category_ids(category_ids)?,
+        None => vec![],
+    };
+    if form_data.sku.as_deref().is_some_and(|sku| !valid_sku(sku)) {
+        return Err(MyError::UnproccessableEntityError);
+    }
+
+    match sqlx::query_as::<_, ItemId>(
+        r#"
+        INSERT INTO "item" ("user_id","title","content","price")
+        VALUES ($1,$2,$3,$4) returning "item_id""#,
+    )
+    .bind(&userwithsession.user_id)
+    .bind(&form_data.title)
+    .bind(&form_data.content)
+    .bind(&form_data.price)
+    .fetch_optional(&mut *txn)
+    .await
+    .map_err(|_| MyError::InternalServerError)?
+    {
+        Some(item_response) => {
+            insert_variant(
+                &mut txn,
+                item_response.item_id,
+                form_data
+                    .sku
+                    .unwrap_or_else(|| item_response.item_id.to_string()),
+                BTreeMap::new(),
+                None,
             )
-            .bind(&userwithsession.user_id)
-            .bind(&form_data.title)
-            .bind(&form_data.content)
-            .bind(&form_data.price)
-            .fetch_optional(&mut *txn)
-            .await
-            .map_err(|_| MyError::InternalServerError)?
-            {
-                Some(item_response) => match form_data.item_media {
-                    Some(media) => {
-                        let mut media_ids: Vec<Uuid> = vec![];
-                        for _media_item in &media {
-                            media_ids.push(Uuid::new_v4());
-                        }
-                        let media_query = r#"
-                            INSERT INTO "item_media" ("media_id","item_id")
-                            (SELECT * FROM UNNEST($1::uuid[],$2::uuid[])) RETURNING "item_id" ;
-                        "#;
-                        match sqlx::query_as::<_, ItemId>(media_query)
-                            .bind(&media_ids)
-                            .bind(vec![item_response.item_id; media_ids.len()])
-                            .fetch_optional(&mut *txn)
-                            .await
-                            .map_err(|_| MyError::InternalServerError)?
-                        {
-                            Some(_response) => {
-                                for (index, media_item) in media.iter().enumerate() {
-                                    let file_key = format!("{}.jpg", media_ids[index]);
-                                    let data_stream = aws_sdk_s3::primitives::ByteStream::from(
-                                        media_item.clone(),
-                                    );
-                                    match put_object(
-                                        &state.s3_client,
-                                        &state.image_bucket,
-                                        file_key,
-                                        data_stream,
-                                    )
-                                    .await
-                                    {
-                                        Err(_e) => return Err(MyError::UnproccessableEntityError),
-                                        _ => (),
-                                    }
+            .await?;
+            if !category_ids.is_empty() {
+                assign_categories(&mut txn, item_response.item_id, &category_ids).await?;
+            }
+            match form_data.item_media {
+                Some(media) => {
+                    let mut media_ids: Vec<Uuid> = vec![];
+                    for _media_item in &media {
+                        media_ids.push(Uuid::new_v4());
+                    }
+                    let media_query = r#"
+                    INSERT INTO "item_media" ("media_id","item_id")
+                    (SELECT * FROM UNNEST($1::uuid[],$2::uuid[])) RETURNING "item_id" ;
+                "#;
+                    match sqlx::query_as::<_, ItemId>(media_query)
+                        .bind(&media_ids)
+                        .bind(vec![item_response.item_id; media_ids.len()])
+                        .fetch_optional(&mut *txn)
+                        .await
+                        .map_err(|_| MyError::InternalServerError)?
+                    {
+                        Some(_response) => {
+                            for (index, media_item) in media.iter().enumerate() {
+                                let file_key = format!("{}.jpg", media_ids[index]);
+                                let data_stream =
+                                    aws_sdk_s3::primitives::ByteStream::from(media_item.clone());
+                                match put_object(
+                                    &state.s3_client,
+                                    &state.image_bucket,
+                                    file_key,
+                                    data_stream,
+                                )
+                                .await
+                                {
+                                    Err(_e) => return Err(MyError::UnproccessableEntityError),
+                                    _ => (),
                                 }
-                                txn.commit().await.unwrap();
-                                Ok((
-                                    StatusCode::CREATED,
-                                    Json(json!(GeneralResponse {
-                                        detail: "Item Created".to_string()
-                                    })),
-                                ))
-                            }
-                            None => {
-                                txn.rollback().await.unwrap();
-                                return Err(MyError::BadRequest);
                             }
+                            txn.commit().await.unwrap();
+                            Ok((
+                                StatusCode::CREATED,
+                                Json(json!(GeneralResponse {
+                                    detail: "Item Created".to_string()
+                                })),
+                            ))
+                        }
+                        None => {
+                            txn.rollback().await.unwrap();
+                            return Err(MyError::BadRequest);
                         }
                     }
-                    None => {
-                        txn.commit().await.unwrap();
-                        return Ok((
-                            StatusCode::CREATED,
-                            Json(json!(GeneralResponse {
-                                detail: "Item Created".to_string()
-                            })),
-                        ));
-                    }
-                },
+                }
                 None => {
-                    txn.rollback().await.unwrap();
-                    return Err(MyError::BadRequest);
+                    txn.commit().await.unwrap();
+                    return Ok((
+                        StatusCode::CREATED,
+                        Json(json!(GeneralResponse {
+                            detail: "Item Created".to_string()
+                        })),
+                    ));
                 }
             }
         }
-        None => Err(MyError::UnauthorizedError),
+        None => {
+            txn.rollback().await.unwrap();
+            return Err(MyError::BadRequest);
+        }
     }
 }
 
@@ -282,42 +475,45 @@ pub async fn create_item(
     delete,
     path = "/item/{id}",
     security(
-        ("session_id"=[])
+        ("session_id" = ["seller"])
     ),
     responses(
         (status = 200 , body = GeneralResponse),
         (status = 401 , body = ErrorResponse),
+        (status = 403, body = ErrorResponse),
+        (status = 409, body = ErrorResponse),
         (status = 500 , body = ErrorResponse),
     )
 )]
 /// Delete Item
 ///
-/// Endpoint to delete an Item
+/// Endpoint to delete an Item. Items which have been ordered are kept for
+/// their orders and cannot be deleted.
 pub async fn delete_item(
-    headers: HeaderMap,
     state: State<AppState>,
+    AuthUser(response): AuthUser,
     Path(item_id): Path<Uuid>,
 ) -> Result<impl IntoResponse, MyError> {
-    let session_id = extract_session_header(headers).await?;
-    match check_session_validity(&state.db_pool, session_id).await {
-        Some(response) => {
-            match sqlx::query_as::<_,ItemId>(r#"DELETE FROM "item" WHERE "item_id" = $1 AND "user_id" = $2 RETURNING "item_id" "#)
-                    .bind(item_id)
-                    .bind(response.user_id)
-                    .fetch_optional(&state.db_pool)
-                    .await
-            .map_err(|_|MyError::InternalServerError)?
-
-                {
-                        Some(_item) => Ok((
-                            StatusCode::OK,
-                            Json(json!(GeneralResponse {
-                                detail: "Item Deleted".to_string()
-                            })),
-                        )),
-                        None => Err(MyError::UnauthorizedError)
-                    }
-        }
+    match sqlx::query_as::<_, ItemId>(
+        r#"DELETE FROM "item" WHERE "item_id" = $1 AND "user_id" = $2 RETURNING "item_id" "#,
+    )
+    .bind(item_id)
+    .bind(response.user_id)
+    .fetch_optional(&state.db_pool)
+    .await
+    .map_err(|e| match e {
+        sqlx::Error::Database(e) if e.is_foreign_key_violation() => MyError::CustomError((
+            409,
+            "Items which have been ordered cannot be deleted".to_string(),
+        )),
+        _ => MyError::InternalServerError,
+    })? {
+        Some(_item) => Ok((
+            StatusCode::OK,
+            Json(json!(GeneralResponse {
+                detail: "Item Deleted".to_string()
+            })),
+        )),
         None => Err(MyError::UnauthorizedError),
     }
 }
@@ -326,11 +522,12 @@ pub async fn delete_item(
     put,
     path = "/item/{id}",
     security(
-        ("session_id"=[])
+        ("session_id" = ["seller"])
     ),
     responses(
         (status = 200 , body = GeneralResponse),
         (status = 401 , body = ErrorResponse),
+        (status = 403, body = ErrorResponse),
         (status = 500 , body = ErrorResponse),
     )
 )]
@@ -338,38 +535,49 @@ pub async fn delete_item(
 ///
 /// Endpoint to edit an Item
 pub async fn edit_item(
-    headers: HeaderMap,
     state: State<AppState>,
+    AuthUser(response): AuthUser,
     Path(item_id): Path<Uuid>,
     Form(form_data): Form<EditItemForm>,
 ) -> Result<impl IntoResponse, MyError> {
-    let session_id = extract_session_header(headers).await?;
-    match check_session_validity(&state.db_pool, session_id).await {
-        Some(response) => {
-            let query = r#"
-                UPDATE "item" SET
-                "title" = $1,
-                "content" = $2,
-                "price" = $3
-                WHERE "item_id" = $4 AND "user_id" = $5 RETURNING "item_id" "#;
-            match sqlx::query_as::<_, ItemId>(query)
-                .bind(&form_data.title)
-                .bind(&form_data.content)
-                .bind(&form_data.price)
-                .bind(item_id)
-                .bind(response.user_id)
-                .fetch_optional(&state.db_pool)
-                .await
-                .map_err(|_| MyError::InternalServerError)?
-            {
-                Some(_item) => Ok((
-                    StatusCode::OK,
-                    Json(json!(GeneralResponse {
-                        detail: "Item Updated".to_string()
-                    })),
-                )),
-                None => Err(MyError::UnauthorizedError),
+    let category_ids = match &form_data.category_ids {
+        Some(category_ids) => Some(parse_category_ids(category_ids)?),
+        None => None,
+    };
+    let mut txn = state
+        .db_pool
+        .begin()
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    let query = r#"
+        UPDATE "item" SET
+        "title" = $1,
+        "content" = $2,
+        "price" = $3
+        WHERE "item_id" = $4 AND "user_id" = $5 RETURNING "item_id" "#;
+    match sqlx::query_as::<_, ItemId>(query)
+        .bind(&form_data.title)
+        .bind(&form_data.content)
+        .bind(&form_data.price)
+        .bind(item_id)
+        .bind(response.user_id)
+        .fetch_optional(&mut *txn)
+        .await
+        .map_err(|_| MyError::InternalServerError)?
+    {
+        Some(item) => {
+            if let Some(category_ids) = category_ids {
+                assign_categories(&mut txn, item.item_id, &category_ids).await?;
             }
+            txn.commit()
+                .await
+                .map_err(|_| MyError::InternalServerError)?;
+            Ok((
+                StatusCode::OK,
+                Json(json!(GeneralResponse {
+                    detail: "Item Updated".to_string()
+                })),
+            ))
         }
         None => Err(MyError::UnauthorizedError),
     }
@@ -379,93 +587,94 @@ pub async fn edit_item(
     get,
     path = "/item/{id}",
     security(
-        ("session_id"=[])
+        (),
+        ("session_id" = [])
     ),
     responses(
         (status = 200 , body = ItemResponse),
         (status = 401 , body = GeneralResponse),
+        (status = 404 , body = ErrorResponse),
         (status = 500 , body = GeneralResponse),
     )
 )]
 ///Get Item By Id
 ///
-///Endpoint to retrieve details of an Item by id
+///Endpoint to retrieve details of an Item by id, `sameuser` tells whether the
+///user behind the session, if any, is selling the item
 pub async fn get_item(
-    headers: HeaderMap,
     state: State<AppState>,
+    user: Option<AuthUser>,
     Path(item_id): Path<Uuid>,
 ) -> Result<impl IntoResponse, MyError> {
-    let session_id = extract_session_header(headers).await?;
-    match check_session_validity(&state.db_pool, session_id).await {
-        Some(uresponse) => {
-            let query = r#"SELECT t1.item_id, t1.user_id,t1.title,t1.content,t1.price,t1.rating,t2.stock 
-            FROM 
-            (SELECT * FROM "item" WHERE "item_id"= $1) AS t1 
-            LEFT JOIN
-            (SELECT "item_id","quantity" as stock FROM "stock" WHERE "item_id" = $1) AS t2 
-            ON 
-            t1."item_id" = t2."item_id""#;
-            match sqlx::query_as::<_, Item>(query)
-                .bind(item_id)
-                .fetch_one(&state.db_pool)
-                .await
-                .map_err(|_| MyError::InternalServerError)?
-            {
-                response => {
-                    let media_urls = get_presigned_urls_for_items(
-                        vec![response.item_id],
-                        &state.db_pool,
-                        &state.s3_client,
-                        &state.image_bucket,
-                    )
-                    .await
-                    .map_err(|_| MyError::InternalServerError)?;
-                    match media_urls.len() {
-                        0 => Ok((
-                            StatusCode::OK,
-                            Json(json!(ItemResponse {
-                                detail: Item {
-                                    item_id: response.item_id,
-                                    user_id: response.user_id,
-                                    title: response.title,
-                                    content: response.content,
-                                    price: response.price,
-                                    rating: response.rating,
-                                    stock: response.stock,
-                                },
-                                media: None,
-                                sameuser: if response.user_id == uresponse.user_id {
-                                    true
-                                } else {
-                                    false
-                                }
-                            })),
-                        )),
-                        _ => Ok((
-                            StatusCode::OK,
-                            Json(json!(ItemResponse {
-                                detail: Item {
-                                    item_id: response.item_id.clone(),
-                                    user_id: response.user_id,
-                                    title: response.title,
-                                    content: response.content,
-                                    price: response.price,
-                                    rating: response.rating,
-                                    stock: response.stock,
-                                },
-                                media: Some(media_urls[&response.item_id].clone()),
-                                sameuser: if response.user_id == uresponse.user_id {
-                                    true
-                                } else {
-                                    false
-                                }
-                            })),
-                        )),
-                    }
-                }
+    // Hidden items are only shown to their seller
+    let query = r#"SELECT t1.item_id, t1.user_id,t1.title,t1.content,t1.price,t1.rating,t2.stock 
+    FROM 
+    (SELECT * FROM "item" WHERE "item_id"= $1 AND ("hidden_at" IS NULL OR "user_id" = $2)) AS t1 
+    LEFT JOIN
+    (SELECT v."item_id",SUM(s."quantity")::INT as stock FROM "stock" s
+     INNER JOIN "item_variant" v ON s."variant_id" = v."variant_id"
+     WHERE v."item_id" = $1 GROUP BY v."item_id") AS t2 
+    ON 
+    t1."item_id" = t2."item_id""#;
+    match sqlx::query_as::<_, Item>(query)
+        .bind(item_id)
+        .bind(user.as_ref().map(|AuthUser(user)| user.user_id))
+        .fetch_optional(&state.db_pool)
+        .await
+        .map_err(|_| MyError::InternalServerError)?
+        .ok_or(MyError::NotFound)?
+    {
+        response => {
+            let sameuser =
+                matches!(&user, Some(AuthUser(user)) if user.user_id == response.user_id);
+            let variants = get_variant_matrix(&state.db_pool, response.item_id).await?;
+            let media_urls = get_presigned_urls_for_items(
+                vec![response.item_id],
+                &state.db_pool,
+                &state.s3_client,
+                &state.image_bucket,
+            )
+            .await
+            .map_err(|_| MyError::InternalServerError)?;
+            match media_urls.len() {
+                0 => Ok((
+                    StatusCode::OK,
+                    Json(json!(ItemResponse {
+                        detail: Item {
+                            item_id: response.item_id,
+                            user_id: response.user_id,
+                            title: response.title,
+                            content: response.content,
+                            price: response.price,
+                            rating: response.rating,
+                            stock: response.stock,
+                        },
+                        media: None,
+                        sameuser,
+                        variants: Some(variants),
+                        highlight: None,
+                    })),
+                )),
+                _ => Ok((
+                    StatusCode::OK,
+                    Json(json!(ItemResponse {
+                        detail: Item {
+                            item_id: response.item_id.clone(),
+                            user_id: response.user_id,
+                            title: response.title,
+                            content: response.content,
+                            price: response.price,
+                            rating: response.rating,
+                            stock: response.stock,
+                        },
+                        media: Some(media_urls[&response.item_id].clone()),
+                        sameuser,
+                        variants: Some(variants),
+                        highlight: None,
+                    })),
+                )),
             }
         }
-        None => Err(MyError::UnauthorizedError),
     }
 }
 
@@ -480,55 +689,54 @@ pub async fn get_item(
     ),
     responses (
         (status = 200, body = PageResponse),
+        (status = 422, body = ErrorResponse),
         (status = 500, body = ErrorResponse)
     )
 )]
 pub async fn get_items(
     state: State<AppState>,
+    OriginalUri(uri): OriginalUri,
     Query(pagination): Query<ItemsQuery>,
 ) -> Result<impl IntoResponse, MyError> {
-    let query = paginate_items(pagination);
-    match sqlx::query_as::<_, Item>(query?.as_str())
-        .fetch_all(&state.db_pool)
-        .await
-        .map_err(|_| MyError::InternalServerError)?
-    {
-        result => {
-            let mut response: Vec<ItemResponse> = vec![];
-            let items: Vec<Uuid> = result
-                .iter()
-                .map(|item| item.item_id)
-                .collect::<Vec<Uuid>>();
-            let media_urls = get_presigned_urls_for_items(
-                items,
-                &state.db_pool,
-                &state.s3_client,
-                &state.image_bucket,
-            )
+    let facets = item_facets(&state.db_pool, &pagination).await?;
+    let search_string = pagination.search_string.clone();
+    let query = paginate_items(pagination)?;
+    let result = fetch_page::<Item>(query, &uri, &state.db_pool).await?;
+    let items: Vec<Uuid> = result
+        .items()
+        .iter()
+        .map(|item| item.item_id)
+        .collect::<Vec<Uuid>>();
+    let mut highlights = match search_string {
+        Some(token) => search_highlights(&state.db_pool, &token, &items).await?,
+        None => HashMap::new(),
+    };
+    let media_urls =
+        get_presigned_urls_for_items(items, &state.db_pool, &state.s3_client, &state.image_bucket)
             .await
             .map_err(|_| MyError::InternalServerError)?;
 
-            for item in result {
-                let media_item = media_urls[&item.item_id].clone();
-                match media_item.len() {
-                    0 => response.push(ItemResponse {
-                        detail: item,
-                        media: None,
-                        sameuser: false,
-                    }),
-                    _ => response.push(ItemResponse {
-                        detail: item,
-                        media: Some(media_item),
-                        sameuser: false,
-                    }),
-                }
-            }
-            Ok((
-                StatusCode::OK,
-                Json(json!(PageResponse { items: response })),
-            ))
+    let page = result.map(|item| {
+        let media_item = media_urls[&item.item_id].clone();
+        let highlight = highlights.remove(&item.item_id);
+        match media_item.len() {
+            0 => ItemResponse {
+                detail: item,
+                media: None,
+                sameuser: false,
+                variants: None,
+                highlight,
+            },
+            _ => ItemResponse {
+                detail: item,
+                media: Some(media_item),
+                sameuser: false,
+                variants: None,
+                highlight,
+            },
         }
-    }
+    });
+    Ok((StatusCode::OK, Json(json!(PageResponse { page, facets }))))
 }
 
 #[utoipa::path(
@@ -537,49 +745,44 @@ pub async fn get_items(
     responses (
         (status = 201, body = GeneralResponse),
         (status = 401, body = GeneralResponse),
+        (status = 403, body = ErrorResponse),
         (status = 100, body = GeneralResponse)
     ),
     security(
-        ("session_id"=[])
+        ("session_id" = ["buyer"])
     )
 )]
 ///Rate an Item
 ///
 /// Endpoint to rate an item
 pub async fn rate_item(
-    headers: HeaderMap,
     state: State<AppState>,
+    AuthUser(user_response): AuthUser,
     Form(form_data): Form<RateForm>,
 ) -> Result<impl IntoResponse, MyError> {
-    let session_id = extract_session_header(headers).await?;
-    match check_session_validity(&state.db_pool, session_id).await {
-        Some(user_response) => {
-            let query = r#"INSERT INTO 
-            "comment" ("user_id","item_id","rating","content") 
-            SELECT $1,$2,$3,$4 WHERE item_ownership($2,$1) IS FALSE RETURNING "item_id";
-            "#;
-            match sqlx::query_as::<_, ItemId>(query)
-                .bind(user_response.user_id)
-                .bind(form_data.item_id)
-                .bind(form_data.rating)
-                .bind(form_data.content)
-                .fetch_optional(&state.db_pool)
-                .await
-                .map_err(|_| MyError::InternalServerError)?
-            {
-                Some(_t) => Ok((
-                    StatusCode::CREATED,
-                    Json(json!(GeneralResponse {
-                        detail: "Comment Created".to_string()
-                    })),
-                )),
-                None => Err(MyError::CustomError((
-                    409,
-                    "Cannot rate one's own item".to_string(),
-                ))),
-            }
-        }
-        None => Err(MyError::UnauthorizedError),
+    let query = r#"INSERT INTO 
+    "comment" ("user_id","item_id","rating","content") 
+    SELECT $1,$2,$3,$4 WHERE item_ownership($2,$1) IS FALSE RETURNING "item_id";
+    "#;
+    match sqlx::query_as::<_, ItemId>(query)
+        .bind(user_response.user_id)
+        .bind(form_data.item_id)
+        .bind(form_data.rating)
+        .bind(form_data.content)
+        .fetch_optional(&state.db_pool)
+        .await
+        .map_err(|_| MyError::InternalServerError)?
+    {
+        Some(_t) => Ok((
+            StatusCode::CREATED,
+            Json(json!(GeneralResponse {
+                detail: "Comment Created".to_string()
+            })),
+        )),
+        None => Err(MyError::CustomError((
+            409,
+            "Cannot rate one's own item".to_string(),
+        ))),
     }
 }
 
@@ -590,7 +793,8 @@ pub async fn rate_item(
         CommentQuery
     ),
     responses(
-        (status = 200, body = Vec<RateForm>),
+        (status = 200, body = Paginated<Comment>),
+        (status = 422, body = ErrorResponse),
         (status = 500, body = ErrorResponse)
     )
 )]
@@ -599,65 +803,200 @@ pub async fn rate_item(
 /// Endpoint to get comments for an item
 pub async fn get_comments(
     state: State<AppState>,
+    OriginalUri(uri): OriginalUri,
     Query(pagination): Query<CommentQuery>,
 ) -> Result<impl IntoResponse, MyError> {
-    let query = paginate_comments(pagination);
-    match sqlx::query_as::<_, RateForm>(query?.as_str())
-        .fetch_all(&state.db_pool)
-        .await
-        .map_err(|_| MyError::InternalServerError)?
-    {
-        response => Ok((StatusCode::OK, Json(json!(response)))),
-    }
+    let query = paginate_comments(pagination)?;
+    let comments = fetch_page::<Comment>(query, &uri, &state.db_pool).await?;
+    Ok((StatusCode::OK, Json(json!(comments))))
 }
 
 #[utoipa::path(
     post,
     path = "/item/stock",
     security(
-        ("session_id" = [])
+        ("session_id" = ["seller"])
     ),
     responses(
-        (status = 200 , body = GeneralResponse),
+        (status = 201 , body = ItemStock),
         (status = 401 , body = GeneralResponse),
+        (status = 403, body = ErrorResponse),
         (status = 404 , body = GeneralResponse),
+        (status = 409 , body = ErrorResponse),
+        (status = 422 , body = ErrorResponse),
         (status = 500 , body = GeneralResponse)
     )
 )]
-///Update Stock for an Item
+///Adjust Stock for an Item
+///
+/// Endpoint to move the stock of an item variant up or down by `delta`,
+/// recording the adjustment in the item's stock history
 pub async fn edit_stock(
-    headers: HeaderMap,
     state: State<AppState>,
-    Form(form_data): Form<ItemStock>,
+    AuthUser(user_response): AuthUser,
+    Form(form_data): Form<StockAdjustmentForm>,
 ) -> Result<impl IntoResponse, MyError> {
-    let session_id = extract_session_header(headers).await?;
-    match check_session_validity(&state.db_pool, session_id).await {
-        Some(user_response) => {
-            let query = r#"INSERT INTO "stock" ("item_id","quantity") 
-            SELECT $1,$2  WHERE item_ownership($1,$3) IS TRUE 
-            ON CONFLICT("item_id")
-            DO UPDATE SET "quantity" = EXCLUDED."quantity" RETURNING "item_id","quantity""#;
-            match sqlx::query_as::<_, ItemStock>(query)
-                .bind(&form_data.item_id)
-                .bind(&form_data.quantity)
-                .bind(user_response.user_id)
-                .fetch_optional(&state.db_pool)
+    if form_data.delta == 0 || !form_data.reason.is_manual() {
+        return Err(MyError::UnproccessableEntityError);
+    }
+    let mut txn = state
+        .db_pool
+        .begin()
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    match sqlx::query_as::<_, VariantId>(
+        r#"SELECT "variant_id" FROM "item_variant" WHERE "variant_id" = $1 AND variant_ownership($1,$2) IS TRUE"#,
+    )
+    .bind(form_data.variant_id)
+    .bind(user_response.user_id)
+    .fetch_optional(&mut *txn)
+    .await
+    .map_err(|_| MyError::InternalServerError)?
+    {
+        Some(variant) => {
+            let quantity = record_movement(
+                &mut txn,
+                variant.variant_id,
+                form_data.delta,
+                form_data.reason,
+                form_data.note,
+                Some(user_response.user_id),
+            )
+            .await?;
+            txn.commit()
                 .await
-                .map_err(|_| MyError::InternalServerError)?
-            {
-                Some(_t) => Ok((
-                    StatusCode::CREATED,
-                    Json(json!(GeneralResponse {
-                        detail: "Stock updated".to_string(),
-                    })),
-                )),
-                None => Err(MyError::UnauthorizedError),
-            }
+                .map_err(|_| MyError::InternalServerError)?;
+            Ok((
+                StatusCode::CREATED,
+                Json(json!(ItemStock {
+                    variant_id: variant.variant_id,
+                    quantity,
+                })),
+            ))
+        }
+        None => Err(MyError::UnauthorizedError),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/item/{item_id}/variant",
+    params(
+        ("item_id" = Uuid, Path, description = "item_id of the item")
+    ),
+    request_body(content = VariantForm, content_type = "application/x-www-form-urlencoded"),
+    security(
+        ("session_id" = ["seller"])
+    ),
+    responses(
+        (status = 201, body = VariantId),
+        (status = 401, body = ErrorResponse),
+        (status = 403, body = ErrorResponse),
+        (status = 409, body = ErrorResponse),
+        (status = 422, body = ErrorResponse),
+        (status = 500, body = ErrorResponse)
+    )
+)]
+/// Create Item Variant
+///
+/// Endpoint for the seller to add a variant to an item,
+/// its stock starts at zero and is set through `/item/stock`
+pub async fn create_variant(
+    state: State<AppState>,
+    AuthUser(user_response): AuthUser,
+    Path(item_id): Path<Uuid>,
+    Form(form_data): Form<VariantForm>,
+) -> Result<impl IntoResponse, MyError> {
+    let attributes = parse_variant_form(&form_data)?;
+    let mut txn = state
+        .db_pool
+        .begin()
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    match sqlx::query_as::<_, ItemId>(
+        r#"SELECT "item_id" FROM "item" WHERE "item_id" = $1 AND "user_id" = $2"#,
+    )
+    .bind(item_id)
+    .bind(user_response.user_id)
+    .fetch_optional(&mut *txn)
+    .await
+    .map_err(|_| MyError::InternalServerError)?
+    {
+        Some(item) => {
+            let variant = insert_variant(
+                &mut txn,
+                item.item_id,
+                form_data.sku,
+                attributes,
+                form_data.price,
+            )
+            .await?;
+            txn.commit()
+                .await
+                .map_err(|_| MyError::InternalServerError)?;
+            Ok((StatusCode::CREATED, Json(json!(variant))))
         }
         None => Err(MyError::UnauthorizedError),
     }
 }
 
+#[utoipa::path(
+    put,
+    path = "/item/variant/{variant_id}",
+    params(
+        ("variant_id" = Uuid, Path, description = "variant_id of the item variant")
+    ),
+    request_body(content = VariantForm, content_type = "application/x-www-form-urlencoded"),
+    security(
+        ("session_id" = ["seller"])
+    ),
+    responses(
+        (status = 200, body = GeneralResponse),
+        (status = 401, body = ErrorResponse),
+        (status = 403, body = ErrorResponse),
+        (status = 409, body = ErrorResponse),
+        (status = 422, body = ErrorResponse),
+        (status = 500, body = ErrorResponse)
+    )
+)]
+/// Edit Item Variant
+///
+/// Endpoint for the seller to change the SKU, attributes or price of a variant
+pub async fn edit_variant(
+    state: State<AppState>,
+    AuthUser(user_response): AuthUser,
+    Path(variant_id): Path<Uuid>,
+    Form(form_data): Form<VariantForm>,
+) -> Result<impl IntoResponse, MyError> {
+    let attributes = parse_variant_form(&form_data)?;
+    let query = r#"
+        UPDATE "item_variant" SET
+        "sku" = $1,
+        "attributes" = $2,
+        "price" = $3
+        WHERE "variant_id" = $4 AND variant_ownership($4,$5) IS TRUE
+        RETURNING "variant_id";
+    "#;
+    match sqlx::query_as::<_, VariantId>(query)
+        .bind(&form_data.sku)
+        .bind(SqlJson(attributes))
+        .bind(form_data.price)
+        .bind(variant_id)
+        .bind(user_response.user_id)
+        .fetch_optional(&state.db_pool)
+        .await
+        .map_err(variant_conflict)?
+    {
+        Some(_variant) => Ok((
+            StatusCode::OK,
+            Json(json!(GeneralResponse {
+                detail: "Variant Updated".to_string()
+            })),
+        )),
+        None => Err(MyError::UnauthorizedError),
+    }
+}
+
 #[utoipa::path(
     get,
     path = "/item/search_suggestions",
@@ -665,197 +1004,536 @@ pub async fn edit_stock(
         SearchQuery
     ),
     responses(
-        (status = 200, body = Vec<Item>),
+        (status = 200, body = SearchResult),
         (status = 500, body = ErrorResponse)
     )
 )]
 ///Get Search Autocompletions
+///
+/// Endpoint to get the ten items best matching a search, with the matches highlighted
 pub async fn search_suggestions(
     state: State<AppState>,
     search_query: Query<SearchQuery>,
 ) -> Result<impl IntoResponse, MyError> {
+    let mut listing = Listing::new(ITEM_COLUMNS);
+    listing
+        .push(ITEM_TABLES)
+        .condition(r#"t1."hidden_at" IS NULL"#)
+        .condition(r#"t1."search_vector" @@ websearch_to_tsquery('english',"#)
+        .bind(search_query.query.clone())
+        .push(")")
+        .sort(r#"ts_rank(t1."search_vector", websearch_to_tsquery('english',"#)
+        .bind(search_query.query.clone())
+        .push(r#")) DESC, t1."item_id""#);
+    let items = listing
+        .page(Page::new(Some(10), None))
+        .build_query_as::<Item>()
+        .fetch_all(&state.db_pool)
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    let mut highlights = search_highlights(
+        &state.db_pool,
+        &search_query.query,
+        &items.iter().map(|item| item.item_id).collect::<Vec<Uuid>>(),
+    )
+    .await?;
+    let keywords = items
+        .into_iter()
+        .filter_map(|item| {
+            let highlight = highlights.remove(&item.item_id)?;
+            Some(SearchHit {
+                detail: item,
+                highlight,
+            })
+        })
+        .collect();
+    Ok((StatusCode::OK, Json(json!(SearchResult { keywords }))))
+}
+
+/// Highlights the words matching `search_string` in each of the items. The
+/// title and content are written by sellers, so they are HTML-escaped before
+/// the marks go in and the marks are the only markup a client renders.
+async fn search_highlights(
+    db_pool: &sqlx::Pool<sqlx::Postgres>,
+    search_string: &str,
+    item_ids: &[Uuid],
+) -> Result<HashMap<Uuid, Highlight>, MyError> {
     let query = r#"
-    SELECT * FROM "item" WHERE to_tsvector("title"|| ' ' ||"content") @@ to_tsquery($1);
+        SELECT i."item_id",
+        ts_headline('english', e."title", q, 'StartSel=<mark>, StopSel=</mark>, HighlightAll=true') AS "title",
+        ts_headline('english', e."content", q, 'StartSel=<mark>, StopSel=</mark>, MaxWords=35, MinWords=15') AS "snippet"
+        FROM "item" i
+        CROSS JOIN websearch_to_tsquery('english', $1) AS q
+        CROSS JOIN LATERAL (
+            SELECT
+            replace(replace(replace(replace(replace(i."title",
+                '&', '&amp;'), '<', '&lt;'), '>', '&gt;'), '"', '&quot;'), '''', '&#39;') AS "title",
+            replace(replace(replace(replace(replace(i."content",
+                '&', '&amp;'), '<', '&lt;'), '>', '&gt;'), '"', '&quot;'), '''', '&#39;') AS "content"
+        ) e
+        WHERE i."item_id" = ANY($2);
     "#;
-    match sqlx::query_as::<_, Item>(query)
-        .bind(&search_query.query)
+    let highlights = sqlx::query_as::<_, Highlight>(query)
+        .bind(search_string)
+        .bind(item_ids)
+        .fetch_all(db_pool)
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    Ok(highlights
+        .into_iter()
+        .map(|highlight| (highlight.item_id, highlight))
+        .collect())
+}
+
+/// Longest autocomplete query, in characters, that is matched against
+const AUTOCOMPLETE_MAX_CHARS: usize = 64;
+
+/// Normalises autocomplete input, collapsing whitespace and capping the length.
+/// Returns None when there is nothing left to match.
+pub fn autocomplete_input(query: &str) -> Option<String> {
+    let input = query
+        .chars()
+        .filter(|c| !c.is_control())
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<&str>>()
+        .join(" ")
+        .chars()
+        .take(AUTOCOMPLETE_MAX_CHARS)
+        .collect::<String>();
+    match input.trim_end() {
+        "" => None,
+        input => Some(input.to_string()),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/item/autocomplete",
+    params(
+        AutocompleteQuery
+    ),
+    responses(
+        (status = 200, body = AutocompleteResult),
+        (status = 500, body = ErrorResponse)
+    )
+)]
+///Autocomplete Search
+///
+/// Endpoint to suggest item titles and category names for a partial, possibly misspelled,
+/// query. Suggestions are ranked by trigram similarity and meant to be fetched on every keystroke
+pub async fn autocomplete(
+    state: State<AppState>,
+    Query(autocomplete_query): Query<AutocompleteQuery>,
+) -> Result<impl IntoResponse, MyError> {
+    let Some(input) = autocomplete_input(&autocomplete_query.query) else {
+        return Ok((
+            StatusCode::OK,
+            Json(json!(AutocompleteResult {
+                suggestions: vec![]
+            })),
+        ));
+    };
+    let limit = i64::from(autocomplete_query.limit.unwrap_or(5).clamp(1, 10));
+    let query = r#"
+        SELECT * FROM (
+            (SELECT 'item'::text AS "kind", "item_id" AS "id", "title"::text AS "text",
+            NULL::text AS "slug", word_similarity($1, "title") AS "score"
+            FROM "item" WHERE $1 <% "title" AND "hidden_at" IS NULL
+            ORDER BY "score" DESC, "title" LIMIT $2)
+            UNION ALL
+            (SELECT 'category'::text, "category_id", "name"::text,
+            "slug"::text, word_similarity($1, "name")
+            FROM "category" WHERE $1 <% "name"
+            ORDER BY 5 DESC, "name" LIMIT $2)
+        ) AS "suggestion"
+        ORDER BY "score" DESC, "text" LIMIT $2;
+    "#;
+    let suggestions = sqlx::query_as::<_, Suggestion>(query)
+        .bind(input)
+        .bind(limit)
         .fetch_all(&state.db_pool)
         .await
-        .map_err(|_| MyError::InternalServerError)?
+        .map_err(|_| MyError::InternalServerError)?;
+    Ok((
+        StatusCode::OK,
+        Json(json!(AutocompleteResult { suggestions })),
+    ))
+}
+
+/// SKUs are letters, digits, dashes, underscores and dots
+fn valid_sku(sku: &str) -> bool {
+    !sku.is_empty()
+        && sku.len() <= 64
+        && sku
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'))
+}
+
+fn parse_variant_form(form_data: &VariantForm) -> Result<BTreeMap<String, String>, MyError> {
+    if !valid_sku(&form_data.sku)
+        || form_data
+            .price
+            .is_some_and(|price| price.is_sign_negative())
     {
-        response => Ok((
-            StatusCode::OK,
-            Json(json!(SearchResult { keywords: response })),
-        )),
+        return Err(MyError::UnproccessableEntityError);
+    }
+    let attributes = match &form_data.attributes {
+        Some(attributes) => serde_json::from_str::<BTreeMap<String, String>>(attributes)
+            .map_err(|_| MyError::UnproccessableEntityError)?,
+        None => BTreeMap::new(),
+    };
+    if attributes.keys().any(|name| name.trim().is_empty()) {
+        return Err(MyError::UnproccessableEntityError);
     }
+    Ok(attributes)
 }
 
-fn fetch_pagination_params(params: &ItemsQuery) -> String {
-    let mut query_params = PaginationParams {
-        take: 10,
-        offset: 0,
-    };
-    // Setting default values for the pagination
-    match params.take {
-        Some(take) => query_params.take = take,
-        None => query_params.take = 10,
+fn variant_conflict(error: sqlx::Error) -> MyError {
+    match error {
+        sqlx::Error::Database(e) if e.is_unique_violation() => MyError::CustomError((
+            409,
+            "A variant with this SKU or these attributes already exists".to_string(),
+        )),
+        _ => MyError::InternalServerError,
     }
-    match params.page_no {
-        Some(page_no) => {
-            query_params.offset = if page_no > 0 {
-                (page_no - 1) * query_params.take
-            } else {
-                0
+}
+
+async fn insert_variant(
+    conn: &mut sqlx::PgConnection,
+    item_id: Uuid,
+    sku: String,
+    attributes: BTreeMap<String, String>,
+    price: Option<rust_decimal::Decimal>,
+) -> Result<VariantId, MyError> {
+    let query = r#"
+        INSERT INTO "item_variant" ("item_id","sku","attributes","price")
+        VALUES ($1,$2,$3,$4) RETURNING "variant_id";
+    "#;
+    sqlx::query_as::<_, VariantId>(query)
+        .bind(item_id)
+        .bind(sku)
+        .bind(SqlJson(attributes))
+        .bind(price)
+        .fetch_one(conn)
+        .await
+        .map_err(variant_conflict)
+}
+
+async fn get_variant_matrix(
+    db_pool: &sqlx::Pool<sqlx::Postgres>,
+    item_id: Uuid,
+) -> Result<VariantMatrix, MyError> {
+    let query = r#"
+        SELECT v."variant_id",v."sku",v."attributes",
+        COALESCE(v."price",i."price") AS "price",
+        COALESCE(s."quantity",0) AS "stock"
+        FROM "item_variant" v
+        INNER JOIN "item" i ON v."item_id" = i."item_id"
+        LEFT JOIN "stock" s ON v."variant_id" = s."variant_id"
+        WHERE v."item_id" = $1
+        ORDER BY v."date_created", v."sku";
+    "#;
+    let variants = sqlx::query_as::<_, ItemVariant>(query)
+        .bind(item_id)
+        .fetch_all(db_pool)
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    let mut options: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for variant in &variants {
+        for (name, value) in variant.attributes.iter() {
+            let values = options.entry(name.clone()).or_default();
+            if !values.contains(value) {
+                values.push(value.clone());
             }
         }
-        None => query_params.offset = 0,
     }
-    let pagination_query = format!("LIMIT {} OFFSET {}", query_params.take, query_params.offset);
-    pagination_query
-}
-
-fn paginate_items(pagination: ItemsQuery) -> Result<String, MyError> {
-    let pagination_query = fetch_pagination_params(&pagination);
-    let mut query = r#"SELECT * FROM "item""#.to_owned();
-    let search_token;
-    match pagination.search_string {
-        Some(token) => {
-            search_token = format!(
-                r#"WHERE to_tsvector("title"|| ' ' ||"content") @@ websearch_to_tsquery('english','{}')"#,
-                token
+    Ok(VariantMatrix { options, variants })
+}
+
+fn item_keyset(filter_type: Filters) -> &'static Keyset {
+    match filter_type {
+        Filters::Alphabetical(Order::Inc) => &Keyset {
+            columns: &[(r#"t1."title""#, "text"), (r#"t1."item_id""#, "uuid")],
+            descending: false,
+        },
+        Filters::Alphabetical(Order::Dec) => &Keyset {
+            columns: &[(r#"t1."title""#, "text"), (r#"t1."item_id""#, "uuid")],
+            descending: true,
+        },
+        Filters::DateOfCreation(Order::Inc) => &Keyset {
+            columns: &[
+                (r#"t1."date_created""#, "timestamp"),
+                (r#"t1."item_id""#, "uuid"),
+            ],
+            descending: false,
+        },
+        Filters::DateOfCreation(Order::Dec) => &NEWEST_ITEMS,
+        // Unrated items come last either way
+        Filters::Rating(Order::Inc) => &Keyset {
+            columns: &[
+                (r#"COALESCE(t1."rating",6)"#, "real"),
+                (r#"t1."item_id""#, "uuid"),
+            ],
+            descending: false,
+        },
+        Filters::Rating(Order::Dec) => &Keyset {
+            columns: &[
+                (r#"COALESCE(t1."rating",-1)"#, "real"),
+                (r#"t1."item_id""#, "uuid"),
+            ],
+            descending: true,
+        },
+        Filters::Price(Order::Inc) => &Keyset {
+            columns: &[(r#"t1."price""#, "numeric"), (r#"t1."item_id""#, "uuid")],
+            descending: false,
+        },
+        Filters::Price(Order::Dec) => &Keyset {
+            columns: &[(r#"t1."price""#, "numeric"), (r#"t1."item_id""#, "uuid")],
+            descending: true,
+        },
+        Filters::Relevance => &Keyset {
+            columns: &[(r#"t1."rank""#, "real"), (r#"t1."item_id""#, "uuid")],
+            descending: true,
+        },
+    }
+}
+
+const NEWEST_ITEMS: Keyset = Keyset {
+    columns: &[
+        (r#"t1."date_created""#, "timestamp"),
+        (r#"t1."item_id""#, "uuid"),
+    ],
+    descending: true,
+};
+
+/// Columns of [`Item`], along with the date the listings sort by
+const ITEM_COLUMNS: &str = r#"SELECT 
+        t1.item_id, t1.user_id,t1.title,t1.content,t1.price,t1.rating,t1.date_created,t2.stock"#;
+
+/// Items with their total stock, filtered by [`filter_items`]
+const ITEM_TABLES: &str = r#"
+         FROM "item" AS t1 
+         LEFT JOIN 
+         ( SELECT v."item_id",SUM(s."quantity")::INT as stock from "stock" s
+           INNER JOIN "item_variant" v ON s."variant_id" = v."variant_id"
+           GROUP BY v."item_id") AS t2 
+         ON t1."item_id" = t2."item_id""#;
+
+/// Conditions of the item listings, which always leave out hidden items
+fn filter_items(listing: &mut Listing<'static>, pagination: &ItemsQuery) -> Result<(), MyError> {
+    listing.condition(r#"t1."hidden_at" IS NULL"#);
+    if let Some(token) = &pagination.search_string {
+        listing
+            .condition(r#"t1."search_vector" @@ websearch_to_tsquery('english',"#)
+            .bind(token.clone())
+            .push(")");
+    }
+    if let Some(slug) = &pagination.category {
+        if !valid_slug(slug) {
+            return Err(MyError::UnproccessableEntityError);
+        }
+        listing
+            .condition(
+                r#"t1."item_id" IN (
+                SELECT "item_id" FROM "item_category" WHERE "category_id" IN (
+                    WITH RECURSIVE "subtree" AS (
+                        SELECT "category_id" FROM "category" WHERE "slug" = "#,
             )
-            .to_owned();
+            .bind(slug.clone())
+            .push(
+                r#"
+                        UNION
+                        SELECT c."category_id" FROM "category" c
+                        INNER JOIN "subtree" s ON c."parent_id" = s."category_id"
+                    )
+                    SELECT "category_id" FROM "subtree"
+                ))"#,
+            );
+    }
+    if let (Some(min_price), Some(max_price)) = (pagination.min_price, pagination.max_price) {
+        if min_price > max_price {
+            return Err(MyError::CustomError((
+                422,
+                "min_price cannot be more than max_price".to_string(),
+            )));
         }
-        None => search_token = r#""#.to_owned(),
     }
-    let mut order_query = "";
-    match pagination.filter {
-        Some(filter_type) => {
-            let filter_type: Filters =
-                serde_json::from_value::<Filters>(serde_json::Value::String(filter_type))
-                    .map_err(|_| MyError::UnproccessableEntityError)?;
-            match filter_type {
-                Filters::Alphabetical(order) => match order {
-                    Order::Inc => {
-                        query = format!("{} {} {}", query, search_token, pagination_query);
-                        order_query = r#"ORDER BY "title" ASC "#;
-                    }
-                    Order::Dec => {
-                        query = format!("{} {} {}", query, search_token, pagination_query);
-                        order_query = r#"ORDER BY "title" DESC "#;
-                    }
-                },
-                Filters::DateOfCreation(order) => match order {
-                    Order::Inc => {
-                        query = format!("{} {} {}", query, search_token, pagination_query);
-                        order_query = r#"ORDER BY "date_created" ASC "#;
-                    }
-                    Order::Dec => {
-                        query = format!("{} {} {}", query, search_token, pagination_query);
-                        order_query = r#"ORDER BY "date_created" DESC "#;
-                    }
-                },
-                Filters::Rating(order) => match order {
-                    Order::Inc => {
-                        query = format!("{} {} {}", query, search_token, pagination_query);
-                        order_query = r#"ORDER BY "rating" ASC "#;
-                    }
-                    Order::Dec => {
-                        query = format!("{} {}  {}", query, search_token, pagination_query);
-                        order_query = r#"ORDER BY "rating" DESC NULLS LAST"#;
-                    }
-                },
-                Filters::Price(order) => match order {
-                    Order::Inc => {
-                        query = format!("{} {} {}", query, search_token, pagination_query);
-                        order_query = r#"ORDER BY "price" ASC "#;
-                    }
-                    Order::Dec => {
-                        query = format!("{} {} {}", query, search_token, pagination_query);
-                        order_query = r#"ORDER BY "price" DESC "#;
-                    }
-                },
-            }
+    if let Some(min_price) = pagination.min_price {
+        listing.condition(r#"t1."price" >= "#).bind(min_price);
+    }
+    if let Some(max_price) = pagination.max_price {
+        listing.condition(r#"t1."price" <= "#).bind(max_price);
+    }
+    if let Some(min_rating) = pagination.min_rating {
+        if !(0.0..=5.0).contains(&min_rating) {
+            return Err(MyError::CustomError((
+                422,
+                "min_rating must be between 0 and 5".to_string(),
+            )));
         }
-        None => query = format!("{} {} {} ", query, search_token, pagination_query),
+        listing.condition(r#"t1."rating" >= "#).bind(min_rating);
     }
-    query = format!(
-        r#"SELECT 
-        t1.item_id, t1.user_id,t1.title,t1.content,t1.price,t1.rating,t2.stock 
-         FROM ({}) AS t1 
-         LEFT JOIN 
-         ( SELECT "item_id","quantity" as stock from "stock") AS t2 
-         ON t1."item_id" = t2."item_id" {}"#,
-        query, order_query
-    );
-    Ok(query)
+    if let Some(seller_id) = pagination.seller_id {
+        listing.condition(r#"t1."user_id" = "#).bind(seller_id);
+    }
+    if pagination.in_stock == Some(true) {
+        listing.condition(r#"COALESCE(t2."stock",0) > 0"#);
+    }
+    if let Some(created_after) = pagination.created_after {
+        listing
+            .condition(r#"t1."date_created" >= "#)
+            .bind(created_after);
+    }
+    Ok(())
 }
 
-fn paginate_comments(pagination: CommentQuery) -> Result<String, MyError> {
-    struct PaginationParams {
-        take: u32,
-        offset: u32,
-    }
-    let mut query_params = PaginationParams {
-        take: 10,
-        offset: 0,
+pub fn paginate_items(pagination: ItemsQuery) -> Result<PageQuery<'static>, MyError> {
+    let filter_type = match &pagination.filter {
+        Some(filter_type) => Some(
+            serde_json::from_value::<Filters>(serde_json::Value::String(filter_type.clone()))
+                .map_err(|_| MyError::UnproccessableEntityError)?,
+        ),
+        None => None,
     };
-    // Setting default values for the pagination
-    match pagination.take {
-        Some(take) => query_params.take = take,
-        None => query_params.take = 10,
-    }
-    match pagination.page_no {
-        Some(page_no) => {
-            query_params.offset = if page_no > 0 {
-                (page_no - 1) * query_params.take
-            } else {
-                0
-            }
-        }
-        None => query_params.offset = 0,
+    let cursor = match &pagination.cursor {
+        Some(token) => Some(Cursor::decode(token)?),
+        None => None,
+    };
+    let page = Page::new(pagination.take, pagination.page_no);
+    let ranked = filter_type == Some(Filters::Relevance);
+    let keyset = match filter_type {
+        // Without a search string there is nothing to rank against
+        Some(Filters::Relevance) if pagination.search_string.is_none() => &NEWEST_ITEMS,
+        Some(filter_type) => item_keyset(filter_type),
+        None => &NEWEST_ITEMS,
+    };
+    // The filtered items are nested so that the listing can page by the rank
+    let mut listing = Listing::keyset(keyset, "t1.*");
+    listing.push(" FROM (").push(ITEM_COLUMNS);
+    if let (true, Some(token)) = (ranked, &pagination.search_string) {
+        listing
+            .push(r#", ts_rank(t1."search_vector", websearch_to_tsquery('english',"#)
+            .bind(token.clone())
+            .push(r#")) AS "rank""#);
     }
-    let mut query = format!(
-        r#"SELECT "rating","content","item_id" FROM "comment" where "item_id"= '{}'"#,
-        pagination.item_id
-    )
-    .to_owned();
-    let pagination_query = format!("LIMIT {} OFFSET {}", query_params.take, query_params.offset);
-    let mut order_query = "";
-    match pagination.filter {
+    listing.push(ITEM_TABLES);
+    filter_items(&mut listing, &pagination)?;
+    listing.nest(") AS t1");
+    listing.paginate(page, cursor)
+}
+
+/// Builds the price, rating and seller facet queries over the filtered items
+pub fn facet_queries(
+    pagination: &ItemsQuery,
+) -> Result<[QueryBuilder<'static, Postgres>; 3], MyError> {
+    let mut price = Listing::new(
+        r#"SELECT b."min_price", b."max_price", COUNT(f."item_id") AS "count"
+        FROM (VALUES (0::NUMERIC,10::NUMERIC),(10,50),(50,100),(100,500),(500,NULL))
+        AS b("min_price","max_price")
+        LEFT JOIN ("#,
+    );
+    price.push(ITEM_COLUMNS).push(ITEM_TABLES);
+    filter_items(&mut price, pagination)?;
+    let price = price.close(
+        r#") AS f ON f."price" >= b."min_price" AND (b."max_price" IS NULL OR f."price" < b."max_price")
+        GROUP BY b."min_price", b."max_price"
+        ORDER BY b."min_price""#,
+    );
+
+    let mut rating = Listing::new(
+        r#"SELECT b."min_rating", COUNT(f."item_id") AS "count"
+        FROM (VALUES (4),(3),(2),(1)) AS b("min_rating")
+        LEFT JOIN ("#,
+    );
+    rating.push(ITEM_COLUMNS).push(ITEM_TABLES);
+    filter_items(&mut rating, pagination)?;
+    let rating = rating.close(
+        r#") AS f ON f."rating" >= b."min_rating"
+        GROUP BY b."min_rating"
+        ORDER BY b."min_rating" DESC"#,
+    );
+
+    let mut sellers =
+        Listing::new(r#"SELECT u."user_id", u."username", COUNT(*) AS "count" FROM ("#);
+    sellers.push(ITEM_COLUMNS).push(ITEM_TABLES);
+    filter_items(&mut sellers, pagination)?;
+    let sellers = sellers.close(
+        r#") AS f INNER JOIN "user" u ON f."user_id" = u."user_id"
+        GROUP BY u."user_id", u."username"
+        ORDER BY "count" DESC, u."username"
+        LIMIT 20"#,
+    );
+    Ok([price, rating, sellers])
+}
+
+async fn item_facets(
+    db_pool: &sqlx::Pool<sqlx::Postgres>,
+    pagination: &ItemsQuery,
+) -> Result<Facets, MyError> {
+    let [mut price, mut rating, mut sellers] = facet_queries(pagination)?;
+    Ok(Facets {
+        price: price
+            .build_query_as::<PriceBucket>()
+            .fetch_all(db_pool)
+            .await
+            .map_err(|_| MyError::InternalServerError)?,
+        rating: rating
+            .build_query_as::<RatingBucket>()
+            .fetch_all(db_pool)
+            .await
+            .map_err(|_| MyError::InternalServerError)?,
+        sellers: sellers
+            .build_query_as::<SellerFacet>()
+            .fetch_all(db_pool)
+            .await
+            .map_err(|_| MyError::InternalServerError)?,
+    })
+}
+
+pub fn paginate_comments(pagination: CommentQuery) -> Result<PageQuery<'static>, MyError> {
+    let keyset: &'static Keyset = match pagination.filter {
         Some(filter_type) => {
-            let filter_type: CommentFilters =
-                serde_json::from_value::<CommentFilters>(serde_json::Value::String(filter_type))
-                    .map_err(|_| MyError::UnproccessableEntityError)?;
-            match filter_type {
-                CommentFilters::DateOfCreation(order) => match order {
-                    Order::Inc => {
-                        query = format!("{} {}", query, pagination_query);
-                        order_query = r#"ORDER BY "date_created" ASC "#;
-                    }
-                    Order::Dec => {
-                        query = format!("{} {}", query, pagination_query);
-                        order_query = r#"ORDER BY "date_created" DESC "#;
-                    }
+            match serde_json::from_value::<CommentFilters>(serde_json::Value::String(filter_type))
+                .map_err(|_| MyError::UnproccessableEntityError)?
+            {
+                CommentFilters::DateOfCreation(Order::Inc) => &Keyset {
+                    columns: &[(r#""date_created""#, "timestamp"), (r#""user_id""#, "uuid")],
+                    descending: false,
                 },
-                CommentFilters::Rating(order) => match order {
-                    Order::Inc => {
-                        query = format!("{} {}", query, pagination_query);
-                        order_query = r#"ORDER BY "rating" ASC "#;
-                    }
-                    Order::Dec => {
-                        query = format!("{} {}", query, pagination_query);
-                        order_query = r#"ORDER BY "rating" DESC "#;
-                    }
+                CommentFilters::DateOfCreation(Order::Dec) => &NEWEST_COMMENTS,
+                CommentFilters::Rating(Order::Inc) => &Keyset {
+                    columns: &[(r#""rating""#, "int"), (r#""user_id""#, "uuid")],
+                    descending: false,
+                },
+                CommentFilters::Rating(Order::Dec) => &Keyset {
+                    columns: &[(r#""rating""#, "int"), (r#""user_id""#, "uuid")],
+                    descending: true,
                 },
             }
         }
-        None => query = format!("{} {}", query, pagination_query),
-    }
-    query = format!(r#"{} {}"#, query, order_query);
-    Ok(query)
+        None => &NEWEST_COMMENTS,
+    };
+    let cursor = match &pagination.cursor {
+        Some(token) => Some(Cursor::decode(token)?),
+        None => None,
+    };
+    let mut listing = Listing::keyset(
+        keyset,
+        r#""user_id","item_id","rating","content","date_created""#,
+    );
+    listing
+        .push(r#" FROM "comment""#)
+        .condition(r#""item_id" = "#)
+        .bind(pagination.item_id);
+    listing.paginate(Page::new(pagination.take, pagination.page_no), cursor)
 }
 
+const NEWEST_COMMENTS: Keyset = Keyset {
+    columns: &[(r#""date_created""#, "timestamp"), (r#""user_id""#, "uuid")],
+    descending: true,
+};
+
 async fn get_presigned_urls_for_items(
     item_ids: Vec<Uuid>,
     db_pool: &sqlx::Pool<sqlx::Postgres>,
@@ -0,0 +1,383 @@
+use axum::http::Uri;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use serde::Serialize;
+use sqlx::{postgres::PgRow, FromRow, Pool, Postgres, QueryBuilder, Row};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::errors::MyError;
+
+/// Page of a listing, taken from the `take` and `page_no` query parameters
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Page {
+    pub take: i64,
+    pub offset: i64,
+    pub number: u32,
+}
+
+/// Most rows a client may ask a page to hold
+pub const MAX_TAKE: u32 = 100;
+
+impl Page {
+    /// Pages hold 10 rows unless `take` says otherwise, and are numbered from 1.
+    /// `take` is clamped to between 1 and [`MAX_TAKE`] rows.
+    pub fn new(take: Option<u32>, page_no: Option<u32>) -> Self {
+        let take = i64::from(take.unwrap_or(10).clamp(1, MAX_TAKE));
+        let number = match page_no {
+            Some(page_no) if page_no > 0 => page_no,
+            _ => 1,
+        };
+        let offset = i64::from(number - 1).saturating_mul(take);
+        Page {
+            take,
+            offset,
+            number,
+        }
+    }
+}
+
+/// Sort order of a listing paged by cursor. Rows are compared with the
+/// cursor as a whole, so every column sorts in the same direction and the
+/// last column is unique.
+pub struct Keyset {
+    /// SQL of each sort column, with the type its cursor value is cast back to
+    pub columns: &'static [(&'static str, &'static str)],
+    pub descending: bool,
+}
+
+/// Sort key of the last row on a page, handed to clients as an opaque token
+#[derive(Debug, PartialEq)]
+pub struct Cursor(Vec<String>);
+
+impl Cursor {
+    pub fn encode(&self) -> String {
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(&self.0).unwrap_or_default())
+    }
+
+    pub fn decode(token: &str) -> Result<Self, MyError> {
+        let bytes = URL_SAFE_NO_PAD
+            .decode(token)
+            .map_err(|_| MyError::UnproccessableEntityError)?;
+        serde_json::from_slice(&bytes)
+            .map(Cursor)
+            .map_err(|_| MyError::UnproccessableEntityError)
+    }
+
+    /// Checks that the cursor holds a value of the right type for each column,
+    /// so a cursor from another listing is turned away before reaching the database
+    fn fits(&self, keyset: &Keyset) -> bool {
+        self.0.len() == keyset.columns.len()
+            && keyset
+                .columns
+                .iter()
+                .zip(&self.0)
+                .all(|((_, sql_type), value)| match *sql_type {
+                    "uuid" => Uuid::parse_str(value).is_ok(),
+                    "int" => value.parse::<i32>().is_ok(),
+                    "bigint" => value.parse::<i64>().is_ok(),
+                    "real" => value.parse::<f32>().is_ok(),
+                    "numeric" => value.parse::<rust_decimal::Decimal>().is_ok(),
+                    "timestamp" => {
+                        chrono::NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S%.f").is_ok()
+                    }
+                    _ => true,
+                })
+    }
+}
+
+/// A row of a listing along with its cursor
+pub struct Keyed<T> {
+    pub cursor: Cursor,
+    pub row: T,
+}
+
+impl<'r, T: FromRow<'r, PgRow>> FromRow<'r, PgRow> for Keyed<T> {
+    fn from_row(row: &'r PgRow) -> Result<Self, sqlx::Error> {
+        Ok(Keyed {
+            cursor: Cursor(row.try_get("cursor")?),
+            row: T::from_row(row)?,
+        })
+    }
+}
+
+/// Queries for one page of a listing and for the number of rows on every page
+pub struct PageQuery<'args> {
+    pub rows: QueryBuilder<'args, Postgres>,
+    pub total: QueryBuilder<'args, Postgres>,
+    page: Page,
+    by_cursor: bool,
+}
+
+#[derive(FromRow)]
+struct Total {
+    total: i64,
+}
+
+/// One page of a listing, the envelope every listing endpoint responds with
+#[derive(Serialize, ToSchema)]
+pub struct Paginated<T> {
+    items: Vec<T>,
+    /// Number of results across every page
+    total: i64,
+    /// Number of this page, null when the page was fetched by cursor
+    page: Option<u32>,
+    /// Most results a page holds
+    page_size: i64,
+    /// Link to the next page, null on the last page
+    next: Option<String>,
+    /// Link to the previous page, only given for pages fetched by page_no
+    prev: Option<String>,
+    /// Pass as `cursor` to fetch the next page, null on the last page
+    next_cursor: Option<String>,
+    /// Whether another page follows this one
+    has_more: bool,
+}
+
+impl<T> Paginated<T> {
+    pub fn items(&self) -> &[T] {
+        &self.items
+    }
+
+    /// Turns each result of the page into another
+    pub fn map<U>(self, f: impl FnMut(T) -> U) -> Paginated<U> {
+        Paginated {
+            items: self.items.into_iter().map(f).collect(),
+            total: self.total,
+            page: self.page,
+            page_size: self.page_size,
+            next: self.next,
+            prev: self.prev,
+            next_cursor: self.next_cursor,
+            has_more: self.has_more,
+        }
+    }
+}
+
+/// Link to another page of the listing at `uri`, keeping its other query parameters
+fn page_link(uri: &Uri, param: &'static str, value: &str) -> String {
+    let mut query = uri
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .filter(|pair| !matches!(pair.split('=').next(), Some("page_no" | "cursor")))
+        .collect::<Vec<&str>>()
+        .join("&");
+    if !query.is_empty() {
+        query.push('&');
+    }
+    format!("{}?{}{}={}", uri.path(), query, param, value)
+}
+
+/// Fetches a page built by [`Listing::paginate`], which asks for one row more
+/// than the page holds to tell whether another page follows. `uri` is the
+/// request the page was asked for with, the links are built from it.
+pub async fn fetch_page<T>(
+    mut query: PageQuery<'_>,
+    uri: &Uri,
+    db_pool: &Pool<Postgres>,
+) -> Result<Paginated<T>, MyError>
+where
+    T: for<'r> FromRow<'r, PgRow> + Send + Unpin,
+{
+    let mut rows = query
+        .rows
+        .build_query_as::<Keyed<T>>()
+        .fetch_all(db_pool)
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    let total = query
+        .total
+        .build_query_as::<Total>()
+        .fetch_one(db_pool)
+        .await
+        .map_err(|_| MyError::InternalServerError)?
+        .total;
+    let take = usize::try_from(query.page.take).unwrap_or(usize::MAX);
+    let has_more = rows.len() > take;
+    rows.truncate(take);
+    let next_cursor = match has_more {
+        true => rows.last().map(|row| row.cursor.encode()),
+        false => None,
+    };
+    // Following `next` carries on by cursor from any page, so that rows added
+    // while paging neither repeat nor go missing
+    let next = next_cursor
+        .as_deref()
+        .map(|cursor| page_link(uri, "cursor", cursor));
+    let number = query.page.number;
+    let (page, prev) = match query.by_cursor {
+        true => (None, None),
+        false => (
+            Some(number),
+            match number {
+                1 => None,
+                _ => Some(page_link(uri, "page_no", &(number - 1).to_string())),
+            },
+        ),
+    };
+    Ok(Paginated {
+        items: rows.into_iter().map(|keyed| keyed.row).collect(),
+        total,
+        page,
+        page_size: query.page.take,
+        next,
+        prev,
+        next_cursor,
+        has_more,
+    })
+}
+
+/// Builds a listing query with every value from the request bound as a
+/// parameter. Only `&'static str` SQL can be pushed outside of a bind, so
+/// the text of the query never depends on what the client sent.
+pub struct Listing<'args> {
+    builder: QueryBuilder<'args, Postgres>,
+    has_where: bool,
+    keyset: Option<&'static Keyset>,
+    /// Counts the rows of a paged listing, everything after the columns is
+    /// pushed to it as well so it always matches the same rows
+    count: Option<QueryBuilder<'args, Postgres>>,
+}
+
+impl<'args> Listing<'args> {
+    pub fn new(select: &'static str) -> Self {
+        Listing {
+            builder: QueryBuilder::new(select),
+            has_where: false,
+            keyset: None,
+            count: None,
+        }
+    }
+
+    /// Starts a listing paged through [`Listing::paginate`] which selects
+    /// `columns`, along with the cursor of each row in a "cursor" column
+    pub fn keyset(keyset: &'static Keyset, columns: &'static str) -> Self {
+        let mut builder = QueryBuilder::new("SELECT ARRAY[");
+        for (i, (column, _)) in keyset.columns.iter().enumerate() {
+            if i > 0 {
+                builder.push(", ");
+            }
+            builder.push(column);
+            builder.push("::text");
+        }
+        builder.push(r#"] AS "cursor", "#);
+        builder.push(columns);
+        Listing {
+            builder,
+            has_where: false,
+            keyset: Some(keyset),
+            count: Some(QueryBuilder::new(r#"SELECT COUNT(*) AS "total""#)),
+        }
+    }
+
+    /// Closes a subquery, conditions after it start a new WHERE clause
+    pub fn nest(&mut self, sql: &'static str) -> &mut Self {
+        self.has_where = false;
+        self.push(sql)
+    }
+
+    /// Starts a new condition, joined to the previous ones with `AND`
+    pub fn condition(&mut self, sql: &'static str) -> &mut Self {
+        let join = if self.has_where { " AND " } else { " WHERE " };
+        self.has_where = true;
+        self.push(join).push(sql)
+    }
+
+    /// Appends SQL to the current condition
+    pub fn push(&mut self, sql: &'static str) -> &mut Self {
+        self.builder.push(sql);
+        if let Some(count) = &mut self.count {
+            count.push(sql);
+        }
+        self
+    }
+
+    /// Appends a bound parameter to the current condition
+    pub fn bind<T>(&mut self, value: T) -> &mut Self
+    where
+        T: 'args + sqlx::Encode<'args, Postgres> + sqlx::Type<Postgres> + Send + Clone,
+    {
+        if let Some(count) = &mut self.count {
+            count.push_bind(value.clone());
+        }
+        self.builder.push_bind(value);
+        self
+    }
+
+    /// Closes a listing which is not paged, such as one wrapped in an aggregate
+    pub fn close(mut self, sql: &'static str) -> QueryBuilder<'args, Postgres> {
+        self.builder.push(sql);
+        self.builder
+    }
+
+    /// Starts the ORDER BY clause, no conditions may be added after it
+    pub fn sort(&mut self, sql: &'static str) -> &mut Self {
+        self.builder.push(" ORDER BY ");
+        self.builder.push(sql);
+        self
+    }
+
+    /// Pages a listing sorted through [`Listing::sort`]
+    pub fn page(mut self, page: Page) -> QueryBuilder<'args, Postgres> {
+        self.builder.push(" LIMIT ");
+        self.builder.push_bind(page.take);
+        self.builder.push(" OFFSET ");
+        self.builder.push_bind(page.offset);
+        self.builder
+    }
+
+    /// Sorts by the keyset and pages the listing, starting after the cursor
+    /// when there is one and at the offset of the page otherwise.
+    /// ORDER BY always comes before LIMIT and OFFSET.
+    pub fn paginate(self, page: Page, cursor: Option<Cursor>) -> Result<PageQuery<'args>, MyError> {
+        let (Some(keyset), Some(total)) = (self.keyset, self.count) else {
+            return Err(MyError::InternalServerError);
+        };
+        let mut builder = self.builder;
+        let by_cursor = cursor.is_some();
+        // The cursor only narrows the page, the total counts every page
+        if let Some(cursor) = cursor {
+            if !cursor.fits(keyset) {
+                return Err(MyError::UnproccessableEntityError);
+            }
+            builder.push(if self.has_where { " AND (" } else { " WHERE (" });
+            for (i, (column, _)) in keyset.columns.iter().enumerate() {
+                if i > 0 {
+                    builder.push(", ");
+                }
+                builder.push(column);
+            }
+            builder.push(if keyset.descending { ") < (" } else { ") > (" });
+            for (i, ((_, sql_type), value)) in keyset.columns.iter().zip(cursor.0).enumerate() {
+                if i > 0 {
+                    builder.push(", ");
+                }
+                builder.push_bind(value);
+                builder.push("::");
+                builder.push(sql_type);
+            }
+            builder.push(")");
+        }
+        builder.push(" ORDER BY ");
+        for (i, (column, _)) in keyset.columns.iter().enumerate() {
+            if i > 0 {
+                builder.push(", ");
+            }
+            builder.push(column);
+            builder.push(if keyset.descending { " DESC" } else { " ASC" });
+        }
+        builder.push(" LIMIT ");
+        builder.push_bind(page.take.saturating_add(1));
+        if !by_cursor {
+            builder.push(" OFFSET ");
+            builder.push_bind(page.offset);
+        }
+        Ok(PageQuery {
+            rows: builder,
+            total,
+            page,
+            by_cursor,
+        })
+    }
+}
@@ -0,0 +1,237 @@
+use crate::{
+    errors::{ErrorResponse, MyError},
+    user::GeneralResponse,
+    AppState,
+};
+use axum::{
+    extract::State,
+    http::{header::RETRY_AFTER, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::FromRow;
+use utoipa::ToSchema;
+
+/// What failed logins are counted against
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize, ToSchema, sqlx::Type)]
+#[sqlx(type_name = "login_scope", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum LoginScope {
+    Username,
+    Ip,
+}
+
+/// Longest key kept, usernames sent by clients can be of any length
+const KEY_MAX_CHARS: usize = 255;
+
+/// Seconds a key is locked out for after `failures` failed logins in a row:
+/// nothing up to `max_failures`, then `base` doubling with every further
+/// failure up to `cap`
+pub fn lockout_secs(failures: i32, max_failures: i32, base: u64, cap: u64) -> u64 {
+    match failures - max_failures {
+        over if over <= 0 => 0,
+        over => 2u64
+            .checked_pow((over - 1) as u32)
+            .and_then(|factor| base.checked_mul(factor))
+            .filter(|secs| *secs <= cap)
+            .unwrap_or(cap),
+    }
+}
+
+#[derive(FromRow)]
+struct Locked {
+    /// seconds left on the longest lockout, null if nothing is locked out
+    retry_after: Option<i64>,
+}
+
+#[derive(FromRow)]
+struct Failures {
+    failures: i32,
+}
+
+fn username_key(username: &str) -> String {
+    username.chars().take(KEY_MAX_CHARS).collect()
+}
+
+fn keys(username: &str, ip_address: Option<&str>) -> Vec<(LoginScope, String)> {
+    let mut keys = vec![(LoginScope::Username, username_key(username))];
+    if let Some(ip_address) = ip_address {
+        keys.push((LoginScope::Ip, ip_address.to_string()));
+    }
+    keys
+}
+
+/// Seconds until the username or the IP may try logging in again, if either
+/// is locked out. Checked before the password, which is costly to verify.
+pub async fn locked_for(
+    state: &AppState,
+    username: &str,
+    ip_address: Option<&str>,
+) -> Result<Option<i64>, MyError> {
+    let query = r#"
+        SELECT CEIL(EXTRACT(EPOCH FROM MAX("locked_until") - CURRENT_TIMESTAMP))::BIGINT
+        AS "retry_after"
+        FROM "login_throttle"
+        WHERE "locked_until" > CURRENT_TIMESTAMP
+        AND (("scope" = 'username' AND "key" = $1) OR ("scope" = 'ip' AND "key" = $2));
+    "#;
+    let locked = sqlx::query_as::<_, Locked>(query)
+        .bind(username_key(username))
+        .bind(ip_address)
+        .fetch_one(&state.db_pool)
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    Ok(locked.retry_after)
+}
+
+/// The answer to a login attempted while locked out
+pub fn locked_out(retry_after: i64) -> Response {
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        [(RETRY_AFTER, retry_after.to_string())],
+        Json(json!(GeneralResponse {
+            detail: format!(
+                "Too many failed logins, try again in {} seconds",
+                retry_after
+            )
+        })),
+    )
+        .into_response()
+}
+
+/// Counts a failed login against the username and the IP, locking out either
+/// once it has failed too often. Failures are forgotten once none has
+/// happened for `login_lockout_max`.
+pub async fn record_failure(
+    state: &AppState,
+    username: &str,
+    ip_address: Option<&str>,
+) -> Result<(), MyError> {
+    let window = state.login_lockout_max.as_secs_f64();
+    sqlx::query(
+        r#"
+        DELETE FROM "login_throttle"
+        WHERE "last_failure_at" < CURRENT_TIMESTAMP - make_interval(secs => $1)
+        AND ("locked_until" IS NULL OR "locked_until" < CURRENT_TIMESTAMP);
+        "#,
+    )
+    .bind(window)
+    .execute(&state.db_pool)
+    .await
+    .map_err(|_| MyError::InternalServerError)?;
+    for (scope, key) in keys(username, ip_address) {
+        let query = r#"
+            INSERT INTO "login_throttle" ("scope","key","failures") VALUES ($1,$2,1)
+            ON CONFLICT ("scope","key") DO UPDATE SET
+            "failures" = CASE
+                WHEN "login_throttle"."last_failure_at" < CURRENT_TIMESTAMP - make_interval(secs => $3)
+                THEN 1 ELSE "login_throttle"."failures" + 1 END,
+            "last_failure_at" = CURRENT_TIMESTAMP
+            RETURNING "failures";
+        "#;
+        let recorded = sqlx::query_as::<_, Failures>(query)
+            .bind(scope)
+            .bind(&key)
+            .bind(window)
+            .fetch_one(&state.db_pool)
+            .await
+            .map_err(|_| MyError::InternalServerError)?;
+        let max_failures = match scope {
+            LoginScope::Username => state.login_max_failures,
+            LoginScope::Ip => state.login_ip_max_failures,
+        };
+        let secs = lockout_secs(
+            recorded.failures,
+            max_failures,
+            state.login_lockout_base.as_secs(),
+            state.login_lockout_max.as_secs(),
+        );
+        if secs == 0 {
+            continue;
+        }
+        let query = r#"
+            WITH locked AS (
+                UPDATE "login_throttle"
+                SET "locked_until" = CURRENT_TIMESTAMP + make_interval(secs => $3)
+                WHERE "scope" = $1 AND "key" = $2
+                RETURNING "scope","key","failures","locked_until"
+            )
+            INSERT INTO "login_lockout" ("scope","key","failures","locked_until")
+            SELECT "scope","key","failures","locked_until" FROM locked;
+        "#;
+        sqlx::query(query)
+            .bind(scope)
+            .bind(&key)
+            .bind(secs as f64)
+            .execute(&state.db_pool)
+            .await
+            .map_err(|_| MyError::InternalServerError)?;
+        println!(
+            "Login locked out for {scope:?} {key} for {secs}s after {} failures",
+            recorded.failures
+        );
+    }
+    Ok(())
+}
+
+/// Forgets the failed logins of a username once it has logged in
+pub async fn clear_failures(state: &AppState, username: &str) -> Result<(), MyError> {
+    sqlx::query(r#"DELETE FROM "login_throttle" WHERE "scope" = 'username' AND "key" = $1"#)
+        .bind(username_key(username))
+        .execute(&state.db_pool)
+        .await
+        .map(|_| ())
+        .map_err(|_| MyError::InternalServerError)
+}
+
+#[derive(Serialize, Deserialize, ToSchema, FromRow)]
+pub struct LoginMetrics {
+    /// usernames which cannot log in right now
+    locked_usernames: i64,
+    /// IPs which cannot log in right now
+    locked_ips: i64,
+    lockouts_last_hour: i64,
+    lockouts_last_day: i64,
+    /// failed logins counted towards a lockout, which are forgotten after a
+    /// while without failures
+    tracked_failures: i64,
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/metrics/logins",
+    security(
+        ("session_id" = ["admin"])
+    ),
+    responses(
+        (status = 200, body = LoginMetrics),
+        (status = 401, body = ErrorResponse),
+        (status = 403, body = ErrorResponse),
+        (status = 500, body = ErrorResponse)
+    )
+)]
+/// Get Login Metrics
+///
+/// Endpoint to see how many logins are locked out, to tell credential
+/// stuffing from users who forgot their password
+pub async fn get_login_metrics(state: State<AppState>) -> Result<impl IntoResponse, MyError> {
+    let query = r#"
+        SELECT
+        (SELECT COUNT(*) FROM "login_throttle"
+            WHERE "scope" = 'username' AND "locked_until" > CURRENT_TIMESTAMP) AS "locked_usernames",
+        (SELECT COUNT(*) FROM "login_throttle"
+            WHERE "scope" = 'ip' AND "locked_until" > CURRENT_TIMESTAMP) AS "locked_ips",
+        (SELECT COUNT(*) FROM "login_lockout"
+            WHERE "created_at" > CURRENT_TIMESTAMP - INTERVAL '1 hour') AS "lockouts_last_hour",
+        (SELECT COUNT(*) FROM "login_lockout"
+            WHERE "created_at" > CURRENT_TIMESTAMP - INTERVAL '1 day') AS "lockouts_last_day",
+        (SELECT COALESCE(SUM("failures"), 0)::BIGINT FROM "login_throttle") AS "tracked_failures";
+    "#;
+    let metrics = sqlx::query_as::<_, LoginMetrics>(query)
+        .fetch_one(&state.db_pool)
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    Ok((StatusCode::OK, Json(json!(metrics))))
+}
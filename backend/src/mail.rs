@@ -0,0 +1,73 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+
+use async_trait::async_trait;
+
+#[derive(Debug)]
+pub enum MailError {
+    /// The mail could not be handed over for delivery, with the reason why
+    Delivery(String),
+}
+
+/// A mail to a single recipient
+#[derive(Debug, Clone)]
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+/// A service which mails users, such as an SMTP relay or a mailing API
+#[async_trait]
+pub trait MailSender: Send + Sync {
+    /// Hands the mail over for delivery, returning once it has been accepted
+    async fn send(&self, mail: Mail) -> Result<(), MailError>;
+}
+
+/// Mail sender for local development and tests which delivers nothing.
+///
+/// Every mail is logged and appended to `<dir>/<recipient>.txt`, so that it
+/// can be read back by whoever is testing the flow which sent it.
+pub struct FileMailSender {
+    dir: PathBuf,
+}
+
+impl FileMailSender {
+    pub fn new(dir: PathBuf) -> Self {
+        FileMailSender { dir }
+    }
+
+    /// The file mails to `to` are appended to
+    pub fn mailbox(&self, to: &str) -> PathBuf {
+        let name = to
+            .chars()
+            .map(
+                |c| match c.is_ascii_alphanumeric() || matches!(c, '@' | '.' | '_' | '+' | '-') {
+                    true => c,
+                    false => '_',
+                },
+            )
+            .collect::<String>();
+        self.dir.join(format!("{}.txt", name))
+    }
+}
+
+#[async_trait]
+impl MailSender for FileMailSender {
+    async fn send(&self, mail: Mail) -> Result<(), MailError> {
+        println!("Mail to {}: {}", mail.to, mail.subject);
+        std::fs::create_dir_all(&self.dir).map_err(|e| MailError::Delivery(e.to_string()))?;
+        let mut mailbox = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.mailbox(&mail.to))
+            .map_err(|e| MailError::Delivery(e.to_string()))?;
+        write!(
+            mailbox,
+            "To: {}\nSubject: {}\n\n{}\n\n",
+            mail.to, mail.subject, mail.body
+        )
+        .map_err(|e| MailError::Delivery(e.to_string()))
+    }
+}
@@ -1,49 +1,99 @@
 use dotenv::dotenv;
 
 use axum::{
-    http::Method,
-    routing::{delete, get, post},
+    http::{
+        header::{CONTENT_TYPE, RETRY_AFTER},
+        HeaderName, HeaderValue, Method,
+    },
+    middleware,
+    routing::{delete, get, post, put},
     Json, Router,
 };
-use tower_http::cors::{Any, CorsLayer};
+use tower_http::cors::{AllowOrigin, CorsLayer};
 // use tokio::runtime::{Runtime,Builder};
 
 use chrono::Duration;
 use serde::{Deserialize, Deserializer, Serialize};
 use sqlx::{postgres::PgPoolOptions, Pool, Postgres};
+use std::sync::Arc;
 use utoipa::ToSchema;
 
 use utoipa::{
-    openapi::security::{ApiKey, ApiKeyValue, SecurityScheme},
+    openapi::security::{ApiKey, ApiKeyValue, SecurityRequirement, SecurityScheme},
     Modify, OpenApi,
 };
 use utoipa_rapidoc::RapiDoc;
 use utoipa_redoc::{Redoc, Servable};
 use utoipa_swagger_ui::SwaggerUi;
 
+mod admin;
+mod auth;
 mod cart;
+mod category;
 mod errors;
+mod inventory;
 mod item;
+mod listing;
+mod lockout;
+mod mail;
 mod objects;
 mod order;
+mod payment;
+mod report;
+mod reservation;
 mod tests;
+mod two_factor;
 mod user;
 
-use cart::{add_item, check_cart, get_cart, update_cart_item, Cart, CartItem, CartResponse};
+use auth::{
+    require_role, require_verified_email, CookieSession, CookieSessionResponse, Requires, Role,
+};
+
+use admin::{
+    get_actions, hide_item, remove_comment, reset_user_two_factor, suspend_user, unhide_item,
+    unsuspend_user, ModerationAction, ModerationForm, ModerationKind, ModerationQuery,
+};
+use cart::{
+    add_item, check_cart, get_cart, update_cart_item, Cart, CartItem, CartResponse,
+    ReservationResponse,
+};
+use category::{create_category, get_categories, Category, CategoryForm, CategoryId, CategoryTree};
 use errors::ErrorResponse;
+use inventory::{get_stock_history, InventoryMovement, MovementReason, StockHistoryQuery};
 use item::{
-    create_item, delete_item, edit_item, edit_stock, get_item, get_items, rate_item,
-    search_suggestions, CommentQuery, EditItemForm, Item, ItemForm, ItemId, ItemResponse,
-    ItemStock, PageResponse, RateForm, SearchQuery, SearchResult,
+    autocomplete, create_item, create_variant, delete_item, edit_item, edit_stock, edit_variant,
+    get_item, get_items, rate_item, search_suggestions, AutocompleteQuery, AutocompleteResult,
+    Comment, CommentQuery, EditItemForm, Facets, Highlight, Item, ItemForm, ItemId, ItemResponse,
+    ItemStock, ItemVariant, PageResponse, PriceBucket, RateForm, RatingBucket, SearchHit,
+    SearchQuery, SearchResult, SellerFacet, StockAdjustmentForm, Suggestion, VariantForm,
+    VariantId, VariantMatrix,
 };
+use lockout::{get_login_metrics, LoginMetrics, LoginScope};
+use mail::{FileMailSender, MailSender};
 use order::{
-    create_order, get_orders, set_dispatch_by_item_id, AllOrderDetails, CartError, DispatchForm,
-    OrderDetails, OrderForm, OrderQuery, Orders,
+    cancel_order, create_order, get_order_status, get_orders, update_order_item_status,
+    AllOrderDetails, CancelForm, CartError, ItemStatus, OrderDetails, OrderForm, OrderQuery,
+    OrderStatus, OrderStatusResponse, StatusChange, StatusForm,
+};
+use payment::{
+    pay_order, payment_webhook, MockPaymentProvider, PayForm, PaymentDetails, PaymentProvider,
+    PaymentResponse, PaymentStatus,
+};
+use report::{
+    get_reports, report, resolve_report, ReportCase, ReportForm, ReportQuery, ReportReason,
+    ReportStatus, ReportTarget, ResolveForm,
+};
+use two_factor::{
+    confirm_two_factor, enroll_two_factor, verify_two_factor, ChallengeForm, ChallengeResponse,
+    LoginChallenge, RecoveryCodes, TotpCodeForm, TotpEnrolment,
 };
 use user::{
-    create_user_address, get_user_by_id, get_user_orders, logout, signup, user_login, Address,
-    AddressId, CreateUserForm, GeneralResponse, MyOrderDetails, MyOrderQuery, Session,
-    SessionResponse, User, UserLogin, UserResponse, UserWithSession,
+    become_seller, confirm_password_reset, create_user_address, get_roles, get_sessions,
+    get_user_by_id, get_user_orders, logout, refresh_session, request_password_reset,
+    resend_verification, revoke_other_sessions, revoke_session, signup, user_login, verify_email,
+    Address, AddressId, CreateUserForm, GeneralResponse, MyOrderDetails, MyOrderQuery, NewSession,
+    RefreshForm, ResetConfirmForm, ResetRequestForm, RolesResponse, Session, SessionInfo,
+    SessionResponse, User, UserLogin, UserResponse, UserWithSession, VerifyEmailForm,
 };
 
 #[derive(Deserialize, PartialEq, ToSchema)]
@@ -57,6 +107,8 @@ pub enum Filters {
     DateOfCreation(Order),
     Alphabetical(Order),
     Price(Order),
+    /// Best match for `search_string` first
+    Relevance,
 }
 
 #[derive(PartialEq, ToSchema)]
@@ -100,6 +152,7 @@ impl<'de> Deserialize<'de> for Filters {
             "Price" => Ok(Filters::Price(Order::Inc)),
             "Price(Inc)" => Ok(Filters::Price(Order::Inc)),
             "Price(Dec)" => Ok(Filters::Price(Order::Dec)),
+            "Relevance" => Ok(Filters::Relevance),
             _ => Err(serde::de::Error::custom("Invalid value")),
         }
     }
@@ -110,6 +163,41 @@ pub struct AppState {
     db_pool: Pool<Postgres>,
     s3_client: aws_sdk_s3::Client,
     image_bucket: String,
+    payment_provider: Arc<dyn PaymentProvider>,
+    payment_timeout: std::time::Duration,
+    reservation_window: std::time::Duration,
+    /// Number of reports which hides an item until a moderator looks at it
+    report_threshold: i32,
+    mail_sender: Arc<dyn MailSender>,
+    /// How long a password reset token can be used for
+    password_reset_ttl: std::time::Duration,
+    /// Whether users have to verify their email id before listing items
+    require_verified_email: bool,
+    /// How long an email verification token can be used for
+    email_verification_ttl: std::time::Duration,
+    /// Least time between two verification mails to the same user
+    email_resend_interval: std::time::Duration,
+    /// How long a session lasts without being used
+    session_idle_timeout: std::time::Duration,
+    /// How long a session lasts however much it is used
+    session_absolute_timeout: std::time::Duration,
+    /// Least time between two renewals of the same session
+    session_renew_interval: std::time::Duration,
+    /// How long the refresh token of a session can be used for
+    refresh_token_ttl: std::time::Duration,
+    /// How long a login has to be finished with a two-factor code
+    login_challenge_ttl: std::time::Duration,
+    /// Failed logins a username is allowed before it is locked out
+    login_max_failures: i32,
+    /// Failed logins an IP is allowed before it is locked out
+    login_ip_max_failures: i32,
+    /// Length of the first lockout, which doubles with every further failure
+    login_lockout_base: std::time::Duration,
+    /// Longest lockout, also how long failures are remembered for
+    login_lockout_max: std::time::Duration,
+    /// Origins of browser frontends, which may send the session cookie and
+    /// the CSRF header from another origin
+    allowed_origins: Vec<String>,
 }
 
 #[derive(Serialize, ToSchema)]
@@ -128,9 +216,26 @@ title = "Sellorama"),
         user::logout,
         user::create_user_address,
         user::get_user_orders,
+        user::get_roles,
+        user::become_seller,
+        user::request_password_reset,
+        user::confirm_password_reset,
+        user::verify_email,
+        user::get_sessions,
+        user::revoke_session,
+        user::revoke_other_sessions,
+        user::refresh_session,
+        two_factor::enroll_two_factor,
+        two_factor::confirm_two_factor,
+        two_factor::verify_two_factor,
+        user::resend_verification,
         order::create_order,
         order::get_orders,
-        order::set_dispatch_by_item_id,
+        order::update_order_item_status,
+        order::get_order_status,
+        order::cancel_order,
+        payment::pay_order,
+        payment::payment_webhook,
         item::create_item,
         item::edit_item,
         item::get_item,
@@ -139,11 +244,28 @@ title = "Sellorama"),
         item::rate_item,
         item::get_comments,
         item::edit_stock,
+        item::create_variant,
+        item::edit_variant,
+        inventory::get_stock_history,
         item::search_suggestions,
+        item::autocomplete,
+        category::create_category,
+        category::get_categories,
         cart::get_cart,
         cart::add_item,
         cart::update_cart_item,
-        cart::check_cart
+        cart::check_cart,
+        admin::suspend_user,
+        admin::unsuspend_user,
+        admin::reset_user_two_factor,
+        lockout::get_login_metrics,
+        admin::hide_item,
+        admin::unhide_item,
+        admin::remove_comment,
+        admin::get_actions,
+        report::report,
+        report::get_reports,
+        report::resolve_report
     ),
     components(
         schemas(
@@ -155,6 +277,16 @@ title = "Sellorama"),
             UserLogin,
             Session,
             UserWithSession,
+            Role,
+            RolesResponse,
+            ResetRequestForm,
+            ResetConfirmForm,
+            VerifyEmailForm,
+            SessionInfo,
+            CookieSession,
+            CookieSessionResponse,
+            NewSession,
+            RefreshForm,
             GeneralResponse,
             SessionResponse,
             UserResponse,
@@ -165,20 +297,50 @@ title = "Sellorama"),
             ItemResponse,
             EditItemForm,
             ItemStock,
+            ItemVariant,
+            VariantForm,
+            VariantId,
+            VariantMatrix,
+            StockAdjustmentForm,
+            MovementReason,
+            InventoryMovement,
+            StockHistoryQuery,
             PageResponse,
+            Facets,
+            PriceBucket,
+            RatingBucket,
+            SellerFacet,
             SearchQuery,
             SearchResult,
-            RateForm,
+            AutocompleteQuery,
+            AutocompleteResult,
+            Suggestion,
+            SearchHit,
+            Highlight,
+            Category,
+            CategoryForm,
+            CategoryId,
+            CategoryTree,
+            Comment,
             CommentFilters,
             CommentQuery,
             Cart,
             CartItem,
             CartResponse,
+            ReservationResponse,
             Filters,
             Order,
-            Orders,
             OrderQuery,
-            DispatchForm,
+            StatusForm,
+            OrderStatus,
+            ItemStatus,
+            StatusChange,
+            OrderStatusResponse,
+            CancelForm,
+            PayForm,
+            PaymentDetails,
+            PaymentResponse,
+            PaymentStatus,
             AllOrderDetails,
             OrderDetails,
             OrderForm,
@@ -186,7 +348,26 @@ title = "Sellorama"),
             MyOrderDetails,
             ErrorResponse,
             CartError,
-            CartItem
+            CartItem,
+            ModerationKind,
+            ModerationForm,
+            ModerationAction,
+            ModerationQuery,
+            ReportTarget,
+            ReportReason,
+            ReportStatus,
+            ReportForm,
+            ResolveForm,
+            ReportCase,
+            ReportQuery,
+            TotpEnrolment,
+            TotpCodeForm,
+            RecoveryCodes,
+            LoginChallenge,
+            ChallengeResponse,
+            ChallengeForm,
+            LoginScope,
+            LoginMetrics
         )
     ),
     modifiers(&SecurityAddon)
@@ -200,8 +381,47 @@ impl Modify for SecurityAddon {
         if let Some(components) = openapi.components.as_mut() {
             components.add_security_scheme(
                 "session_id",
-                SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new("session_id"))),
-            )
+                SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::with_description(
+                    "session_id",
+                    "Session id from /user/login. The scopes of an endpoint are the roles \
+                    allowed to call it, any one of them will do",
+                ))),
+            );
+            components.add_security_scheme(
+                "session_cookie",
+                SecurityScheme::ApiKey(ApiKey::Cookie(ApiKeyValue::with_description(
+                    auth::SESSION_COOKIE,
+                    "HttpOnly cookie set by /user/login when asked for with `cookie`. \
+                    Requests other than GET, HEAD and OPTIONS also need the token of the \
+                    `csrf_token` cookie in the `x-csrf-token` header",
+                ))),
+            );
+        }
+        // Every endpoint taking the session header takes the cookie just as well
+        for path in openapi.paths.paths.values_mut() {
+            let operations = [
+                &mut path.get,
+                &mut path.put,
+                &mut path.post,
+                &mut path.delete,
+                &mut path.patch,
+            ];
+            for security in operations
+                .into_iter()
+                .flatten()
+                .filter_map(|operation| operation.security.as_mut())
+            {
+                let cookies = security
+                    .iter()
+                    .filter_map(|requirement| {
+                        let schemes: std::collections::BTreeMap<String, Vec<String>> =
+                            serde_json::from_value(serde_json::to_value(requirement).ok()?).ok()?;
+                        schemes.get("session_id").cloned()
+                    })
+                    .map(|roles| SecurityRequirement::new("session_cookie", roles))
+                    .collect::<Vec<_>>();
+                security.extend(cookies);
+            }
         }
     }
 }
@@ -220,6 +440,137 @@ async fn main() {
     let s3_endpoint_url = std::env::var("AWS_ENDPOINT_URL").expect("AWS_ENDPOINT_URL must be set");
     let s3_region = std::env::var("AWS_REGION").expect("AWS_REGION must be set");
     let image_bucket = std::env::var("IMAGE_BUCKET").expect("IMAGE_BUCKET_NAME must be set");
+    // Getting payment env variables
+    let payment_provider: Arc<dyn PaymentProvider> = match std::env::var("PAYMENT_PROVIDER")
+        .unwrap_or_else(|_| "mock".to_string())
+        .as_str()
+    {
+        "mock" => Arc::new(MockPaymentProvider::new(
+            std::env::var("PAYMENT_WEBHOOK_SECRET").expect("PAYMENT_WEBHOOK_SECRET must be set"),
+        )),
+        provider => panic!("Unknown PAYMENT_PROVIDER {provider}"),
+    };
+    let payment_timeout = std::env::var("PAYMENT_TIMEOUT_SECS")
+        .map(|secs| secs.parse().expect("PAYMENT_TIMEOUT_SECS must be a number"))
+        .unwrap_or(10);
+    // Getting stock reservation env variables
+    let reservation_window = std::env::var("RESERVATION_WINDOW_SECS")
+        .map(|secs| {
+            secs.parse()
+                .expect("RESERVATION_WINDOW_SECS must be a number")
+        })
+        .unwrap_or(900);
+    // Getting mail env variables
+    let mail_sender: Arc<dyn MailSender> = match std::env::var("MAIL_SENDER")
+        .unwrap_or_else(|_| "file".to_string())
+        .as_str()
+    {
+        "file" => Arc::new(FileMailSender::new(
+            std::env::var("MAIL_DIR")
+                .unwrap_or_else(|_| "mail".to_string())
+                .into(),
+        )),
+        sender => panic!("Unknown MAIL_SENDER {sender}"),
+    };
+    let password_reset_ttl = std::env::var("PASSWORD_RESET_TTL_SECS")
+        .map(|secs| {
+            secs.parse()
+                .expect("PASSWORD_RESET_TTL_SECS must be a number")
+        })
+        .unwrap_or(3600);
+    // Getting email verification env variables
+    let require_verified_email = std::env::var("REQUIRE_VERIFIED_EMAIL")
+        .map(|required| {
+            required
+                .parse()
+                .expect("REQUIRE_VERIFIED_EMAIL must be true or false")
+        })
+        .unwrap_or(true);
+    let email_verification_ttl = std::env::var("EMAIL_VERIFICATION_TTL_SECS")
+        .map(|secs| {
+            secs.parse()
+                .expect("EMAIL_VERIFICATION_TTL_SECS must be a number")
+        })
+        .unwrap_or(86400);
+    let email_resend_interval = std::env::var("EMAIL_RESEND_INTERVAL_SECS")
+        .map(|secs| {
+            secs.parse()
+                .expect("EMAIL_RESEND_INTERVAL_SECS must be a number")
+        })
+        .unwrap_or(60);
+    // Getting session lifetime env variables
+    let session_idle_timeout = std::env::var("SESSION_IDLE_SECS")
+        .map(|secs| secs.parse().expect("SESSION_IDLE_SECS must be a number"))
+        .unwrap_or(86400);
+    let session_absolute_timeout = std::env::var("SESSION_ABSOLUTE_SECS")
+        .map(|secs| {
+            secs.parse()
+                .expect("SESSION_ABSOLUTE_SECS must be a number")
+        })
+        .unwrap_or(604800);
+    let session_renew_interval = std::env::var("SESSION_RENEW_SECS")
+        .map(|secs| secs.parse().expect("SESSION_RENEW_SECS must be a number"))
+        .unwrap_or(60);
+    let refresh_token_ttl = std::env::var("REFRESH_TOKEN_TTL_SECS")
+        .map(|secs| {
+            secs.parse()
+                .expect("REFRESH_TOKEN_TTL_SECS must be a number")
+        })
+        .unwrap_or(2592000);
+    let login_challenge_ttl = std::env::var("LOGIN_CHALLENGE_TTL_SECS")
+        .map(|secs| {
+            secs.parse()
+                .expect("LOGIN_CHALLENGE_TTL_SECS must be a number")
+        })
+        .unwrap_or(300);
+    // Getting login lockout env variables
+    let login_max_failures = std::env::var("LOGIN_MAX_FAILURES")
+        .map(|count| count.parse().expect("LOGIN_MAX_FAILURES must be a number"))
+        .unwrap_or(5);
+    let login_ip_max_failures = std::env::var("LOGIN_IP_MAX_FAILURES")
+        .map(|count| {
+            count
+                .parse()
+                .expect("LOGIN_IP_MAX_FAILURES must be a number")
+        })
+        .unwrap_or(50);
+    let login_lockout_base = std::env::var("LOGIN_LOCKOUT_SECS")
+        .map(|secs| secs.parse().expect("LOGIN_LOCKOUT_SECS must be a number"))
+        .unwrap_or(30);
+    let login_lockout_max = std::env::var("LOGIN_LOCKOUT_MAX_SECS")
+        .map(|secs| {
+            secs.parse()
+                .expect("LOGIN_LOCKOUT_MAX_SECS must be a number")
+        })
+        .unwrap_or(3600);
+    let report_threshold = std::env::var("REPORT_HIDE_THRESHOLD")
+        .map(|count| {
+            count
+                .parse()
+                .expect("REPORT_HIDE_THRESHOLD must be a number")
+        })
+        .unwrap_or(5);
+    let reservation_sweep = std::env::var("RESERVATION_SWEEP_SECS")
+        .map(|secs| {
+            secs.parse()
+                .expect("RESERVATION_SWEEP_SECS must be a number")
+        })
+        .unwrap_or(60);
+    let order_payment_window = std::env::var("ORDER_PAYMENT_WINDOW_SECS")
+        .map(|secs| {
+            secs.parse()
+                .expect("ORDER_PAYMENT_WINDOW_SECS must be a number")
+        })
+        .unwrap_or(1800);
+    let allowed_origins = std::env::var("CORS_ALLOWED_ORIGINS")
+        .map(|origins| {
+            origins
+                .split(',')
+                .map(|origin| origin.trim().to_string())
+                .filter(|origin| !origin.is_empty())
+                .collect()
+        })
+        .unwrap_or_default();
 
     let s3_credentials = objects::S3Credentials::new(
         s3_access_key,
@@ -239,19 +590,62 @@ async fn main() {
         .await
         .expect("Error building a connection pool");
 
-    match sqlx::migrate!("./migrations").run(&pool).await {
-        Err(e) => println!("{e}"),
-        Ok(_) => (),
-    };
+    // Serving against a schema which is only partly migrated fails in odd ways
+    sqlx::migrate!("./migrations")
+        .run(&pool)
+        .await
+        .expect("Error running migrations");
+
+    // The operator account, which has to be signed up first
+    if let Ok(admin_username) = std::env::var("ADMIN_USERNAME") {
+        if auth::grant_admin(&pool, &admin_username).await.is_err() {
+            println!("Could not grant the admin role to {admin_username}");
+        }
+    }
 
     let appstate = AppState {
         db_pool: pool.clone(),
         s3_client: s3_client,
         image_bucket: image_bucket,
+        payment_provider,
+        payment_timeout: std::time::Duration::from_secs(payment_timeout),
+        reservation_window: std::time::Duration::from_secs(reservation_window),
+        report_threshold,
+        mail_sender,
+        password_reset_ttl: std::time::Duration::from_secs(password_reset_ttl),
+        require_verified_email,
+        email_verification_ttl: std::time::Duration::from_secs(email_verification_ttl),
+        email_resend_interval: std::time::Duration::from_secs(email_resend_interval),
+        session_idle_timeout: std::time::Duration::from_secs(session_idle_timeout),
+        session_absolute_timeout: std::time::Duration::from_secs(session_absolute_timeout),
+        session_renew_interval: std::time::Duration::from_secs(session_renew_interval),
+        refresh_token_ttl: std::time::Duration::from_secs(refresh_token_ttl),
+        login_challenge_ttl: std::time::Duration::from_secs(login_challenge_ttl),
+        login_max_failures,
+        login_ip_max_failures,
+        login_lockout_base: std::time::Duration::from_secs(login_lockout_base),
+        login_lockout_max: std::time::Duration::from_secs(login_lockout_max),
+        allowed_origins,
     };
 
+    reservation::spawn_reservation_sweeper(
+        pool.clone(),
+        std::time::Duration::from_secs(reservation_sweep),
+        std::time::Duration::from_secs(order_payment_window),
+    );
+    payment::spawn_refund_sweeper(
+        appstate.clone(),
+        std::time::Duration::from_secs(reservation_sweep),
+    );
+
     let listener = tokio::net::TcpListener::bind(api_url).await.unwrap();
-    axum::serve(listener, app(appstate)).await.unwrap();
+    // The peer address is recorded with the sessions created
+    axum::serve(
+        listener,
+        app(appstate).into_make_service_with_connect_info::<std::net::SocketAddr>(),
+    )
+    .await
+    .unwrap();
 }
 
 #[utoipa::path(
@@ -270,32 +664,101 @@ async fn ping() -> Json<Ping> {
     response
 }
 pub fn app(appstate: AppState) -> Router {
+    // Credentials cannot be allowed for any origin, so only the configured
+    // frontends may send the session cookie along with the CSRF header
+    let origins = appstate
+        .allowed_origins
+        .iter()
+        .map(|origin| {
+            HeaderValue::from_str(origin).expect("CORS_ALLOWED_ORIGINS must be a list of origins")
+        })
+        .collect::<Vec<_>>();
     let cors = CorsLayer::new()
-        // allow `GET` and `POST` when accessing the resource
         .allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE])
-        // allow requests from any origin
-        .allow_origin(Any);
+        .allow_headers([
+            CONTENT_TYPE,
+            HeaderName::from_static(auth::SESSION_HEADER),
+            HeaderName::from_static(auth::CSRF_HEADER),
+        ])
+        .expose_headers([RETRY_AFTER])
+        .allow_credentials(true)
+        .allow_origin(AllowOrigin::list(origins));
+
+    // Layer letting through users who hold any of the given roles
+    let requires = |roles: &'static [Role]| {
+        middleware::from_fn_with_state((appstate.clone(), Requires(roles)), require_role)
+    };
+    let buyer = requires(&[Role::Buyer]);
+    let seller = requires(&[Role::Seller]);
+    let admin = requires(&[Role::Admin]);
+    let party = requires(&[Role::Buyer, Role::Seller]);
+    let moderator = requires(&[Role::Admin, Role::Support]);
 
     let user_router = Router::new()
         .route("/login", post(user_login))
         .route("/signup", post(signup))
         .route("/logout", post(logout))
+        .route("/roles", get(get_roles))
+        .route("/seller", post(become_seller))
+        .route("/password/reset", post(request_password_reset))
+        .route("/password/confirm", post(confirm_password_reset))
+        .route("/email/verify", post(verify_email))
+        .route("/email/resend", post(resend_verification))
+        .route("/sessions", get(get_sessions).delete(revoke_other_sessions))
+        .route("/sessions/refresh", post(refresh_session))
+        .route("/2fa/enroll", post(enroll_two_factor))
+        .route("/2fa/confirm", post(confirm_two_factor))
+        .route("/2fa/verify", post(verify_two_factor))
+        .route("/sessions/{session_id}", delete(revoke_session))
         .route("/{username}", get(get_user_by_id))
-        .route("/address", post(create_user_address))
-        .route("/myorders", get(get_user_orders))
+        .route(
+            "/address",
+            post(create_user_address).route_layer(buyer.clone()),
+        )
+        .route("/myorders", get(get_user_orders).route_layer(buyer.clone()))
         .with_state(appstate.clone());
 
     let item_router = Router::new()
-        .route("/create", post(create_item))
+        .route(
+            "/create",
+            post(create_item)
+                .route_layer(middleware::from_fn_with_state(
+                    appstate.clone(),
+                    require_verified_email,
+                ))
+                .route_layer(seller.clone()),
+        )
         .route(
             "/{item_id}",
-            delete(delete_item).put(edit_item).get(get_item),
+            get(get_item).merge(
+                delete(delete_item)
+                    .put(edit_item)
+                    .route_layer(seller.clone()),
+            ),
         )
         .route("/comments", get(item::get_comments))
         .route("/", get(get_items))
-        .route("/stock", post(edit_stock))
+        .route("/stock", post(edit_stock).route_layer(seller.clone()))
+        .route(
+            "/{item_id}/variant",
+            post(create_variant).route_layer(seller.clone()),
+        )
+        .route(
+            "/variant/{variant_id}",
+            put(edit_variant).route_layer(seller.clone()),
+        )
+        .route(
+            "/{item_id}/stock/history",
+            get(get_stock_history).route_layer(seller.clone()),
+        )
         .route("/search_suggestions", get(search_suggestions))
-        .route("/rate", post(rate_item))
+        .route("/autocomplete", get(autocomplete))
+        .route("/rate", post(rate_item).route_layer(buyer.clone()))
+        .with_state(appstate.clone());
+
+    let category_router = Router::new()
+        .route("/", get(get_categories))
+        .route("/create", post(create_category).route_layer(admin.clone()))
         .with_state(appstate.clone());
 
     let cart_router = Router::new()
@@ -303,12 +766,43 @@ pub fn app(appstate: AppState) -> Router {
         .route("/item", post(add_item))
         .route("/update", post(update_cart_item))
         .route("/subcheckout", get(check_cart))
+        .route_layer(buyer.clone())
         .with_state(appstate.clone());
 
     let order_router = Router::new()
-        .route("/create", post(create_order))
-        .route("/orders", get(get_orders))
-        .route("/dispatch", post(set_dispatch_by_item_id))
+        .route("/create", post(create_order).route_layer(buyer.clone()))
+        .route("/orders", get(get_orders).route_layer(seller))
+        .route(
+            "/status",
+            post(update_order_item_status).route_layer(party.clone()),
+        )
+        .route(
+            "/{order_id}/status",
+            get(get_order_status).route_layer(party),
+        )
+        .route("/cancel", post(cancel_order).route_layer(buyer.clone()))
+        .route("/pay", post(pay_order).route_layer(buyer.clone()))
+        .route("/payment/webhook", post(payment_webhook))
+        .with_state(appstate.clone());
+
+    let report_router = Router::new()
+        .route("/", post(report))
+        .route_layer(buyer)
+        .with_state(appstate.clone());
+
+    let admin_router = Router::new()
+        .route("/user/{user_id}/suspend", post(suspend_user))
+        .route("/user/{user_id}/unsuspend", post(unsuspend_user))
+        .route("/user/{user_id}/2fa/reset", post(reset_user_two_factor))
+        .route("/metrics/logins", get(get_login_metrics))
+        .route_layer(admin)
+        .route("/item/{item_id}/hide", post(hide_item))
+        .route("/item/{item_id}/unhide", post(unhide_item))
+        .route("/item/{item_id}/comment/{user_id}", delete(remove_comment))
+        .route("/actions", get(get_actions))
+        .route("/reports", get(get_reports))
+        .route("/reports/{case_id}/resolve", post(resolve_report))
+        .route_layer(moderator)
         .with_state(appstate.clone());
 
     let app = Router::new()
@@ -316,7 +810,10 @@ pub fn app(appstate: AppState) -> Router {
         .nest("/cart", cart_router)
         .nest("/user", user_router)
         .nest("/item", item_router)
+        .nest("/category", category_router)
         .nest("/order", order_router)
+        .nest("/report", report_router)
+        .nest("/admin", admin_router)
         .merge(SwaggerUi::new("/docs").url("/apidoc", ApiDoc::openapi()))
         .merge(Redoc::with_url("/redoc", ApiDoc::openapi()))
         .merge(RapiDoc::new("/apidoc").path("/rapidoc"))
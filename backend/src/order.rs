@@ -1,23 +1,106 @@
 use crate::{
+    auth::AuthUser,
     errors::MyError,
-    user::{check_session_validity, extract_session_header, GeneralResponse},
-    AppState, CartItem, ErrorResponse,
+    listing::{fetch_page, Cursor, Keyset, Listing, Page, PageQuery, Paginated},
+    payment::{issue_refund, refund_items},
+    reservation::lock_cart_stock,
+    user::GeneralResponse,
+    AppState, CartItem, ErrorResponse, ItemId,
 };
 
 use axum::extract::Query;
 use axum::{
-    extract::State,
-    http::{HeaderMap, StatusCode},
+    extract::{OriginalUri, Path, State},
+    http::StatusCode,
     response::IntoResponse,
     Form, Json,
 };
 // use chrono::{NaiveDateTime, Utc};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use sqlx::FromRow;
+use sqlx::{FromRow, PgConnection};
 use utoipa::{IntoParams, ToSchema};
 use uuid::Uuid;
 
+/// Fulfilment status of an order item, and the rolled up status of an order.
+///
+/// The variants are declared in lifecycle order, which the database enum
+/// mirrors so that an order can take the least advanced status of its items.
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize, ToSchema, sqlx::Type,
+)]
+#[sqlx(type_name = "order_status", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum OrderStatus {
+    PendingPayment,
+    Placed,
+    Paid,
+    Packed,
+    Shipped,
+    Delivered,
+    Returned,
+    Cancelled,
+}
+
+impl OrderStatus {
+    /// Whether moving from this status to `next` is a legal transition
+    pub fn can_transition_to(&self, next: OrderStatus) -> bool {
+        use OrderStatus::*;
+        matches!(
+            (self, next),
+            (PendingPayment, Paid)
+                | (Placed, Packed)
+                | (Paid, Packed)
+                | (Packed, Shipped)
+                | (Shipped, Delivered)
+                | (Delivered, Returned)
+                | (PendingPayment, Cancelled)
+                | (Placed, Cancelled)
+                | (Paid, Cancelled)
+                | (Packed, Cancelled)
+        )
+    }
+
+    /// The least advanced status of the live items, or cancelled once every
+    /// item is, as `order_status_rollup` does for the whole order
+    pub fn rollup(statuses: impl IntoIterator<Item = OrderStatus>) -> OrderStatus {
+        statuses
+            .into_iter()
+            .filter(|status| *status != OrderStatus::Cancelled)
+            .min()
+            .unwrap_or(OrderStatus::Cancelled)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OrderStatus::PendingPayment => "pending_payment",
+            OrderStatus::Placed => "placed",
+            OrderStatus::Paid => "paid",
+            OrderStatus::Packed => "packed",
+            OrderStatus::Shipped => "shipped",
+            OrderStatus::Delivered => "delivered",
+            OrderStatus::Returned => "returned",
+            OrderStatus::Cancelled => "cancelled",
+        }
+    }
+}
+
+/// The party requesting a status change on an order item
+pub enum Actor {
+    Seller,
+    Buyer,
+}
+
+impl Actor {
+    /// Statuses each party may move an order item into through `/order/status`
+    pub fn may_set(&self, status: OrderStatus) -> bool {
+        match self {
+            Actor::Seller => matches!(status, OrderStatus::Packed | OrderStatus::Shipped),
+            Actor::Buyer => matches!(status, OrderStatus::Delivered | OrderStatus::Returned),
+        }
+    }
+}
+
 #[derive(FromRow, ToSchema, Deserialize, Serialize)]
 pub struct OrderForm {
     ///address_id of the user to deliver the order
@@ -30,33 +113,80 @@ pub struct OrderDetails {
     order_id: Uuid,
     ///order_date of the order
     order_date: chrono::NaiveDateTime,
+    ///sum of the line totals at the time of purchase
+    subtotal: rust_decimal::Decimal,
+    ///amount charged for the order
+    total: rust_decimal::Decimal,
 }
 
 #[derive(FromRow, ToSchema, Deserialize, Serialize, IntoParams)]
 pub struct OrderQuery {
-    ///page_no of the orders
+    ///page_no of the orders, ignored when a cursor is given
     page_no: Option<u32>,
+    ///next_cursor of the previous page, to fetch the page after it
+    cursor: Option<String>,
     ///take of the orders
     take: Option<u32>,
-    ///dispatched status of the orders
-    dispatched: Option<bool>,
+    ///status of the order items
+    status: Option<OrderStatus>,
     ///Order of the orders
     order: Option<bool>,
 }
 
+#[derive(FromRow, ToSchema, Deserialize, Serialize)]
+pub struct StatusForm {
+    ///order_id of the order
+    order_id: Uuid,
+    ///variant_id of the item variant in the order
+    variant_id: Uuid,
+    ///status to move the order item into
+    status: OrderStatus,
+}
+
 #[derive(FromRow)]
-pub struct DispatchStatus {
-    dispatched: bool,
+struct ItemStatusRow {
+    status: OrderStatus,
+    is_seller: bool,
+    is_buyer: bool,
 }
 
-#[derive(FromRow, ToSchema, Deserialize, Serialize)]
-pub struct DispatchForm {
-    order_id: Uuid,
-    item_id: Uuid,
+#[derive(FromRow, ToSchema, Serialize)]
+pub struct ItemStatus {
+    ///item_id of the item in the order
+    pub item_id: Uuid,
+    ///variant_id of the item variant in the order
+    pub variant_id: Uuid,
+    ///status of the order item
+    pub status: OrderStatus,
 }
+
 #[derive(FromRow, ToSchema, Serialize)]
-pub struct Orders {
-    detail: Vec<AllOrderDetails>,
+pub struct StatusChange {
+    ///item_id of the item in the order
+    item_id: Uuid,
+    ///variant_id of the item variant in the order
+    variant_id: Uuid,
+    ///status before the change, empty for the initial status
+    from_status: Option<OrderStatus>,
+    ///status after the change
+    to_status: OrderStatus,
+    ///user_id of the user who made the change
+    changed_by: Option<Uuid>,
+    ///time of the change
+    changed_at: chrono::NaiveDateTime,
+}
+
+#[derive(ToSchema, Serialize)]
+pub struct OrderStatusResponse {
+    ///order_id of the order
+    order_id: Uuid,
+    ///status rolled up from the items visible to the user, which for sellers
+    ///are only their own
+    status: OrderStatus,
+    ///status of each item in the order visible to the user
+    items: Vec<ItemStatus>,
+    ///history of status changes, oldest first
+    history: Vec<StatusChange>,
 }
 
 #[derive(FromRow, ToSchema, Serialize)]
@@ -65,14 +195,40 @@ pub struct AllOrderDetails {
     order_id: Uuid,
     ///item_id of the item in the order
     item_id: Uuid,
+    ///variant_id of the item variant in the order
+    variant_id: Uuid,
     ///quantity of the item in the order
     quantity: i32,
+    ///title of the item at the time of purchase
+    title: String,
+    ///price of a single unit at the time of purchase
+    unit_price: rust_decimal::Decimal,
+    ///unit_price multiplied by quantity
+    line_total: rust_decimal::Decimal,
+    ///subtotal of the whole order
+    subtotal: rust_decimal::Decimal,
+    ///total of the whole order
+    total: rust_decimal::Decimal,
     ///address_id of the user to deliver the order
     address_id: Uuid,
     ///order_date of the order
     order_date: chrono::NaiveDateTime,
-    ///dispatched status of the order
-    dispatched: bool,
+    ///status of the order item
+    status: OrderStatus,
+    ///reason given by the buyer if the order item was cancelled
+    cancellation_reason: Option<String>,
+    ///time at which the order item was cancelled
+    cancelled_at: Option<chrono::NaiveDateTime>,
+}
+
+#[derive(FromRow, ToSchema, Deserialize, Serialize)]
+pub struct CancelForm {
+    ///order_id of the order to cancel
+    order_id: Uuid,
+    ///variant_id of a single item variant to cancel, the whole order is cancelled if empty
+    variant_id: Option<Uuid>,
+    ///reason for the cancellation, shown to the seller
+    reason: Option<String>,
 }
 
 #[derive(FromRow, ToSchema, Deserialize, Serialize)]
@@ -84,88 +240,101 @@ pub struct CartError {
     post,
     path = "/order/create",
     security(
-        ("session_id" = [])
+        ("session_id" = ["buyer"])
     ),
     responses(
         (status = 201 , body = OrderDetails),
         (status = 401, body = GeneralResponse),
+        (status = 403, body = ErrorResponse),
         (status = 409, body = CartError),
         (status = 500, body = GeneralResponse)
     )
 )]
 /// Create Order
 ///
-/// Endpoint to create an order from the items in the user's cart
+/// Endpoint to create an order from the items in the user's cart.
+/// The order waits in pending_payment until it is paid through `/order/pay`.
 pub async fn create_order(
-    headers: HeaderMap,
     state: State<AppState>,
+    AuthUser(user): AuthUser,
     Form(form_data): Form<OrderForm>,
 ) -> Result<impl IntoResponse, MyError> {
-    let session_id = extract_session_header(headers).await?;
-    match check_session_validity(&state.db_pool, session_id).await {
-        Some(user) => {
-            let mut txn = state.db_pool.begin().await.unwrap();
-            let query = r#"
-                DELETE FROM "cart" 
-                where
-                stock_validation("item_id","quantity") IS NOT TRUE 
-                AND
-                "cart_id" = $1 RETURNING "item_id","quantity"; 
-            "#;
-            match sqlx::query_as::<_, CartItem>(query)
-                .bind(user.user_id)
-                .fetch_all(&mut *txn)
-                .await
-                .map_err(|_| MyError::InternalServerError)?
-            {
-                items => match items.len() {
-                    0 => {
-                        let query = r#"
-                        WITH "cart_items" AS (
-                            DELETE FROM "cart" 
-                            where
-                            stock_validation("item_id","quantity") IS TRUE 
-                            AND
-                            "cart_id" = $1 RETURNING "item_id","quantity" 
-                        ),
-                        "order_details" as (
-                            INSERT INTO "order"("user_id","address_id")
-                            VALUES($1,$2) RETURNING "order_id","order_date"
+    let mut txn = state.db_pool.begin().await.unwrap();
+    lock_cart_stock(&mut txn, user.user_id).await?;
+    let query = r#"
+        DELETE FROM "cart" 
+        where
+        stock_validation("variant_id","quantity","cart_id") IS NOT TRUE 
+        AND
+        "cart_id" = $1 RETURNING "variant_id","quantity"; 
+    "#;
+    match sqlx::query_as::<_, CartItem>(query)
+        .bind(user.user_id)
+        .fetch_all(&mut *txn)
+        .await
+        .map_err(|_| MyError::InternalServerError)?
+    {
+        items => match items.len() {
+            0 => {
+                let query = r#"
+                WITH "cart_items" AS (
+                    DELETE FROM "cart" 
+                    where
+                    stock_validation("variant_id","quantity","cart_id") IS TRUE 
+                    AND
+                    "cart_id" = $1 RETURNING "variant_id","quantity" 
+                ),
+                "priced_items" AS (
+                    SELECT cart_items."variant_id","item_variant"."item_id",cart_items."quantity","item"."title",
+                    COALESCE("item_variant"."price","item"."price") AS "price"
+                    FROM cart_items
+                    INNER JOIN "item_variant" ON cart_items."variant_id" = "item_variant"."variant_id"
+                    INNER JOIN "item" ON "item_variant"."item_id" = "item"."item_id"
+                ),
+                "order_details" as (
+                    INSERT INTO "order"("user_id","address_id","subtotal","total")
+                    SELECT $1,$2,COALESCE(SUM("price" * "quantity"),0),COALESCE(SUM("price" * "quantity"),0)
+                    FROM priced_items
+                    RETURNING "order_id","order_date","subtotal","total"
+                ),
+                "released_holds" AS (
+                    DELETE FROM "stock_reservation" WHERE "cart_id" = $1
+                ),
+                "stock_updation" as (
+                    INSERT INTO "inventory_movement"("variant_id","delta","reason","order_id","created_by")
+                    SELECT cart_items."variant_id",-cart_items."quantity",'sale',order_details."order_id",$1
+                    FROM cart_items,order_details
+                ),
+                result AS(
+                INSERT INTO "order_items"("order_id","item_id","variant_id","quantity","title","unit_price","status")
+                SELECT "order_id","item_id","variant_id","quantity","title","price",'pending_payment' FROM priced_items,order_details RETURNING "order_id","item_id","variant_id","status"
+                ),
+                "status_history" AS (
+                    INSERT INTO "order_status_history"("order_id","item_id","variant_id","to_status","changed_by")
+                    SELECT "order_id","item_id","variant_id","status",$1 FROM result
+                )
+                SELECT result."order_id",order_details."order_date",order_details."subtotal",order_details."total" from result,order_details;
+                "#;
+                match sqlx::query_as::<_, OrderDetails>(query)
+                    .bind(user.user_id)
+                    .bind(form_data.address_id)
+                    .fetch_optional(&mut *txn)
+                    .await
+                    .map_err(|e| match e {
+                        // Stock went below zero, taken by another order since it was validated
+                        sqlx::Error::Database(e) if e.is_check_violation() => MyError::CustomError(
+                            (409, "Not enough stock left for this order".to_string()),
                         ),
-                        "stock_updation" as (
-                            UPDATE "stock" set "quantity" = "stock"."quantity" - cart_items."quantity" FROM cart_items WHERE cart_items."item_id" = stock."item_id"
-                        ),
-                        result AS(
-                        INSERT INTO "order_items"("order_id","item_id","quantity")
-                        SELECT "order_id","item_id","quantity" FROM cart_items,order_details RETURNING "order_id"
-                        )
-                        SELECT result."order_id",order_details."order_date" from result,order_details;
-                        "#;
-                        match sqlx::query_as::<_, OrderDetails>(query)
-                            .bind(user.user_id)
-                            .bind(form_data.address_id)
-                            .fetch_optional(&mut *txn)
-                            .await
-                            .map_err(|e| {
-                                println!("{e}");
-                                return MyError::InternalServerError;
-                            })? {
-                            Some(order) => {
-                                txn.commit().await.unwrap();
-                                Ok((StatusCode::CREATED, Json(json!(order))))
-                            }
-                            None => {
-                                txn.rollback()
-                                    .await
-                                    .map_err(|_| MyError::InternalServerError)?;
-                                Ok((
-                                    StatusCode::CONFLICT,
-                                    Json(json!(CartError { detail: items })),
-                                ))
-                            }
+                        e => {
+                            println!("{e}");
+                            MyError::InternalServerError
                         }
+                    })? {
+                    Some(order) => {
+                        txn.commit().await.unwrap();
+                        Ok((StatusCode::CREATED, Json(json!(order))))
                     }
-                    _ => {
+                    None => {
                         txn.rollback()
                             .await
                             .map_err(|_| MyError::InternalServerError)?;
@@ -174,10 +343,18 @@ pub async fn create_order(
                             Json(json!(CartError { detail: items })),
                         ))
                     }
-                },
+                }
             }
-        }
-        None => Err(MyError::UnauthorizedError),
+            _ => {
+                txn.rollback()
+                    .await
+                    .map_err(|_| MyError::InternalServerError)?;
+                Ok((
+                    StatusCode::CONFLICT,
+                    Json(json!(CartError { detail: items })),
+                ))
+            }
+        },
     }
 }
 
@@ -188,11 +365,13 @@ pub async fn create_order(
         OrderQuery
     ),
     security(
-        ("session_id" = [])
+        ("session_id" = ["seller"])
     ),
     responses(
-        (status = 200, body = Orders),
+        (status = 200, body = Paginated<AllOrderDetails>),
         (status = 401, body = GeneralResponse),
+        (status = 403, body = ErrorResponse),
+        (status = 422, body = ErrorResponse),
         (status = 500, body = GeneralResponse)
     ),
 )]
@@ -200,125 +379,439 @@ pub async fn create_order(
 ///
 /// Endpoint to get all the orders placed for a user
 pub async fn get_orders(
-    headers: HeaderMap,
     state: State<AppState>,
+    AuthUser(user): AuthUser,
+    OriginalUri(uri): OriginalUri,
     Query(form_data): Query<OrderQuery>,
 ) -> Result<impl IntoResponse, MyError> {
-    let session_id = extract_session_header(headers).await?;
-    match check_session_validity(&state.db_pool, session_id).await {
-        Some(user) => {
-            let query = paginate_orders(form_data);
-            match sqlx::query_as::<_, AllOrderDetails>(query.as_str())
-                .bind(user.user_id)
-                .fetch_all(&state.db_pool)
-                .await
-                .map_err(|_| MyError::InternalServerError)?
-            {
-                orders => Ok(Json(Orders { detail: orders })),
-            }
-        }
-        None => Err(MyError::UnauthorizedError),
-    }
+    let query = paginate_orders(form_data, user.user_id)?;
+    let orders = fetch_page::<AllOrderDetails>(query, &uri, &state.db_pool).await?;
+    Ok(Json(orders))
 }
 
 #[utoipa::path(
     post,
-    path = "/order/dispatch",
+    path = "/order/status",
     security(
-        ("session_id" = [])
+        ("session_id" = ["buyer", "seller"])
     ),
     responses(
         (status = 200, body = GeneralResponse),
         (status = 401, body = ErrorResponse),
+        (status = 403, body = ErrorResponse),
+        (status = 404, body = ErrorResponse),
+        (status = 409, body = ErrorResponse),
         (status = 500, body = ErrorResponse)
     ),
 )]
-pub async fn set_dispatch_by_item_id(
-    headers: HeaderMap,
+/// Update Order Item Status
+///
+/// Endpoint to advance the status of an item in an order.
+/// Sellers may mark their items packed or shipped,
+/// buyers may mark their items delivered or returned.
+pub async fn update_order_item_status(
     state: State<AppState>,
-    Form(form_data): Form<DispatchForm>,
+    AuthUser(user): AuthUser,
+    Form(form_data): Form<StatusForm>,
 ) -> Result<impl IntoResponse, MyError> {
-    let session_id = extract_session_header(headers).await?;
-    match check_session_validity(&state.db_pool, session_id).await {
-        Some(user) => {
-            let query = r#"UPDATE "order_items" 
-        SET "dispatched" = TRUE
-        WHERE
-        "item_id" = $2 AND "order_id" = $3 AND "dispatched" = FALSE AND item_ownership("item_id",$1)
-        RETURNING "dispatched";"#;
-            match sqlx::query_as::<_, DispatchStatus>(query)
-                .bind(user.user_id)
-                .bind(form_data.item_id)
-                .bind(form_data.order_id)
-                .fetch_optional(&state.db_pool)
-                .await
-                .map_err(|_| MyError::InternalServerError)?
-            {
-                Some(status) => {
-                    if status.dispatched {
-                        Ok(Json(GeneralResponse {
-                            detail: "Order item dispatched successfully".to_string(),
-                        }))
-                    } else {
-                        Err(MyError::UnauthorizedError)
-                    }
-                }
-                None => Err(MyError::UnauthorizedError),
-            }
-        }
-        None => Err(MyError::UnauthorizedError),
+    let mut txn = state
+        .db_pool
+        .begin()
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    let query = r#"
+        SELECT oi."status",
+        item_ownership(oi."item_id",$1) AS "is_seller",
+        o."user_id" = $1 AS "is_buyer"
+        FROM "order_items" oi
+        INNER JOIN "order" o ON oi."order_id" = o."order_id"
+        WHERE oi."order_id" = $2 AND oi."variant_id" = $3
+        FOR UPDATE OF oi;
+    "#;
+    let current = sqlx::query_as::<_, ItemStatusRow>(query)
+        .bind(user.user_id)
+        .bind(form_data.order_id)
+        .bind(form_data.variant_id)
+        .fetch_optional(&mut *txn)
+        .await
+        .map_err(|_| MyError::InternalServerError)?
+        .ok_or(MyError::NotFound)?;
+    let actor = if current.is_seller {
+        Actor::Seller
+    } else if current.is_buyer {
+        Actor::Buyer
+    } else {
+        return Err(MyError::NotFound);
+    };
+    if !actor.may_set(form_data.status) {
+        return Err(MyError::CustomError((
+            403,
+            format!(
+                "Not allowed to mark this order item {}",
+                form_data.status.as_str()
+            ),
+        )));
     }
+    set_item_status(
+        &mut txn,
+        form_data.order_id,
+        form_data.variant_id,
+        current.status,
+        form_data.status,
+        Some(user.user_id),
+    )
+    .await?;
+    txn.commit()
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    Ok(Json(GeneralResponse {
+        detail: "Order item status updated".to_string(),
+    }))
 }
 
-fn paginate_orders(pagination: OrderQuery) -> String {
-    struct PaginationParams {
-        take: u32,
-        offset: u32,
-        dispatched: String,
-        order: String,
+#[utoipa::path(
+    get,
+    path = "/order/{order_id}/status",
+    params(
+        ("order_id" = Uuid, Path, description = "order_id of the order")
+    ),
+    security(
+        ("session_id" = ["buyer", "seller"])
+    ),
+    responses(
+        (status = 200, body = OrderStatusResponse),
+        (status = 401, body = ErrorResponse),
+        (status = 403, body = ErrorResponse),
+        (status = 404, body = ErrorResponse),
+        (status = 500, body = ErrorResponse)
+    ),
+)]
+/// Get Order Status
+///
+/// Endpoint to get the status and status history of an order.
+/// Buyers see every item in the order, sellers only see their own items
+/// and the status rolled up from them.
+pub async fn get_order_status(
+    state: State<AppState>,
+    AuthUser(user): AuthUser,
+    Path(order_id): Path<Uuid>,
+) -> Result<impl IntoResponse, MyError> {
+    let items_query = r#"
+        SELECT oi."item_id", oi."variant_id", oi."status" FROM "order_items" oi
+        INNER JOIN "order" o ON oi."order_id" = o."order_id"
+        WHERE oi."order_id" = $1
+        AND (o."user_id" = $2 OR item_ownership(oi."item_id",$2) IS TRUE)
+        ORDER BY oi."item_id", oi."variant_id";
+    "#;
+    let items = sqlx::query_as::<_, ItemStatus>(items_query)
+        .bind(order_id)
+        .bind(user.user_id)
+        .fetch_all(&state.db_pool)
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    if items.is_empty() {
+        return Err(MyError::NotFound);
     }
-    // Default values
-    let mut query_params = PaginationParams {
-        take: 10,
-        offset: 0,
-        dispatched: "".to_string(),
-        order: "DESC".to_string(),
-    };
-    // Set values from pagination
-    match pagination.take {
-        Some(take) => query_params.take = take,
-        None => query_params.take = 10,
+    // Buyers see every item, so theirs is the status of the whole order.
+    // Sellers do not learn how far the other sellers' items have got.
+    let status = OrderStatus::rollup(items.iter().map(|item| item.status));
+    let history_query = r#"
+        SELECT "item_id","variant_id","from_status","to_status","changed_by","changed_at"
+        FROM "order_status_history"
+        WHERE "order_id" = $1 AND "variant_id" = ANY($2)
+        ORDER BY "changed_at" ASC;
+    "#;
+    let history = sqlx::query_as::<_, StatusChange>(history_query)
+        .bind(order_id)
+        .bind(
+            items
+                .iter()
+                .map(|item| item.variant_id)
+                .collect::<Vec<Uuid>>(),
+        )
+        .fetch_all(&state.db_pool)
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    Ok(Json(OrderStatusResponse {
+        order_id,
+        status,
+        items,
+        history,
+    }))
+}
+
+#[utoipa::path(
+    post,
+    path = "/order/cancel",
+    security(
+        ("session_id" = ["buyer"])
+    ),
+    responses(
+        (status = 200, body = GeneralResponse),
+        (status = 401, body = ErrorResponse),
+        (status = 403, body = ErrorResponse),
+        (status = 404, body = ErrorResponse),
+        (status = 409, body = ErrorResponse),
+        (status = 422, body = ErrorResponse),
+        (status = 500, body = ErrorResponse)
+    ),
+)]
+/// Cancel Order
+///
+/// Endpoint for the buyer to cancel a whole order or a single item in it.
+/// Only items which have not been shipped can be cancelled, the
+/// cancelled quantity is returned to stock and paid items are refunded.
+pub async fn cancel_order(
+    state: State<AppState>,
+    AuthUser(user): AuthUser,
+    Form(form_data): Form<CancelForm>,
+) -> Result<impl IntoResponse, MyError> {
+    let reason = form_data
+        .reason
+        .map(|reason| reason.trim().to_string())
+        .filter(|reason| !reason.is_empty());
+    if reason.as_ref().is_some_and(|reason| reason.len() > 500) {
+        return Err(MyError::CustomError((
+            422,
+            "Cancellation reason must be at most 500 characters".to_string(),
+        )));
     }
-    match pagination.page_no {
-        Some(page_no) => {
-            query_params.offset = if page_no > 0 {
-                (page_no - 1) * query_params.take
-            } else {
-                0
-            }
-        }
-        None => query_params.offset = 0,
+    let mut txn = state
+        .db_pool
+        .begin()
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    let query = r#"
+        SELECT oi."item_id", oi."variant_id", oi."status" FROM "order_items" oi
+        INNER JOIN "order" o ON oi."order_id" = o."order_id"
+        WHERE oi."order_id" = $1 AND o."user_id" = $2
+        AND ($3::uuid IS NULL OR oi."variant_id" = $3)
+        FOR UPDATE OF oi;
+    "#;
+    let rows = sqlx::query_as::<_, ItemStatus>(query)
+        .bind(form_data.order_id)
+        .bind(user.user_id)
+        .bind(form_data.variant_id)
+        .fetch_all(&mut *txn)
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    if rows.is_empty() {
+        return Err(MyError::NotFound);
     }
-    match pagination.dispatched {
-        Some(dispatched) => {
-            query_params.dispatched = format!(r#"WHERE "dispatched" = {}"#, dispatched)
-        }
-        None => query_params.dispatched = "".to_string(),
+    let items: Vec<ItemStatus> = rows
+        .into_iter()
+        .filter(|item| item.status != OrderStatus::Cancelled)
+        .collect();
+    if items.is_empty() {
+        return Err(MyError::CustomError((
+            409,
+            "Order is already cancelled".to_string(),
+        )));
     }
-    match pagination.order {
-        Some(order) => match order {
-            true => query_params.order = "ASC".to_string(),
-            false => query_params.order = "DESC".to_string(),
-        },
-        None => query_params.order = "DESC".to_string(),
+    if items
+        .iter()
+        .any(|item| !item.status.can_transition_to(OrderStatus::Cancelled))
+    {
+        return Err(MyError::CustomError((
+            409,
+            "Order items which have been shipped cannot be cancelled".to_string(),
+        )));
     }
-    format!(
-        r#"SELECT t1."order_id",t1."item_id",t1."quantity",t2."order_date",t2."address_id",t1."dispatched" FROM 
-        (SELECT * from "order_items" WHERE item_ownership("item_id",$1) IS TRUE ) as t1 
-        INNER JOIN
-        (SELECT * FROM "order" ) as t2
-        ON t1."order_id" = t2."order_id"
-        {} ORDER BY t2."order_date" {} LIMIT {} OFFSET {};"#,
-        query_params.dispatched, query_params.order, query_params.take, query_params.offset
+    cancel_items(
+        &mut txn,
+        form_data.order_id,
+        &items,
+        Some(user.user_id),
+        reason,
     )
+    .await?;
+    // Lines are only packed once paid for, so both were charged
+    let paid_items: Vec<Uuid> = items
+        .iter()
+        .filter(|item| matches!(item.status, OrderStatus::Paid | OrderStatus::Packed))
+        .map(|item| item.variant_id)
+        .collect();
+    let refund = match paid_items.is_empty() {
+        true => None,
+        false => refund_items(&state, &mut txn, form_data.order_id, &paid_items).await?,
+    };
+    txn.commit()
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    // The cancellation stands either way, a refund which fails is retried
+    if let Some(refund_id) = refund {
+        if issue_refund(&state, refund_id).await.is_err() {
+            println!("Refund {refund_id} was not issued, it will be retried");
+        }
+    }
+    Ok(Json(GeneralResponse {
+        detail: format!("{} order item(s) cancelled", items.len()),
+    }))
 }
+
+/// Cancels the given order items, recording who cancelled them and why,
+/// and returns the cancelled quantities to stock.
+pub async fn cancel_items(
+    conn: &mut PgConnection,
+    order_id: Uuid,
+    items: &[ItemStatus],
+    cancelled_by: Option<Uuid>,
+    reason: Option<String>,
+) -> Result<(), MyError> {
+    for item in items {
+        set_item_status(
+            conn,
+            order_id,
+            item.variant_id,
+            item.status,
+            OrderStatus::Cancelled,
+            cancelled_by,
+        )
+        .await?;
+    }
+    let query = r#"
+        WITH "cancelled" AS (
+            UPDATE "order_items" SET
+            "cancelled_by" = $2,
+            "cancellation_reason" = $3,
+            "cancelled_at" = CURRENT_TIMESTAMP
+            WHERE "order_id" = $1 AND "variant_id" = ANY($4)
+            RETURNING "variant_id","quantity"
+        )
+        INSERT INTO "inventory_movement" ("variant_id","delta","reason","order_id","created_by")
+        SELECT "variant_id","quantity",'cancellation',$1,$2 FROM "cancelled";
+    "#;
+    sqlx::query(query)
+        .bind(order_id)
+        .bind(cancelled_by)
+        .bind(reason)
+        .bind(
+            items
+                .iter()
+                .map(|item| item.variant_id)
+                .collect::<Vec<Uuid>>(),
+        )
+        .execute(conn)
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    Ok(())
+}
+
+/// Locks and returns the items of an order which are in `status`
+pub async fn items_with_status(
+    conn: &mut PgConnection,
+    order_id: Uuid,
+    status: OrderStatus,
+) -> Result<Vec<ItemStatus>, MyError> {
+    let query = r#"
+        SELECT "item_id","variant_id","status" FROM "order_items"
+        WHERE "order_id" = $1 AND "status" = $2
+        FOR UPDATE;
+    "#;
+    sqlx::query_as::<_, ItemStatus>(query)
+        .bind(order_id)
+        .bind(status)
+        .fetch_all(conn)
+        .await
+        .map_err(|_| MyError::InternalServerError)
+}
+
+/// Moves an order item from `from` to `to` and records the change in the
+/// status history. Fails with a conflict if the transition is illegal or
+/// the item is no longer in `from`.
+pub async fn set_item_status(
+    conn: &mut PgConnection,
+    order_id: Uuid,
+    variant_id: Uuid,
+    from: OrderStatus,
+    to: OrderStatus,
+    changed_by: Option<Uuid>,
+) -> Result<(), MyError> {
+    if !from.can_transition_to(to) {
+        return Err(MyError::CustomError((
+            409,
+            format!(
+                "Cannot move order item from {} to {}",
+                from.as_str(),
+                to.as_str()
+            ),
+        )));
+    }
+    let query = r#"
+        WITH "updated" AS (
+            UPDATE "order_items" SET "status" = $4
+            WHERE "order_id" = $1 AND "variant_id" = $2 AND "status" = $3
+            RETURNING "order_id","item_id","variant_id"
+        )
+        INSERT INTO "order_status_history" ("order_id","item_id","variant_id","from_status","to_status","changed_by")
+        SELECT "order_id","item_id","variant_id",$3,$4,$5 FROM "updated"
+        RETURNING "item_id";
+    "#;
+    match sqlx::query_as::<_, ItemId>(query)
+        .bind(order_id)
+        .bind(variant_id)
+        .bind(from)
+        .bind(to)
+        .bind(changed_by)
+        .fetch_optional(conn)
+        .await
+        .map_err(|_| MyError::InternalServerError)?
+    {
+        Some(_) => Ok(()),
+        None => Err(MyError::CustomError((
+            409,
+            "Order item status changed, try again".to_string(),
+        ))),
+    }
+}
+
+/// Order lines containing the seller's items
+pub fn paginate_orders(
+    pagination: OrderQuery,
+    seller_id: Uuid,
+) -> Result<PageQuery<'static>, MyError> {
+    let keyset = match pagination.order {
+        Some(true) => &OLDEST_ORDERS,
+        _ => &NEWEST_ORDERS,
+    };
+    let cursor = match &pagination.cursor {
+        Some(token) => Some(Cursor::decode(token)?),
+        None => None,
+    };
+    let mut listing = Listing::keyset(
+        keyset,
+        r#"t1."order_id",t1."item_id",t1."variant_id",t1."quantity",t1."title",t1."unit_price",t1."line_total",t2."subtotal",t2."total",t2."order_date",t2."address_id",t1."status",t1."cancellation_reason",t1."cancelled_at""#,
+    );
+    listing
+        .push(ORDER_LINES)
+        .condition(r#"item_ownership(t1."item_id","#)
+        .bind(seller_id)
+        .push(") IS TRUE");
+    if let Some(status) = pagination.status {
+        listing.condition(r#"t1."status" = "#).bind(status);
+    }
+    listing.paginate(Page::new(pagination.take, pagination.page_no), cursor)
+}
+
+/// Order lines along with the order they belong to
+pub const ORDER_LINES: &str = r#" FROM 
+        "order_items" as t1 
+        INNER JOIN
+        "order" as t2
+        ON t1."order_id" = t2."order_id""#;
+
+/// Order lines sorted by the date of their order, the order and variant
+/// ids break ties between lines placed at the same time
+pub const NEWEST_ORDERS: Keyset = Keyset {
+    columns: &[
+        (r#"t2."order_date""#, "timestamp"),
+        (r#"t1."order_id""#, "uuid"),
+        (r#"t1."variant_id""#, "uuid"),
+    ],
+    descending: true,
+};
+
+const OLDEST_ORDERS: Keyset = Keyset {
+    columns: NEWEST_ORDERS.columns,
+    descending: false,
+};
@@ -0,0 +1,904 @@
+use std::future::Future;
+use std::time::Duration;
+
+use crate::{
+    auth::AuthUser,
+    errors::MyError,
+    order::{cancel_items, items_with_status, set_item_status, OrderStatus},
+    user::GeneralResponse,
+    AppState, ErrorResponse,
+};
+use async_trait::async_trait;
+use axum::{
+    body::Bytes,
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    Form, Json,
+};
+use hmac::{Hmac, Mac};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use sqlx::{FromRow, PgConnection};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+#[derive(Debug)]
+pub enum PaymentError {
+    /// The provider refused the payment, with the reason it gave
+    Declined(String),
+    /// The provider did not answer within the configured timeout
+    Timeout,
+    /// A webhook could not be verified as coming from the provider
+    InvalidSignature,
+    /// Any other failure reported by the provider
+    Provider(String),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize, ToSchema, sqlx::Type)]
+#[sqlx(type_name = "payment_status", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum PaymentStatus {
+    Pending,
+    Authorized,
+    Captured,
+    Declined,
+    Failed,
+    Refunded,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookEventKind {
+    Captured,
+    Failed,
+    Refunded,
+}
+
+/// A verified notification from the payment provider
+#[derive(Debug, Deserialize, Serialize)]
+pub struct WebhookEvent {
+    /// Reference the provider returned when the payment was authorized
+    pub reference: String,
+    /// Idempotency key the payment was authorized with, which is all that is
+    /// stored of an authorization whose answer never arrived
+    #[serde(default)]
+    pub idempotency_key: Option<Uuid>,
+    pub event: WebhookEventKind,
+    pub reason: Option<String>,
+}
+
+/// A payment gateway which orders are charged through.
+///
+/// Implementations only talk to the gateway, recording payments and moving
+/// orders along is left to the checkout handlers.
+#[async_trait]
+pub trait PaymentProvider: Send + Sync {
+    /// Name stored against every payment made through this provider
+    fn name(&self) -> &'static str;
+
+    /// Reserves `amount` on the buyer's payment method and returns the
+    /// provider's reference for the payment. Calls with the same
+    /// `idempotency_key` reserve the amount once, so they can be retried.
+    async fn authorize(
+        &self,
+        idempotency_key: Uuid,
+        amount: Decimal,
+        payment_token: &str,
+    ) -> Result<String, PaymentError>;
+
+    /// Collects a previously authorized payment
+    async fn capture(&self, reference: &str, amount: Decimal) -> Result<(), PaymentError>;
+
+    /// Returns `amount` of a captured payment to the buyer. Calls with the
+    /// same `idempotency_key` refund the amount once, so they can be retried.
+    async fn refund(
+        &self,
+        idempotency_key: Uuid,
+        reference: &str,
+        amount: Decimal,
+    ) -> Result<(), PaymentError>;
+
+    /// Checks that a webhook was sent by the provider and parses it
+    fn verify_webhook(
+        &self,
+        headers: &HeaderMap,
+        body: &[u8],
+    ) -> Result<WebhookEvent, PaymentError>;
+}
+
+/// In-process payment provider for local development and tests.
+///
+/// The outcome of a payment is picked by its token: `mock_success` is
+/// captured, `mock_decline` is declined and `mock_timeout` never answers.
+/// References are derived from the idempotency key, so retries get the same one.
+/// Webhooks carry a hex HMAC-SHA256 of the body in the `mock-signature` header.
+pub struct MockPaymentProvider {
+    webhook_secret: String,
+}
+
+impl MockPaymentProvider {
+    pub fn new(webhook_secret: String) -> Self {
+        MockPaymentProvider { webhook_secret }
+    }
+}
+
+#[async_trait]
+impl PaymentProvider for MockPaymentProvider {
+    fn name(&self) -> &'static str {
+        "mock"
+    }
+
+    async fn authorize(
+        &self,
+        idempotency_key: Uuid,
+        _amount: Decimal,
+        payment_token: &str,
+    ) -> Result<String, PaymentError> {
+        match payment_token {
+            "mock_success" => Ok(format!("mock_{idempotency_key}")),
+            "mock_decline" => Err(PaymentError::Declined("Card declined".to_string())),
+            "mock_timeout" => std::future::pending().await,
+            _ => Err(PaymentError::Declined("Unknown payment token".to_string())),
+        }
+    }
+
+    async fn capture(&self, reference: &str, _amount: Decimal) -> Result<(), PaymentError> {
+        match reference.starts_with("mock_") {
+            true => Ok(()),
+            false => Err(PaymentError::Provider("Unknown payment".to_string())),
+        }
+    }
+
+    async fn refund(
+        &self,
+        _idempotency_key: Uuid,
+        reference: &str,
+        _amount: Decimal,
+    ) -> Result<(), PaymentError> {
+        match reference.starts_with("mock_") {
+            true => Ok(()),
+            false => Err(PaymentError::Provider("Unknown payment".to_string())),
+        }
+    }
+
+    fn verify_webhook(
+        &self,
+        headers: &HeaderMap,
+        body: &[u8],
+    ) -> Result<WebhookEvent, PaymentError> {
+        let signature = headers
+            .get("mock-signature")
+            .and_then(|signature| signature.to_str().ok())
+            .and_then(|signature| hex::decode(signature).ok())
+            .ok_or(PaymentError::InvalidSignature)?;
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.webhook_secret.as_bytes())
+            .expect("HMAC accepts keys of any length");
+        mac.update(body);
+        mac.verify_slice(&signature)
+            .map_err(|_| PaymentError::InvalidSignature)?;
+        serde_json::from_slice::<WebhookEvent>(body)
+            .map_err(|e| PaymentError::Provider(format!("Invalid webhook body: {e}")))
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct PayForm {
+    ///order_id of the order to pay for
+    order_id: Uuid,
+    ///token for the buyer's payment method issued by the payment provider
+    payment_token: String,
+}
+
+#[derive(FromRow, ToSchema, Serialize)]
+pub struct PaymentDetails {
+    ///payment_id of the payment
+    payment_id: Uuid,
+    ///order_id of the order paid for
+    order_id: Uuid,
+    ///amount charged
+    amount: Decimal,
+    ///status of the payment
+    status: PaymentStatus,
+}
+
+#[derive(ToSchema, Serialize)]
+pub struct PaymentResponse {
+    detail: PaymentDetails,
+}
+
+#[derive(FromRow)]
+struct PayableOrder {
+    status: OrderStatus,
+    /// line totals of the lines which have not been cancelled
+    amount: Decimal,
+}
+
+/// A payment of an order which has not been settled yet
+#[derive(FromRow)]
+struct OpenPayment {
+    payment_id: Uuid,
+    provider_reference: Option<String>,
+    amount: Decimal,
+    status: PaymentStatus,
+}
+
+#[derive(FromRow)]
+struct PaymentRow {
+    payment_id: Uuid,
+    order_id: Uuid,
+    provider_reference: Option<String>,
+    amount: Decimal,
+    status: PaymentStatus,
+}
+
+#[derive(FromRow)]
+struct RefundAmount {
+    amount: Decimal,
+}
+
+#[derive(FromRow)]
+struct QueuedRefund {
+    refund_id: Uuid,
+}
+
+#[derive(FromRow)]
+struct PendingRefund {
+    refund_id: Uuid,
+    payment_id: Uuid,
+    amount: Decimal,
+    provider_reference: Option<String>,
+}
+
+#[utoipa::path(
+    post,
+    path = "/order/pay",
+    security(
+        ("session_id" = ["buyer"])
+    ),
+    responses(
+        (status = 200, body = PaymentResponse),
+        (status = 401, body = ErrorResponse),
+        (status = 403, body = ErrorResponse),
+        (status = 402, body = ErrorResponse),
+        (status = 404, body = ErrorResponse),
+        (status = 409, body = ErrorResponse),
+        (status = 502, body = ErrorResponse),
+        (status = 504, body = ErrorResponse)
+    )
+)]
+/// Pay For Order
+///
+/// Endpoint to authorize and capture the payment for an order awaiting payment.
+/// A declined payment cancels the order and returns its items to stock,
+/// a payment which times out leaves the order awaiting payment so it can be retried.
+pub async fn pay_order(
+    state: State<AppState>,
+    AuthUser(user): AuthUser,
+    Form(form_data): Form<PayForm>,
+) -> Result<impl IntoResponse, MyError> {
+    let payment = open_payment(&state, user.user_id, form_data.order_id).await?;
+    let reference = match payment.provider_reference {
+        Some(reference) if payment.status == PaymentStatus::Authorized => reference,
+        _ => {
+            match call_provider(
+                state.payment_timeout,
+                state.payment_provider.authorize(
+                    payment.payment_id,
+                    payment.amount,
+                    &form_data.payment_token,
+                ),
+            )
+            .await
+            {
+                Ok(reference) => {
+                    let query = r#"
+                        UPDATE "payment" SET
+                        "status" = 'authorized',
+                        "provider_reference" = $2,
+                        "updated_at" = CURRENT_TIMESTAMP
+                        WHERE "payment_id" = $1 AND "status" = 'pending';
+                    "#;
+                    sqlx::query(query)
+                        .bind(payment.payment_id)
+                        .bind(&reference)
+                        .execute(&state.db_pool)
+                        .await
+                        .map_err(|_| MyError::InternalServerError)?;
+                    reference
+                }
+                Err(error) => {
+                    return Err(payment_failed(
+                        &state,
+                        form_data.order_id,
+                        payment.payment_id,
+                        error,
+                    )
+                    .await)
+                }
+            }
+        }
+    };
+    match call_provider(
+        state.payment_timeout,
+        state.payment_provider.capture(&reference, payment.amount),
+    )
+    .await
+    {
+        Ok(()) => {
+            let mut txn = state
+                .db_pool
+                .begin()
+                .await
+                .map_err(|_| MyError::InternalServerError)?;
+            let query = r#"
+                SELECT "payment_id","order_id","provider_reference","amount","status" FROM "payment"
+                WHERE "payment_id" = $1
+                FOR UPDATE;
+            "#;
+            let current = sqlx::query_as::<_, PaymentRow>(query)
+                .bind(payment.payment_id)
+                .fetch_one(&mut *txn)
+                .await
+                .map_err(|_| MyError::InternalServerError)?;
+            // A webhook or a retry racing this one may have settled it already
+            let refund = match current.status {
+                PaymentStatus::Captured => None,
+                _ => settle_capture(&mut txn, &current).await?,
+            };
+            txn.commit()
+                .await
+                .map_err(|_| MyError::InternalServerError)?;
+            if let Some(refund_id) = refund {
+                issue_refund(&state, refund_id).await?;
+                return Err(MyError::CustomError((
+                    409,
+                    "Order expired before it was paid, the payment has been refunded".to_string(),
+                )));
+            }
+            Ok((
+                StatusCode::OK,
+                Json(PaymentResponse {
+                    detail: PaymentDetails {
+                        payment_id: payment.payment_id,
+                        order_id: form_data.order_id,
+                        amount: payment.amount,
+                        status: PaymentStatus::Captured,
+                    },
+                }),
+            ))
+        }
+        Err(error) => {
+            Err(payment_failed(&state, form_data.order_id, payment.payment_id, error).await)
+        }
+    }
+}
+
+/// Returns the payment an order awaiting payment is being paid with, starting
+/// one if there is none. Its payment_id is the idempotency key sent to the
+/// provider, so it is committed before the provider is called and a payment
+/// left pending or authorized by a call that timed out is picked up again
+/// rather than charging the buyer twice. The buyer is charged for the lines
+/// of the order which have not been cancelled.
+async fn open_payment(
+    state: &AppState,
+    user_id: Uuid,
+    order_id: Uuid,
+) -> Result<OpenPayment, MyError> {
+    let mut txn = state
+        .db_pool
+        .begin()
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    let query = r#"
+        SELECT o."status",(
+            SELECT COALESCE(SUM(oi."line_total"),0) FROM "order_items" oi
+            WHERE oi."order_id" = o."order_id" AND oi."status" <> 'cancelled'
+        ) AS "amount"
+        FROM "order" o
+        WHERE o."order_id" = $1 AND o."user_id" = $2
+        FOR UPDATE OF o;
+    "#;
+    let order = sqlx::query_as::<_, PayableOrder>(query)
+        .bind(order_id)
+        .bind(user_id)
+        .fetch_optional(&mut *txn)
+        .await
+        .map_err(|_| MyError::InternalServerError)?
+        .ok_or(MyError::NotFound)?;
+    if order.status != OrderStatus::PendingPayment {
+        return Err(MyError::CustomError((
+            409,
+            "Order is not awaiting payment".to_string(),
+        )));
+    }
+    let query = r#"
+        SELECT "payment_id","provider_reference","amount","status" FROM "payment"
+        WHERE "order_id" = $1 AND "status" IN ('pending','authorized') AND "provider" = $2
+        ORDER BY "created_at" DESC LIMIT 1;
+    "#;
+    let open = sqlx::query_as::<_, OpenPayment>(query)
+        .bind(order_id)
+        .bind(state.payment_provider.name())
+        .fetch_optional(&mut *txn)
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    // Lines are only ever cancelled while the order awaits payment, so an
+    // amount which changed since the payment was opened has gone down
+    let payment = match open {
+        Some(payment) if payment.amount == order.amount => payment,
+        // Less than was authorized is captured
+        Some(payment) if payment.status == PaymentStatus::Authorized => {
+            let query = r#"
+                UPDATE "payment" SET "amount" = $2, "updated_at" = CURRENT_TIMESTAMP
+                WHERE "payment_id" = $1
+                RETURNING "payment_id","provider_reference","amount","status";
+            "#;
+            sqlx::query_as::<_, OpenPayment>(query)
+                .bind(payment.payment_id)
+                .bind(order.amount)
+                .fetch_one(&mut *txn)
+                .await
+                .map_err(|_| MyError::InternalServerError)?
+        }
+        // A payment which may have reached the provider for the old amount is
+        // given up on, its idempotency key cannot be sent with another amount
+        stale => {
+            if let Some(stale) = stale {
+                set_payment_status(
+                    &mut txn,
+                    stale.payment_id,
+                    PaymentStatus::Failed,
+                    Some("Order changed before it was paid".to_string()),
+                )
+                .await?;
+            }
+            let query = r#"
+                INSERT INTO "payment" ("order_id","provider","amount")
+                VALUES ($1,$2,$3)
+                RETURNING "payment_id","provider_reference","amount","status";
+            "#;
+            sqlx::query_as::<_, OpenPayment>(query)
+                .bind(order_id)
+                .bind(state.payment_provider.name())
+                .bind(order.amount)
+                .fetch_one(&mut *txn)
+                .await
+                .map_err(|_| MyError::InternalServerError)?
+        }
+    };
+    txn.commit()
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    Ok(payment)
+}
+
+#[utoipa::path(
+    post,
+    path = "/order/payment/webhook",
+    request_body(content_type = "application/json", content = String),
+    responses(
+        (status = 200, body = GeneralResponse),
+        (status = 401, body = ErrorResponse),
+        (status = 404, body = ErrorResponse),
+        (status = 500, body = ErrorResponse)
+    )
+)]
+/// Payment Webhook
+///
+/// Endpoint for the payment provider to report payments which were
+/// captured, failed or refunded outside of `/order/pay`.
+pub async fn payment_webhook(
+    state: State<AppState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<impl IntoResponse, MyError> {
+    let event = state
+        .payment_provider
+        .verify_webhook(&headers, &body)
+        .map_err(|_| MyError::UnauthorizedError)?;
+    let mut txn = state
+        .db_pool
+        .begin()
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    // Payments whose authorization timed out only have their idempotency key
+    let query = r#"
+        SELECT "payment_id","order_id","provider_reference","amount","status" FROM "payment"
+        WHERE "provider" = $2
+        AND ("payment_id" = $3 OR ($3::uuid IS NULL AND "provider_reference" = $1))
+        FOR UPDATE;
+    "#;
+    let payment = sqlx::query_as::<_, PaymentRow>(query)
+        .bind(&event.reference)
+        .bind(state.payment_provider.name())
+        .bind(event.idempotency_key)
+        .fetch_optional(&mut *txn)
+        .await
+        .map_err(|_| MyError::InternalServerError)?
+        .ok_or(MyError::NotFound)?;
+    if payment.provider_reference.is_none() {
+        let query = r#"
+            UPDATE "payment" SET "provider_reference" = $2 WHERE "payment_id" = $1;
+        "#;
+        sqlx::query(query)
+            .bind(payment.payment_id)
+            .bind(&event.reference)
+            .execute(&mut *txn)
+            .await
+            .map_err(|_| MyError::InternalServerError)?;
+    }
+    let mut refund = None;
+    let unsettled = matches!(
+        payment.status,
+        PaymentStatus::Pending | PaymentStatus::Authorized
+    );
+    match event.event {
+        // Captures of payments which were given up on are still settled,
+        // refunding them if their order is no longer awaiting payment
+        WebhookEventKind::Captured
+            if !matches!(
+                payment.status,
+                PaymentStatus::Captured | PaymentStatus::Refunded
+            ) =>
+        {
+            refund = settle_capture(&mut txn, &payment).await?;
+        }
+        WebhookEventKind::Failed if unsettled => {
+            let reason = event.reason.unwrap_or_else(|| "Payment failed".to_string());
+            set_payment_status(
+                &mut txn,
+                payment.payment_id,
+                PaymentStatus::Failed,
+                Some(reason.clone()),
+            )
+            .await?;
+            fail_order(&mut txn, payment.order_id, reason).await?;
+        }
+        WebhookEventKind::Refunded => {
+            let query = r#"
+                UPDATE "payment" SET
+                "status" = 'refunded',
+                "refunded_amount" = "amount",
+                "updated_at" = CURRENT_TIMESTAMP
+                WHERE "payment_id" = $1;
+            "#;
+            sqlx::query(query)
+                .bind(payment.payment_id)
+                .execute(&mut *txn)
+                .await
+                .map_err(|_| MyError::InternalServerError)?;
+        }
+        // Events for payments which were already settled are repeats
+        _ => (),
+    }
+    txn.commit()
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    // The event is recorded either way, a refund which fails is retried
+    if let Some(refund_id) = refund {
+        if issue_refund(&state, refund_id).await.is_err() {
+            println!("Refund {refund_id} was not issued, it will be retried");
+        }
+    }
+    Ok(Json(GeneralResponse {
+        detail: "Webhook processed".to_string(),
+    }))
+}
+
+/// Records a captured payment and marks its order paid. An order which
+/// expired or was cancelled while it was being paid has nothing left
+/// awaiting payment, so a refund of the whole payment is queued instead and
+/// returned, to be issued once the transaction has committed.
+async fn settle_capture(
+    conn: &mut PgConnection,
+    payment: &PaymentRow,
+) -> Result<Option<Uuid>, MyError> {
+    set_payment_status(
+        &mut *conn,
+        payment.payment_id,
+        PaymentStatus::Captured,
+        None,
+    )
+    .await?;
+    if mark_order_paid(&mut *conn, payment.order_id).await? > 0 {
+        return Ok(None);
+    }
+    queue_refund(conn, payment.payment_id, payment.amount)
+        .await
+        .map(Some)
+}
+
+/// Queues a refund of the line totals of the given paid order item variants
+/// against the order's captured payment, to be issued with [`issue_refund`]
+/// once the transaction has committed. Orders which were never paid through
+/// the provider have nothing to refund.
+pub async fn refund_items(
+    state: &AppState,
+    conn: &mut PgConnection,
+    order_id: Uuid,
+    variant_ids: &[Uuid],
+) -> Result<Option<Uuid>, MyError> {
+    let query = r#"
+        SELECT "payment_id","order_id","provider_reference","amount","status" FROM "payment"
+        WHERE "order_id" = $1 AND "status" = 'captured' AND "provider" = $2
+        FOR UPDATE;
+    "#;
+    let payment = sqlx::query_as::<_, PaymentRow>(query)
+        .bind(order_id)
+        .bind(state.payment_provider.name())
+        .fetch_optional(&mut *conn)
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    let Some(payment) = payment.filter(|payment| payment.provider_reference.is_some()) else {
+        return Ok(None);
+    };
+    let query = r#"
+        SELECT COALESCE(SUM("line_total"),0) AS "amount" FROM "order_items"
+        WHERE "order_id" = $1 AND "variant_id" = ANY($2);
+    "#;
+    let refund = sqlx::query_as::<_, RefundAmount>(query)
+        .bind(order_id)
+        .bind(variant_ids)
+        .fetch_one(&mut *conn)
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    if refund.amount <= Decimal::ZERO {
+        return Ok(None);
+    }
+    queue_refund(conn, payment.payment_id, refund.amount)
+        .await
+        .map(Some)
+}
+
+/// Records a pending refund, which holds no lock once its transaction commits
+async fn queue_refund(
+    conn: &mut PgConnection,
+    payment_id: Uuid,
+    amount: Decimal,
+) -> Result<Uuid, MyError> {
+    let query = r#"
+        INSERT INTO "payment_refund" ("payment_id","amount") VALUES ($1,$2)
+        RETURNING "refund_id";
+    "#;
+    let queued = sqlx::query_as::<_, QueuedRefund>(query)
+        .bind(payment_id)
+        .bind(amount)
+        .fetch_one(conn)
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    Ok(queued.refund_id)
+}
+
+/// Asks the provider for a pending refund and records it against its
+/// payment. Nothing is locked while the provider answers, the refund_id is
+/// the idempotency key, so a refund which failed is left pending and issued
+/// again by [`retry_pending_refunds`].
+pub async fn issue_refund(state: &AppState, refund_id: Uuid) -> Result<(), MyError> {
+    let query = r#"
+        SELECT r."refund_id",r."payment_id",r."amount",p."provider_reference"
+        FROM "payment_refund" r INNER JOIN "payment" p ON r."payment_id" = p."payment_id"
+        WHERE r."refund_id" = $1 AND r."status" = 'pending';
+    "#;
+    let Some(refund) = sqlx::query_as::<_, PendingRefund>(query)
+        .bind(refund_id)
+        .fetch_optional(&state.db_pool)
+        .await
+        .map_err(|_| MyError::InternalServerError)?
+    else {
+        // Issued by a retry in the meantime
+        return Ok(());
+    };
+    let reference = refund
+        .provider_reference
+        .ok_or(MyError::InternalServerError)?;
+    if let Err(error) = call_provider(
+        state.payment_timeout,
+        state
+            .payment_provider
+            .refund(refund.refund_id, &reference, refund.amount),
+    )
+    .await
+    {
+        sqlx::query(r#"UPDATE "payment_refund" SET "failure_reason" = $2 WHERE "refund_id" = $1"#)
+            .bind(refund.refund_id)
+            .bind(format!("{error:?}"))
+            .execute(&state.db_pool)
+            .await
+            .map_err(|_| MyError::InternalServerError)?;
+        return Err(MyError::CustomError((
+            502,
+            "Refund could not be issued, it will be retried".to_string(),
+        )));
+    }
+    let mut txn = state
+        .db_pool
+        .begin()
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    let query = r#"
+        UPDATE "payment_refund" SET "status" = 'issued', "issued_at" = CURRENT_TIMESTAMP
+        WHERE "refund_id" = $1 AND "status" = 'pending';
+    "#;
+    let issued = sqlx::query(query)
+        .bind(refund.refund_id)
+        .execute(&mut *txn)
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    // Only the call which moved it out of pending counts it against the payment
+    if issued.rows_affected() == 1 {
+        let query = r#"
+            UPDATE "payment" SET
+            "refunded_amount" = "refunded_amount" + $2,
+            "status" = CASE WHEN "refunded_amount" + $2 >= "amount" THEN 'refunded' ELSE "status" END,
+            "updated_at" = CURRENT_TIMESTAMP
+            WHERE "payment_id" = $1;
+        "#;
+        sqlx::query(query)
+            .bind(refund.payment_id)
+            .bind(refund.amount)
+            .execute(&mut *txn)
+            .await
+            .map_err(|_| MyError::InternalServerError)?;
+    }
+    txn.commit().await.map_err(|_| MyError::InternalServerError)
+}
+
+#[derive(FromRow)]
+struct StaleRefund {
+    refund_id: Uuid,
+}
+
+/// Issues the refunds which are still pending a while after they were
+/// queued, returning how many went through
+pub async fn retry_pending_refunds(state: &AppState) -> Result<u64, String> {
+    let query = r#"
+        SELECT "refund_id" FROM "payment_refund"
+        WHERE "status" = 'pending' AND "created_at" < CURRENT_TIMESTAMP - make_interval(secs => $1)
+        ORDER BY "created_at";
+    "#;
+    let stale = sqlx::query_as::<_, StaleRefund>(query)
+        .bind(state.payment_timeout.as_secs_f64())
+        .fetch_all(&state.db_pool)
+        .await
+        .map_err(|e| format!("Could not list pending refunds: {e}"))?;
+    let mut issued = 0;
+    for refund in stale {
+        match issue_refund(state, refund.refund_id).await {
+            Ok(()) => issued += 1,
+            Err(_) => println!("Refund {} was not issued again", refund.refund_id),
+        }
+    }
+    Ok(issued)
+}
+
+/// Retries pending refunds every `every` in the background
+pub fn spawn_refund_sweeper(state: AppState, every: Duration) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(every);
+        loop {
+            interval.tick().await;
+            match retry_pending_refunds(&state).await {
+                Ok(0) => (),
+                Ok(issued) => println!("Issued {issued} pending refunds"),
+                Err(e) => println!("{e}"),
+            }
+        }
+    });
+}
+
+/// Runs a provider call, giving up once `limit` has passed
+async fn call_provider<T>(
+    limit: Duration,
+    call: impl Future<Output = Result<T, PaymentError>>,
+) -> Result<T, PaymentError> {
+    tokio::time::timeout(limit, call)
+        .await
+        .unwrap_or(Err(PaymentError::Timeout))
+}
+
+/// Records a failed payment attempt, returning the error for the buyer
+async fn payment_failed(
+    state: &AppState,
+    order_id: Uuid,
+    payment_id: Uuid,
+    error: PaymentError,
+) -> MyError {
+    let (status, reason, response) = match error {
+        PaymentError::Declined(reason) => {
+            let response = MyError::CustomError((402, format!("Payment declined: {reason}")));
+            (PaymentStatus::Declined, reason, response)
+        }
+        // The provider may still complete the payment and report it through
+        // the webhook, and a retry authorizes it under the same idempotency
+        // key, so the payment is left as it is and the order awaiting payment
+        PaymentError::Timeout => {
+            return MyError::CustomError((504, "Payment provider timed out, try again".to_string()))
+        }
+        PaymentError::Provider(reason) => (
+            PaymentStatus::Failed,
+            reason,
+            MyError::CustomError((502, "Payment could not be processed".to_string())),
+        ),
+        PaymentError::InvalidSignature => (
+            PaymentStatus::Failed,
+            "Invalid provider response".to_string(),
+            MyError::CustomError((502, "Payment could not be processed".to_string())),
+        ),
+    };
+    match record_payment_failure(state, order_id, payment_id, status, reason).await {
+        Ok(()) => response,
+        Err(e) => e,
+    }
+}
+
+/// Marks the payment failed or declined, a declined payment also cancels the order
+async fn record_payment_failure(
+    state: &AppState,
+    order_id: Uuid,
+    payment_id: Uuid,
+    status: PaymentStatus,
+    reason: String,
+) -> Result<(), MyError> {
+    let mut txn = state
+        .db_pool
+        .begin()
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    set_payment_status(&mut txn, payment_id, status, Some(reason.clone())).await?;
+    if status == PaymentStatus::Declined {
+        fail_order(&mut txn, order_id, reason).await?;
+    }
+    txn.commit().await.map_err(|_| MyError::InternalServerError)
+}
+
+async fn set_payment_status(
+    conn: &mut PgConnection,
+    payment_id: Uuid,
+    status: PaymentStatus,
+    failure_reason: Option<String>,
+) -> Result<(), MyError> {
+    let query = r#"
+        UPDATE "payment" SET
+        "status" = $2,
+        "failure_reason" = COALESCE($3,"failure_reason"),
+        "updated_at" = CURRENT_TIMESTAMP
+        WHERE "payment_id" = $1;
+    "#;
+    sqlx::query(query)
+        .bind(payment_id)
+        .bind(status)
+        .bind(failure_reason)
+        .execute(conn)
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    Ok(())
+}
+
+/// Moves every item of the order awaiting payment to paid, returning how many there were
+async fn mark_order_paid(conn: &mut PgConnection, order_id: Uuid) -> Result<usize, MyError> {
+    let items = items_with_status(&mut *conn, order_id, OrderStatus::PendingPayment).await?;
+    for item in &items {
+        set_item_status(
+            &mut *conn,
+            order_id,
+            item.variant_id,
+            item.status,
+            OrderStatus::Paid,
+            None,
+        )
+        .await?;
+    }
+    Ok(items.len())
+}
+
+/// Cancels every item of the order awaiting payment and returns them to stock
+async fn fail_order(
+    conn: &mut PgConnection,
+    order_id: Uuid,
+    reason: String,
+) -> Result<(), MyError> {
+    let items = items_with_status(&mut *conn, order_id, OrderStatus::PendingPayment).await?;
+    cancel_items(conn, order_id, &items, None, Some(reason)).await
+}
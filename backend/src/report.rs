@@ -0,0 +1,441 @@
+use crate::{
+    admin::{free_text, record_action, ModerationKind},
+    auth::AuthUser,
+    errors::{ErrorResponse, MyError},
+    listing::{fetch_page, Cursor, Keyset, Listing, Page, Paginated},
+    user::GeneralResponse,
+    AppState,
+};
+use axum::{
+    extract::{OriginalUri, Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Form, Json,
+};
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{types::Json as SqlJson, FromRow, Postgres, Transaction};
+use std::collections::BTreeMap;
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
+
+/// What is reported
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize, ToSchema, sqlx::Type)]
+#[sqlx(type_name = "report_target", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum ReportTarget {
+    Item,
+    Comment,
+    User,
+}
+
+/// Why it is reported
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize, ToSchema, sqlx::Type)]
+#[sqlx(type_name = "report_reason", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum ReportReason {
+    Counterfeit,
+    Prohibited,
+    Misleading,
+    Abusive,
+    Spam,
+    Fraud,
+    Other,
+}
+
+/// Where the reports about a target stand in the moderation queue
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize, ToSchema, sqlx::Type)]
+#[sqlx(type_name = "report_status", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum ReportStatus {
+    Open,
+    Actioned,
+    Dismissed,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct ReportForm {
+    target: ReportTarget,
+    /// The reported item, or the item the reported comment is on
+    item_id: Option<Uuid>,
+    /// The reported user, or the author of the reported comment
+    user_id: Option<Uuid>,
+    reason: ReportReason,
+    /// Details for the moderators
+    note: Option<String>,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct ResolveForm {
+    /// Either actioned or dismissed
+    status: ReportStatus,
+    /// What was done about the reports
+    note: Option<String>,
+}
+
+/// The reports about one item, comment or user
+#[derive(Serialize, FromRow, ToSchema)]
+pub struct ReportCase {
+    case_id: Uuid,
+    target: ReportTarget,
+    /// The reported item, or the item the reported comment is on
+    item_id: Option<Uuid>,
+    /// The reported user, or the author of the reported comment
+    user_id: Option<Uuid>,
+    status: ReportStatus,
+    /// Number of users who reported it
+    report_count: i32,
+    /// Number of reports given for each reason
+    #[schema(value_type = Object)]
+    reasons: SqlJson<BTreeMap<String, i64>>,
+    opened_at: NaiveDateTime,
+    last_reported_at: Option<NaiveDateTime>,
+    resolved_by: Option<Uuid>,
+    resolved_at: Option<NaiveDateTime>,
+    resolution_note: Option<String>,
+}
+
+#[derive(Deserialize, ToSchema, IntoParams)]
+pub struct ReportQuery {
+    /// Number of cases to fetch per page
+    take: Option<u32>,
+    /// Page number to fetch, ignored when a cursor is given
+    page_no: Option<u32>,
+    /// `next_cursor` of the previous page, to fetch the page after it
+    cursor: Option<String>,
+    /// Only fetch cases in this status, open ones unless given
+    status: Option<ReportStatus>,
+    /// Only fetch cases about this kind of target
+    target: Option<ReportTarget>,
+}
+
+/// The queue puts the most reported cases first
+const MOST_REPORTED: Keyset = Keyset {
+    columns: &[(r#"c."report_count""#, "int"), (r#"c."case_id""#, "uuid")],
+    descending: true,
+};
+
+#[derive(FromRow)]
+struct Owner {
+    owner_id: Uuid,
+}
+
+#[derive(FromRow)]
+struct CaseId {
+    case_id: Uuid,
+}
+
+#[derive(FromRow)]
+struct ReportCount {
+    report_count: i32,
+}
+
+#[derive(FromRow)]
+struct Resolvable {
+    status: ReportStatus,
+}
+
+/// Checks that the reported target exists and returns the user behind it.
+/// The ids which do not belong to the target are cleared.
+async fn reported_owner(
+    txn: &mut Transaction<'_, Postgres>,
+    form_data: &mut ReportForm,
+) -> Result<Uuid, MyError> {
+    let owner = match (form_data.target, form_data.item_id, form_data.user_id) {
+        (ReportTarget::Item, Some(item_id), _) => {
+            form_data.user_id = None;
+            sqlx::query_as::<_, Owner>(
+                r#"SELECT "user_id" AS "owner_id" FROM "item" WHERE "item_id" = $1"#,
+            )
+            .bind(item_id)
+            .fetch_optional(&mut **txn)
+            .await
+        }
+        (ReportTarget::Comment, Some(item_id), Some(user_id)) => {
+            sqlx::query_as::<_, Owner>(
+                r#"SELECT "user_id" AS "owner_id" FROM "comment"
+                WHERE "item_id" = $1 AND "user_id" = $2"#,
+            )
+            .bind(item_id)
+            .bind(user_id)
+            .fetch_optional(&mut **txn)
+            .await
+        }
+        (ReportTarget::User, _, Some(user_id)) => {
+            form_data.item_id = None;
+            sqlx::query_as::<_, Owner>(
+                r#"SELECT "user_id" AS "owner_id" FROM "user" WHERE "user_id" = $1"#,
+            )
+            .bind(user_id)
+            .fetch_optional(&mut **txn)
+            .await
+        }
+        _ => {
+            return Err(MyError::CustomError((
+                422,
+                "item_id is needed to report an item, user_id to report a user and both to report a comment".to_string(),
+            )))
+        }
+    };
+    owner
+        .map_err(|_| MyError::InternalServerError)?
+        .map(|owner| owner.owner_id)
+        .ok_or(MyError::NotFound)
+}
+
+#[utoipa::path(
+    post,
+    path = "/report",
+    request_body(content = ReportForm, content_type = "application/x-www-form-urlencoded"),
+    security(
+        ("session_id" = ["buyer"])
+    ),
+    responses(
+        (status = 201, body = GeneralResponse),
+        (status = 401, body = ErrorResponse),
+        (status = 403, body = ErrorResponse),
+        (status = 404, body = ErrorResponse),
+        (status = 409, body = ErrorResponse),
+        (status = 422, body = ErrorResponse),
+        (status = 500, body = ErrorResponse)
+    )
+)]
+/// Report
+///
+/// Endpoint to flag an item, a comment or a user to the moderators. Each user
+/// reports the same thing once, and an item is hidden from the listings once
+/// enough users have reported it.
+pub async fn report(
+    state: State<AppState>,
+    AuthUser(user): AuthUser,
+    Form(mut form_data): Form<ReportForm>,
+) -> Result<impl IntoResponse, MyError> {
+    let note = free_text(form_data.note.take(), "note")?;
+    let mut txn = state
+        .db_pool
+        .begin()
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    if reported_owner(&mut txn, &mut form_data).await? == user.user_id {
+        return Err(MyError::CustomError((
+            422,
+            "Cannot report oneself".to_string(),
+        )));
+    }
+    // The expressions are those of the unique index on the target
+    let query = r#"
+        INSERT INTO "report_case" ("target","item_id","user_id") VALUES ($1,$2,$3)
+        ON CONFLICT (
+            "target",
+            COALESCE("item_id", '00000000-0000-0000-0000-000000000000'),
+            COALESCE("user_id", '00000000-0000-0000-0000-000000000000')
+        ) DO UPDATE SET "target" = EXCLUDED."target"
+        RETURNING "case_id";
+    "#;
+    let case = sqlx::query_as::<_, CaseId>(query)
+        .bind(form_data.target)
+        .bind(form_data.item_id)
+        .bind(form_data.user_id)
+        .fetch_one(&mut *txn)
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    let query = r#"
+        INSERT INTO "report" ("case_id","reporter_id","reason","note") VALUES ($1,$2,$3,$4)
+        ON CONFLICT DO NOTHING
+        RETURNING "case_id";
+    "#;
+    sqlx::query_as::<_, CaseId>(query)
+        .bind(case.case_id)
+        .bind(user.user_id)
+        .bind(form_data.reason)
+        .bind(note)
+        .fetch_optional(&mut *txn)
+        .await
+        .map_err(|_| MyError::InternalServerError)?
+        .ok_or(MyError::CustomError((409, "Already reported".to_string())))?;
+    // A resolved case is opened again by new reports
+    let query = r#"
+        UPDATE "report_case" SET "report_count" = "report_count" + 1, "status" = 'open',
+        "resolved_by" = NULL, "resolved_at" = NULL, "resolution_note" = NULL
+        WHERE "case_id" = $1
+        RETURNING "report_count";
+    "#;
+    let count = sqlx::query_as::<_, ReportCount>(query)
+        .bind(case.case_id)
+        .fetch_one(&mut *txn)
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    // Only the report reaching the threshold hides the item, so an item a
+    // moderator put back is not hidden again by the reports after it
+    if let (ReportTarget::Item, Some(item_id)) = (form_data.target, form_data.item_id) {
+        if count.report_count == state.report_threshold {
+            hide_reported_item(&mut txn, item_id, count.report_count).await?;
+        }
+    }
+    txn.commit()
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    Ok((
+        StatusCode::CREATED,
+        Json(json!(GeneralResponse {
+            detail: "Report submitted".to_string()
+        })),
+    ))
+}
+
+async fn hide_reported_item(
+    txn: &mut Transaction<'_, Postgres>,
+    item_id: Uuid,
+    report_count: i32,
+) -> Result<(), MyError> {
+    let hidden = sqlx::query(
+        r#"UPDATE "item" SET "hidden_at" = CURRENT_TIMESTAMP
+        WHERE "item_id" = $1 AND "hidden_at" IS NULL"#,
+    )
+    .bind(item_id)
+    .execute(&mut **txn)
+    .await
+    .map_err(|_| MyError::InternalServerError)?;
+    if hidden.rows_affected() == 0 {
+        return Ok(());
+    }
+    record_action(
+        txn,
+        None,
+        ModerationKind::HideItem,
+        None,
+        Some(item_id),
+        Some(format!("Hidden after {} reports", report_count)),
+    )
+    .await
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/reports",
+    params(
+        ReportQuery
+    ),
+    security(
+        ("session_id" = ["admin", "support"])
+    ),
+    responses(
+        (status = 200, body = Paginated<ReportCase>),
+        (status = 401, body = ErrorResponse),
+        (status = 403, body = ErrorResponse),
+        (status = 422, body = ErrorResponse),
+        (status = 500, body = ErrorResponse)
+    )
+)]
+/// Get Moderation Queue
+///
+/// Endpoint to page through the reported items, comments and users, most reported first
+pub async fn get_reports(
+    state: State<AppState>,
+    OriginalUri(uri): OriginalUri,
+    Query(pagination): Query<ReportQuery>,
+) -> Result<impl IntoResponse, MyError> {
+    let cursor = match &pagination.cursor {
+        Some(token) => Some(Cursor::decode(token)?),
+        None => None,
+    };
+    let mut listing = Listing::keyset(
+        &MOST_REPORTED,
+        r#"c."case_id",c."target",c."item_id",c."user_id",c."status",c."report_count",
+        COALESCE((SELECT jsonb_object_agg(r."reason", r."count") FROM (
+            SELECT "reason", COUNT(*) AS "count" FROM "report"
+            WHERE "report"."case_id" = c."case_id" GROUP BY "reason"
+        ) AS r), '{}') AS "reasons",
+        c."opened_at",
+        (SELECT MAX("created_at") FROM "report" WHERE "report"."case_id" = c."case_id") AS "last_reported_at",
+        c."resolved_by",c."resolved_at",c."resolution_note""#,
+    );
+    listing
+        .push(r#" FROM "report_case" c"#)
+        .condition(r#"c."status" = "#)
+        .bind(pagination.status.unwrap_or(ReportStatus::Open));
+    if let Some(target) = pagination.target {
+        listing.condition(r#"c."target" = "#).bind(target);
+    }
+    let query = listing.paginate(Page::new(pagination.take, pagination.page_no), cursor)?;
+    let response = fetch_page::<ReportCase>(query, &uri, &state.db_pool).await?;
+    Ok((StatusCode::OK, Json(json!(response))))
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/reports/{case_id}/resolve",
+    request_body(content = ResolveForm, content_type = "application/x-www-form-urlencoded"),
+    security(
+        ("session_id" = ["admin", "support"])
+    ),
+    responses(
+        (status = 200, body = GeneralResponse),
+        (status = 401, body = ErrorResponse),
+        (status = 403, body = ErrorResponse),
+        (status = 404, body = ErrorResponse),
+        (status = 409, body = ErrorResponse),
+        (status = 422, body = ErrorResponse),
+        (status = 500, body = ErrorResponse)
+    )
+)]
+/// Resolve Report Case
+///
+/// Endpoint to close an open case, as actioned once the target was dealt with
+/// through the other moderation endpoints or as dismissed
+pub async fn resolve_report(
+    state: State<AppState>,
+    AuthUser(moderator): AuthUser,
+    Path(case_id): Path<Uuid>,
+    Form(form_data): Form<ResolveForm>,
+) -> Result<impl IntoResponse, MyError> {
+    if form_data.status == ReportStatus::Open {
+        return Err(MyError::CustomError((
+            422,
+            "status must be actioned or dismissed".to_string(),
+        )));
+    }
+    let note = free_text(form_data.note, "note")?;
+    let mut txn = state
+        .db_pool
+        .begin()
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    let current = sqlx::query_as::<_, Resolvable>(
+        r#"SELECT "status" FROM "report_case" WHERE "case_id" = $1 FOR UPDATE"#,
+    )
+    .bind(case_id)
+    .fetch_optional(&mut *txn)
+    .await
+    .map_err(|_| MyError::InternalServerError)?
+    .ok_or(MyError::NotFound)?;
+    if current.status != ReportStatus::Open {
+        return Err(MyError::CustomError((
+            409,
+            "Report case is already resolved".to_string(),
+        )));
+    }
+    let query = r#"
+        UPDATE "report_case" SET "status" = $2, "resolved_by" = $3,
+        "resolved_at" = CURRENT_TIMESTAMP, "resolution_note" = $4
+        WHERE "case_id" = $1;
+    "#;
+    sqlx::query(query)
+        .bind(case_id)
+        .bind(form_data.status)
+        .bind(moderator.user_id)
+        .bind(note)
+        .execute(&mut *txn)
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    txn.commit()
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    Ok((
+        StatusCode::OK,
+        Json(json!(GeneralResponse {
+            detail: "Report case resolved".to_string()
+        })),
+    ))
+}
@@ -0,0 +1,184 @@
+use std::time::Duration;
+
+use crate::{
+    errors::MyError,
+    order::{cancel_items, ItemStatus, OrderStatus},
+};
+use chrono::NaiveDateTime;
+use sqlx::{FromRow, PgConnection, Pool, Postgres};
+use uuid::Uuid;
+
+#[derive(FromRow)]
+struct ReservationExpiry {
+    expires_at: Option<NaiveDateTime>,
+}
+
+#[derive(FromRow)]
+struct UnpaidItem {
+    order_id: Uuid,
+    item_id: Uuid,
+    variant_id: Uuid,
+    status: OrderStatus,
+}
+
+/// Locks the stock rows of every item in a cart, so that checkouts racing
+/// for the same items are validated one after the other.
+pub async fn lock_cart_stock(conn: &mut PgConnection, cart_id: Uuid) -> Result<(), MyError> {
+    let query = r#"
+        SELECT "variant_id" FROM "stock"
+        WHERE "variant_id" IN (SELECT "variant_id" FROM "cart" WHERE "cart_id" = $1)
+        ORDER BY "variant_id"
+        FOR UPDATE;
+    "#;
+    sqlx::query(query)
+        .bind(cart_id)
+        .execute(conn)
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    Ok(())
+}
+
+/// Holds the current contents of a cart for `window`, replacing any earlier
+/// hold of the cart, and returns when the hold expires.
+///
+/// The cart must already have been checked against `stock_validation`
+/// with its stock locked through [`lock_cart_stock`].
+pub async fn reserve_cart(
+    conn: &mut PgConnection,
+    cart_id: Uuid,
+    window: Duration,
+) -> Result<Option<NaiveDateTime>, MyError> {
+    let query = r#"
+        DELETE FROM "stock_reservation"
+        WHERE "cart_id" = $1
+        AND "variant_id" NOT IN (SELECT "variant_id" FROM "cart" WHERE "cart_id" = $1);
+    "#;
+    sqlx::query(query)
+        .bind(cart_id)
+        .execute(&mut *conn)
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    let query = r#"
+        WITH "held" AS (
+            INSERT INTO "stock_reservation" ("cart_id","variant_id","quantity","expires_at")
+            SELECT "cart_id","variant_id","quantity",CURRENT_TIMESTAMP + make_interval(secs => $2)
+            FROM "cart" WHERE "cart_id" = $1
+            ON CONFLICT ("cart_id","variant_id")
+            DO UPDATE SET "quantity" = EXCLUDED."quantity", "expires_at" = EXCLUDED."expires_at"
+            RETURNING "expires_at"
+        )
+        SELECT MIN("expires_at") AS "expires_at" FROM "held";
+    "#;
+    let held = sqlx::query_as::<_, ReservationExpiry>(query)
+        .bind(cart_id)
+        .bind(window.as_secs_f64())
+        .fetch_one(conn)
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    Ok(held.expires_at)
+}
+
+/// Deletes the reservations whose hold has run out and returns how many
+/// there were.
+///
+/// `stock_validation` already ignores expired reservations, so releasing
+/// them late never keeps stock from other carts.
+pub async fn release_expired_reservations(pool: &Pool<Postgres>) -> Result<u64, String> {
+    match sqlx::query(r#"DELETE FROM "stock_reservation" WHERE "expires_at" < CURRENT_TIMESTAMP"#)
+        .execute(pool)
+        .await
+    {
+        Ok(result) => Ok(result.rows_affected()),
+        Err(e) => Err(format!("Error :{}", e)),
+    }
+}
+
+/// Cancels the items of orders which have been awaiting payment for longer
+/// than `window`, returning them to stock through the inventory ledger, and
+/// returns how many were cancelled.
+///
+/// Payments still open for those orders are given up on, a capture the
+/// provider reports for one afterwards is refunded.
+pub async fn expire_unpaid_orders(pool: &Pool<Postgres>, window: Duration) -> Result<u64, String> {
+    let mut txn = pool.begin().await.map_err(|e| format!("Error :{}", e))?;
+    let query = r#"
+        SELECT oi."order_id", oi."item_id", oi."variant_id", oi."status" FROM "order_items" oi
+        INNER JOIN "order" o ON oi."order_id" = o."order_id"
+        WHERE oi."status" = 'pending_payment'
+        AND o."order_date" < CURRENT_TIMESTAMP - make_interval(secs => $1)
+        ORDER BY oi."order_id"
+        FOR UPDATE OF oi SKIP LOCKED;
+    "#;
+    let unpaid = sqlx::query_as::<_, UnpaidItem>(query)
+        .bind(window.as_secs_f64())
+        .fetch_all(&mut *txn)
+        .await
+        .map_err(|e| format!("Error :{}", e))?;
+    let mut orders: Vec<(Uuid, Vec<ItemStatus>)> = Vec::new();
+    for item in &unpaid {
+        let line = ItemStatus {
+            item_id: item.item_id,
+            variant_id: item.variant_id,
+            status: item.status,
+        };
+        match orders.last_mut() {
+            Some((order_id, items)) if *order_id == item.order_id => items.push(line),
+            _ => orders.push((item.order_id, vec![line])),
+        }
+    }
+    for (order_id, items) in &orders {
+        cancel_items(
+            &mut txn,
+            *order_id,
+            items,
+            None,
+            Some("Not paid in time".to_string()),
+        )
+        .await
+        .map_err(|_| format!("Error :could not cancel unpaid order {order_id}"))?;
+    }
+    let query = r#"
+        UPDATE "payment" SET
+        "status" = 'failed',
+        "failure_reason" = 'Order expired before it was paid',
+        "updated_at" = CURRENT_TIMESTAMP
+        WHERE "order_id" = ANY($1) AND "status" IN ('pending','authorized');
+    "#;
+    sqlx::query(query)
+        .bind(
+            orders
+                .iter()
+                .map(|(order_id, _)| *order_id)
+                .collect::<Vec<Uuid>>(),
+        )
+        .execute(&mut *txn)
+        .await
+        .map_err(|e| format!("Error :{}", e))?;
+    txn.commit().await.map_err(|e| format!("Error :{}", e))?;
+    Ok(unpaid.len() as u64)
+}
+
+/// Periodically deletes expired stock reservations and cancels orders left
+/// unpaid for longer than `payment_window`.
+///
+/// Expired reservations already stop counting against stock, deleting them
+/// only keeps the table from growing. Unpaid orders hold their stock until
+/// they are cancelled.
+pub fn spawn_reservation_sweeper(pool: Pool<Postgres>, every: Duration, payment_window: Duration) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(every);
+        loop {
+            interval.tick().await;
+            match release_expired_reservations(&pool).await {
+                Ok(0) => (),
+                Ok(released) => println!("Released {released} expired stock reservations"),
+                Err(e) => println!("{e}"),
+            }
+            match expire_unpaid_orders(&pool, payment_window).await {
+                Ok(0) => (),
+                Ok(cancelled) => println!("Cancelled {cancelled} order items left unpaid"),
+                Err(e) => println!("{e}"),
+            }
+        }
+    });
+}
@@ -1,6 +1,6 @@
 #[cfg(test)]
 mod tests {
-    use crate::{dotenv, objects, AppState, PgPoolOptions};
+    use crate::{dotenv, objects, AppState, FileMailSender, MockPaymentProvider, PgPoolOptions};
 
     use axum::Router;
     use reqwest::multipart;
@@ -40,16 +40,61 @@ mod tests {
             db_pool: pool.clone(),
             s3_client: s3_client,
             image_bucket: image_bucket,
+            payment_provider: std::sync::Arc::new(MockPaymentProvider::new(
+                "test_webhook_secret".to_string(),
+            )),
+            payment_timeout: std::time::Duration::from_secs(1),
+            reservation_window: std::time::Duration::from_secs(900),
+            report_threshold: 2,
+            mail_sender: std::sync::Arc::new(FileMailSender::new(mail_dir())),
+            password_reset_ttl: std::time::Duration::from_secs(600),
+            require_verified_email: true,
+            email_verification_ttl: std::time::Duration::from_secs(600),
+            email_resend_interval: std::time::Duration::from_secs(60),
+            session_idle_timeout: std::time::Duration::from_secs(600),
+            session_absolute_timeout: std::time::Duration::from_secs(3600),
+            // Renewed on every request, so the tests see it
+            session_renew_interval: std::time::Duration::ZERO,
+            refresh_token_ttl: std::time::Duration::from_secs(3600),
+            login_challenge_ttl: std::time::Duration::from_secs(300),
+            login_max_failures: 3,
+            // Every test logs in from the same IP
+            login_ip_max_failures: 10000,
+            login_lockout_base: std::time::Duration::from_secs(60),
+            login_lockout_max: std::time::Duration::from_secs(600),
+            allowed_origins: vec!["http://frontend.test".to_string()],
         };
         let app = crate::app(appstate);
         (app, api_url)
     }
 
+    /// Mail sent by the test app is kept here
+    fn mail_dir() -> std::path::PathBuf {
+        std::env::temp_dir().join("sellorama-test-mail")
+    }
+
+    /// The token in the last mail sent to `email_id`
+    fn last_mailed_token(email_id: &str) -> String {
+        let mailbox =
+            std::fs::read_to_string(FileMailSender::new(mail_dir()).mailbox(email_id)).unwrap();
+        mailbox
+            .lines()
+            .filter_map(|line| line.strip_prefix("Token: "))
+            .next_back()
+            .unwrap()
+            .to_string()
+    }
+
     async fn start_app_instance() -> String {
         let (app, url) = create_app().await;
         let listener = tokio::net::TcpListener::bind(url.clone()).await.unwrap();
         tokio::spawn(async move {
-            axum::serve(listener, app).await.unwrap();
+            axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<std::net::SocketAddr>(),
+            )
+            .await
+            .unwrap();
         });
         url
     }
@@ -68,14 +113,134 @@ mod tests {
             .await
             .map_err(|_| assert!(false))
             .unwrap();
+        // Signed up here when a test runs before test_01 on a fresh database
+        let res = match res.status() {
+            reqwest::StatusCode::UNAUTHORIZED => client
+                .post(format!("http://{}/user/signup", url))
+                .form(&params)
+                .send()
+                .await
+                .unwrap(),
+            _ => res,
+        };
 
         let session: crate::SessionResponse =
             serde_json::from_str(res.text().await.unwrap().as_str()).unwrap();
+        // test_user lists the items of every test
+        let res = client
+            .post(format!("http://{}/user/seller", url))
+            .header("session_id", session.detail.session_id.to_string())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        verify_email_id(
+            &url,
+            &session.detail.session_id.to_string(),
+            "test@testing.com",
+        )
+        .await;
         session.detail.session_id
     }
+
+    /// Verifies the email id of a user with the last token mailed to it
+    async fn verify_email_id(url: &str, session_id: &str, email_id: &str) {
+        let client = reqwest::Client::new();
+        let res = client
+            .post(format!("http://{}/user/email/resend", url))
+            .header("session_id", session_id)
+            .send()
+            .await
+            .unwrap();
+        if res.status() == reqwest::StatusCode::CONFLICT {
+            return;
+        }
+        let res = client
+            .post(format!("http://{}/user/email/verify", url))
+            .form(&[("token", last_mailed_token(email_id))])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+    }
+
+    /// Signs up a verified user nobody else uses, returning its username and session
+    async fn sign_up_fresh(url: String, prefix: &str) -> (String, String) {
+        let (username, session_id) = sign_up_unverified(url.clone(), prefix).await;
+        let email_id = format!("{}@testing.com", username);
+        verify_email_id(&url, &session_id, &email_id).await;
+        (username, session_id)
+    }
+
+    /// Signs up a user who has not verified their email id yet
+    async fn sign_up_unverified(url: String, prefix: &str) -> (String, String) {
+        let username = format!("{}_{}", prefix, uuid::Uuid::new_v4().simple());
+        let email_id = format!("{}@testing.com", username);
+        let res = reqwest::Client::new()
+            .post(format!("http://{}/user/signup", url))
+            .form(&[
+                ("username", username.as_str()),
+                ("password", "test_pass"),
+                ("email_id", email_id.as_str()),
+            ])
+            .send()
+            .await
+            .unwrap();
+        let session: crate::SessionResponse =
+            serde_json::from_str(&res.text().await.unwrap()).unwrap();
+        (username, session.detail.session_id.to_string())
+    }
+
+    /// Operators are made through the database, as with `ADMIN_USERNAME`
+    async fn make_admin(username: &str) {
+        dotenv().ok();
+        let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+        let pool = PgPoolOptions::new()
+            .max_connections(1)
+            .connect(&database_url)
+            .await
+            .expect("Error building a connection pool");
+        assert!(crate::auth::grant_admin(&pool, username).await.is_ok());
+    }
+
+    /// Connection to the test database, for checking what the API does not show
+    async fn connect_db() -> sqlx::PgPool {
+        dotenv().ok();
+        let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+        PgPoolOptions::new()
+            .max_connections(2)
+            .connect(&database_url)
+            .await
+            .expect("Error building a connection pool")
+    }
+
+    /// Forgets the failed logins of a test which fails them on purpose, every
+    /// test logs in from the same IP
+    async fn forget_failed_logins(username: &str) {
+        let pool = connect_db().await;
+        sqlx::query(
+            r#"DELETE FROM "login_throttle"
+            WHERE ("scope" = 'username' AND "key" = $1) OR "scope" = 'ip'"#,
+        )
+        .bind(username)
+        .execute(&pool)
+        .await
+        .unwrap();
+    }
+
+    async fn user_id_of(username: &str) -> uuid::Uuid {
+        let pool = connect_db().await;
+        let user: (uuid::Uuid,) =
+            sqlx::query_as(r#"SELECT "user_id" FROM "user" WHERE "username" = $1"#)
+                .bind(username)
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        user.0
+    }
     #[tokio::test]
 
-    async fn test_1_signup_with_valid_creds() {
+    async fn test_01_signup_with_valid_creds() {
         let url = start_app_instance().await;
         let mut params = std::collections::HashMap::new();
         params.insert("username", "test_user");
@@ -94,7 +259,7 @@ mod tests {
     }
     #[tokio::test]
 
-    async fn test_2_signup_with_invalid_creds() {
+    async fn test_02_signup_with_invalid_creds() {
         let url = start_app_instance().await;
         let mut params = std::collections::HashMap::new();
         params.insert("username", "test_user");
@@ -113,7 +278,7 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn test_3_login_with_valid_creds() {
+    async fn test_03_login_with_valid_creds() {
         let url = start_app_instance().await;
         let mut params = std::collections::HashMap::new();
         params.insert("username", "test_user");
@@ -130,10 +295,12 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn test_4_login_with_invalid_creds() {
+    async fn test_04_login_with_invalid_creds() {
         let url = start_app_instance().await;
+        // Not test_user, whose failed logins would count against every test
+        let (username, _) = sign_up_fresh(url.clone(), "mistyped").await;
         let mut params = std::collections::HashMap::new();
-        params.insert("username", "test_user");
+        params.insert("username", username.as_str());
         params.insert("password", "test_notpass");
         let client = reqwest::Client::new();
         let endpoint_url = format!("http://{}/user/login", url);
@@ -147,7 +314,7 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn test_5_create_post_without_image() {
+    async fn test_05_create_post_without_image() {
         let url = start_app_instance().await;
         let session_id = get_session_id(url.clone()).await;
         let mut headers = reqwest::header::HeaderMap::new();
@@ -174,7 +341,7 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn test_6_create_post_with_image() {
+    async fn test_06_create_post_with_image() {
         let url = start_app_instance().await;
         let session_id = get_session_id(url.clone()).await;
         //headers
@@ -205,7 +372,7 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn test_7_get_items() {
+    async fn test_07_get_items() {
         let url = start_app_instance().await;
         let session_id = get_session_id(url.clone()).await;
         //headers
@@ -220,4 +387,3005 @@ mod tests {
         let res = client.get(endpoint_url).send().await.unwrap();
         assert_eq!(res.status(), reqwest::StatusCode::OK);
     }
+
+    async fn get_buyer_session_id(url: String) -> uuid::Uuid {
+        let mut params = std::collections::HashMap::new();
+        params.insert("username", "test_buyer");
+        params.insert("password", "test_pass");
+        params.insert("email_id", "buyer@testing.com");
+        let client = reqwest::Client::new();
+        let res = client
+            .post(format!("http://{}/user/signup", url))
+            .form(&params)
+            .send()
+            .await
+            .unwrap();
+        let res = match res.status() {
+            reqwest::StatusCode::CONFLICT => client
+                .post(format!("http://{}/user/login", url))
+                .form(&params)
+                .send()
+                .await
+                .unwrap(),
+            _ => res,
+        };
+        let session: crate::SessionResponse =
+            serde_json::from_str(res.text().await.unwrap().as_str()).unwrap();
+        session.detail.session_id
+    }
+
+    /// Lists a fresh item as test_user with `stock` units of its only variant,
+    /// returning the item_id and the variant_id
+    async fn list_item(url: String, title: &str, stock: i32) -> (String, String) {
+        let seller_session = get_session_id(url.clone()).await;
+        list_item_as(url, seller_session, title, stock).await
+    }
+
+    /// Lists a fresh item as the seller with `seller_session`, as [`list_item`] does
+    async fn list_item_as(
+        url: String,
+        seller_session: uuid::Uuid,
+        title: &str,
+        stock: i32,
+    ) -> (String, String) {
+        let client = reqwest::Client::new();
+        let form = multipart::Form::new()
+            .text("title", title.to_string())
+            .text("content", "Item bought in order tests")
+            .text("price", "10.00");
+        let res = client
+            .post(format!("http://{}/item/create", url))
+            .header("session_id", seller_session.to_string())
+            .multipart(form)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::CREATED);
+
+        let res = client
+            .get(format!("http://{}/item", url))
+            .query(&[("filter", "DateOfCreation(Dec)"), ("take", "1")])
+            .send()
+            .await
+            .unwrap();
+        let page: serde_json::Value = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+        let item_id = page["items"][0]["detail"]["item_id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+
+        let res = client
+            .get(format!("http://{}/item/{}", url, item_id))
+            .header("session_id", seller_session.to_string())
+            .send()
+            .await
+            .unwrap();
+        let item: serde_json::Value = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+        let variant_id = item["variants"]["variants"][0]["variant_id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+
+        let res = client
+            .post(format!("http://{}/item/stock", url))
+            .header("session_id", seller_session.to_string())
+            .form(&[
+                ("variant_id", variant_id.as_str()),
+                ("delta", stock.to_string().as_str()),
+                ("reason", "restock"),
+            ])
+            .send()
+            .await
+            .unwrap();
+        assert!(res.status().is_success());
+        (item_id, variant_id)
+    }
+
+    /// Orders `quantity` units of each variant as test_buyer, returning the order_id
+    async fn order_variants(
+        url: String,
+        buyer_session: uuid::Uuid,
+        lines: &[(&str, i32)],
+    ) -> String {
+        let client = reqwest::Client::new();
+        let res = client
+            .post(format!("http://{}/user/address", url))
+            .header("session_id", buyer_session.to_string())
+            .form(&[
+                ("address_line_1", "1 Test Street"),
+                ("city", "Testville"),
+                ("country", "Testland"),
+                ("pincode", "000000"),
+            ])
+            .send()
+            .await
+            .unwrap();
+        let address: serde_json::Value = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+        let address_id = address["address_id"].as_str().unwrap().to_string();
+
+        for (variant_id, quantity) in lines {
+            let res = client
+                .post(format!("http://{}/cart/item", url))
+                .header("session_id", buyer_session.to_string())
+                .form(&[
+                    ("variant_id", variant_id.to_string()),
+                    ("quantity", quantity.to_string()),
+                ])
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(res.status(), reqwest::StatusCode::OK);
+        }
+
+        let res = client
+            .post(format!("http://{}/order/create", url))
+            .header("session_id", buyer_session.to_string())
+            .form(&[("address_id", address_id.as_str())])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::CREATED);
+        let order: serde_json::Value = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+        order["order_id"].as_str().unwrap().to_string()
+    }
+
+    /// Lists a fresh item as test_user and orders two of the five units of its
+    /// only variant as
+    /// test_buyer, returning the buyer's session, the order_id and the item_id
+    async fn place_order(url: String) -> (uuid::Uuid, String, String) {
+        let (item_id, variant_id) = list_item(url.clone(), "Payment test item", 5).await;
+        let buyer_session = get_buyer_session_id(url.clone()).await;
+        let order_id = order_variants(url.clone(), buyer_session, &[(&variant_id, 2)]).await;
+        (buyer_session, order_id, item_id)
+    }
+
+    /// Units of an item in stock
+    async fn stock_of(url: String, session_id: uuid::Uuid, item_id: &str) -> i64 {
+        let client = reqwest::Client::new();
+        let res = client
+            .get(format!("http://{}/item/{}", url, item_id))
+            .header("session_id", session_id.to_string())
+            .send()
+            .await
+            .unwrap();
+        let item: serde_json::Value = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+        item["detail"]["stock"].as_i64().unwrap()
+    }
+
+    async fn cancel(url: String, session_id: uuid::Uuid, form: &[(&str, &str)]) -> u16 {
+        let client = reqwest::Client::new();
+        let res = client
+            .post(format!("http://{}/order/cancel", url))
+            .header("session_id", session_id.to_string())
+            .form(form)
+            .send()
+            .await
+            .unwrap();
+        res.status().as_u16()
+    }
+
+    async fn pay(url: String, session_id: uuid::Uuid, order_id: &str, token: &str) -> u16 {
+        let client = reqwest::Client::new();
+        let res = client
+            .post(format!("http://{}/order/pay", url))
+            .header("session_id", session_id.to_string())
+            .form(&[("order_id", order_id), ("payment_token", token)])
+            .send()
+            .await
+            .unwrap();
+        res.status().as_u16()
+    }
+
+    async fn get_order_status(url: String, session_id: uuid::Uuid, order_id: &str) -> String {
+        let client = reqwest::Client::new();
+        let res = client
+            .get(format!("http://{}/order/{}/status", url, order_id))
+            .header("session_id", session_id.to_string())
+            .send()
+            .await
+            .unwrap();
+        let status: serde_json::Value = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+        status["status"].as_str().unwrap().to_string()
+    }
+
+    /// variant_id of the first line of the order
+    async fn variant_of(url: String, session_id: uuid::Uuid, order_id: &str) -> String {
+        let client = reqwest::Client::new();
+        let res = client
+            .get(format!("http://{}/order/{}/status", url, order_id))
+            .header("session_id", session_id.to_string())
+            .send()
+            .await
+            .unwrap();
+        let status: serde_json::Value = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+        status["items"][0]["variant_id"]
+            .as_str()
+            .unwrap()
+            .to_string()
+    }
+
+    /// Moves an item of the order into `status`, returning the response status
+    async fn set_item_status(
+        url: String,
+        session_id: uuid::Uuid,
+        order_id: &str,
+        variant_id: &str,
+        status: &str,
+    ) -> u16 {
+        let client = reqwest::Client::new();
+        let res = client
+            .post(format!("http://{}/order/status", url))
+            .header("session_id", session_id.to_string())
+            .form(&[
+                ("order_id", order_id),
+                ("variant_id", variant_id),
+                ("status", status),
+            ])
+            .send()
+            .await
+            .unwrap();
+        res.status().as_u16()
+    }
+
+    /// Whether a page of order lines fetched from `path` has a line of the order
+    async fn lists_order(url: String, session_id: uuid::Uuid, path: &str, order_id: &str) -> bool {
+        let client = reqwest::Client::new();
+        let res = client
+            .get(format!("http://{}{}", url, path))
+            .header("session_id", session_id.to_string())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        let page: serde_json::Value = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+        page["items"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|line| line["order_id"] == order_id)
+    }
+
+    #[tokio::test]
+    async fn test_08_payment_success_marks_order_paid() {
+        let url = start_app_instance().await;
+        let (session_id, order_id, _item_id) = place_order(url.clone()).await;
+        assert_eq!(
+            get_order_status(url.clone(), session_id, &order_id).await,
+            "pending_payment"
+        );
+        assert_eq!(
+            pay(url.clone(), session_id, &order_id, "mock_success").await,
+            200
+        );
+        assert_eq!(
+            get_order_status(url.clone(), session_id, &order_id).await,
+            "paid"
+        );
+        assert_eq!(
+            pay(url.clone(), session_id, &order_id, "mock_success").await,
+            409
+        );
+    }
+
+    #[tokio::test]
+    async fn test_09_payment_decline_cancels_order() {
+        let url = start_app_instance().await;
+        let (session_id, order_id, item_id) = place_order(url.clone()).await;
+        assert_eq!(
+            pay(url.clone(), session_id, &order_id, "mock_decline").await,
+            402
+        );
+        assert_eq!(
+            get_order_status(url.clone(), session_id, &order_id).await,
+            "cancelled"
+        );
+        let client = reqwest::Client::new();
+        let res = client
+            .get(format!("http://{}/item/{}", url, item_id))
+            .header("session_id", session_id.to_string())
+            .send()
+            .await
+            .unwrap();
+        let item: serde_json::Value = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+        assert_eq!(item["detail"]["stock"], 5);
+    }
+
+    #[tokio::test]
+    async fn test_10_payment_timeout_leaves_order_pending() {
+        let url = start_app_instance().await;
+        let (session_id, order_id, _item_id) = place_order(url.clone()).await;
+        assert_eq!(
+            pay(url.clone(), session_id, &order_id, "mock_timeout").await,
+            504
+        );
+        assert_eq!(
+            get_order_status(url.clone(), session_id, &order_id).await,
+            "pending_payment"
+        );
+        assert_eq!(
+            pay(url.clone(), session_id, &order_id, "mock_success").await,
+            200
+        );
+    }
+
+    /// Sends a webhook signed the way the mock provider signs them
+    async fn send_webhook(url: String, body: serde_json::Value) -> u16 {
+        use hmac::Mac;
+        let body = body.to_string();
+        let mut mac = hmac::Hmac::<sha2::Sha256>::new_from_slice(b"test_webhook_secret").unwrap();
+        mac.update(body.as_bytes());
+        let client = reqwest::Client::new();
+        let res = client
+            .post(format!("http://{}/order/payment/webhook", url))
+            .header("mock-signature", hex::encode(mac.finalize().into_bytes()))
+            .body(body)
+            .send()
+            .await
+            .unwrap();
+        res.status().as_u16()
+    }
+
+    #[tokio::test]
+    async fn test_11_payment_webhook_rejects_bad_signature() {
+        let url = start_app_instance().await;
+        let client = reqwest::Client::new();
+        let res = client
+            .post(format!("http://{}/order/payment/webhook", url))
+            .header("mock-signature", "00")
+            .body(r#"{"reference":"mock_unknown","event":"captured"}"#)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_12_category_filter_includes_descendants() {
+        let url = start_app_instance().await;
+        let session_id = get_session_id(url.clone()).await;
+        make_admin("test_user").await;
+        let client = reqwest::Client::new();
+        let suffix = uuid::Uuid::new_v4().simple().to_string();
+        let parent_slug = format!("parent-{}", suffix);
+        let res = client
+            .post(format!("http://{}/category/create", url))
+            .header("session_id", session_id.to_string())
+            .form(&[("name", "Parent"), ("slug", parent_slug.as_str())])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::CREATED);
+        let parent: serde_json::Value = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+        let parent_id = parent["category_id"].as_str().unwrap().to_string();
+        let child_slug = format!("child-{}", suffix);
+        let res = client
+            .post(format!("http://{}/category/create", url))
+            .header("session_id", session_id.to_string())
+            .form(&[
+                ("name", "Child"),
+                ("slug", child_slug.as_str()),
+                ("parent_id", parent_id.as_str()),
+            ])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::CREATED);
+        let child: serde_json::Value = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+        let child_id = child["category_id"].as_str().unwrap().to_string();
+
+        let form = multipart::Form::new()
+            .text("title", "Categorised Item")
+            .text("content", "Filed under the child category")
+            .text("price", "10")
+            .text("category_ids", child_id);
+        let res = client
+            .post(format!("http://{}/item/create", url))
+            .header("session_id", session_id.to_string())
+            .multipart(form)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::CREATED);
+
+        let res = client
+            .get(format!("http://{}/item?category={}", url, parent_slug))
+            .send()
+            .await
+            .unwrap();
+        let page: serde_json::Value = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+        assert_eq!(page["items"].as_array().unwrap().len(), 1);
+
+        let res = client
+            .get(format!("http://{}/item?category=not%20a%20slug", url))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[tokio::test]
+    async fn test_13_item_variants_form_a_matrix() {
+        let url = start_app_instance().await;
+        let session_id = get_session_id(url.clone()).await;
+        let client = reqwest::Client::new();
+        let suffix = uuid::Uuid::new_v4().simple().to_string();
+        let form = multipart::Form::new()
+            .text("title", "Shirt")
+            .text("content", "Sold in several sizes")
+            .text("price", "20.00")
+            .text("sku", format!("SHIRT-S-{}", suffix));
+        let res = client
+            .post(format!("http://{}/item/create", url))
+            .header("session_id", session_id.to_string())
+            .multipart(form)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::CREATED);
+
+        let res = client
+            .get(format!("http://{}/item", url))
+            .query(&[("filter", "DateOfCreation(Dec)"), ("take", "1")])
+            .send()
+            .await
+            .unwrap();
+        let page: serde_json::Value = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+        let item_id = page["items"][0]["detail"]["item_id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        let res = client
+            .get(format!("http://{}/item/{}", url, item_id))
+            .header("session_id", session_id.to_string())
+            .send()
+            .await
+            .unwrap();
+        let item: serde_json::Value = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+        let default_variant = item["variants"]["variants"][0]["variant_id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+
+        let res = client
+            .put(format!("http://{}/item/variant/{}", url, default_variant))
+            .header("session_id", session_id.to_string())
+            .form(&[
+                ("sku", format!("SHIRT-S-{}", suffix).as_str()),
+                ("attributes", r#"{"size":"S"}"#),
+            ])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        let res = client
+            .post(format!("http://{}/item/{}/variant", url, item_id))
+            .header("session_id", session_id.to_string())
+            .form(&[
+                ("sku", format!("SHIRT-L-{}", suffix).as_str()),
+                ("attributes", r#"{"size":"L"}"#),
+                ("price", "24.00"),
+            ])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::CREATED);
+        let res = client
+            .post(format!("http://{}/item/{}/variant", url, item_id))
+            .header("session_id", session_id.to_string())
+            .form(&[
+                ("sku", format!("SHIRT-L2-{}", suffix).as_str()),
+                ("attributes", r#"{"size":"L"}"#),
+            ])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::CONFLICT);
+
+        let res = client
+            .get(format!("http://{}/item/{}", url, item_id))
+            .header("session_id", session_id.to_string())
+            .send()
+            .await
+            .unwrap();
+        let item: serde_json::Value = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+        assert_eq!(
+            item["variants"]["options"]["size"],
+            serde_json::json!(["S", "L"])
+        );
+        assert_eq!(item["variants"]["variants"][1]["price"], "24.00");
+    }
+
+    #[tokio::test]
+    async fn test_14_item_filters_and_facets() {
+        let url = start_app_instance().await;
+        let (_session_id, _order_id, item_id) = place_order(url.clone()).await;
+        let client = reqwest::Client::new();
+        let res = client
+            .get(format!("http://{}/item/{}", url, item_id))
+            .header("session_id", get_session_id(url.clone()).await.to_string())
+            .send()
+            .await
+            .unwrap();
+        let item: serde_json::Value = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+        let seller_id = item["detail"]["user_id"].as_str().unwrap().to_string();
+
+        let res = client
+            .get(format!("http://{}/item", url))
+            .query(&[
+                ("seller_id", seller_id.as_str()),
+                ("min_price", "9.99"),
+                ("max_price", "10.00"),
+                ("in_stock", "true"),
+                ("take", "100"),
+            ])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        let page: serde_json::Value = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+        let items = page["items"].as_array().unwrap();
+        assert!(items
+            .iter()
+            .any(|item| item["detail"]["item_id"] == item_id.as_str()));
+        assert!(items
+            .iter()
+            .all(|item| item["detail"]["user_id"] == seller_id.as_str()));
+        assert_eq!(page["facets"]["sellers"].as_array().unwrap().len(), 1);
+        assert_eq!(
+            page["facets"]["sellers"][0]["count"].as_i64().unwrap(),
+            items.len() as i64
+        );
+        assert_eq!(page["facets"]["price"][0]["count"], 0);
+
+        let res = client
+            .get(format!("http://{}/item", url))
+            .query(&[("min_price", "20"), ("max_price", "10")])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[tokio::test]
+    async fn test_15_autocomplete_tolerates_typos() {
+        let url = start_app_instance().await;
+        let session_id = get_session_id(url.clone()).await;
+        let client = reqwest::Client::new();
+        let title = format!("Handwoven Basket {}", uuid::Uuid::new_v4().simple());
+        let form = multipart::Form::new()
+            .text("title", title.clone())
+            .text("content", "Woven by hand")
+            .text("price", "25");
+        let res = client
+            .post(format!("http://{}/item/create", url))
+            .header("session_id", session_id.to_string())
+            .multipart(form)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::CREATED);
+
+        let res = client
+            .get(format!("http://{}/item/autocomplete", url))
+            .query(&[("query", "handwovn"), ("limit", "10")])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+        let suggestions = body["suggestions"].as_array().unwrap();
+        assert!(suggestions.len() <= 10);
+        assert!(suggestions
+            .iter()
+            .any(|suggestion| suggestion["kind"] == "item" && suggestion["text"] == title));
+
+        for query in ["'); DROP TABLE \"item\"; --", "%_\\", "   "] {
+            let res = client
+                .get(format!("http://{}/item/autocomplete", url))
+                .query(&[("query", query)])
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(res.status(), reqwest::StatusCode::OK);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_16_pages_cover_every_item_once() {
+        let url = start_app_instance().await;
+        let session_id = get_session_id(url.clone()).await;
+        let client = reqwest::Client::new();
+        let token = uuid::Uuid::new_v4().simple().to_string();
+        for n in 0..3 {
+            let form = multipart::Form::new()
+                .text("title", format!("Cursor {} {}", token, n))
+                .text("content", "Paged by cursor")
+                .text("price", "5");
+            let res = client
+                .post(format!("http://{}/item/create", url))
+                .header("session_id", session_id.to_string())
+                .multipart(form)
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(res.status(), reqwest::StatusCode::CREATED);
+        }
+
+        let mut seen = vec![];
+        let mut cursor: Option<String> = None;
+        loop {
+            let mut params = vec![
+                ("search_string", token.clone()),
+                ("take", "2".to_string()),
+                ("filter", "Alphabetical(Inc)".to_string()),
+            ];
+            if let Some(cursor) = cursor.take() {
+                params.push(("cursor", cursor));
+            }
+            let res = client
+                .get(format!("http://{}/item", url))
+                .query(&params)
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(res.status(), reqwest::StatusCode::OK);
+            let page: serde_json::Value = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+            for item in page["items"].as_array().unwrap() {
+                seen.push(item["detail"]["title"].as_str().unwrap().to_string());
+            }
+            assert_eq!(page["total"], 3);
+            assert_eq!(page["page_size"], 2);
+            match page["next_cursor"].as_str() {
+                Some(next) => {
+                    assert!(page["has_more"].as_bool().unwrap());
+                    assert!(page["next"]
+                        .as_str()
+                        .unwrap()
+                        .ends_with(&format!("cursor={}", next)));
+                    cursor = Some(next.to_string());
+                }
+                None => {
+                    assert!(!page["has_more"].as_bool().unwrap());
+                    break;
+                }
+            }
+        }
+        assert_eq!(
+            seen,
+            (0..3)
+                .map(|n| format!("Cursor {} {}", token, n))
+                .collect::<Vec<String>>()
+        );
+
+        let res = client
+            .get(format!("http://{}/item", url))
+            .query(&[
+                ("search_string", token.as_str()),
+                ("take", "2"),
+                ("page_no", "2"),
+            ])
+            .send()
+            .await
+            .unwrap();
+        let page: serde_json::Value = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+        assert_eq!(page["page"], 2);
+        assert_eq!(page["items"].as_array().unwrap().len(), 1);
+        assert!(page["next"].is_null());
+        assert!(page["prev"].as_str().unwrap().ends_with("page_no=1"));
+
+        let res = client
+            .get(format!("http://{}/item?cursor=garbage", url))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[tokio::test]
+    async fn test_17_sessions_are_checked_by_the_extractor() {
+        let url = start_app_instance().await;
+        let client = reqwest::Client::new();
+        let res = client
+            .get(format!("http://{}/cart", url))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::UNAUTHORIZED);
+        for session_id in ["not-a-uuid".to_string(), uuid::Uuid::new_v4().to_string()] {
+            let res = client
+                .get(format!("http://{}/cart", url))
+                .header("session_id", session_id)
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(res.status(), reqwest::StatusCode::UNAUTHORIZED);
+        }
+
+        let session_id = get_session_id(url.clone()).await;
+        let token = uuid::Uuid::new_v4().simple().to_string();
+        let form = multipart::Form::new()
+            .text("title", format!("Public Item {}", token))
+            .text("content", "Visible without a session")
+            .text("price", "3");
+        let res = client
+            .post(format!("http://{}/item/create", url))
+            .header("session_id", session_id.to_string())
+            .multipart(form)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::CREATED);
+        let res = client
+            .get(format!("http://{}/item", url))
+            .query(&[("search_string", token.as_str())])
+            .send()
+            .await
+            .unwrap();
+        let page: serde_json::Value = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+        let item_id = page["items"][0]["detail"]["item_id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        let res = client
+            .get(format!("http://{}/item/{}", url, item_id))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        let item: serde_json::Value = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+        assert_eq!(item["sameuser"], false);
+        let res = client
+            .get(format!("http://{}/item/{}", url, item_id))
+            .header("session_id", session_id.to_string())
+            .send()
+            .await
+            .unwrap();
+        let item: serde_json::Value = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+        assert_eq!(item["sameuser"], true);
+    }
+
+    #[tokio::test]
+    async fn test_18_routes_require_their_roles() {
+        let url = start_app_instance().await;
+        let client = reqwest::Client::new();
+        let (username, session_id) = sign_up_fresh(url.clone(), "role").await;
+
+        let res = client
+            .get(format!("http://{}/user/roles", url))
+            .header("session_id", session_id.as_str())
+            .send()
+            .await
+            .unwrap();
+        let roles: serde_json::Value = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+        assert_eq!(roles["roles"], serde_json::json!(["buyer"]));
+        let res = client
+            .get(format!("http://{}/cart", url))
+            .header("session_id", session_id.as_str())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+
+        let create_item = || {
+            client
+                .post(format!("http://{}/item/create", url))
+                .header("session_id", session_id.as_str())
+                .multipart(
+                    multipart::Form::new()
+                        .text("title", "Role Item")
+                        .text("content", "Listed once the user sells")
+                        .text("price", "4"),
+                )
+                .send()
+        };
+        let res = create_item().await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::FORBIDDEN);
+        let res = client
+            .post(format!("http://{}/category/create", url))
+            .header("session_id", session_id.as_str())
+            .form(&[("name", "Forbidden"), ("slug", username.as_str())])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::FORBIDDEN);
+
+        let res = client
+            .post(format!("http://{}/user/seller", url))
+            .header("session_id", session_id.as_str())
+            .send()
+            .await
+            .unwrap();
+        let roles: serde_json::Value = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+        assert_eq!(roles["roles"], serde_json::json!(["buyer", "seller"]));
+        let res = create_item().await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn test_19_moderators_hide_items_remove_comments_and_suspend_users() {
+        let url = start_app_instance().await;
+        let client = reqwest::Client::new();
+        let admin_session = get_session_id(url.clone()).await.to_string();
+        make_admin("test_user").await;
+        let (seller, seller_session) = sign_up_fresh(url.clone(), "moderated").await;
+        client
+            .post(format!("http://{}/user/seller", url))
+            .header("session_id", seller_session.as_str())
+            .send()
+            .await
+            .unwrap();
+        let res = client
+            .post(format!("http://{}/item/create", url))
+            .header("session_id", seller_session.as_str())
+            .multipart(
+                multipart::Form::new()
+                    .text("title", format!("Counterfeit {}", seller))
+                    .text("content", "Taken down by a moderator")
+                    .text("price", "9"),
+            )
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::CREATED);
+        let listed = || async {
+            let res = client
+                .get(format!("http://{}/item", url))
+                .query(&[("search_string", seller.as_str())])
+                .send()
+                .await
+                .unwrap();
+            let page: serde_json::Value = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+            page["items"].as_array().unwrap().clone()
+        };
+        let items = listed().await;
+        assert_eq!(items.len(), 1);
+        let item_id = items[0]["detail"]["item_id"].as_str().unwrap().to_string();
+
+        // Only operators reach the admin router
+        let res = client
+            .post(format!("http://{}/admin/item/{}/hide", url, item_id))
+            .header("session_id", seller_session.as_str())
+            .form(&[("reason", "hiding my own item")])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::FORBIDDEN);
+        let res = client
+            .post(format!("http://{}/admin/item/{}/hide", url, item_id))
+            .header("session_id", admin_session.as_str())
+            .form(&[("reason", "counterfeit")])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        assert!(listed().await.is_empty());
+        let res = client
+            .get(format!("http://{}/item/{}", url, item_id))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::NOT_FOUND);
+        let res = client
+            .get(format!("http://{}/item/{}", url, item_id))
+            .header("session_id", seller_session.as_str())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        let res = client
+            .post(format!("http://{}/admin/item/{}/unhide", url, item_id))
+            .header("session_id", admin_session.as_str())
+            .form(&[("reason", "")])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        assert_eq!(listed().await.len(), 1);
+
+        let (_, buyer_session) = sign_up_fresh(url.clone(), "reviewer").await;
+        let res = client
+            .post(format!("http://{}/item/rate", url))
+            .header("session_id", buyer_session.as_str())
+            .form(&[
+                ("rating", "1"),
+                ("content", "abusive"),
+                ("item_id", item_id.as_str()),
+            ])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::CREATED);
+        assert_eq!(listed().await[0]["detail"]["rating"], 1.0);
+        let res = client
+            .get(format!("http://{}/item/comments", url))
+            .query(&[("item_id", item_id.as_str())])
+            .send()
+            .await
+            .unwrap();
+        let comments: serde_json::Value = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+        let author_id = comments["items"][0]["user_id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        let res = client
+            .delete(format!(
+                "http://{}/admin/item/{}/comment/{}",
+                url, item_id, author_id
+            ))
+            .header("session_id", admin_session.as_str())
+            .form(&[("reason", "abuse")])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        assert_eq!(
+            listed().await[0]["detail"]["rating"],
+            serde_json::Value::Null
+        );
+
+        let res = client
+            .get(format!("http://{}/admin/actions", url))
+            .header("session_id", admin_session.as_str())
+            .query(&[("item_id", item_id.as_str())])
+            .send()
+            .await
+            .unwrap();
+        let actions: serde_json::Value = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+        assert_eq!(actions["total"], 3);
+        let kinds = actions["items"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|action| action["kind"].as_str().unwrap())
+            .collect::<Vec<&str>>();
+        assert_eq!(kinds, ["remove_comment", "unhide_item", "hide_item"]);
+        assert_eq!(actions["items"][1]["reason"], serde_json::Value::Null);
+        assert_eq!(actions["items"][2]["reason"], "counterfeit");
+
+        let seller_id = items[0]["detail"]["user_id"].as_str().unwrap().to_string();
+        let res = client
+            .post(format!("http://{}/admin/user/{}/suspend", url, seller_id))
+            .header("session_id", admin_session.as_str())
+            .form(&[("reason", "fraud")])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        let res = client
+            .get(format!("http://{}/user/roles", url))
+            .header("session_id", seller_session.as_str())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::UNAUTHORIZED);
+        let login = || {
+            client
+                .post(format!("http://{}/user/login", url))
+                .form(&[("username", seller.as_str()), ("password", "test_pass")])
+                .send()
+        };
+        assert_eq!(
+            login().await.unwrap().status(),
+            reqwest::StatusCode::FORBIDDEN
+        );
+        let res = client
+            .post(format!("http://{}/admin/user/{}/unsuspend", url, seller_id))
+            .header("session_id", admin_session.as_str())
+            .form(&[("reason", "appeal upheld")])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        assert_eq!(
+            login().await.unwrap().status(),
+            reqwest::StatusCode::CREATED
+        );
+    }
+
+    #[tokio::test]
+    async fn test_20_reported_items_are_queued_and_hidden() {
+        let url = start_app_instance().await;
+        let client = reqwest::Client::new();
+        let admin_session = get_session_id(url.clone()).await.to_string();
+        make_admin("test_user").await;
+        let (seller, seller_session) = sign_up_fresh(url.clone(), "reported").await;
+        client
+            .post(format!("http://{}/user/seller", url))
+            .header("session_id", seller_session.as_str())
+            .send()
+            .await
+            .unwrap();
+        client
+            .post(format!("http://{}/item/create", url))
+            .header("session_id", seller_session.as_str())
+            .multipart(
+                multipart::Form::new()
+                    .text("title", format!("Replica {}", seller))
+                    .text("content", "Reported by two buyers")
+                    .text("price", "12"),
+            )
+            .send()
+            .await
+            .unwrap();
+        let res = client
+            .get(format!("http://{}/item", url))
+            .query(&[("search_string", seller.as_str())])
+            .send()
+            .await
+            .unwrap();
+        let page: serde_json::Value = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+        let item_id = page["items"][0]["detail"]["item_id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        let report = |session_id: String, reason: &'static str| {
+            client
+                .post(format!("http://{}/report", url))
+                .header("session_id", session_id)
+                .form(&[
+                    ("target", "item"),
+                    ("item_id", item_id.as_str()),
+                    ("reason", reason),
+                ])
+                .send()
+        };
+
+        let res = report(seller_session.clone(), "spam").await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::UNPROCESSABLE_ENTITY);
+        let (_, first) = sign_up_fresh(url.clone(), "reporter").await;
+        let res = report(first.clone(), "counterfeit").await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::CREATED);
+        let res = report(first, "spam").await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::CONFLICT);
+        let res = client
+            .get(format!("http://{}/item/{}", url, item_id))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        // The second reporter reaches the threshold of the test app
+        let (_, second) = sign_up_fresh(url.clone(), "reporter").await;
+        let res = report(second, "spam").await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::CREATED);
+        let res = client
+            .get(format!("http://{}/item/{}", url, item_id))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::NOT_FOUND);
+
+        let queued = |status: &'static str| {
+            let client = client.clone();
+            let url = url.clone();
+            let admin_session = admin_session.clone();
+            let item_id = item_id.clone();
+            async move {
+                let res = client
+                    .get(format!("http://{}/admin/reports", url))
+                    .header("session_id", admin_session)
+                    .query(&[("target", "item"), ("status", status), ("take", "100")])
+                    .send()
+                    .await
+                    .unwrap();
+                let page: serde_json::Value =
+                    serde_json::from_str(&res.text().await.unwrap()).unwrap();
+                page["items"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .find(|case| case["item_id"] == item_id.as_str())
+                    .cloned()
+            }
+        };
+        let case = queued("open").await.unwrap();
+        assert_eq!(case["report_count"], 2);
+        assert_eq!(
+            case["reasons"],
+            serde_json::json!({ "counterfeit": 1, "spam": 1 })
+        );
+        let res = client
+            .post(format!(
+                "http://{}/admin/reports/{}/resolve",
+                url,
+                case["case_id"].as_str().unwrap()
+            ))
+            .header("session_id", admin_session.as_str())
+            .form(&[("status", "actioned"), ("note", "kept hidden")])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        assert!(queued("open").await.is_none());
+        let case = queued("actioned").await.unwrap();
+        assert_eq!(case["resolution_note"], "kept hidden");
+
+        let res = client
+            .get(format!("http://{}/admin/actions", url))
+            .header("session_id", admin_session.as_str())
+            .query(&[("item_id", item_id.as_str())])
+            .send()
+            .await
+            .unwrap();
+        let actions: serde_json::Value = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+        assert_eq!(actions["items"][0]["kind"], "hide_item");
+        assert_eq!(actions["items"][0]["moderator_id"], serde_json::Value::Null);
+        assert_eq!(actions["items"][0]["reason"], "Hidden after 2 reports");
+    }
+
+    #[tokio::test]
+    async fn test_21_password_reset_tokens_are_single_use() {
+        let url = start_app_instance().await;
+        let client = reqwest::Client::new();
+        let (username, session_id) = sign_up_fresh(url.clone(), "forgetful").await;
+        let email_id = format!("{}@testing.com", username);
+        let request_reset = |email_id: String| {
+            client
+                .post(format!("http://{}/user/password/reset", url))
+                .form(&[("email_id", email_id)])
+                .send()
+        };
+        let res = request_reset("nobody@testing.com".to_string())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::ACCEPTED);
+        let res = request_reset(email_id.clone()).await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::ACCEPTED);
+        let first = last_mailed_token(&email_id);
+        // Asking again replaces the first token
+        request_reset(email_id.clone()).await.unwrap();
+        let token = last_mailed_token(&email_id);
+        assert_ne!(first, token);
+
+        let confirm = |token: String| {
+            client
+                .post(format!("http://{}/user/password/confirm", url))
+                .form(&[("token", token.as_str()), ("password", "new_test_pass")])
+                .send()
+        };
+        let res = confirm(first).await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::BAD_REQUEST);
+        let res = confirm(token.clone()).await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        let res = confirm(token).await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::BAD_REQUEST);
+
+        let res = client
+            .get(format!("http://{}/user/roles", url))
+            .header("session_id", session_id)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::UNAUTHORIZED);
+        let login = |password: &'static str| {
+            client
+                .post(format!("http://{}/user/login", url))
+                .form(&[("username", username.as_str()), ("password", password)])
+                .send()
+        };
+        assert_eq!(
+            login("test_pass").await.unwrap().status(),
+            reqwest::StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            login("new_test_pass").await.unwrap().status(),
+            reqwest::StatusCode::CREATED
+        );
+    }
+
+    #[tokio::test]
+    async fn test_22_unverified_users_cannot_list_items() {
+        let url = start_app_instance().await;
+        let client = reqwest::Client::new();
+        let (username, session_id) = sign_up_unverified(url.clone(), "unverified").await;
+        let email_id = format!("{}@testing.com", username);
+        let res = client
+            .post(format!("http://{}/user/seller", url))
+            .header("session_id", session_id.as_str())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        let create_item = || {
+            client
+                .post(format!("http://{}/item/create", url))
+                .header("session_id", session_id.as_str())
+                .multipart(
+                    multipart::Form::new()
+                        .text("title", "Unverified Item")
+                        .text("content", "Listed once the email id is verified")
+                        .text("price", "4"),
+                )
+                .send()
+        };
+        let res = create_item().await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::FORBIDDEN);
+        // Browsing and buying stay open
+        let res = client
+            .get(format!("http://{}/cart", url))
+            .header("session_id", session_id.as_str())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+
+        let resend = || {
+            client
+                .post(format!("http://{}/user/email/resend", url))
+                .header("session_id", session_id.as_str())
+                .send()
+        };
+        let res = resend().await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::TOO_MANY_REQUESTS);
+        let verify = |token: String| {
+            client
+                .post(format!("http://{}/user/email/verify", url))
+                .form(&[("token", token)])
+                .send()
+        };
+        let token = last_mailed_token(&email_id);
+        let res = verify(token.clone()).await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        let res = verify(token).await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::BAD_REQUEST);
+        let res = resend().await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::CONFLICT);
+        let res = create_item().await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn test_23_users_list_and_revoke_their_sessions() {
+        let url = start_app_instance().await;
+        let client = reqwest::Client::new();
+        let (username, first) = sign_up_fresh(url.clone(), "traveller").await;
+        let login = |agent: &'static str| {
+            client
+                .post(format!("http://{}/user/login", url))
+                .header("user-agent", agent)
+                .form(&[("username", username.as_str()), ("password", "test_pass")])
+                .send()
+        };
+        let session_of = |res: String| {
+            serde_json::from_str::<crate::SessionResponse>(&res)
+                .unwrap()
+                .detail
+                .session_id
+                .to_string()
+        };
+        let phone = session_of(login("phone").await.unwrap().text().await.unwrap());
+        let laptop = session_of(login("laptop").await.unwrap().text().await.unwrap());
+
+        let res = client
+            .get(format!("http://{}/user/sessions", url))
+            .header("session_id", laptop.as_str())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        let sessions: Vec<serde_json::Value> =
+            serde_json::from_str(&res.text().await.unwrap()).unwrap();
+        assert_eq!(sessions.len(), 3);
+        let listed = |session_id: &str| {
+            sessions
+                .iter()
+                .find(|session| session["session_id"] == session_id)
+                .unwrap()
+                .clone()
+        };
+        assert_eq!(listed(&phone)["user_agent"], "phone");
+        assert!(listed(&phone)["ip_address"].is_string());
+        assert_eq!(listed(&phone)["current"], false);
+        assert_eq!(listed(&laptop)["current"], true);
+
+        let roles = |session_id: String| {
+            client
+                .get(format!("http://{}/user/roles", url))
+                .header("session_id", session_id)
+                .send()
+        };
+        let res = client
+            .delete(format!("http://{}/user/sessions/{}", url, phone))
+            .header("session_id", laptop.as_str())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        let res = roles(phone.clone()).await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::UNAUTHORIZED);
+        // Sessions of other users cannot be revoked
+        let (_, stranger) = sign_up_fresh(url.clone(), "stranger").await;
+        let res = client
+            .delete(format!("http://{}/user/sessions/{}", url, stranger))
+            .header("session_id", laptop.as_str())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::NOT_FOUND);
+
+        let res = client
+            .delete(format!("http://{}/user/sessions", url))
+            .header("session_id", laptop.as_str())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        let res = roles(first).await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::UNAUTHORIZED);
+        let res = roles(laptop).await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        let res = roles(stranger).await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_24_sessions_slide_and_refresh() {
+        let url = start_app_instance().await;
+        let client = reqwest::Client::new();
+        let (username, _) = sign_up_fresh(url.clone(), "shopper").await;
+        let res = client
+            .post(format!("http://{}/user/login", url))
+            .form(&[("username", username.as_str()), ("password", "test_pass")])
+            .send()
+            .await
+            .unwrap();
+        let session: crate::SessionResponse =
+            serde_json::from_str(&res.text().await.unwrap()).unwrap();
+        let session_id = session.detail.session_id.to_string();
+        let expiry = |session_id: String| {
+            let sessions = client
+                .get(format!("http://{}/user/sessions", url))
+                .header("session_id", session_id)
+                .send();
+            async move {
+                let res = sessions.await.unwrap();
+                let sessions: Vec<serde_json::Value> =
+                    serde_json::from_str(&res.text().await.unwrap()).unwrap();
+                let current = sessions
+                    .into_iter()
+                    .find(|session| session["current"] == true)
+                    .unwrap();
+                serde_json::from_value::<chrono::NaiveDateTime>(current["expiry"].clone()).unwrap()
+            }
+        };
+        let first = expiry(session_id.clone()).await;
+        let second = expiry(session_id.clone()).await;
+        assert!(second > first);
+
+        let refresh = |refresh_token: String| {
+            client
+                .post(format!("http://{}/user/sessions/refresh", url))
+                .form(&[("refresh_token", refresh_token)])
+                .send()
+        };
+        let res = refresh(session.detail.refresh_token.clone()).await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::CREATED);
+        let renewed: crate::SessionResponse =
+            serde_json::from_str(&res.text().await.unwrap()).unwrap();
+        assert_ne!(renewed.detail.session_id, session.detail.session_id);
+        assert_ne!(renewed.detail.refresh_token, session.detail.refresh_token);
+        let roles = |session_id: String| {
+            client
+                .get(format!("http://{}/user/roles", url))
+                .header("session_id", session_id)
+                .send()
+        };
+        let res = roles(session_id).await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::UNAUTHORIZED);
+        let res = roles(renewed.detail.session_id.to_string()).await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        // Refresh tokens are single use
+        let res = refresh(session.detail.refresh_token).await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::UNAUTHORIZED);
+    }
+
+    /// The cookies a response sets, by name, with their attributes
+    fn set_cookies(res: &reqwest::Response) -> std::collections::HashMap<String, String> {
+        res.headers()
+            .get_all("set-cookie")
+            .iter()
+            .filter_map(|value| value.to_str().ok()?.split_once('='))
+            .map(|(name, rest)| (name.to_string(), rest.to_string()))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_25_sessions_kept_in_cookies_need_csrf_tokens() {
+        let url = start_app_instance().await;
+        let client = reqwest::Client::new();
+        let (username, _) = sign_up_fresh(url.clone(), "browser").await;
+        let res = client
+            .post(format!("http://{}/user/login", url))
+            .form(&[
+                ("username", username.as_str()),
+                ("password", "test_pass"),
+                ("cookie", "true"),
+            ])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::CREATED);
+        let cookies = set_cookies(&res);
+        let body: serde_json::Value = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+        assert_eq!(body["detail"]["session_id"], serde_json::Value::Null);
+        let csrf_token = body["detail"]["csrf_token"].as_str().unwrap().to_string();
+        let attributes = |name: &str| cookies[name].split_once(';').unwrap().1.to_string();
+        let value = |name: &str| cookies[name].split_once(';').unwrap().0.to_string();
+        assert!(attributes("session_id").contains("HttpOnly"));
+        assert!(attributes("session_id").contains("Secure"));
+        assert!(attributes("session_id").contains("SameSite=Strict"));
+        assert!(!attributes("csrf_token").contains("HttpOnly"));
+        assert_eq!(value("csrf_token"), csrf_token);
+        let session_cookie = format!("session_id={}", value("session_id"));
+
+        let res = client
+            .get(format!("http://{}/user/roles", url))
+            .header("cookie", session_cookie.as_str())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        let become_seller = |csrf_token: &str| {
+            let request = client
+                .post(format!("http://{}/user/seller", url))
+                .header("cookie", session_cookie.as_str());
+            match csrf_token {
+                "" => request.send(),
+                csrf_token => request.header("x-csrf-token", csrf_token).send(),
+            }
+        };
+        // Forged requests are turned away before they renew the session
+        let pool = connect_db().await;
+        let session_id = uuid::Uuid::parse_str(&value("session_id")).unwrap();
+        let last_seen = || {
+            sqlx::query_as::<_, (chrono::NaiveDateTime,)>(
+                r#"SELECT "last_seen_at" FROM "session" WHERE "session_id" = $1"#,
+            )
+            .bind(session_id)
+            .fetch_one(&pool)
+        };
+        let seen = last_seen().await.unwrap();
+        let res = become_seller("").await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::FORBIDDEN);
+        let res = become_seller("forged").await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::FORBIDDEN);
+        assert_eq!(last_seen().await.unwrap(), seen);
+        let res = become_seller(&csrf_token).await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        assert!(last_seen().await.unwrap() > seen);
+
+        let res = client
+            .post(format!("http://{}/user/sessions/refresh", url))
+            .header(
+                "cookie",
+                format!("refresh_token={}", value("refresh_token")),
+            )
+            .header("x-csrf-token", csrf_token.as_str())
+            .header("content-type", "application/x-www-form-urlencoded")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::CREATED);
+        let renewed = set_cookies(&res);
+        let renewed_session = renewed["session_id"].split_once(';').unwrap().0.to_string();
+        let renewed_csrf = renewed["csrf_token"].split_once(';').unwrap().0.to_string();
+        assert_ne!(format!("session_id={}", renewed_session), session_cookie);
+
+        let res = client
+            .post(format!("http://{}/user/logout", url))
+            .header("cookie", format!("session_id={}", renewed_session))
+            .header("x-csrf-token", renewed_csrf)
+            .header("content-type", "application/x-www-form-urlencoded")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        assert!(set_cookies(&res)["session_id"].contains("Max-Age=0"));
+    }
+
+    #[tokio::test]
+    async fn test_26_logins_with_two_factor_take_a_code() {
+        use crate::two_factor::{base32_decode, time_step, totp_code};
+        let url = start_app_instance().await;
+        let client = reqwest::Client::new();
+        let (username, session_id) = sign_up_fresh(url.clone(), "careful").await;
+        let res = client
+            .post(format!("http://{}/user/2fa/enroll", url))
+            .header("session_id", session_id.as_str())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        let enrolment: crate::TotpEnrolment =
+            serde_json::from_str(&res.text().await.unwrap()).unwrap();
+        assert!(enrolment
+            .otpauth_uri
+            .starts_with("otpauth://totp/Sellorama:careful_"));
+        let secret = base32_decode(&enrolment.secret).unwrap();
+        // A step ahead, so that the code used to confirm is not taken as replayed
+        let code =
+            |ahead: i64| totp_code(&secret, time_step(chrono::Utc::now().timestamp()) + ahead);
+
+        let confirm = |code: String| {
+            client
+                .post(format!("http://{}/user/2fa/confirm", url))
+                .header("session_id", session_id.as_str())
+                .form(&[("code", code)])
+                .send()
+        };
+        let res = confirm("000000".to_string()).await.unwrap();
+        assert!(res.status().is_client_error());
+        let res = confirm(code(0)).await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        let recovery: crate::RecoveryCodes =
+            serde_json::from_str(&res.text().await.unwrap()).unwrap();
+        assert_eq!(recovery.recovery_codes.len(), 10);
+
+        let login = || {
+            client
+                .post(format!("http://{}/user/login", url))
+                .form(&[("username", username.as_str()), ("password", "test_pass")])
+                .send()
+        };
+        let challenge = || async {
+            let res = login().await.unwrap();
+            assert_eq!(res.status(), reqwest::StatusCode::ACCEPTED);
+            let challenge: crate::ChallengeResponse =
+                serde_json::from_str(&res.text().await.unwrap()).unwrap();
+            challenge.detail.challenge_token
+        };
+        let verify = |challenge_token: String, code: String| {
+            client
+                .post(format!("http://{}/user/2fa/verify", url))
+                .form(&[("challenge_token", challenge_token), ("code", code)])
+                .send()
+        };
+        let token = challenge().await;
+        let res = verify(token.clone(), "000000".to_string()).await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::UNAUTHORIZED);
+        let res = verify(token.clone(), code(1)).await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::CREATED);
+        // The challenge is used up with the session
+        let res = verify(token, code(1)).await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+        let recovery_code = recovery.recovery_codes[0].to_lowercase();
+        let res = verify(challenge().await, recovery_code.clone())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::CREATED);
+        let res = verify(challenge().await, recovery_code).await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+        let admin_session = get_session_id(url.clone()).await.to_string();
+        make_admin("test_user").await;
+        let res = client
+            .post(format!(
+                "http://{}/admin/user/{}/2fa/reset",
+                url,
+                user_id_of(&username).await
+            ))
+            .header("session_id", admin_session.as_str())
+            .form(&[("reason", "Lost their phone")])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        let res = login().await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::CREATED);
+        forget_failed_logins(&username).await;
+    }
+
+    #[test]
+    fn totp_codes_match_the_rfc_test_vectors() {
+        use crate::two_factor::{
+            base32_decode, base32_encode, constant_time_eq, matching_step, time_step, totp_code,
+        };
+        // RFC 6238 appendix B, the last six of its eight digits
+        let secret = b"12345678901234567890";
+        assert_eq!(totp_code(secret, time_step(59)), "287082");
+        assert_eq!(totp_code(secret, time_step(1111111109)), "081804");
+        assert_eq!(totp_code(secret, time_step(1234567890)), "005924");
+        assert_eq!(totp_code(secret, time_step(20000000000)), "353130");
+        assert_eq!(base32_encode(secret), "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ");
+        assert_eq!(base32_decode(&base32_encode(secret)).unwrap(), secret);
+        // A step of drift either way is let through, no more
+        let step = time_step(1234567890);
+        assert_eq!(matching_step(secret, "005924", step + 1), Some(step));
+        assert_eq!(matching_step(secret, "005924", step + 2), None);
+        assert!(constant_time_eq(b"005924", b"005924"));
+        assert!(!constant_time_eq(b"005924", b"005925"));
+        assert!(!constant_time_eq(b"005924", b"00592"));
+    }
+
+    #[tokio::test]
+    async fn test_27_failed_logins_lock_the_username_out() {
+        let url = start_app_instance().await;
+        let client = reqwest::Client::new();
+        let (username, _) = sign_up_fresh(url.clone(), "forgetful").await;
+        let login = |username: String, password: &'static str| {
+            client
+                .post(format!("http://{}/user/login", url))
+                .form(&[("username", username.as_str()), ("password", password)])
+                .send()
+        };
+        // Unknown usernames cannot be told apart from wrong passwords
+        let res = login(format!("{}_unknown", username), "test_pass")
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::UNAUTHORIZED);
+        let unknown = res.text().await.unwrap();
+        for _ in 0..3 {
+            let res = login(username.clone(), "wrong_pass").await.unwrap();
+            assert_eq!(res.status(), reqwest::StatusCode::UNAUTHORIZED);
+            assert_eq!(res.text().await.unwrap(), unknown);
+        }
+        let res = login(username.clone(), "wrong_pass").await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::UNAUTHORIZED);
+        // Even the right password is turned away while locked out
+        let res = login(username.clone(), "test_pass").await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::TOO_MANY_REQUESTS);
+        let retry_after: u64 = res.headers()["retry-after"]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(retry_after > 0 && retry_after <= 60);
+
+        let admin_session = get_session_id(url.clone()).await.to_string();
+        make_admin("test_user").await;
+        let res = client
+            .get(format!("http://{}/admin/metrics/logins", url))
+            .header("session_id", admin_session)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        let metrics: serde_json::Value = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+        assert!(metrics["locked_usernames"].as_i64().unwrap() >= 1);
+        assert!(metrics["lockouts_last_hour"].as_i64().unwrap() >= 1);
+        forget_failed_logins(&username).await;
+    }
+
+    #[tokio::test]
+    async fn test_28_order_items_move_through_their_statuses() {
+        let url = start_app_instance().await;
+        let client = reqwest::Client::new();
+        let (buyer_session, order_id, _item_id) = place_order(url.clone()).await;
+        let seller_session = get_session_id(url.clone()).await;
+        assert_eq!(
+            pay(url.clone(), buyer_session, &order_id, "mock_success").await,
+            200
+        );
+        let variant_id = variant_of(url.clone(), buyer_session, &order_id).await;
+        let set = |session_id: uuid::Uuid, status: &'static str| {
+            set_item_status(url.clone(), session_id, &order_id, &variant_id, status)
+        };
+
+        // Each party only sets the statuses it is responsible for
+        assert_eq!(set(buyer_session, "packed").await, 403);
+        assert_eq!(set(seller_session, "delivered").await, 403);
+        assert_eq!(set(seller_session, "shipped").await, 409);
+        assert_eq!(set(seller_session, "packed").await, 200);
+        assert_eq!(set(seller_session, "shipped").await, 200);
+        assert_eq!(set(buyer_session, "delivered").await, 200);
+        assert_eq!(set(seller_session, "packed").await, 409);
+
+        let res = client
+            .get(format!("http://{}/order/{}/status", url, order_id))
+            .header("session_id", buyer_session.to_string())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        let status: serde_json::Value = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+        assert_eq!(status["status"], "delivered");
+        let history: Vec<(serde_json::Value, serde_json::Value)> = status["history"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|change| (change["from_status"].clone(), change["to_status"].clone()))
+            .collect();
+        assert_eq!(
+            history,
+            [
+                (serde_json::Value::Null, "pending_payment".into()),
+                ("pending_payment".into(), "paid".into()),
+                ("paid".into(), "packed".into()),
+                ("packed".into(), "shipped".into()),
+                ("shipped".into(), "delivered".into()),
+            ]
+        );
+
+        // Both listings filter their lines by status
+        assert!(
+            lists_order(
+                url.clone(),
+                seller_session,
+                "/order/orders?status=delivered&take=100",
+                &order_id
+            )
+            .await
+        );
+        assert!(
+            !lists_order(
+                url.clone(),
+                seller_session,
+                "/order/orders?status=paid&take=100",
+                &order_id
+            )
+            .await
+        );
+        assert!(
+            lists_order(
+                url.clone(),
+                buyer_session,
+                "/user/myorders?status=delivered&take=100",
+                &order_id
+            )
+            .await
+        );
+        assert!(
+            !lists_order(
+                url.clone(),
+                buyer_session,
+                "/user/myorders?status=cancelled&take=100",
+                &order_id
+            )
+            .await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_29_buyers_cancel_orders_and_single_lines() {
+        let url = start_app_instance().await;
+        let (shirt_id, shirt_variant) = list_item(url.clone(), "Cancelled shirt", 5).await;
+        let (hat_id, hat_variant) = list_item(url.clone(), "Cancelled hat", 5).await;
+        let buyer_session = get_buyer_session_id(url.clone()).await;
+        let order_id = order_variants(
+            url.clone(),
+            buyer_session,
+            &[(&shirt_variant, 2), (&hat_variant, 1)],
+        )
+        .await;
+        assert_eq!(stock_of(url.clone(), buyer_session, &shirt_id).await, 3);
+        assert_eq!(stock_of(url.clone(), buyer_session, &hat_id).await, 4);
+
+        // A single line is cancelled and its units go back to stock
+        let hat_line = [
+            ("order_id", order_id.as_str()),
+            ("variant_id", hat_variant.as_str()),
+            ("reason", "Changed my mind"),
+        ];
+        assert_eq!(cancel(url.clone(), buyer_session, &hat_line).await, 200);
+        assert_eq!(stock_of(url.clone(), buyer_session, &hat_id).await, 5);
+        assert_eq!(stock_of(url.clone(), buyer_session, &shirt_id).await, 3);
+        assert_eq!(
+            get_order_status(url.clone(), buyer_session, &order_id).await,
+            "pending_payment"
+        );
+        assert_eq!(cancel(url.clone(), buyer_session, &hat_line).await, 409);
+
+        // Cancelling the order cancels the lines left
+        let whole_order = [("order_id", order_id.as_str())];
+        assert_eq!(cancel(url.clone(), buyer_session, &whole_order).await, 200);
+        assert_eq!(stock_of(url.clone(), buyer_session, &shirt_id).await, 5);
+        assert_eq!(stock_of(url.clone(), buyer_session, &hat_id).await, 5);
+        assert_eq!(
+            get_order_status(url.clone(), buyer_session, &order_id).await,
+            "cancelled"
+        );
+        assert_eq!(cancel(url.clone(), buyer_session, &whole_order).await, 409);
+
+        // Other buyers do not see the order at all
+        let seller_session = get_session_id(url.clone()).await;
+        assert_eq!(cancel(url.clone(), seller_session, &whole_order).await, 404);
+
+        let pool = connect_db().await;
+        let cancellations: Vec<(uuid::Uuid, Option<uuid::Uuid>, Option<String>)> = sqlx::query_as(
+            r#"SELECT "variant_id","cancelled_by","cancellation_reason" FROM "order_items"
+            WHERE "order_id" = $1 AND "cancelled_at" IS NOT NULL"#,
+        )
+        .bind(uuid::Uuid::parse_str(&order_id).unwrap())
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+        let buyer_id = user_id_of("test_buyer").await;
+        assert_eq!(cancellations.len(), 2);
+        for (variant_id, cancelled_by, reason) in cancellations {
+            assert_eq!(cancelled_by, Some(buyer_id));
+            match variant_id.to_string() == hat_variant {
+                true => assert_eq!(reason.as_deref(), Some("Changed my mind")),
+                false => assert_eq!(reason, None),
+            }
+        }
+
+        // Lines which have been shipped can no longer be cancelled
+        let (buyer_session, order_id, item_id) = place_order(url.clone()).await;
+        assert_eq!(
+            pay(url.clone(), buyer_session, &order_id, "mock_success").await,
+            200
+        );
+        let variant_id = variant_of(url.clone(), buyer_session, &order_id).await;
+        for status in ["packed", "shipped"] {
+            assert_eq!(
+                set_item_status(url.clone(), seller_session, &order_id, &variant_id, status).await,
+                200
+            );
+        }
+        assert_eq!(
+            cancel(
+                url.clone(),
+                buyer_session,
+                &[("order_id", order_id.as_str())]
+            )
+            .await,
+            409
+        );
+        assert_eq!(stock_of(url.clone(), buyer_session, &item_id).await, 3);
+        assert_eq!(
+            get_order_status(url.clone(), buyer_session, &order_id).await,
+            "shipped"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_30_order_lines_keep_the_price_they_were_bought_at() {
+        let url = start_app_instance().await;
+        let client = reqwest::Client::new();
+        let (buyer_session, order_id, item_id) = place_order(url.clone()).await;
+        let seller_session = get_session_id(url.clone()).await;
+        let res = client
+            .put(format!("http://{}/item/{}", url, item_id))
+            .header("session_id", seller_session.to_string())
+            .form(&[
+                ("title", "Renamed test item"),
+                ("content", "Item edited after it was bought"),
+                ("price", "25.00"),
+            ])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+
+        let res = client
+            .get(format!("http://{}/user/myorders", url))
+            .query(&[("take", "100")])
+            .header("session_id", buyer_session.to_string())
+            .send()
+            .await
+            .unwrap();
+        let page: serde_json::Value = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+        let line = page["items"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|line| line["order_id"] == order_id.as_str())
+            .unwrap()
+            .clone();
+        let amount = |field: &str| {
+            line[field]
+                .as_str()
+                .unwrap()
+                .parse::<rust_decimal::Decimal>()
+                .unwrap()
+        };
+        assert_eq!(line["title"], "Payment test item");
+        assert_eq!(amount("unit_price"), rust_decimal::Decimal::new(1000, 2));
+        assert_eq!(amount("line_total"), rust_decimal::Decimal::new(2000, 2));
+        assert_eq!(amount("subtotal"), rust_decimal::Decimal::new(2000, 2));
+        assert_eq!(amount("total"), rust_decimal::Decimal::new(2000, 2));
+
+        // The snapshot cannot be rewritten behind the API's back either
+        let pool = connect_db().await;
+        let order_id = uuid::Uuid::parse_str(&order_id).unwrap();
+        for query in [
+            r#"UPDATE "order_items" SET "unit_price" = 25 WHERE "order_id" = $1"#,
+            r#"UPDATE "order_items" SET "title" = 'Renamed test item' WHERE "order_id" = $1"#,
+            r#"UPDATE "order_items" SET "quantity" = 1 WHERE "order_id" = $1"#,
+            r#"UPDATE "order" SET "total" = 50 WHERE "order_id" = $1"#,
+        ] {
+            assert!(sqlx::query(query)
+                .bind(order_id)
+                .execute(&pool)
+                .await
+                .is_err());
+        }
+        assert!(sqlx::query(
+            r#"UPDATE "order_items" SET "cancellation_reason" = NULL WHERE "order_id" = $1"#
+        )
+        .bind(order_id)
+        .execute(&pool)
+        .await
+        .is_ok());
+
+        // Nor is it taken away with the item
+        let res = client
+            .delete(format!("http://{}/item/{}", url, item_id))
+            .header("session_id", seller_session.to_string())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::CONFLICT);
+        let lines: (i64,) =
+            sqlx::query_as(r#"SELECT COUNT(*) FROM "order_items" WHERE "order_id" = $1"#)
+                .bind(order_id)
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        assert_eq!(lines.0, 1);
+    }
+
+    #[tokio::test]
+    async fn test_31_cancelled_packed_lines_are_refunded() {
+        let url = start_app_instance().await;
+        let (buyer_session, order_id, _item_id) = place_order(url.clone()).await;
+        assert_eq!(
+            pay(url.clone(), buyer_session, &order_id, "mock_success").await,
+            200
+        );
+        let seller_session = get_session_id(url.clone()).await;
+        let variant_id = variant_of(url.clone(), buyer_session, &order_id).await;
+        assert_eq!(
+            set_item_status(
+                url.clone(),
+                seller_session,
+                &order_id,
+                &variant_id,
+                "packed"
+            )
+            .await,
+            200
+        );
+        assert_eq!(
+            cancel(
+                url.clone(),
+                buyer_session,
+                &[("order_id", order_id.as_str())]
+            )
+            .await,
+            200
+        );
+
+        let pool = connect_db().await;
+        let payment: (
+            rust_decimal::Decimal,
+            rust_decimal::Decimal,
+            crate::PaymentStatus,
+        ) = sqlx::query_as(
+            r#"SELECT "amount","refunded_amount","status" FROM "payment"
+                WHERE "order_id" = $1 AND "refunded_amount" > 0"#,
+        )
+        .bind(uuid::Uuid::parse_str(&order_id).unwrap())
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(payment.1, payment.0);
+        assert_eq!(payment.2, crate::PaymentStatus::Refunded);
+        // The refund was queued with the cancellation and issued after it
+        let refunds: Vec<(rust_decimal::Decimal, Option<chrono::NaiveDateTime>)> = sqlx::query_as(
+            r#"SELECT r."amount",r."issued_at" FROM "payment_refund" r
+                INNER JOIN "payment" p ON r."payment_id" = p."payment_id"
+                WHERE p."order_id" = $1 AND r."status" = 'issued'"#,
+        )
+        .bind(uuid::Uuid::parse_str(&order_id).unwrap())
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+        assert_eq!(refunds.len(), 1);
+        assert_eq!(refunds[0].0, payment.0);
+        assert!(refunds[0].1.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_32_timed_out_payments_are_settled_by_their_idempotency_key() {
+        let url = start_app_instance().await;
+        let pool = connect_db().await;
+        let payments = |order_id: String| {
+            sqlx::query_as::<_, (uuid::Uuid, Option<String>, crate::PaymentStatus)>(
+                r#"SELECT "payment_id","provider_reference","status" FROM "payment" WHERE "order_id" = $1"#,
+            )
+            .bind(uuid::Uuid::parse_str(&order_id).unwrap())
+            .fetch_all(&pool)
+        };
+
+        // The provider reports the payment it never answered for
+        let (session_id, order_id, _item_id) = place_order(url.clone()).await;
+        assert_eq!(
+            pay(url.clone(), session_id, &order_id, "mock_timeout").await,
+            504
+        );
+        let pending = payments(order_id.clone()).await.unwrap();
+        assert_eq!(pending.len(), 1);
+        let (payment_id, reference, status) = pending[0].clone();
+        assert_eq!((reference, status), (None, crate::PaymentStatus::Pending));
+        let event = serde_json::json!({
+            "reference": format!("mock_{payment_id}"),
+            "idempotency_key": payment_id,
+            "event": "captured",
+        });
+        assert_eq!(send_webhook(url.clone(), event.clone()).await, 200);
+        assert_eq!(
+            get_order_status(url.clone(), session_id, &order_id).await,
+            "paid"
+        );
+        assert_eq!(
+            payments(order_id.clone()).await.unwrap(),
+            [(
+                payment_id,
+                Some(format!("mock_{payment_id}")),
+                crate::PaymentStatus::Captured
+            )]
+        );
+        assert_eq!(send_webhook(url.clone(), event).await, 200);
+        assert_eq!(
+            pay(url.clone(), session_id, &order_id, "mock_success").await,
+            409
+        );
+
+        // A retry authorizes the same payment again rather than a second one
+        let (session_id, order_id, _item_id) = place_order(url.clone()).await;
+        assert_eq!(
+            pay(url.clone(), session_id, &order_id, "mock_timeout").await,
+            504
+        );
+        assert_eq!(
+            pay(url.clone(), session_id, &order_id, "mock_success").await,
+            200
+        );
+        let settled = payments(order_id.clone()).await.unwrap();
+        assert_eq!(settled.len(), 1);
+        assert_eq!(settled[0].2, crate::PaymentStatus::Captured);
+    }
+
+    #[tokio::test]
+    async fn test_33_unpaid_orders_expire_and_restock() {
+        let url = start_app_instance().await;
+        let (session_id, order_id, item_id) = place_order(url.clone()).await;
+        assert_eq!(
+            pay(url.clone(), session_id, &order_id, "mock_timeout").await,
+            504
+        );
+        assert_eq!(stock_of(url.clone(), session_id, &item_id).await, 3);
+
+        // Only orders older than the window are expired, so the other tests'
+        // orders are left alone
+        let pool = connect_db().await;
+        let order_uuid = uuid::Uuid::parse_str(&order_id).unwrap();
+        sqlx::query(
+            r#"UPDATE "order" SET "order_date" = "order_date" - INTERVAL '2 hours' WHERE "order_id" = $1"#,
+        )
+        .bind(order_uuid)
+        .execute(&pool)
+        .await
+        .unwrap();
+        let expired =
+            crate::reservation::expire_unpaid_orders(&pool, std::time::Duration::from_secs(3600))
+                .await
+                .unwrap();
+        assert!(expired >= 1);
+        assert_eq!(
+            get_order_status(url.clone(), session_id, &order_id).await,
+            "cancelled"
+        );
+        assert_eq!(stock_of(url.clone(), session_id, &item_id).await, 5);
+        let restocked: Vec<(i32,)> = sqlx::query_as(
+            r#"SELECT "delta" FROM "inventory_movement"
+            WHERE "order_id" = $1 AND "reason" = 'cancellation'"#,
+        )
+        .bind(order_uuid)
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+        assert_eq!(restocked, [(2,)]);
+        assert_eq!(
+            pay(url.clone(), session_id, &order_id, "mock_success").await,
+            409
+        );
+
+        // The payment was given up on, a capture reported later is refunded
+        let payment: (uuid::Uuid, crate::PaymentStatus) =
+            sqlx::query_as(r#"SELECT "payment_id","status" FROM "payment" WHERE "order_id" = $1"#)
+                .bind(order_uuid)
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        assert_eq!(payment.1, crate::PaymentStatus::Failed);
+        let event = serde_json::json!({
+            "reference": format!("mock_{}", payment.0),
+            "idempotency_key": payment.0,
+            "event": "captured",
+        });
+        assert_eq!(send_webhook(url.clone(), event).await, 200);
+        let payment: (
+            crate::PaymentStatus,
+            rust_decimal::Decimal,
+            rust_decimal::Decimal,
+        ) = sqlx::query_as(
+            r#"SELECT "status","amount","refunded_amount" FROM "payment" WHERE "order_id" = $1"#,
+        )
+        .bind(order_uuid)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(payment.0, crate::PaymentStatus::Refunded);
+        assert_eq!(payment.1, payment.2);
+        assert_eq!(
+            get_order_status(url.clone(), session_id, &order_id).await,
+            "cancelled"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_34_held_stock_is_kept_for_the_cart_holding_it() {
+        let url = start_app_instance().await;
+        let client = reqwest::Client::new();
+        let (holder_name, holder) = sign_up_fresh(url.clone(), "holder").await;
+        let (_, latecomer) = sign_up_fresh(url.clone(), "latecomer").await;
+        let (idler, idle_session) = sign_up_fresh(url.clone(), "idler").await;
+        let add_to_cart = |session_id: String, variant_id: String| {
+            client
+                .post(format!("http://{}/cart/item", url))
+                .header("session_id", session_id)
+                .form(&[("variant_id", variant_id.as_str()), ("quantity", "1")])
+                .send()
+        };
+        let subcheckout = |session_id: String| {
+            client
+                .get(format!("http://{}/cart/subcheckout", url))
+                .header("session_id", session_id)
+                .send()
+        };
+
+        // The last unit is held for the first cart to check out
+        let (item_id, variant_id) = list_item(url.clone(), "Last unit", 1).await;
+        for session_id in [&holder, &latecomer] {
+            let res = add_to_cart(session_id.clone(), variant_id.clone())
+                .await
+                .unwrap();
+            assert_eq!(res.status(), reqwest::StatusCode::OK);
+        }
+        let res = subcheckout(holder.clone()).await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        let hold: serde_json::Value = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+        assert!(hold["reserved_until"].is_string());
+        let res = subcheckout(latecomer.clone()).await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::CONFLICT);
+        let cart: serde_json::Value = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+        assert_eq!(cart["items"][0]["variant_id"], variant_id.as_str());
+
+        // The hold becomes the order line, and the unit is gone for good
+        let holder_id = uuid::Uuid::parse_str(&holder).unwrap();
+        let order_id = order_variants(url.clone(), holder_id, &[]).await;
+        let lines: serde_json::Value = {
+            let res = client
+                .get(format!("http://{}/order/{}/status", url, order_id))
+                .header("session_id", holder.clone())
+                .send()
+                .await
+                .unwrap();
+            serde_json::from_str(&res.text().await.unwrap()).unwrap()
+        };
+        assert_eq!(lines["items"][0]["variant_id"], variant_id.as_str());
+        assert_eq!(lines["items"].as_array().unwrap().len(), 1);
+        let pool = connect_db().await;
+        let holds: Vec<(uuid::Uuid,)> =
+            sqlx::query_as(r#"SELECT "variant_id" FROM "stock_reservation" WHERE "cart_id" = $1"#)
+                .bind(user_id_of(&holder_name).await)
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+        assert!(holds.is_empty());
+        assert_eq!(stock_of(url.clone(), holder_id, &item_id).await, 0);
+
+        // A hold which has run out no longer keeps the unit from others
+        let (_, variant_id) = list_item(url.clone(), "Abandoned unit", 1).await;
+        let res = add_to_cart(idle_session.clone(), variant_id.clone())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        let res = subcheckout(idle_session.clone()).await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        let res = add_to_cart(latecomer.clone(), variant_id.clone())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::CONFLICT);
+
+        let idler_id = user_id_of(&idler).await;
+        sqlx::query(
+            r#"UPDATE "stock_reservation" SET "expires_at" = CURRENT_TIMESTAMP - INTERVAL '1 minute'
+            WHERE "cart_id" = $1"#,
+        )
+        .bind(idler_id)
+        .execute(&pool)
+        .await
+        .unwrap();
+        let res = add_to_cart(latecomer.clone(), variant_id.clone())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        let res = subcheckout(latecomer.clone()).await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+
+        assert!(
+            crate::reservation::release_expired_reservations(&pool)
+                .await
+                .unwrap()
+                >= 1
+        );
+        let holds: Vec<(uuid::Uuid,)> =
+            sqlx::query_as(r#"SELECT "variant_id" FROM "stock_reservation" WHERE "cart_id" = $1"#)
+                .bind(idler_id)
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+        assert!(holds.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_35_stock_moves_through_the_ledger() {
+        let url = start_app_instance().await;
+        let client = reqwest::Client::new();
+        let seller_session = get_session_id(url.clone()).await.to_string();
+        let (item_id, variant_id) = list_item(url.clone(), "Ledger item", 5).await;
+        let adjust = |delta: &'static str, reason: &'static str| {
+            client
+                .post(format!("http://{}/item/stock", url))
+                .header("session_id", seller_session.clone())
+                .form(&[
+                    ("variant_id", variant_id.as_str()),
+                    ("delta", delta),
+                    ("reason", reason),
+                ])
+                .send()
+        };
+
+        // Adjustments made at the same time all land
+        let tasks: Vec<_> = (0..10)
+            .map(|_| {
+                let request = adjust("1", "restock");
+                tokio::spawn(async move { request.await.unwrap().status() })
+            })
+            .collect();
+        for task in tasks {
+            assert_eq!(task.await.unwrap(), reqwest::StatusCode::CREATED);
+        }
+        let seller_id = uuid::Uuid::parse_str(&seller_session).unwrap();
+        assert_eq!(stock_of(url.clone(), seller_id, &item_id).await, 15);
+
+        // Stock never goes below zero
+        let res = adjust("-16", "correction").await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::CONFLICT);
+        let res = adjust("-15", "correction").await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::CREATED);
+        let stock: serde_json::Value = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+        assert_eq!(stock["quantity"], 0);
+
+        // Sales and cancellations are only recorded by orders
+        for (delta, reason) in [("-1", "sale"), ("1", "cancellation"), ("0", "restock")] {
+            let res = adjust(delta, reason).await.unwrap();
+            assert_eq!(res.status(), reqwest::StatusCode::UNPROCESSABLE_ENTITY);
+        }
+
+        // Pages of the history follow each other without gaps or repeats
+        let history = |params: Vec<(&'static str, String)>| {
+            client
+                .get(format!("http://{}/item/{}/stock/history", url, item_id))
+                .header("session_id", seller_session.clone())
+                .query(&params)
+                .send()
+        };
+        let mut movements: Vec<serde_json::Value> = Vec::new();
+        let mut cursor: Option<String> = None;
+        loop {
+            let mut params = vec![("take", "4".to_string())];
+            if let Some(cursor) = cursor.take() {
+                params.push(("cursor", cursor));
+            }
+            let res = history(params).await.unwrap();
+            assert_eq!(res.status(), reqwest::StatusCode::OK);
+            let page: serde_json::Value = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+            assert_eq!(page["total"], 12);
+            movements.extend(page["items"].as_array().unwrap().iter().cloned());
+            match page["next_cursor"].as_str() {
+                Some(next) => cursor = Some(next.to_string()),
+                None => break,
+            }
+        }
+        assert_eq!(movements.len(), 12);
+        let mut balances: Vec<i64> = movements
+            .iter()
+            .map(|movement| movement["balance_after"].as_i64().unwrap())
+            .collect();
+        assert_eq!((balances[0], balances[11]), (0, 5));
+        // Racing adjustments may take their balance in another order than their place
+        balances.sort();
+        assert_eq!(balances, [0, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15]);
+        assert_eq!(movements[0]["reason"], "correction");
+        assert_eq!(movements[11]["reason"], "restock");
+
+        let res = history(vec![
+            ("take", "4".to_string()),
+            ("page_no", "2".to_string()),
+        ])
+        .await
+        .unwrap();
+        let page: serde_json::Value = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+        assert_eq!(page["items"].as_array().unwrap()[..], movements[4..8]);
+    }
+
+    #[tokio::test]
+    async fn test_36_refreshed_sessions_keep_their_absolute_expiry() {
+        let url = start_app_instance().await;
+        let client = reqwest::Client::new();
+        let (username, _) = sign_up_fresh(url.clone(), "lingering").await;
+        let login = || {
+            client
+                .post(format!("http://{}/user/login", url))
+                .form(&[("username", username.as_str()), ("password", "test_pass")])
+                .send()
+        };
+        let refresh = |refresh_token: String| {
+            client
+                .post(format!("http://{}/user/sessions/refresh", url))
+                .form(&[("refresh_token", refresh_token)])
+                .send()
+        };
+        let pool = connect_db().await;
+        let limits = |session_id: uuid::Uuid| {
+            sqlx::query_as::<_, (chrono::NaiveDateTime, Option<chrono::NaiveDateTime>)>(
+                r#"SELECT "absolute_expiry","refresh_expires_at" FROM "session" WHERE "session_id" = $1"#,
+            )
+            .bind(session_id)
+            .fetch_one(&pool)
+        };
+
+        // The new session ends when the one it replaced would have
+        let session: crate::SessionResponse =
+            serde_json::from_str(&login().await.unwrap().text().await.unwrap()).unwrap();
+        let before = limits(session.detail.session_id).await.unwrap();
+        let res = refresh(session.detail.refresh_token).await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::CREATED);
+        let renewed: crate::SessionResponse =
+            serde_json::from_str(&res.text().await.unwrap()).unwrap();
+        assert_eq!(limits(renewed.detail.session_id).await.unwrap(), before);
+
+        // Once the absolute expiry passes, only logging in again helps
+        sqlx::query(
+            r#"UPDATE "session" SET
+            "absolute_expiry" = CURRENT_TIMESTAMP - INTERVAL '1 second',
+            "expiry" = CURRENT_TIMESTAMP - INTERVAL '1 second'
+            WHERE "session_id" = $1"#,
+        )
+        .bind(renewed.detail.session_id)
+        .execute(&pool)
+        .await
+        .unwrap();
+        let res = refresh(renewed.detail.refresh_token).await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::UNAUTHORIZED);
+        let res = login().await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn test_37_wrong_codes_count_as_failed_logins() {
+        use crate::two_factor::{base32_decode, time_step, totp_code};
+        let url = start_app_instance().await;
+        let client = reqwest::Client::new();
+        let (username, session_id) = sign_up_fresh(url.clone(), "guessed").await;
+        let res = client
+            .post(format!("http://{}/user/2fa/enroll", url))
+            .header("session_id", session_id.as_str())
+            .send()
+            .await
+            .unwrap();
+        let enrolment: crate::TotpEnrolment =
+            serde_json::from_str(&res.text().await.unwrap()).unwrap();
+        let secret = base32_decode(&enrolment.secret).unwrap();
+        let code =
+            |ahead: i64| totp_code(&secret, time_step(chrono::Utc::now().timestamp()) + ahead);
+        let res = client
+            .post(format!("http://{}/user/2fa/confirm", url))
+            .header("session_id", session_id.as_str())
+            .form(&[("code", code(0))])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+
+        let challenge = || async {
+            let res = client
+                .post(format!("http://{}/user/login", url))
+                .form(&[("username", username.as_str()), ("password", "test_pass")])
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(res.status(), reqwest::StatusCode::ACCEPTED);
+            let challenge: crate::ChallengeResponse =
+                serde_json::from_str(&res.text().await.unwrap()).unwrap();
+            challenge.detail.challenge_token
+        };
+        let verify = |challenge_token: String, code: String| {
+            client
+                .post(format!("http://{}/user/2fa/verify", url))
+                .form(&[("challenge_token", challenge_token), ("code", code)])
+                .send()
+        };
+        let pool = connect_db().await;
+        let user_id = user_id_of(&username).await;
+        let open_challenges = || {
+            sqlx::query_as::<_, (i64,)>(
+                r#"SELECT COUNT(*) FROM "login_challenge" WHERE "user_id" = $1"#,
+            )
+            .bind(user_id)
+            .fetch_one(&pool)
+        };
+
+        // Only the three newest challenges are kept
+        let oldest = challenge().await;
+        for _ in 0..3 {
+            challenge().await;
+        }
+        assert_eq!(open_challenges().await.unwrap().0, 3);
+        let res = verify(oldest, "000000".to_string()).await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+        // Expired challenges are thrown away by the next login
+        sqlx::query(
+            r#"UPDATE "login_challenge" SET "expires_at" = CURRENT_TIMESTAMP - INTERVAL '1 second'
+            WHERE "user_id" = $1"#,
+        )
+        .bind(user_id)
+        .execute(&pool)
+        .await
+        .unwrap();
+        let token = challenge().await;
+        assert_eq!(open_challenges().await.unwrap().0, 1);
+
+        // Giving the password again does not forget the wrong codes
+        for _ in 0..3 {
+            let res = verify(token.clone(), "000000".to_string()).await.unwrap();
+            assert_eq!(res.status(), reqwest::StatusCode::UNAUTHORIZED);
+        }
+        let token = challenge().await;
+        let res = verify(token.clone(), "000000".to_string()).await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::UNAUTHORIZED);
+        // Even the right code is turned away while locked out
+        let res = verify(token, code(1)).await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::TOO_MANY_REQUESTS);
+        assert!(res.headers().contains_key("retry-after"));
+        forget_failed_logins(&username).await;
+    }
+
+    #[tokio::test]
+    async fn test_38_payments_leave_out_cancelled_lines() {
+        let url = start_app_instance().await;
+        let (_, shirt_variant) = list_item(url.clone(), "Paid shirt", 5).await;
+        let (_, hat_variant) = list_item(url.clone(), "Unpaid hat", 5).await;
+        let buyer_session = get_buyer_session_id(url.clone()).await;
+        let order_id = order_variants(
+            url.clone(),
+            buyer_session,
+            &[(&shirt_variant, 2), (&hat_variant, 1)],
+        )
+        .await;
+        let pool = connect_db().await;
+        let payments = || {
+            sqlx::query_as::<_, (rust_decimal::Decimal, crate::PaymentStatus)>(
+                r#"SELECT "amount","status" FROM "payment" WHERE "order_id" = $1 ORDER BY "created_at""#,
+            )
+            .bind(uuid::Uuid::parse_str(&order_id).unwrap())
+            .fetch_all(&pool)
+        };
+
+        // The payment opened for the whole order is given up on once a line
+        // is cancelled, and the lines left are charged
+        assert_eq!(
+            pay(url.clone(), buyer_session, &order_id, "mock_timeout").await,
+            504
+        );
+        let hat_line = [
+            ("order_id", order_id.as_str()),
+            ("variant_id", hat_variant.as_str()),
+        ];
+        assert_eq!(cancel(url.clone(), buyer_session, &hat_line).await, 200);
+        assert_eq!(
+            pay(url.clone(), buyer_session, &order_id, "mock_success").await,
+            200
+        );
+        assert_eq!(
+            payments().await.unwrap(),
+            [
+                (
+                    rust_decimal::Decimal::new(3000, 2),
+                    crate::PaymentStatus::Failed
+                ),
+                (
+                    rust_decimal::Decimal::new(2000, 2),
+                    crate::PaymentStatus::Captured
+                )
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_39_search_highlights_escape_listing_markup() {
+        let url = start_app_instance().await;
+        let session_id = get_session_id(url.clone()).await;
+        let client = reqwest::Client::new();
+        let word = format!("glowlamp{}", uuid::Uuid::new_v4().simple());
+        let form = multipart::Form::new()
+            .text("title", format!("<b>Bright</b> {}", word))
+            .text(
+                "content",
+                format!("A {} <img src=x onerror=alert(1)> & its \"shade\"", word),
+            )
+            .text("price", "12");
+        let res = client
+            .post(format!("http://{}/item/create", url))
+            .header("session_id", session_id.to_string())
+            .multipart(form)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::CREATED);
+
+        let res = client
+            .get(format!("http://{}/item/search_suggestions", url))
+            .query(&[("query", word.as_str())])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+        let highlight = &body["keywords"][0]["highlight"];
+        let title = highlight["title"].as_str().unwrap();
+        assert_eq!(
+            title,
+            format!("&lt;b&gt;Bright&lt;/b&gt; <mark>{}</mark>", word)
+        );
+        let snippet = highlight["snippet"].as_str().unwrap();
+        assert!(snippet.contains(&format!("<mark>{}</mark>", word)));
+        assert!(snippet.contains("&lt;img src=x onerror=alert(1)&gt; &amp; its &quot;shade&quot;"));
+    }
+
+    #[tokio::test]
+    async fn test_40_sellers_see_the_status_of_their_own_lines() {
+        let url = start_app_instance().await;
+        let client = reqwest::Client::new();
+        let (_, own_variant) = list_item(url.clone(), "Shipped first", 5).await;
+        let (_, other_session) = sign_up_fresh(url.clone(), "other_seller").await;
+        let res = client
+            .post(format!("http://{}/user/seller", url))
+            .header("session_id", other_session.as_str())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        let other_session = uuid::Uuid::parse_str(&other_session).unwrap();
+        let (_, other_variant) = list_item_as(url.clone(), other_session, "Shipped later", 5).await;
+        let buyer_session = get_buyer_session_id(url.clone()).await;
+        let order_id = order_variants(
+            url.clone(),
+            buyer_session,
+            &[(&own_variant, 1), (&other_variant, 1)],
+        )
+        .await;
+        assert_eq!(
+            pay(url.clone(), buyer_session, &order_id, "mock_success").await,
+            200
+        );
+        let seller_session = get_session_id(url.clone()).await;
+        for status in ["packed", "shipped"] {
+            assert_eq!(
+                set_item_status(url.clone(), seller_session, &order_id, &own_variant, status).await,
+                200
+            );
+        }
+
+        // The buyer sees the order held up by the line still to be shipped,
+        // each seller only sees how far their own lines have got
+        assert_eq!(
+            get_order_status(url.clone(), buyer_session, &order_id).await,
+            "paid"
+        );
+        assert_eq!(
+            get_order_status(url.clone(), seller_session, &order_id).await,
+            "shipped"
+        );
+        assert_eq!(
+            get_order_status(url.clone(), other_session, &order_id).await,
+            "paid"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_41_configured_frontends_may_send_credentials() {
+        let url = start_app_instance().await;
+        let client = reqwest::Client::new();
+        let preflight = |origin: &'static str| {
+            client
+                .request(
+                    reqwest::Method::OPTIONS,
+                    format!("http://{}/user/seller", url),
+                )
+                .header("origin", origin)
+                .header("access-control-request-method", "POST")
+                .header("access-control-request-headers", "x-csrf-token,session_id")
+                .send()
+        };
+        let res = preflight("http://frontend.test").await.unwrap();
+        let headers = res.headers();
+        assert_eq!(
+            headers["access-control-allow-origin"],
+            "http://frontend.test"
+        );
+        assert_eq!(headers["access-control-allow-credentials"], "true");
+        let allowed = headers["access-control-allow-headers"].to_str().unwrap();
+        assert!(allowed.contains("x-csrf-token") && allowed.contains("session_id"));
+
+        // Other origins are not let in
+        let res = preflight("http://elsewhere.test").await.unwrap();
+        assert!(!res.headers().contains_key("access-control-allow-origin"));
+    }
+
+    #[test]
+    fn order_statuses_only_move_forward() {
+        use crate::order::{Actor, OrderStatus, OrderStatus::*};
+        assert!(PendingPayment.can_transition_to(Paid));
+        assert!(Paid.can_transition_to(Packed));
+        assert!(Packed.can_transition_to(Shipped));
+        assert!(Shipped.can_transition_to(Delivered));
+        assert!(Delivered.can_transition_to(Returned));
+        assert!(Packed.can_transition_to(Cancelled));
+        assert!(!Delivered.can_transition_to(Packed));
+        assert!(!PendingPayment.can_transition_to(Shipped));
+        assert!(!Shipped.can_transition_to(Cancelled));
+        assert!(!Cancelled.can_transition_to(Paid));
+        assert_eq!(OrderStatus::rollup([Shipped, Cancelled, Paid]), Paid);
+        assert_eq!(OrderStatus::rollup([Cancelled, Delivered]), Delivered);
+        assert_eq!(OrderStatus::rollup([Cancelled, Cancelled]), Cancelled);
+        for status in [
+            PendingPayment,
+            Placed,
+            Paid,
+            Packed,
+            Shipped,
+            Delivered,
+            Returned,
+            Cancelled,
+        ] {
+            assert!(!status.can_transition_to(status));
+        }
+
+        assert!(Actor::Seller.may_set(Packed));
+        assert!(Actor::Seller.may_set(Shipped));
+        assert!(!Actor::Seller.may_set(Delivered));
+        assert!(!Actor::Seller.may_set(Cancelled));
+        assert!(Actor::Buyer.may_set(Delivered));
+        assert!(Actor::Buyer.may_set(Returned));
+        assert!(!Actor::Buyer.may_set(Packed));
+    }
+
+    #[test]
+    fn lockouts_double_up_to_their_cap() {
+        use crate::lockout::lockout_secs;
+        assert_eq!(lockout_secs(5, 5, 30, 3600), 0);
+        assert_eq!(lockout_secs(6, 5, 30, 3600), 30);
+        assert_eq!(lockout_secs(7, 5, 30, 3600), 60);
+        assert_eq!(lockout_secs(12, 5, 30, 3600), 1920);
+        assert_eq!(lockout_secs(13, 5, 30, 3600), 3600);
+        assert_eq!(lockout_secs(i32::MAX, 5, 30, 3600), 3600);
+    }
+
+    #[test]
+    fn mailed_tokens_are_stored_by_their_hash() {
+        let (token, hash) = crate::user::new_token();
+        assert_eq!(hash, crate::user::token_hash(&token));
+        assert_eq!(hash.len(), 64);
+        assert!(!hash.contains(&token));
+        assert_ne!(crate::user::new_token().0, token);
+    }
+
+    #[test]
+    fn required_roles_are_documented() {
+        use utoipa::OpenApi;
+        let doc = serde_json::to_value(crate::ApiDoc::openapi()).unwrap();
+        let scopes = |path: &str, method: &str| doc["paths"][path][method]["security"].clone();
+        assert_eq!(
+            scopes("/category/create", "post"),
+            serde_json::json!([{ "session_id": ["admin"] }, { "session_cookie": ["admin"] }])
+        );
+        assert_eq!(
+            scopes("/item/create", "post"),
+            serde_json::json!([{ "session_id": ["seller"] }, { "session_cookie": ["seller"] }])
+        );
+        assert_eq!(
+            scopes("/order/status", "post"),
+            serde_json::json!([{ "session_id": ["buyer", "seller"] }, { "session_cookie": ["buyer", "seller"] }])
+        );
+        assert_eq!(
+            scopes("/admin/user/{user_id}/suspend", "post"),
+            serde_json::json!([{ "session_id": ["admin"] }, { "session_cookie": ["admin"] }])
+        );
+        assert_eq!(
+            scopes("/admin/item/{item_id}/hide", "post"),
+            serde_json::json!([{ "session_id": ["admin", "support"] }, { "session_cookie": ["admin", "support"] }])
+        );
+        assert_eq!(scopes("/item", "get"), serde_json::Value::Null);
+    }
+
+    /// The listings are built without a database, so these check the SQL
+    /// text itself: whatever the client sends, only the bound values change.
+    mod query_building {
+        use crate::item::{
+            autocomplete_input, facet_queries, paginate_comments, paginate_items, CommentQuery,
+            ItemsQuery,
+        };
+        use crate::listing::{Cursor, Page};
+        use proptest::prelude::*;
+
+        fn items_query(value: serde_json::Value) -> ItemsQuery {
+            serde_json::from_value(value).unwrap()
+        }
+
+        fn items_sql(value: serde_json::Value) -> Result<String, ()> {
+            paginate_items(items_query(value))
+                .map(|query| query.rows.sql().to_string())
+                .map_err(|_| ())
+        }
+
+        const HOSTILE: [&str; 6] = [
+            "'); DROP TABLE \"item\"; --",
+            "' OR '1'='1",
+            "$1; DELETE FROM \"item\"",
+            "\\'; SELECT pg_sleep(10); --",
+            "ORDER BY 1 LIMIT 1000000",
+            "\u{0}",
+        ];
+
+        #[test]
+        fn hostile_search_strings_do_not_reach_the_sql() {
+            let baseline = items_sql(serde_json::json!({ "search_string": "shirt" })).unwrap();
+            for token in HOSTILE {
+                let sql = items_sql(serde_json::json!({ "search_string": token })).unwrap();
+                assert_eq!(sql, baseline);
+                assert!(!sql.contains(token));
+            }
+        }
+
+        #[test]
+        fn order_by_comes_before_limit_and_offset() {
+            for filter in [
+                "Rating(Inc)",
+                "Rating(Dec)",
+                "DateOfCreation(Inc)",
+                "DateOfCreation(Dec)",
+                "Alphabetical(Inc)",
+                "Alphabetical(Dec)",
+                "Price(Inc)",
+                "Price(Dec)",
+                "Relevance",
+            ] {
+                let sql = items_sql(serde_json::json!({ "filter": filter, "take": 5 })).unwrap();
+                let order_by = sql.rfind("ORDER BY").unwrap();
+                let limit = sql.rfind("LIMIT").unwrap();
+                let offset = sql.rfind("OFFSET").unwrap();
+                assert!(order_by < limit && limit < offset, "{sql}");
+                assert!(!sql[order_by..].contains('5'), "{sql}");
+            }
+        }
+
+        #[test]
+        fn relevance_ranks_with_a_bound_search_string() {
+            for token in HOSTILE {
+                let sql = items_sql(serde_json::json!({
+                    "filter": "Relevance",
+                    "search_string": token,
+                }))
+                .unwrap();
+                let order_by = sql.rfind("ORDER BY").unwrap();
+                assert!(sql[..order_by].contains("ts_rank"), "{sql}");
+                assert!(sql[order_by..].contains(r#"t1."rank" DESC"#), "{sql}");
+                assert!(order_by < sql.rfind("LIMIT").unwrap(), "{sql}");
+                assert!(!sql.contains(token));
+            }
+        }
+
+        fn cursor(values: &[&str]) -> String {
+            use base64::Engine;
+            base64::engine::general_purpose::URL_SAFE_NO_PAD
+                .encode(serde_json::to_vec(values).unwrap())
+        }
+
+        #[test]
+        fn cursors_replace_the_offset() {
+            let token = cursor(&["19.99", "00000000-0000-0000-0000-000000000000"]);
+            assert_eq!(
+                Cursor::decode(&token).map_err(|_| ()).unwrap().encode(),
+                token
+            );
+            let sql = items_sql(serde_json::json!({
+                "filter": "Price(Dec)",
+                "page_no": 3,
+                "cursor": token,
+            }))
+            .unwrap();
+            let order_by = sql.rfind("ORDER BY").unwrap();
+            assert!(
+                sql[..order_by].contains(r#"(t1."price", t1."item_id") < ($"#),
+                "{sql}"
+            );
+            assert!(!sql.contains("OFFSET") && !sql.contains("19.99"), "{sql}");
+        }
+
+        #[test]
+        fn totals_count_every_page_of_the_same_rows() {
+            let query = |value: serde_json::Value| {
+                paginate_items(items_query(value)).map_err(|_| ()).unwrap()
+            };
+            let filters = serde_json::json!({
+                "filter": "Relevance",
+                "search_string": "shirt",
+                "min_price": "1",
+                "in_stock": true,
+                "page_no": 2,
+            });
+            let first = query(filters.clone());
+            let mut with_cursor = filters;
+            with_cursor["cursor"] = cursor(&["0.5", "00000000-0000-0000-0000-000000000000"]).into();
+            let next = query(with_cursor);
+            assert_eq!(first.total.sql(), next.total.sql());
+            let total = first.total.sql();
+            let rows = first.rows.sql();
+            assert!(total.starts_with("SELECT COUNT(*)"), "{total}");
+            assert!(
+                !total.contains("ORDER BY") && !total.contains("LIMIT"),
+                "{total}"
+            );
+            let from = rows.find(" FROM (").unwrap();
+            assert!(
+                rows[from..].starts_with(&total[total.find(" FROM (").unwrap()..]),
+                "{rows}"
+            );
+        }
+
+        #[test]
+        fn malformed_cursors_are_rejected() {
+            for token in [
+                "not a cursor".to_string(),
+                cursor(&[]),
+                cursor(&["19.99"]),
+                cursor(&["cheap", "00000000-0000-0000-0000-000000000000"]),
+                cursor(&["19.99", "' OR '1'='1"]),
+            ] {
+                assert!(items_sql(serde_json::json!({
+                    "filter": "Price(Dec)",
+                    "cursor": token,
+                }))
+                .is_err());
+            }
+        }
+
+        #[test]
+        fn page_sizes_are_clamped() {
+            assert_eq!(Page::new(None, None).take, 10);
+            assert_eq!(Page::new(Some(0), None).take, 1);
+            assert_eq!(Page::new(Some(100), None).take, 100);
+            assert_eq!(Page::new(Some(u32::MAX), Some(3)).take, 100);
+            assert_eq!(Page::new(Some(u32::MAX), Some(3)).offset, 200);
+        }
+
+        proptest! {
+            #[test]
+            fn cursor_values_never_change_the_sql(title in any::<String>(), item_id in any::<u128>()) {
+                let sql = |title: &str, item_id: uuid::Uuid| items_sql(serde_json::json!({
+                    "filter": "Alphabetical(Inc)",
+                    "cursor": cursor(&[title, &item_id.to_string()]),
+                }));
+                prop_assert_eq!(
+                    sql(&title, uuid::Uuid::from_u128(item_id)).unwrap(),
+                    sql("shirt", uuid::Uuid::nil()).unwrap()
+                );
+            }
+
+            #[test]
+            fn autocomplete_input_is_trimmed_and_capped(query in any::<String>()) {
+                if let Some(input) = autocomplete_input(&query) {
+                    prop_assert!(!input.is_empty() && input.chars().count() <= 64);
+                    prop_assert_eq!(input.trim(), input.as_str());
+                    prop_assert!(!input.chars().any(char::is_control));
+                    prop_assert!(!input.contains("  "));
+                }
+            }
+        }
+
+        proptest! {
+            #[test]
+            fn search_strings_never_change_the_sql(token in any::<String>()) {
+                let baseline = items_sql(serde_json::json!({ "search_string": "shirt" })).unwrap();
+                let sql = items_sql(serde_json::json!({ "search_string": token })).unwrap();
+                prop_assert_eq!(sql, baseline);
+            }
+
+            #[test]
+            fn categories_are_bound_or_rejected(
+                slug in any::<String>(),
+                token in proptest::option::of(any::<String>()),
+            ) {
+                let baseline = items_sql(serde_json::json!({
+                    "category": "shirts",
+                    "search_string": token.as_ref().map(|_| "shirt"),
+                }))
+                .unwrap();
+                if let Ok(sql) = items_sql(serde_json::json!({
+                    "category": slug,
+                    "search_string": token,
+                })) {
+                    prop_assert_eq!(sql, baseline);
+                }
+            }
+
+            #[test]
+            fn comment_listings_bind_the_item(
+                item_id in any::<u128>(),
+                take in proptest::option::of(any::<u32>()),
+                page_no in proptest::option::of(any::<u32>()),
+            ) {
+                let query = |item_id: uuid::Uuid| -> CommentQuery {
+                    serde_json::from_value(serde_json::json!({
+                        "item_id": item_id,
+                        "take": take,
+                        "page_no": page_no,
+                        "filter": "Rating(Dec)",
+                    }))
+                    .unwrap()
+                };
+                let baseline = paginate_comments(query(uuid::Uuid::nil())).map_err(|_| ()).unwrap();
+                let sql = paginate_comments(query(uuid::Uuid::from_u128(item_id))).map_err(|_| ()).unwrap();
+                prop_assert_eq!(sql.rows.sql(), baseline.rows.sql());
+                prop_assert_eq!(sql.total.sql(), baseline.total.sql());
+            }
+
+            #[test]
+            fn facet_queries_bind_every_filter(
+                token in any::<String>(),
+                min_price in 0..1_000_000i64,
+                min_rating in 0.0..=5.0f32,
+                seller_id in any::<u128>(),
+            ) {
+                let sql = |query: serde_json::Value| -> Vec<String> {
+                    facet_queries(&items_query(query))
+                        .map_err(|_| ())
+                        .unwrap()
+                        .iter()
+                        .map(|query| query.sql().to_string())
+                        .collect()
+                };
+                let baseline = sql(serde_json::json!({
+                    "search_string": "shirt",
+                    "min_price": "1",
+                    "min_rating": 1.0,
+                    "seller_id": uuid::Uuid::nil(),
+                    "in_stock": true,
+                }));
+                prop_assert_eq!(
+                    sql(serde_json::json!({
+                        "search_string": token,
+                        "min_price": rust_decimal::Decimal::new(min_price, 2).to_string(),
+                        "min_rating": min_rating,
+                        "seller_id": uuid::Uuid::from_u128(seller_id),
+                        "in_stock": true,
+                    })),
+                    baseline
+                );
+            }
+
+            #[test]
+            fn pages_do_not_overlap(take in 1..1000u32, page_no in 1..1000u32) {
+                let page = Page::new(Some(take), Some(page_no));
+                let next = Page::new(Some(take), Some(page_no + 1));
+                prop_assert_eq!(page.offset + page.take, next.offset);
+            }
+        }
+    }
 }
@@ -0,0 +1,601 @@
+use crate::{
+    auth::{session_created, AuthUser, ClientInfo},
+    errors::{ErrorResponse, MyError},
+    lockout::{clear_failures, locked_for, locked_out, record_failure},
+    user::{create_session, new_token, token_hash, GeneralResponse, SessionResponse},
+    AppState,
+};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Form, Json,
+};
+use chrono::{Duration, NaiveDateTime, Utc};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha1::Sha1;
+use sqlx::{FromRow, Postgres, Transaction};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+/// Seconds each code is valid for, as authenticator apps expect
+const STEP_SECS: i64 = 30;
+/// Digits in a code
+const DIGITS: u32 = 6;
+/// Recovery codes handed out when two-factor authentication is enabled
+const RECOVERY_CODES: usize = 10;
+/// Wrong codes a login challenge takes before it is thrown away
+const CHALLENGE_ATTEMPTS: i32 = 5;
+/// Login challenges a user may have open at once, the oldest make way
+const OPEN_CHALLENGES: i64 = 3;
+/// Issuer shown by authenticator apps next to the username
+const ISSUER: &str = "Sellorama";
+
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// RFC 4648 base32 without padding, which is how secrets are shown to users
+pub fn base32_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::new();
+    for chunk in bytes.chunks(5) {
+        let mut buffer = [0u8; 5];
+        buffer[..chunk.len()].copy_from_slice(chunk);
+        let bits = buffer
+            .iter()
+            .fold(0u64, |bits, byte| bits << 8 | *byte as u64);
+        let chars = (chunk.len() * 8).div_ceil(5);
+        for i in 0..chars {
+            let index = (bits >> (35 - i * 5)) & 0x1f;
+            encoded.push(BASE32_ALPHABET[index as usize] as char);
+        }
+    }
+    encoded
+}
+
+pub fn base32_decode(encoded: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let (mut bits, mut count) = (0u64, 0);
+    for c in encoded.trim_end_matches('=').chars() {
+        let value = BASE32_ALPHABET
+            .iter()
+            .position(|a| *a as char == c.to_ascii_uppercase())?;
+        bits = bits << 5 | value as u64;
+        count += 5;
+        if count >= 8 {
+            count -= 8;
+            bytes.push((bits >> count) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+/// The RFC 6238 code of `secret` for a time step
+pub fn totp_code(secret: &[u8], step: i64) -> String {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC takes keys of any size");
+    mac.update(&step.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([
+        digest[offset] & 0x7f,
+        digest[offset + 1],
+        digest[offset + 2],
+        digest[offset + 3],
+    ]);
+    format!(
+        "{:0width$}",
+        binary % 10u32.pow(DIGITS),
+        width = DIGITS as usize
+    )
+}
+
+/// The time step of a unix timestamp
+pub fn time_step(unix_secs: i64) -> i64 {
+    unix_secs.div_euclid(STEP_SECS)
+}
+
+/// Compares every byte whatever the first difference, so that the time taken
+/// does not tell how much of a guess was right
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// The step `code` is right for, allowing a step of clock drift either way
+pub fn matching_step(secret: &[u8], code: &str, step: i64) -> Option<i64> {
+    // Every step is compared, rather than stopping at the one which matches
+    (step - 1..=step + 1).fold(None, |found, step| {
+        match constant_time_eq(totp_code(secret, step).as_bytes(), code.as_bytes()) {
+            true => found.or(Some(step)),
+            false => found,
+        }
+    })
+}
+
+/// The URI authenticator apps are enrolled with, usually shown as a QR code
+pub fn otpauth_uri(account: &str, secret: &str) -> String {
+    let label = format!("{}:{}", ISSUER, account)
+        .chars()
+        .map(
+            |c| match c.is_ascii_alphanumeric() || matches!(c, ':' | '.' | '_' | '-') {
+                true => c.to_string(),
+                false => format!("%{:02X}", c as u32),
+            },
+        )
+        .collect::<String>();
+    format!(
+        "otpauth://totp/{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        label, secret, ISSUER, DIGITS, STEP_SECS
+    )
+}
+
+/// Recovery codes are compared without their dashes and case
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_uppercase())
+        .collect()
+}
+
+fn new_recovery_code() -> String {
+    let mut bytes = [0u8; 5];
+    OsRng.fill_bytes(&mut bytes);
+    let code = base32_encode(&bytes);
+    format!("{}-{}", &code[..4], &code[4..])
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct TotpEnrolment {
+    /// Base32 secret, for authenticator apps which cannot read the URI
+    pub secret: String,
+    pub otpauth_uri: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct TotpCodeForm {
+    /// Code shown by the authenticator app
+    code: String,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct RecoveryCodes {
+    /// Each logs in once in place of a code. They are only ever shown here.
+    pub recovery_codes: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct LoginChallenge {
+    /// Sent to `/user/2fa/verify` along with a code
+    pub challenge_token: String,
+    pub expires_at: NaiveDateTime,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct ChallengeResponse {
+    pub detail: LoginChallenge,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct ChallengeForm {
+    challenge_token: String,
+    /// Code shown by the authenticator app, or an unused recovery code
+    code: String,
+}
+
+#[derive(FromRow)]
+struct TotpSecret {
+    secret: String,
+    last_step: Option<i64>,
+    enabled: bool,
+}
+
+#[derive(FromRow)]
+struct Challenge {
+    user_id: Uuid,
+    username: String,
+    cookie: bool,
+}
+
+#[derive(FromRow)]
+struct RecoveryCode {
+    code_hash: String,
+}
+
+#[derive(FromRow)]
+struct Account {
+    username: String,
+}
+
+async fn totp_secret(
+    txn: &mut Transaction<'_, Postgres>,
+    user_id: Uuid,
+) -> Result<Option<TotpSecret>, MyError> {
+    let query = r#"
+        SELECT "secret","last_step","enabled_at" IS NOT NULL AS "enabled"
+        FROM "user_totp" WHERE "user_id" = $1 FOR UPDATE;
+    "#;
+    sqlx::query_as::<_, TotpSecret>(query)
+        .bind(user_id)
+        .fetch_optional(&mut **txn)
+        .await
+        .map_err(|_| MyError::InternalServerError)
+}
+
+/// Checks a code against the secret, taking each time step only once so that
+/// a code which has been seen cannot be replayed
+async fn accept_code(
+    txn: &mut Transaction<'_, Postgres>,
+    user_id: Uuid,
+    totp: &TotpSecret,
+    code: &str,
+) -> Result<bool, MyError> {
+    let secret = base32_decode(&totp.secret).ok_or(MyError::InternalServerError)?;
+    let step = match matching_step(&secret, code.trim(), time_step(Utc::now().timestamp())) {
+        Some(step) if totp.last_step.is_none_or(|last| step > last) => step,
+        _ => return Ok(false),
+    };
+    sqlx::query(r#"UPDATE "user_totp" SET "last_step" = $2 WHERE "user_id" = $1"#)
+        .bind(user_id)
+        .bind(step)
+        .execute(&mut **txn)
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    Ok(true)
+}
+
+/// Marks an unused recovery code of the user as used. The unused codes are
+/// compared here rather than looked up, so that every guess takes as long.
+async fn accept_recovery_code(
+    txn: &mut Transaction<'_, Postgres>,
+    user_id: Uuid,
+    code: &str,
+) -> Result<bool, MyError> {
+    let query = r#"
+        SELECT "code_hash" FROM "recovery_code"
+        WHERE "user_id" = $1 AND "used_at" IS NULL FOR UPDATE;
+    "#;
+    let unused = sqlx::query_as::<_, RecoveryCode>(query)
+        .bind(user_id)
+        .fetch_all(&mut **txn)
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    let guess = token_hash(&normalize_recovery_code(code));
+    let matched = unused.into_iter().fold(None, |found, unused| {
+        match constant_time_eq(unused.code_hash.as_bytes(), guess.as_bytes()) {
+            true => Some(unused),
+            false => found,
+        }
+    });
+    let Some(matched) = matched else {
+        return Ok(false);
+    };
+    sqlx::query(
+        r#"UPDATE "recovery_code" SET "used_at" = CURRENT_TIMESTAMP WHERE "code_hash" = $1"#,
+    )
+    .bind(matched.code_hash)
+    .execute(&mut **txn)
+    .await
+    .map_err(|_| MyError::InternalServerError)?;
+    Ok(true)
+}
+
+/// Answers a login whose password was right with a challenge when the user
+/// has two-factor authentication enabled, or None when a session can be made
+pub async fn login_challenge(
+    state: &AppState,
+    user_id: Uuid,
+    cookie: bool,
+) -> Result<Option<Response>, MyError> {
+    let query = r#"
+        SELECT 1 FROM "user_totp" WHERE "user_id" = $1 AND "enabled_at" IS NOT NULL;
+    "#;
+    let enabled = sqlx::query(query)
+        .bind(user_id)
+        .fetch_optional(&state.db_pool)
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    if enabled.is_none() {
+        return Ok(None);
+    }
+    let (challenge_token, challenge_hash) = new_token();
+    let ttl =
+        Duration::from_std(state.login_challenge_ttl).map_err(|_| MyError::InternalServerError)?;
+    let expires_at = Utc::now().naive_utc() + ttl;
+    let mut txn = state
+        .db_pool
+        .begin()
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    // Challenges which can no longer be answered are thrown away, along with
+    // the oldest of the user's beyond those it may have open
+    let query = r#"
+        DELETE FROM "login_challenge"
+        WHERE "expires_at" <= CURRENT_TIMESTAMP OR "attempts" >= $3
+        OR "token_hash" IN (
+            SELECT "token_hash" FROM "login_challenge" WHERE "user_id" = $1
+            ORDER BY "expires_at" DESC OFFSET $2 - 1
+        );
+    "#;
+    sqlx::query(query)
+        .bind(user_id)
+        .bind(OPEN_CHALLENGES)
+        .bind(CHALLENGE_ATTEMPTS)
+        .execute(&mut *txn)
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    let query = r#"
+        INSERT INTO "login_challenge" ("token_hash","user_id","cookie","expires_at")
+        VALUES ($1,$2,$3,$4);
+    "#;
+    sqlx::query(query)
+        .bind(challenge_hash)
+        .bind(user_id)
+        .bind(cookie)
+        .bind(expires_at)
+        .execute(&mut *txn)
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    txn.commit()
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    Ok(Some(
+        (
+            StatusCode::ACCEPTED,
+            Json(json!(ChallengeResponse {
+                detail: LoginChallenge {
+                    challenge_token,
+                    expires_at,
+                }
+            })),
+        )
+            .into_response(),
+    ))
+}
+
+#[utoipa::path(
+    post,
+    path = "/user/2fa/enroll",
+    security(
+        ("session_id" = [])
+    ),
+    responses(
+        (status = 200, body = TotpEnrolment),
+        (status = 401, body = ErrorResponse),
+        (status = 409, body = ErrorResponse),
+        (status = 500, body = ErrorResponse)
+    )
+)]
+/// Enroll Two-Factor Authentication
+///
+/// Endpoint to get a new TOTP secret for an authenticator app. It only takes
+/// effect once a code from the app is sent to `/user/2fa/confirm`, and
+/// enrolling again before that replaces the secret.
+pub async fn enroll_two_factor(
+    state: State<AppState>,
+    AuthUser(user): AuthUser,
+) -> Result<impl IntoResponse, MyError> {
+    let mut bytes = [0u8; 20];
+    OsRng.fill_bytes(&mut bytes);
+    let secret = base32_encode(&bytes);
+    let query = r#"
+        INSERT INTO "user_totp" ("user_id","secret") VALUES ($1,$2)
+        ON CONFLICT ("user_id") DO UPDATE
+        SET "secret" = EXCLUDED."secret", "last_step" = NULL, "created_at" = CURRENT_TIMESTAMP
+        WHERE "user_totp"."enabled_at" IS NULL
+        RETURNING "user_id";
+    "#;
+    sqlx::query(query)
+        .bind(user.user_id)
+        .bind(&secret)
+        .fetch_optional(&state.db_pool)
+        .await
+        .map_err(|_| MyError::InternalServerError)?
+        .ok_or(MyError::CustomError((
+            409,
+            "Two-factor authentication is already enabled".to_string(),
+        )))?;
+    let account =
+        sqlx::query_as::<_, Account>(r#"SELECT "username" FROM "user" WHERE "user_id" = $1"#)
+            .bind(user.user_id)
+            .fetch_one(&state.db_pool)
+            .await
+            .map_err(|_| MyError::InternalServerError)?;
+    Ok((
+        StatusCode::OK,
+        Json(json!(TotpEnrolment {
+            otpauth_uri: otpauth_uri(&account.username, &secret),
+            secret,
+        })),
+    ))
+}
+
+#[utoipa::path(
+    post,
+    path = "/user/2fa/confirm",
+    request_body(content = TotpCodeForm, content_type = "application/x-www-form-urlencoded"),
+    security(
+        ("session_id" = [])
+    ),
+    responses(
+        (status = 200, body = RecoveryCodes),
+        (status = 400, body = ErrorResponse),
+        (status = 401, body = ErrorResponse),
+        (status = 404, body = ErrorResponse),
+        (status = 409, body = ErrorResponse),
+        (status = 500, body = ErrorResponse)
+    )
+)]
+/// Confirm Two-Factor Authentication
+///
+/// Endpoint to enable two-factor authentication with a code from the
+/// authenticator app, which answers with the recovery codes of the user
+pub async fn confirm_two_factor(
+    state: State<AppState>,
+    AuthUser(user): AuthUser,
+    Form(form_data): Form<TotpCodeForm>,
+) -> Result<impl IntoResponse, MyError> {
+    let mut txn = state
+        .db_pool
+        .begin()
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    let totp = totp_secret(&mut txn, user.user_id)
+        .await?
+        .ok_or(MyError::CustomError((
+            404,
+            "Enroll in two-factor authentication first".to_string(),
+        )))?;
+    if totp.enabled {
+        return Err(MyError::CustomError((
+            409,
+            "Two-factor authentication is already enabled".to_string(),
+        )));
+    }
+    if !accept_code(&mut txn, user.user_id, &totp, &form_data.code).await? {
+        return Err(MyError::CustomError((400, "Code is invalid".to_string())));
+    }
+    sqlx::query(r#"UPDATE "user_totp" SET "enabled_at" = CURRENT_TIMESTAMP WHERE "user_id" = $1"#)
+        .bind(user.user_id)
+        .execute(&mut *txn)
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    sqlx::query(r#"DELETE FROM "recovery_code" WHERE "user_id" = $1"#)
+        .bind(user.user_id)
+        .execute(&mut *txn)
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    let recovery_codes = (0..RECOVERY_CODES)
+        .map(|_| new_recovery_code())
+        .collect::<Vec<_>>();
+    for code in &recovery_codes {
+        sqlx::query(r#"INSERT INTO "recovery_code" ("code_hash","user_id") VALUES ($1,$2)"#)
+            .bind(token_hash(&normalize_recovery_code(code)))
+            .bind(user.user_id)
+            .execute(&mut *txn)
+            .await
+            .map_err(|_| MyError::InternalServerError)?;
+    }
+    txn.commit()
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    Ok((
+        StatusCode::OK,
+        Json(json!(RecoveryCodes { recovery_codes })),
+    ))
+}
+
+#[utoipa::path(
+    post,
+    path = "/user/2fa/verify",
+    request_body(content = ChallengeForm, content_type = "application/x-www-form-urlencoded"),
+    responses(
+        (status = 201, body = SessionResponse),
+        (status = 401, body = ErrorResponse),
+        (status = 429, body = GeneralResponse, description = "Too many failed logins from the username or the IP"),
+        (status = 500, body = ErrorResponse)
+    )
+)]
+/// Verify Login Challenge
+///
+/// Endpoint to finish a login answered with a challenge, with a code from
+/// the authenticator app or a recovery code. A challenge takes five wrong
+/// codes before it has to be started again from `/user/login`, and a user
+/// has at most three open at once. Wrong codes count as failed logins of the
+/// username and the IP.
+pub async fn verify_two_factor(
+    state: State<AppState>,
+    client: ClientInfo,
+    Form(form_data): Form<ChallengeForm>,
+) -> Result<Response, MyError> {
+    let mut txn = state
+        .db_pool
+        .begin()
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    let query = r#"
+        SELECT c."user_id",u."username",c."cookie" FROM "login_challenge" c
+        INNER JOIN "user" u ON c."user_id" = u."user_id"
+        WHERE c."token_hash" = $1 AND c."expires_at" > CURRENT_TIMESTAMP
+        AND c."attempts" < $2 AND u."suspended_at" IS NULL
+        FOR UPDATE OF c;
+    "#;
+    let challenge_hash = token_hash(form_data.challenge_token.trim());
+    let challenge = sqlx::query_as::<_, Challenge>(query)
+        .bind(&challenge_hash)
+        .bind(CHALLENGE_ATTEMPTS)
+        .fetch_optional(&mut *txn)
+        .await
+        .map_err(|_| MyError::InternalServerError)?
+        .ok_or(MyError::CustomError((
+            401,
+            "Login challenge is invalid or expired".to_string(),
+        )))?;
+    let ip_address = client.ip_address.as_deref();
+    if let Some(retry_after) = locked_for(&state, &challenge.username, ip_address).await? {
+        return Ok(locked_out(retry_after));
+    }
+    let totp = totp_secret(&mut txn, challenge.user_id)
+        .await?
+        .filter(|totp| totp.enabled);
+    let accepted = match totp {
+        Some(totp) if form_data.code.trim().len() == DIGITS as usize => {
+            accept_code(&mut txn, challenge.user_id, &totp, &form_data.code).await?
+        }
+        Some(_) => accept_recovery_code(&mut txn, challenge.user_id, &form_data.code).await?,
+        // Two-factor authentication was reset since the challenge was handed out
+        None => false,
+    };
+    let query = match accepted {
+        true => r#"DELETE FROM "login_challenge" WHERE "token_hash" = $1"#,
+        false => {
+            r#"UPDATE "login_challenge" SET "attempts" = "attempts" + 1 WHERE "token_hash" = $1"#
+        }
+    };
+    sqlx::query(query)
+        .bind(&challenge_hash)
+        .execute(&mut *txn)
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    txn.commit()
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    if !accepted {
+        record_failure(&state, &challenge.username, ip_address).await?;
+        return Err(MyError::CustomError((401, "Code is invalid".to_string())));
+    }
+    clear_failures(&state, &challenge.username).await?;
+    let session = create_session(&state, challenge.user_id, &client)
+        .await
+        .ok_or(MyError::InternalServerError)?;
+    Ok(session_created(&state, session, challenge.cookie))
+}
+
+/// Turns off two-factor authentication for a user who lost their
+/// authenticator app and recovery codes, in the transaction recording it
+pub async fn reset_two_factor(
+    txn: &mut Transaction<'_, Postgres>,
+    user_id: Uuid,
+) -> Result<(), MyError> {
+    let reset = sqlx::query(r#"DELETE FROM "user_totp" WHERE "user_id" = $1"#)
+        .bind(user_id)
+        .execute(&mut **txn)
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    if reset.rows_affected() == 0 {
+        return Err(MyError::CustomError((
+            409,
+            "Two-factor authentication is not enabled".to_string(),
+        )));
+    }
+    for query in [
+        r#"DELETE FROM "recovery_code" WHERE "user_id" = $1"#,
+        r#"DELETE FROM "login_challenge" WHERE "user_id" = $1"#,
+    ] {
+        sqlx::query(query)
+            .bind(user_id)
+            .execute(&mut **txn)
+            .await
+            .map_err(|_| MyError::InternalServerError)?;
+    }
+    Ok(())
+}
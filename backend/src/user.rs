@@ -1,24 +1,39 @@
-use crate::errors::MyError;
+use crate::auth::{
+    check_csrf, clear_session_cookies, cookie, grant_role, session_created, user_roles, AuthUser,
+    ClientInfo, Role, CSRF_HEADER, REFRESH_COOKIE, SESSION_COOKIE,
+};
+use crate::errors::{ErrorResponse, MyError};
+use crate::listing::{fetch_page, Cursor, Listing, Page, PageQuery, Paginated};
+use crate::lockout::{clear_failures, locked_for, locked_out, record_failure};
+use crate::mail::{Mail, MailError};
+use crate::order::{OrderStatus, NEWEST_ORDERS, ORDER_LINES};
+use crate::two_factor::{login_challenge, ChallengeResponse};
 use crate::AppState;
 use crate::Duration;
 use argon2::PasswordHash;
 use argon2::{
-    password_hash::{rand_core::OsRng, PasswordHasher, PasswordVerifier, SaltString},
+    password_hash::{
+        rand_core::{OsRng, RngCore},
+        PasswordHasher, PasswordVerifier, SaltString,
+    },
     Argon2,
 };
 use axum::extract::Query;
 use axum::{
-    extract::{Path, State},
+    extract::{OriginalUri, Path, State},
     http::{HeaderMap, StatusCode},
-    response::IntoResponse,
+    response::{IntoResponse, Response},
     Form, Json,
 };
 
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
 use chrono::{NaiveDateTime, Utc};
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
+use sha2::{Digest, Sha256};
 use sqlx::{prelude::FromRow, types::chrono, Pool, Postgres};
+use std::sync::LazyLock;
 use utoipa::IntoParams;
 use utoipa::ToSchema;
 use uuid::Uuid;
@@ -30,22 +45,28 @@ pub struct User {
     email_id: String,
     date_created: chrono::NaiveDateTime,
     post_count: i32,
+    email_verified: bool,
 }
 #[derive(Deserialize, Serialize, ToSchema)]
 pub struct UserLogin {
     username: String,
     #[schema(value_type = String, format = Password)]
     password: String,
+    /// Keep the session in HttpOnly cookies rather than handing it out
+    #[serde(default)]
+    cookie: bool,
 }
 
 #[derive(FromRow)]
 pub struct UserCreds {
     user_id: Uuid,
     hashed_pass: String,
+    suspended: bool,
 }
 #[derive(Deserialize, Serialize, ToSchema, FromRow)]
 pub struct Session {
-    pub session_id: Uuid,
+    /// Left out to log out of the session kept in cookies
+    pub session_id: Option<Uuid>,
 }
 
 #[derive(FromRow, ToSchema, Serialize)]
@@ -53,16 +74,26 @@ pub struct MyOrderDetails {
     order_id: Uuid,
     order_date: NaiveDateTime,
     item_id: Uuid,
-    dispatched: bool,
+    variant_id: Uuid,
+    quantity: i32,
+    title: String,
+    unit_price: rust_decimal::Decimal,
+    line_total: rust_decimal::Decimal,
+    subtotal: rust_decimal::Decimal,
+    total: rust_decimal::Decimal,
+    status: OrderStatus,
 }
 
 #[derive(Deserialize, Serialize, ToSchema, IntoParams)]
 pub struct MyOrderQuery {
+    /// Page number to fetch, ignored when a cursor is given
     page_no: Option<u32>,
     take: Option<u32>,
-    dispatched: Option<bool>,
+    /// `next_cursor` of the previous page, to fetch the page after it
+    cursor: Option<String>,
+    status: Option<OrderStatus>,
 }
-#[derive(Deserialize, Serialize, ToSchema, FromRow, Debug)]
+#[derive(Clone, Deserialize, Serialize, ToSchema, FromRow, Debug)]
 pub struct UserWithSession {
     pub session_id: Uuid,
     pub user_id: Uuid,
@@ -73,12 +104,23 @@ pub struct CreateUserForm {
     username: String,
     email_id: String,
     password: String,
+    /// Keep the session in HttpOnly cookies rather than handing it out
+    #[serde(default)]
+    cookie: bool,
 }
 #[derive(FromRow, Serialize)]
 pub struct UserId {
     user_id: Uuid,
 }
 
+/// The limits a session traded for a new one hands on to it
+#[derive(FromRow)]
+struct RefreshedSession {
+    user_id: Uuid,
+    absolute_expiry: NaiveDateTime,
+    refresh_expires_at: NaiveDateTime,
+}
+
 #[derive(ToSchema, Serialize)]
 pub struct GeneralResponse {
     pub detail: String,
@@ -89,9 +131,74 @@ pub struct UserResponse {
     detail: User,
 }
 
+/// A session just created, along with the refresh token which renews it
+#[derive(ToSchema, Serialize, Deserialize, FromRow)]
+pub struct NewSession {
+    pub session_id: Uuid,
+    /// Until when the session can be used, pushed back by every request
+    pub expiry: NaiveDateTime,
+    /// Trades the session for a new one at `/user/sessions/refresh`, even
+    /// after it has expired. It is only given out once.
+    #[sqlx(default)]
+    pub refresh_token: String,
+    /// Only of use to sessions kept in cookies, which are told it apart
+    #[serde(skip)]
+    #[sqlx(default)]
+    pub csrf_token: String,
+}
+
 #[derive(ToSchema, Serialize, Deserialize)]
 pub struct SessionResponse {
-    pub detail: Session,
+    pub detail: NewSession,
+}
+
+#[derive(Deserialize, Serialize, ToSchema)]
+pub struct RefreshForm {
+    /// Left out when the refresh token is kept in cookies
+    refresh_token: Option<String>,
+}
+
+#[derive(ToSchema, Serialize, Deserialize)]
+pub struct RolesResponse {
+    pub roles: Vec<Role>,
+}
+
+/// A session of the user, as listed by `/user/sessions`
+#[derive(Serialize, ToSchema, FromRow)]
+pub struct SessionInfo {
+    session_id: Uuid,
+    created_at: NaiveDateTime,
+    last_seen_at: NaiveDateTime,
+    expiry: NaiveDateTime,
+    user_agent: Option<String>,
+    ip_address: Option<String>,
+    /// Whether this is the session the request was sent with
+    current: bool,
+}
+
+#[derive(Deserialize, Serialize, ToSchema)]
+pub struct VerifyEmailForm {
+    /// Token mailed on signup or by `/user/email/resend`
+    token: String,
+}
+
+#[derive(FromRow)]
+struct LastMailed {
+    /// seconds since the last verification mail, null if none was sent
+    elapsed: Option<i64>,
+}
+
+#[derive(Deserialize, Serialize, ToSchema)]
+pub struct ResetRequestForm {
+    email_id: String,
+}
+
+#[derive(Deserialize, Serialize, ToSchema)]
+pub struct ResetConfirmForm {
+    /// Token mailed by `/user/password/reset`
+    token: String,
+    #[schema(value_type = String, format = Password)]
+    password: String,
 }
 
 #[derive(FromRow, ToSchema, Serialize, Deserialize)]
@@ -118,15 +225,17 @@ pub struct AddressId {
     )]
 pub async fn signup(
     state: State<AppState>,
+    client: ClientInfo,
     Form(form_data): Form<CreateUserForm>,
-) -> impl IntoResponse {
+) -> Response {
     if form_data.password.len() < 6 {
         return (
             StatusCode::UNPROCESSABLE_ENTITY,
             Json(json!(GeneralResponse {
                 detail: "Password must be atleast 6 characters long".to_string()
             })),
-        );
+        )
+            .into_response();
     }
     let (username, email_id, db_pool, password) = (
         &form_data.username,
@@ -144,7 +253,8 @@ pub async fn signup(
             Json(json!(GeneralResponse {
                 detail: "Invalid Email Id".to_string()
             })),
-        );
+        )
+            .into_response();
     }
     let check_query = r#"
         SELECT * FROM "user" WHERE "username" = $1 OR "email_id" = $2;
@@ -171,7 +281,8 @@ pub async fn signup(
             Json(json!(GeneralResponse {
                 detail: "user or email_id exists".to_string()
             })),
-        ),
+        )
+            .into_response(),
         None => match sqlx::query_as::<_, UserId>(insert_query)
             .bind(&username)
             .bind(&email_id)
@@ -181,26 +292,22 @@ pub async fn signup(
             .expect("Server Error")
         {
             Some(user) => {
-                match create_session(
-                    db_pool,
-                    user.user_id,
-                    Utc::now().naive_utc() + Duration::days(1),
-                )
-                .await
+                // The account is usable straight away, only listing items waits for it
+                if send_verification(&state, user.user_id, email_id)
+                    .await
+                    .is_err()
                 {
-                    Some(session) => {
-                        println!("Session {} created", session.session_id);
-                        (
-                            StatusCode::CREATED,
-                            Json(json!(SessionResponse { detail: session })),
-                        )
-                    }
+                    println!("Error mailing the email verification token");
+                }
+                match create_session(&state, user.user_id, &client).await {
+                    Some(session) => session_created(&state, session, form_data.cookie),
                     None => (
                         StatusCode::INTERNAL_SERVER_ERROR,
                         Json(json!(GeneralResponse {
                             detail: "Error Creating Session".to_string()
                         })),
-                    ),
+                    )
+                        .into_response(),
                 }
             }
             None => (
@@ -208,7 +315,8 @@ pub async fn signup(
                 Json(json!(GeneralResponse {
                     detail: "Internal Server Error".to_string()
                 })),
-            ),
+            )
+                .into_response(),
         },
     }
 }
@@ -250,14 +358,29 @@ pub async fn get_user_by_id(
         path = "/user/login",
         responses(
             (status = 201, body=SessionResponse),
-            (status = 401, body=GeneralResponse)
+            (status = 202, body=ChallengeResponse, description = "Two-factor authentication is enabled, finish at /user/2fa/verify"),
+            (status = 401, body=GeneralResponse),
+            (status = 403, body=GeneralResponse),
+            (status = 429, body=GeneralResponse, description = "Too many failed logins from the username or the IP")
         )
     )]
+/// Login
+///
+/// Endpoint to log in. Failed logins are counted by username and by IP, and
+/// too many of them lock either out for a while, doubling with every failure.
 pub async fn user_login(
     state: State<AppState>,
+    client: ClientInfo,
     Form(form_data): Form<UserLogin>,
-) -> impl IntoResponse {
+) -> Response {
     let (username, password) = (form_data.username, form_data.password);
+    let ip_address = client.ip_address.as_deref();
+    // Locked out logins are turned away before the costly password check
+    match locked_for(&state, &username, ip_address).await {
+        Ok(Some(retry_after)) => return locked_out(retry_after),
+        Ok(None) => (),
+        Err(e) => return e.into_response(),
+    }
     invalidate_dangling_sessions(&state.db_pool)
         .await
         .expect("Error Deleting invalid sessions");
@@ -266,83 +389,123 @@ pub async fn user_login(
                 SELECT "user_id" FROM "user"
                 WHERE "username" = $1 
             )
-            SELECT "user_id","hashed_pass" FROM "password"
-            WHERE "user_id" in (SELECT * FROM INS)
+            SELECT p."user_id",p."hashed_pass",u."suspended_at" IS NOT NULL AS "suspended"
+            FROM "password" p INNER JOIN "user" u ON p."user_id" = u."user_id"
+            WHERE p."user_id" in (SELECT * FROM INS)
         "#;
 
-    match sqlx::query_as::<_, UserCreds>(query)
-        .bind(username)
-        .bind(&password)
-        .fetch_one(&state.db_pool)
+    let user = match sqlx::query_as::<_, UserCreds>(query)
+        .bind(&username)
+        .fetch_optional(&state.db_pool)
         .await
     {
-        Ok(user) => match validate_password(&password, user.hashed_pass) {
-            Ok(_) => {
-                match create_session(
-                    &state.db_pool,
-                    user.user_id,
-                    Utc::now().naive_utc() + Duration::days(1),
-                )
-                .await
-                {
-                    Some(session) => {
-                        println!("Session {} created", session.session_id);
-                        (
-                            StatusCode::CREATED,
-                            Json(json!(SessionResponse { detail: session })),
-                        )
-                    }
-                    None => (
-                        StatusCode::INTERNAL_SERVER_ERROR,
-                        Json(json!(GeneralResponse {
-                            detail: "Error Creating Session".to_string()
-                        })),
-                    ),
-                }
+        Ok(user) => user,
+        Err(_) => return MyError::InternalServerError.into_response(),
+    };
+    // Unknown usernames take as long as wrong passwords, and get the same answer
+    let verified = match &user {
+        Some(user) => validate_password(&password, user.hashed_pass.clone()).is_ok(),
+        None => {
+            let _ = validate_password(&password, UNKNOWN_USER_HASH.clone());
+            false
+        }
+    };
+    let user = match user {
+        Some(user) if verified => user,
+        _ => {
+            if let Err(e) = record_failure(&state, &username, ip_address).await {
+                return e.into_response();
             }
-            Err(_e) => (
+            return (
                 StatusCode::UNAUTHORIZED,
                 Json(json!(GeneralResponse {
                     detail: "Wrong username or password".to_string()
                 })),
-            ),
-        },
-        Err(_e) => (
-            StatusCode::UNAUTHORIZED,
+            )
+                .into_response();
+        }
+    };
+    if user.suspended {
+        return (
+            StatusCode::FORBIDDEN,
             Json(json!(GeneralResponse {
-                detail: "Invalid Username or password".to_string()
+                detail: "User is suspended".to_string()
             })),
-        ),
+        )
+            .into_response();
+    }
+    match login_challenge(&state, user.user_id, form_data.cookie).await {
+        Ok(Some(challenge)) => challenge,
+        // Failures are kept until the code is given too, or logging in again
+        // would take away the lockout from guessing codes
+        Ok(None) => {
+            if let Err(e) = clear_failures(&state, &username).await {
+                return e.into_response();
+            }
+            match create_session(&state, user.user_id, &client).await {
+                Some(session) => session_created(&state, session, form_data.cookie),
+                None => (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(json!(GeneralResponse {
+                        detail: "Error Creating Session".to_string()
+                    })),
+                )
+                    .into_response(),
+            }
+        }
+        Err(e) => e.into_response(),
     }
 }
 
 #[utoipa::path(
         post,
         path = "/user/logout",
+        request_body(content = Session, content_type = "application/x-www-form-urlencoded"),
         responses(
-            (status = 201, body=SessionResponse),
-            (status = 401, body=GeneralResponse)
+            (status = 200, body=GeneralResponse),
+            (status = 400, body=ErrorResponse),
+            (status = 403, body=ErrorResponse)
         )
     )]
-pub async fn logout(state: State<AppState>, Form(form_data): Form<Session>) -> impl IntoResponse {
-    match invalidate_session(&state.db_pool, form_data.session_id).await {
+pub async fn logout(
+    state: State<AppState>,
+    headers: HeaderMap,
+    Form(form_data): Form<Session>,
+) -> Result<Response, MyError> {
+    let (session_id, from_cookie) = match form_data.session_id {
+        Some(session_id) => (session_id, false),
+        None => {
+            let session_id = cookie(&headers, SESSION_COOKIE)
+                .and_then(|value| Uuid::parse_str(value).ok())
+                .ok_or(MyError::CustomError((
+                    400,
+                    "Session id is missing".to_string(),
+                )))?;
+            check_csrf(&state, &headers, session_id).await?;
+            (session_id, true)
+        }
+    };
+    match invalidate_session(&state.db_pool, session_id).await {
         Ok(t) => {
             println!("Session {t} deleted");
-            (
+            let mut response = (
                 StatusCode::OK,
                 Json(json!(GeneralResponse {
                     detail: "User logged out".to_string()
                 })),
             )
+                .into_response();
+            if from_cookie {
+                clear_session_cookies(response.headers_mut());
+            }
+            Ok(response)
         }
         Err(e) => {
             println!("Error: {e}");
-            (
-                StatusCode::BAD_REQUEST,
-                Json(json!(GeneralResponse {
-                    detail: "Error while logging out".to_string()
-                })),
-            )
+            Err(MyError::CustomError((
+                400,
+                "Error while logging out".to_string(),
+            )))
         }
     }
 }
@@ -351,45 +514,40 @@ pub async fn logout(state: State<AppState>, Form(form_data): Form<Session>) -> i
     post,
     path = "/user/address",
     security(
-        ("session_id"=[])
+        ("session_id" = ["buyer"])
     ),
     responses(
         (status =200 ,body = GeneralResponse),
         (status =500 ,body = GeneralResponse),
         (status =401 ,body = GeneralResponse),
+        (status = 403, body = ErrorResponse),
     )
 )]
 /// Create User Address
 ///
 /// Endpoint to create a new address for the user
 pub async fn create_user_address(
-    headers: HeaderMap,
     state: State<AppState>,
+    AuthUser(user): AuthUser,
     Form(form_data): Form<Address>,
 ) -> Result<impl IntoResponse, MyError> {
-    let session_id = extract_session_header(headers).await?;
-    match check_session_validity(&state.db_pool, session_id).await {
-        Some(user) => {
-            let query = r#"
-                INSERT INTO "address" ("user_id","address_line_1", "address_line_2", "city", "country", "pincode")
-                VALUES ($1, $2, $3, $4, $5, $6)
-                RETURNING "address_id";
-            "#;
-            match sqlx::query_as::<_, AddressId>(query)
-                .bind(user.user_id)
-                .bind(form_data.address_line_1)
-                .bind(form_data.address_line_2)
-                .bind(form_data.city)
-                .bind(form_data.country)
-                .bind(form_data.pincode)
-                .fetch_one(&state.db_pool)
-                .await
-                .map_err(|_| MyError::InternalServerError)?
-            {
-                response => Ok((StatusCode::OK, Json(json!(response)))),
-            }
-        }
-        None => Err(MyError::UnauthorizedError),
+    let query = r#"
+        INSERT INTO "address" ("user_id","address_line_1", "address_line_2", "city", "country", "pincode")
+        VALUES ($1, $2, $3, $4, $5, $6)
+        RETURNING "address_id";
+    "#;
+    match sqlx::query_as::<_, AddressId>(query)
+        .bind(user.user_id)
+        .bind(form_data.address_line_1)
+        .bind(form_data.address_line_2)
+        .bind(form_data.city)
+        .bind(form_data.country)
+        .bind(form_data.pincode)
+        .fetch_one(&state.db_pool)
+        .await
+        .map_err(|_| MyError::InternalServerError)?
+    {
+        response => Ok((StatusCode::OK, Json(json!(response)))),
     }
 }
 
@@ -400,11 +558,13 @@ pub async fn create_user_address(
         MyOrderQuery
     ),
     security(
-        ("session_id" = [])
+        ("session_id" = ["buyer"])
     ),
     responses(
-        (status = 200, body = Vec<MyOrderDetails>),
+        (status = 200, body = Paginated<MyOrderDetails>),
         (status = 401, body = GeneralResponse),
+        (status = 403, body = ErrorResponse),
+        (status = 422, body = ErrorResponse),
         (status = 500, body = GeneralResponse)
     )
 )]
@@ -412,25 +572,556 @@ pub async fn create_user_address(
 ///
 /// Endpoint to get all the orders placed by the user
 pub async fn get_user_orders(
-    headers: HeaderMap,
     state: State<AppState>,
+    AuthUser(user): AuthUser,
+    OriginalUri(uri): OriginalUri,
     Query(form_data): Query<MyOrderQuery>,
 ) -> Result<impl IntoResponse, MyError> {
-    let session_id = extract_session_header(headers).await?;
-    match check_session_validity(&state.db_pool, session_id).await {
-        Some(user) => {
-            let query = paginate_orders(form_data);
-            match sqlx::query_as::<_, MyOrderDetails>(query.as_str())
-                .bind(user.user_id)
-                .fetch_all(&state.db_pool)
-                .await
-                .map_err(|_| MyError::InternalServerError)?
-            {
-                response => Ok((StatusCode::OK, Json(json!(response)))),
-            }
+    let query = paginate_orders(form_data, user.user_id)?;
+    let response = fetch_page::<MyOrderDetails>(query, &uri, &state.db_pool).await?;
+    Ok((StatusCode::OK, Json(json!(response))))
+}
+
+#[utoipa::path(
+    get,
+    path = "/user/roles",
+    security(
+        ("session_id" = [])
+    ),
+    responses(
+        (status = 200, body = RolesResponse),
+        (status = 401, body = ErrorResponse),
+        (status = 500, body = ErrorResponse)
+    )
+)]
+/// Get User Roles
+///
+/// Endpoint to get the roles held by the user
+pub async fn get_roles(
+    state: State<AppState>,
+    AuthUser(user): AuthUser,
+) -> Result<impl IntoResponse, MyError> {
+    let roles = user_roles(&state.db_pool, user.user_id).await?;
+    Ok((StatusCode::OK, Json(json!(RolesResponse { roles }))))
+}
+
+#[utoipa::path(
+    post,
+    path = "/user/seller",
+    security(
+        ("session_id" = [])
+    ),
+    responses(
+        (status = 200, body = RolesResponse),
+        (status = 401, body = ErrorResponse),
+        (status = 500, body = ErrorResponse)
+    )
+)]
+/// Become Seller
+///
+/// Endpoint for the user to start selling, which grants the seller role
+pub async fn become_seller(
+    state: State<AppState>,
+    AuthUser(user): AuthUser,
+) -> Result<impl IntoResponse, MyError> {
+    grant_role(&state.db_pool, user.user_id, Role::Seller).await?;
+    let roles = user_roles(&state.db_pool, user.user_id).await?;
+    Ok((StatusCode::OK, Json(json!(RolesResponse { roles }))))
+}
+
+#[utoipa::path(
+    post,
+    path = "/user/password/reset",
+    request_body(content = ResetRequestForm, content_type = "application/x-www-form-urlencoded"),
+    responses(
+        (status = 202, body = GeneralResponse),
+        (status = 500, body = ErrorResponse)
+    )
+)]
+/// Request Password Reset
+///
+/// Endpoint to mail a single use token for resetting the password of the
+/// account with the email id. The response is the same whether or not there is
+/// such an account.
+pub async fn request_password_reset(
+    state: State<AppState>,
+    Form(form_data): Form<ResetRequestForm>,
+) -> Result<impl IntoResponse, MyError> {
+    let accepted = (
+        StatusCode::ACCEPTED,
+        Json(json!(GeneralResponse {
+            detail: "If the email id belongs to an account, a reset token has been mailed to it"
+                .to_string()
+        })),
+    );
+    let user = sqlx::query_as::<_, UserId>(r#"SELECT "user_id" FROM "user" WHERE "email_id" = $1"#)
+        .bind(&form_data.email_id)
+        .fetch_optional(&state.db_pool)
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    let Some(user) = user else {
+        return Ok(accepted);
+    };
+    let (token, token_hash) = new_token();
+    let ttl =
+        Duration::from_std(state.password_reset_ttl).map_err(|_| MyError::InternalServerError)?;
+    let mut txn = state
+        .db_pool
+        .begin()
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    // A new token replaces the ones the user has not used
+    sqlx::query(r#"DELETE FROM "password_reset" WHERE "user_id" = $1 AND "used_at" IS NULL"#)
+        .bind(user.user_id)
+        .execute(&mut *txn)
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    let query = r#"
+        INSERT INTO "password_reset" ("token_hash","user_id","expires_at") VALUES ($1,$2,$3);
+    "#;
+    sqlx::query(query)
+        .bind(token_hash)
+        .bind(user.user_id)
+        .bind(Utc::now().naive_utc() + ttl)
+        .execute(&mut *txn)
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    txn.commit()
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    let mail = Mail {
+        to: form_data.email_id,
+        subject: "Reset your Sellorama password".to_string(),
+        body: format!(
+            "Someone asked to reset the password of your Sellorama account.\n\n\
+            Token: {}\n\n\
+            It can be used once in the next {} minutes. If it was not you, ignore this mail.",
+            token,
+            ttl.num_minutes()
+        ),
+    };
+    if let Err(MailError::Delivery(reason)) = state.mail_sender.send(mail).await {
+        println!("Error mailing a password reset token: {reason}");
+    }
+    Ok(accepted)
+}
+
+#[utoipa::path(
+    post,
+    path = "/user/password/confirm",
+    request_body(content = ResetConfirmForm, content_type = "application/x-www-form-urlencoded"),
+    responses(
+        (status = 200, body = GeneralResponse),
+        (status = 400, body = ErrorResponse),
+        (status = 422, body = GeneralResponse),
+        (status = 500, body = ErrorResponse)
+    )
+)]
+/// Confirm Password Reset
+///
+/// Endpoint to set a new password with a token mailed by `/user/password/reset`.
+/// Every session of the user is ended, so they have to log in again.
+pub async fn confirm_password_reset(
+    state: State<AppState>,
+    Form(form_data): Form<ResetConfirmForm>,
+) -> Result<impl IntoResponse, MyError> {
+    if form_data.password.len() < 6 {
+        return Err(MyError::CustomError((
+            422,
+            "Password must be atleast 6 characters long".to_string(),
+        )));
+    }
+    let mut txn = state
+        .db_pool
+        .begin()
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    let query = r#"
+        UPDATE "password_reset" SET "used_at" = CURRENT_TIMESTAMP
+        WHERE "token_hash" = $1 AND "used_at" IS NULL AND "expires_at" > CURRENT_TIMESTAMP
+        RETURNING "user_id";
+    "#;
+    let user = sqlx::query_as::<_, UserId>(query)
+        .bind(token_hash(form_data.token.trim()))
+        .fetch_optional(&mut *txn)
+        .await
+        .map_err(|_| MyError::InternalServerError)?
+        .ok_or(MyError::CustomError((
+            400,
+            "Reset token is invalid, used or expired".to_string(),
+        )))?;
+    sqlx::query(r#"UPDATE "password" SET "hashed_pass" = $2 WHERE "user_id" = $1"#)
+        .bind(user.user_id)
+        .bind(create_hashed_password(form_data.password))
+        .execute(&mut *txn)
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    sqlx::query(r#"DELETE FROM "session" WHERE "user_id" = $1"#)
+        .bind(user.user_id)
+        .execute(&mut *txn)
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    txn.commit()
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    Ok((
+        StatusCode::OK,
+        Json(json!(GeneralResponse {
+            detail: "Password reset, log in with the new password".to_string()
+        })),
+    ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/user/sessions",
+    security(
+        ("session_id" = [])
+    ),
+    responses(
+        (status = 200, body = Vec<SessionInfo>),
+        (status = 401, body = ErrorResponse),
+        (status = 500, body = ErrorResponse)
+    )
+)]
+/// Get User Sessions
+///
+/// Endpoint to list the sessions of the user which can still be used or
+/// refreshed, most recently used first
+pub async fn get_sessions(
+    state: State<AppState>,
+    AuthUser(user): AuthUser,
+) -> Result<impl IntoResponse, MyError> {
+    let query = r#"
+        SELECT "session_id","created_at","last_seen_at","expiry","user_agent","ip_address",
+        "session_id" = $2 AS "current"
+        FROM "session" WHERE "user_id" = $1
+        AND ("expiry" > CURRENT_TIMESTAMP OR "refresh_expires_at" > CURRENT_TIMESTAMP)
+        ORDER BY "last_seen_at" DESC, "created_at" DESC;
+    "#;
+    let sessions = sqlx::query_as::<_, SessionInfo>(query)
+        .bind(user.user_id)
+        .bind(user.session_id)
+        .fetch_all(&state.db_pool)
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    Ok((StatusCode::OK, Json(json!(sessions))))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/user/sessions/{session_id}",
+    params(
+        ("session_id" = Uuid, Path, description = "Session to revoke")
+    ),
+    security(
+        ("session_id" = [])
+    ),
+    responses(
+        (status = 200, body = GeneralResponse),
+        (status = 401, body = ErrorResponse),
+        (status = 404, body = ErrorResponse),
+        (status = 500, body = ErrorResponse)
+    )
+)]
+/// Revoke Session
+///
+/// Endpoint to sign the user out of one of their sessions, which may be the
+/// one the request is sent with
+pub async fn revoke_session(
+    state: State<AppState>,
+    AuthUser(user): AuthUser,
+    Path(session_id): Path<Uuid>,
+) -> Result<impl IntoResponse, MyError> {
+    let revoked =
+        sqlx::query(r#"DELETE FROM "session" WHERE "session_id" = $1 AND "user_id" = $2"#)
+            .bind(session_id)
+            .bind(user.user_id)
+            .execute(&state.db_pool)
+            .await
+            .map_err(|_| MyError::InternalServerError)?;
+    if revoked.rows_affected() == 0 {
+        return Err(MyError::CustomError((404, "Session not found".to_string())));
+    }
+    Ok((
+        StatusCode::OK,
+        Json(json!(GeneralResponse {
+            detail: "Session revoked".to_string()
+        })),
+    ))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/user/sessions",
+    security(
+        ("session_id" = [])
+    ),
+    responses(
+        (status = 200, body = GeneralResponse),
+        (status = 401, body = ErrorResponse),
+        (status = 500, body = ErrorResponse)
+    )
+)]
+/// Revoke Other Sessions
+///
+/// Endpoint to sign the user out everywhere except the session the request
+/// is sent with
+pub async fn revoke_other_sessions(
+    state: State<AppState>,
+    AuthUser(user): AuthUser,
+) -> Result<impl IntoResponse, MyError> {
+    let revoked = invalidate_sessions_user(&state.db_pool, user.user_id, user.session_id).await?;
+    Ok((
+        StatusCode::OK,
+        Json(json!(GeneralResponse {
+            detail: format!("{} other sessions revoked", revoked)
+        })),
+    ))
+}
+
+#[utoipa::path(
+    post,
+    path = "/user/sessions/refresh",
+    request_body(content = RefreshForm, content_type = "application/x-www-form-urlencoded"),
+    responses(
+        (status = 201, body = SessionResponse),
+        (status = 401, body = ErrorResponse),
+        (status = 403, body = ErrorResponse),
+        (status = 500, body = ErrorResponse)
+    )
+)]
+/// Refresh Session
+///
+/// Endpoint to trade a refresh token for a new session and refresh token,
+/// without logging in again. The old session and refresh token stop working,
+/// and the new ones expire when they would have, so that sessions still end
+/// at their absolute expiry.
+/// Browsers holding the session in cookies send the form empty, along with
+/// the `x-csrf-token` header.
+pub async fn refresh_session(
+    state: State<AppState>,
+    client: ClientInfo,
+    headers: HeaderMap,
+    Form(form_data): Form<RefreshForm>,
+) -> Result<Response, MyError> {
+    // A refresh token kept in cookies is only taken along with the CSRF token
+    // of its session, and the new session is kept in cookies as well
+    let (refresh_token, csrf_hash) = match form_data.refresh_token {
+        Some(refresh_token) => (refresh_token, None),
+        None => {
+            let refresh_token = cookie(&headers, REFRESH_COOKIE).ok_or(MyError::CustomError((
+                401,
+                "Refresh token is missing".to_string(),
+            )))?;
+            let csrf_token = headers
+                .get(CSRF_HEADER)
+                .and_then(|value| value.to_str().ok())
+                .ok_or(MyError::CustomError((
+                    403,
+                    "CSRF token is missing".to_string(),
+                )))?;
+            (
+                refresh_token.to_string(),
+                Some(token_hash(csrf_token.trim())),
+            )
         }
-        None => Err(MyError::UnauthorizedError),
+    };
+    let from_cookie = csrf_hash.is_some();
+    let query = r#"
+        DELETE FROM "session" s USING "user" u
+        WHERE s."refresh_hash" = $1 AND s."refresh_expires_at" > CURRENT_TIMESTAMP
+        AND s."absolute_expiry" > CURRENT_TIMESTAMP
+        AND ($2::VARCHAR IS NULL OR s."csrf_hash" = $2)
+        AND u."user_id" = s."user_id" AND u."suspended_at" IS NULL
+        RETURNING s."user_id", s."absolute_expiry", s."refresh_expires_at";
+    "#;
+    let refreshed = sqlx::query_as::<_, RefreshedSession>(query)
+        .bind(token_hash(refresh_token.trim()))
+        .bind(csrf_hash)
+        .fetch_optional(&state.db_pool)
+        .await
+        .map_err(|_| MyError::InternalServerError)?
+        .ok_or(MyError::CustomError((
+            401,
+            "Refresh token is invalid or expired".to_string(),
+        )))?;
+    let session = insert_session(
+        &state,
+        refreshed.user_id,
+        &client,
+        refreshed.absolute_expiry,
+        refreshed.refresh_expires_at,
+    )
+    .await
+    .ok_or(MyError::InternalServerError)?;
+    Ok(session_created(&state, session, from_cookie))
+}
+
+/// Mails the user a token which verifies `email_id` as theirs
+async fn send_verification(state: &AppState, user_id: Uuid, email_id: &str) -> Result<(), MyError> {
+    let (token, token_hash) = new_token();
+    let ttl = Duration::from_std(state.email_verification_ttl)
+        .map_err(|_| MyError::InternalServerError)?;
+    let query = r#"
+        INSERT INTO "email_verification" ("token_hash","user_id","email_id","expires_at")
+        VALUES ($1,$2,$3,$4);
+    "#;
+    sqlx::query(query)
+        .bind(token_hash)
+        .bind(user_id)
+        .bind(email_id)
+        .bind(Utc::now().naive_utc() + ttl)
+        .execute(&state.db_pool)
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    let mail = Mail {
+        to: email_id.to_string(),
+        subject: "Verify your Sellorama email id".to_string(),
+        body: format!(
+            "Welcome to Sellorama! Verify your email id to start listing items.\n\n\
+            Token: {}\n\n\
+            It can be used in the next {} hours.",
+            token,
+            ttl.num_hours()
+        ),
+    };
+    match state.mail_sender.send(mail).await {
+        Ok(()) => Ok(()),
+        Err(MailError::Delivery(reason)) => {
+            println!("Error mailing an email verification token: {reason}");
+            Err(MyError::InternalServerError)
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/user/email/verify",
+    request_body(content = VerifyEmailForm, content_type = "application/x-www-form-urlencoded"),
+    responses(
+        (status = 200, body = GeneralResponse),
+        (status = 400, body = ErrorResponse),
+        (status = 500, body = ErrorResponse)
+    )
+)]
+/// Verify Email Id
+///
+/// Endpoint to verify the email id of an account with a mailed token. A token
+/// no longer verifies anything once the user changes their email id.
+pub async fn verify_email(
+    state: State<AppState>,
+    Form(form_data): Form<VerifyEmailForm>,
+) -> Result<impl IntoResponse, MyError> {
+    let mut txn = state
+        .db_pool
+        .begin()
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    let query = r#"
+        UPDATE "email_verification" v SET "used_at" = CURRENT_TIMESTAMP
+        FROM "user" u
+        WHERE v."token_hash" = $1 AND v."used_at" IS NULL AND v."expires_at" > CURRENT_TIMESTAMP
+        AND u."user_id" = v."user_id" AND u."email_id" = v."email_id"
+        RETURNING v."user_id";
+    "#;
+    let user = sqlx::query_as::<_, UserId>(query)
+        .bind(token_hash(form_data.token.trim()))
+        .fetch_optional(&mut *txn)
+        .await
+        .map_err(|_| MyError::InternalServerError)?
+        .ok_or(MyError::CustomError((
+            400,
+            "Verification token is invalid, used or expired".to_string(),
+        )))?;
+    sqlx::query(r#"UPDATE "user" SET "email_verified" = TRUE WHERE "user_id" = $1"#)
+        .bind(user.user_id)
+        .execute(&mut *txn)
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    txn.commit()
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    Ok((
+        StatusCode::OK,
+        Json(json!(GeneralResponse {
+            detail: "Email id verified".to_string()
+        })),
+    ))
+}
+
+#[utoipa::path(
+    post,
+    path = "/user/email/resend",
+    security(
+        ("session_id" = [])
+    ),
+    responses(
+        (status = 202, body = GeneralResponse),
+        (status = 401, body = ErrorResponse),
+        (status = 409, body = ErrorResponse),
+        (status = 429, body = ErrorResponse),
+        (status = 500, body = ErrorResponse)
+    )
+)]
+/// Resend Verification Mail
+///
+/// Endpoint to mail the user a new token for verifying their email id. Mails
+/// are sent at most once every `EMAIL_RESEND_INTERVAL_SECS`.
+pub async fn resend_verification(
+    state: State<AppState>,
+    AuthUser(user): AuthUser,
+) -> Result<impl IntoResponse, MyError> {
+    let account = sqlx::query_as::<_, User>(r#"SELECT * FROM "user" WHERE "user_id" = $1"#)
+        .bind(user.user_id)
+        .fetch_one(&state.db_pool)
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    if account.email_verified {
+        return Err(MyError::CustomError((
+            409,
+            "Email id is already verified".to_string(),
+        )));
+    }
+    let query = r#"
+        SELECT EXTRACT(EPOCH FROM CURRENT_TIMESTAMP - MAX("created_at"))::BIGINT AS "elapsed"
+        FROM "email_verification" WHERE "user_id" = $1;
+    "#;
+    let last = sqlx::query_as::<_, LastMailed>(query)
+        .bind(user.user_id)
+        .fetch_one(&state.db_pool)
+        .await
+        .map_err(|_| MyError::InternalServerError)?;
+    let interval = i64::try_from(state.email_resend_interval.as_secs()).unwrap_or(i64::MAX);
+    if let Some(elapsed) = last.elapsed.filter(|elapsed| *elapsed < interval) {
+        return Err(MyError::CustomError((
+            429,
+            format!(
+                "Wait {} seconds before asking for another mail",
+                interval - elapsed
+            ),
+        )));
     }
+    send_verification(&state, user.user_id, &account.email_id).await?;
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(json!(GeneralResponse {
+            detail: "Verification mail sent".to_string()
+        })),
+    ))
+}
+
+/// A random token to mail to a user, along with the hash it is stored by
+pub fn new_token() -> (String, String) {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    let token = URL_SAFE_NO_PAD.encode(bytes);
+    let hash = token_hash(&token);
+    (token, hash)
+}
+
+pub fn token_hash(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
 }
 
 fn create_hashed_password(password: String) -> String {
@@ -442,6 +1133,11 @@ fn create_hashed_password(password: String) -> String {
     password_hash
 }
 
+/// Hash checked against when the username does not exist, so that the
+/// answer takes as long as for a wrong password
+static UNKNOWN_USER_HASH: LazyLock<String> =
+    LazyLock::new(|| create_hashed_password("unknown user".to_string()));
+
 fn validate_password(password: &String, hashed_password: String) -> Result<(), ()> {
     let password_ref = PasswordHash::new(&hashed_password.as_str()).unwrap();
     match Argon2::default().verify_password(password.as_bytes(), &password_ref) {
@@ -450,48 +1146,82 @@ fn validate_password(password: &String, hashed_password: String) -> Result<(), (
     }
 }
 
-async fn create_session(
-    pool: &Pool<Postgres>,
+pub async fn create_session(
+    state: &AppState,
     user_id: Uuid,
-    expiry: NaiveDateTime,
-) -> Option<Session> {
+    client: &ClientInfo,
+) -> Option<NewSession> {
+    let now = Utc::now().naive_utc();
+    let absolute_expiry = now + Duration::from_std(state.session_absolute_timeout).ok()?;
+    let refresh_expires_at = now + Duration::from_std(state.refresh_token_ttl).ok()?;
+    insert_session(state, user_id, client, absolute_expiry, refresh_expires_at).await
+}
+
+/// Starts a session which lasts until `absolute_expiry` at the latest and
+/// whose refresh token works until `refresh_expires_at`
+async fn insert_session(
+    state: &AppState,
+    user_id: Uuid,
+    client: &ClientInfo,
+    absolute_expiry: NaiveDateTime,
+    refresh_expires_at: NaiveDateTime,
+) -> Option<NewSession> {
+    let (refresh_token, refresh_hash) = new_token();
+    let (csrf_token, csrf_hash) = new_token();
+    let now = Utc::now().naive_utc();
+    let expiry = absolute_expiry.min(now + Duration::from_std(state.session_idle_timeout).ok()?);
     let query = r#"
-            INSERT INTO "session" ("user_id","expiry") VALUES ($1,$2) RETURNING "session_id";
+            INSERT INTO "session" (
+                "user_id","expiry","absolute_expiry","refresh_hash","refresh_expires_at",
+                "csrf_hash","user_agent","ip_address"
+            )
+            VALUES ($1,$2,$3,$4,$5,$6,$7,$8) RETURNING "session_id","expiry";
         "#;
 
-    match sqlx::query_as::<_, Session>(query)
+    match sqlx::query_as::<_, NewSession>(query)
         .bind(user_id)
         .bind(expiry)
-        .fetch_optional(pool)
+        .bind(absolute_expiry)
+        .bind(refresh_hash)
+        .bind(refresh_expires_at)
+        .bind(csrf_hash)
+        .bind(&client.user_agent)
+        .bind(&client.ip_address)
+        .fetch_optional(&state.db_pool)
         .await
         .expect("Error accessing database")
     {
-        Some(response) => Some(Session {
-            session_id: response.session_id,
+        Some(session) => Some(NewSession {
+            refresh_token,
+            csrf_token,
+            ..session
         }),
         None => None,
     }
 }
 
-#[allow(dead_code)]
-async fn invalidate_sessions_user(pool: &Pool<Postgres>, user_id: Uuid) -> Result<String, String> {
-    match sqlx::query!(
-        r#"DELETE FROM "session" WHERE "user_id" = $1 AND expiry < CURRENT_TIMESTAMP"#,
-        user_id
-    )
-    .execute(pool)
-    .await
-    {
-        Ok(_) => Ok(format!("all sessions for {} deleted", user_id)),
-        Err(_) => Err(String::from("Error Deleting session")),
-    }
+/// Ends every session of the user except `keep`, returning how many were ended
+async fn invalidate_sessions_user(
+    pool: &Pool<Postgres>,
+    user_id: Uuid,
+    keep: Uuid,
+) -> Result<u64, MyError> {
+    sqlx::query(r#"DELETE FROM "session" WHERE "user_id" = $1 AND "session_id" <> $2"#)
+        .bind(user_id)
+        .bind(keep)
+        .execute(pool)
+        .await
+        .map(|result| result.rows_affected())
+        .map_err(|_| MyError::InternalServerError)
 }
 
+/// Removes the sessions which can neither be used nor refreshed any more
 async fn invalidate_dangling_sessions(pool: &Pool<Postgres>) -> Result<String, String> {
-    match sqlx::query!(r#"DELETE FROM "session" where  "expiry" < CURRENT_TIMESTAMP"#)
-        .execute(pool)
-        .await
-    {
+    let query = r#"
+        DELETE FROM "session" WHERE "expiry" < CURRENT_TIMESTAMP
+        AND ("refresh_expires_at" IS NULL OR "refresh_expires_at" < CURRENT_TIMESTAMP)
+    "#;
+    match sqlx::query(query).execute(pool).await {
         Ok(_) => Ok(format!("Dangling sessions removed")),
         Err(e) => Err(format!("Error :{}", e)),
     }
@@ -510,79 +1240,58 @@ pub async fn invalidate_session(pool: &Pool<Postgres>, session_id: Uuid) -> Resu
     }
 }
 
+/// The user behind a session which has not expired. Using a session pushes
+/// its expiry back by the idle timeout, though at most once every
+/// `session_renew_interval` so that requests do not all write to it.
 pub async fn check_session_validity(
-    pool: &Pool<Postgres>,
+    state: &AppState,
     session_id: Uuid,
-) -> Option<UserWithSession> {
+) -> Result<Option<UserWithSession>, MyError> {
     let query = r#"
-            SELECT "user_id","session_id" FROM "session" 
-            WHERE 
-            "session_id" = $1 AND "expiry" > CURRENT_TIMESTAMP;
+            WITH valid AS (
+                SELECT s."user_id",s."session_id",s."last_seen_at" FROM "session" s
+                INNER JOIN "user" u ON s."user_id" = u."user_id"
+                WHERE
+                s."session_id" = $1 AND s."expiry" > CURRENT_TIMESTAMP
+                AND u."suspended_at" IS NULL
+            ), seen AS (
+                UPDATE "session" SET "last_seen_at" = CURRENT_TIMESTAMP,
+                "expiry" = LEAST(CURRENT_TIMESTAMP + make_interval(secs => $2), "absolute_expiry")
+                WHERE "session_id" IN (
+                    SELECT "session_id" FROM valid
+                    WHERE "last_seen_at" <= CURRENT_TIMESTAMP - make_interval(secs => $3)
+                )
+            )
+            SELECT "user_id","session_id" FROM valid;
         "#;
-    match sqlx::query_as::<_, UserWithSession>(query)
+    sqlx::query_as::<_, UserWithSession>(query)
         .bind(session_id)
-        .fetch_optional(pool)
+        .bind(state.session_idle_timeout.as_secs_f64())
+        .bind(state.session_renew_interval.as_secs_f64())
+        .fetch_optional(&state.db_pool)
         .await
-        .expect("Error accessing database")
-    {
-        Some(response) => Some(UserWithSession {
-            session_id: response.session_id,
-            user_id: response.user_id,
-        }),
-        None => None,
-    }
-}
-
-pub async fn extract_session_header(headers: HeaderMap) -> Result<uuid::Uuid, MyError> {
-    let session;
-    match headers.get("session_id") {
-        Some(session_id) => session = session_id,
-        None => return Err(MyError::UnauthorizedError),
-    }
-    let session_id = uuid::Uuid::parse_str(session.to_str().unwrap()).unwrap();
-    Ok(session_id)
+        .map_err(|_| MyError::InternalServerError)
 }
 
-fn paginate_orders(pagination: MyOrderQuery) -> String {
-    struct PaginationParams {
-        take: u32,
-        offset: u32,
-        dispatched: String,
-    }
-    // Default values
-    let mut query_params = PaginationParams {
-        take: 10,
-        offset: 0,
-        dispatched: "".to_string(),
+/// Order lines of the orders placed by the buyer
+pub fn paginate_orders(
+    pagination: MyOrderQuery,
+    buyer_id: Uuid,
+) -> Result<PageQuery<'static>, MyError> {
+    let cursor = match &pagination.cursor {
+        Some(token) => Some(Cursor::decode(token)?),
+        None => None,
     };
-    // Set values from pagination
-    match pagination.take {
-        Some(take) => query_params.take = take,
-        None => query_params.take = 10,
-    }
-    match pagination.page_no {
-        Some(page_no) => {
-            query_params.offset = if page_no > 0 {
-                (page_no - 1) * query_params.take
-            } else {
-                0
-            }
-        }
-        None => query_params.offset = 0,
-    }
-    match pagination.dispatched {
-        Some(dispatched) => {
-            query_params.dispatched = format!(r#"WHERE "dispatched" = {}"#, dispatched)
-        }
-        None => query_params.dispatched = "".to_string(),
+    let mut listing = Listing::keyset(
+        &NEWEST_ORDERS,
+        r#"t1."order_id",t1."item_id",t1."variant_id",t1."quantity",t1."title",t1."unit_price",t1."line_total",t2."subtotal",t2."total",t2."order_date",t2."address_id",t1."status""#,
+    );
+    listing
+        .push(ORDER_LINES)
+        .condition(r#"t2."user_id" = "#)
+        .bind(buyer_id);
+    if let Some(status) = pagination.status {
+        listing.condition(r#"t1."status" = "#).bind(status);
     }
-    format!(
-        r#"SELECT t1."order_id",t1."item_id",t1."quantity",t2."order_date",t2."address_id",t1."dispatched" FROM 
-        (SELECT * from "order_items" ) as t1 
-        INNER JOIN
-        (SELECT * FROM "order" WHERE "user_id" = $1 ) as t2
-        ON t1."order_id" = t2."order_id"
-        {} ORDER BY t2."order_date" DESC LIMIT {} OFFSET {};"#,
-        query_params.dispatched, query_params.take, query_params.offset
-    )
+    listing.paginate(Page::new(pagination.take, pagination.page_no), cursor)
 }